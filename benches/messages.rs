@@ -0,0 +1,168 @@
+//! The hot path of every connection: parsing what clients send and preparing what they get.
+//! Run with `cargo bench`, and compare against a baseline with `--save-baseline`/`--baseline`.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ie_net::messages::client_command::ClientCommand;
+use ie_net::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use ie_net::messages::login_server::WelcomeServerMessage;
+use ie_net::messages::raw_command::try_parse_raw_command;
+use ie_net::messages::server_messages::{
+    JoinGameMessage, NewGameMessage, SendMessage, SyncStatsMessage, UserJoinedMessage,
+};
+use ie_net::messages::{ServerMessage, WireMessage};
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+use uuid::Uuid;
+
+/// Version 2.2
+fn game_version() -> Uuid {
+    Uuid::parse_str("534ba248-a87c-4ce9-8bee-bc376aae6134").unwrap()
+}
+
+fn raw_command(c: &mut Criterion) {
+    let mut group = c.benchmark_group("raw_command");
+    let commands: [(&str, &[u8]); 3] = [
+        ("chat", b"/send \"gg, that rush came out of nowhere\""),
+        (
+            "host",
+            b"/plays \"2v2 no rush\" \"534ba248-a87c-4ce9-8bee-bc376aae6134\"",
+        ),
+        ("pong", b"/pong"),
+    ];
+    for (name, command) in commands.iter() {
+        group.bench_function(*name, |b| {
+            b.iter(|| try_parse_raw_command(black_box(command)))
+        });
+    }
+    // what the client handler does with every command it receives
+    group.bench_function("client_command", |b| {
+        b.iter_batched(
+            || BytesMut::from(&b"/send \"gg, that rush came out of nowhere\"\0/pong\0"[..]),
+            |mut received| {
+                while let Ok(Some(command)) = ClientCommand::try_parse(&mut received) {
+                    black_box(command);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn login_messages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("login_messages");
+    let ident = IdentClientMessage {
+        game_version: game_version(),
+        language: b"English".to_vec(),
+        extra_bytes: 0,
+    }
+    .prepare_message()
+    .unwrap();
+    let ident = BytesMut::from(&ident[..]);
+    let login = LoginClientMessage {
+        username: b"Oscar".to_vec(),
+        password: b"correct horse battery staple".to_vec(),
+        extra_bytes: 0,
+    }
+    .prepare_message()
+    .unwrap();
+    let login = BytesMut::from(&login[..]);
+    group.bench_function("ident", |b| {
+        b.iter_batched(
+            || ident.clone(),
+            |mut received| IdentClientMessage::try_parse(&mut received),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("login", |b| {
+        b.iter_batched(
+            || login.clone(),
+            |mut received| LoginClientMessage::try_parse(&mut received),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn prepare_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("prepare_message");
+    let messages: Vec<(&str, ServerMessage)> = vec![
+        (
+            "send",
+            SendMessage {
+                username: "Oscar".to_string(),
+                message: b"gg, that rush came out of nowhere".to_vec(),
+            }
+            .into(),
+        ),
+        (
+            "user_joined",
+            UserJoinedMessage {
+                username: "Oscar".to_string(),
+                version_idx: 0,
+                origin: Some("General".to_string()),
+            }
+            .into(),
+        ),
+        (
+            "new_game",
+            NewGameMessage {
+                game_name: "2v2 no rush".to_string(),
+                id: Uuid::new_v4(),
+                players: 1,
+                max_players: 4,
+                spectators_only: false,
+                tag: None,
+            }
+            .into(),
+        ),
+        (
+            "join_game",
+            JoinGameMessage {
+                version: game_version(),
+                game_name: "2v2 no rush".to_string(),
+                password: Vec::new(),
+                ip_addr: Ipv4Addr::new(192, 0, 2, 1),
+                id: Uuid::new_v4(),
+                port: None,
+            }
+            .into(),
+        ),
+        (
+            "sync_stats",
+            SyncStatsMessage {
+                users_online: 42,
+                users_total: 1337,
+                games_open: 3,
+                games_total: 9001,
+                channels_total: 5,
+            }
+            .into(),
+        ),
+        // compressed, unlike the others
+        (
+            "welcome",
+            WelcomeServerMessage {
+                server_ident: "IE::Net".to_string(),
+                welcome_message: "Welcome to IE::Net".to_string(),
+                players_total: 1337,
+                players_online: 42,
+                channels_total: 5,
+                games_total: 9001,
+                games_running: 2,
+                games_available: 3,
+                game_versions: vec!["tmp2.2".to_string(); 3],
+                initial_channel: "General".to_string(),
+            }
+            .into(),
+        ),
+    ];
+    for (name, message) in &messages {
+        group.bench_function(*name, |b| b.iter(|| message.prepare_message().unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, raw_command, login_messages, prepare_message);
+criterion_main!(benches);
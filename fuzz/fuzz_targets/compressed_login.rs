@@ -0,0 +1,13 @@
+#![no_main]
+use bytes::BytesMut;
+use ie_net::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use libfuzzer_sys::fuzz_target;
+
+// the input becomes the zlib payload of a correctly framed message, so the fuzzer spends its
+// time on the decompression path instead of guessing length prefixes
+fuzz_target!(|data: &[u8]| {
+    let mut framed = BytesMut::from(&(data.len() as u32 + 4).to_le_bytes()[..]);
+    framed.extend_from_slice(data);
+    let _ = IdentClientMessage::try_parse(&mut framed.clone());
+    let _ = LoginClientMessage::try_parse(&mut framed);
+});
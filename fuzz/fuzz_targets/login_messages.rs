@@ -0,0 +1,12 @@
+#![no_main]
+use bytes::BytesMut;
+use ie_net::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use libfuzzer_sys::fuzz_target;
+
+// feeds the input the way the client handler does, parsing until nothing more can be read
+fuzz_target!(|data: &[u8]| {
+    let mut received = BytesMut::from(data);
+    while let Ok(Some(_)) = IdentClientMessage::try_parse(&mut received) {}
+    let mut received = BytesMut::from(data);
+    while let Ok(Some(_)) = LoginClientMessage::try_parse(&mut received) {}
+});
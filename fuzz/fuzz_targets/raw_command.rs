@@ -0,0 +1,7 @@
+#![no_main]
+use ie_net::messages::raw_command::try_parse_raw_command;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = try_parse_raw_command(data);
+});
@@ -0,0 +1,262 @@
+use crate::broker::control::ControlCommand;
+use crate::broker::predicate::UserPredicate;
+use crate::broker::{Event, EventSender};
+use crate::config::AdminApiConfig;
+use crate::http::{self, serve, Request, Response};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{oneshot, watch};
+
+const ENDPOINTS: &[&str] = &[
+    "/api/users",
+    "/api/channels",
+    "/api/games",
+    "/api/builds",
+    "/api/daily",
+    "/api/kick",
+    "/api/ban",
+    "/api/unban",
+    "/api/broadcast",
+    "/api/trace",
+    "/api/reload-words",
+];
+
+#[derive(Deserialize)]
+struct UserRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct TraceRequest {
+    username: String,
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    message: String,
+    /// only users matching this are addressed, e.g. `build ~ tmp2.1`
+    #[serde(default)]
+    filter: Option<String>,
+}
+
+/// Sends a control command to the broker and waits for its answer
+pub(crate) async fn query<T>(
+    broker: &mut EventSender,
+    command: impl FnOnce(oneshot::Sender<T>) -> ControlCommand,
+) -> Result<T> {
+    let (respond_to, response) = oneshot::channel();
+    broker
+        .send(Event::Control {
+            command: command(respond_to),
+        })
+        .await?;
+    Ok(response.await?)
+}
+
+async fn route(request: &Request, broker: &mut EventSender) -> Result<(u16, Value)> {
+    let username =
+        || -> Result<String> { Ok(serde_json::from_slice::<UserRequest>(&request.body)?.username) };
+    Ok(match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/users") => (
+            200,
+            json!(
+                query(broker, |respond_to| ControlCommand::ListUsers {
+                    respond_to
+                })
+                .await?
+            ),
+        ),
+        ("GET", "/api/channels") => (
+            200,
+            json!(
+                query(broker, |respond_to| ControlCommand::ListChannels {
+                    respond_to
+                })
+                .await?
+            ),
+        ),
+        ("GET", "/api/games") => (
+            200,
+            json!(
+                query(broker, |respond_to| ControlCommand::ListGames {
+                    respond_to
+                })
+                .await?
+            ),
+        ),
+        ("GET", "/api/builds") => (
+            200,
+            json!(
+                query(broker, |respond_to| ControlCommand::ListBuilds {
+                    respond_to
+                })
+                .await?
+            ),
+        ),
+        ("GET", "/api/daily") => (
+            200,
+            json!(
+                query(broker, |respond_to| ControlCommand::DailyStats {
+                    respond_to
+                })
+                .await?
+            ),
+        ),
+        ("POST", "/api/kick") => {
+            let username = username()?;
+            let kicked = query(broker, |respond_to| ControlCommand::Kick {
+                username,
+                respond_to,
+            })
+            .await?;
+            (200, json!({ "kicked": kicked }))
+        }
+        ("POST", "/api/ban") => {
+            let username = username()?;
+            query(broker, |respond_to| ControlCommand::Ban {
+                username,
+                respond_to,
+            })
+            .await?;
+            (200, json!({ "banned": true }))
+        }
+        ("POST", "/api/unban") => {
+            let username = username()?;
+            let unbanned = query(broker, |respond_to| ControlCommand::Unban {
+                username,
+                respond_to,
+            })
+            .await?;
+            (200, json!({ "unbanned": unbanned }))
+        }
+        ("POST", "/api/trace") => {
+            let trace = serde_json::from_slice::<TraceRequest>(&request.body)?;
+            let online = query(broker, |respond_to| ControlCommand::TraceProtocol {
+                username: trace.username,
+                enabled: trace.enabled,
+                respond_to,
+            })
+            .await?;
+            (200, json!({ "online": online }))
+        }
+        ("POST", "/api/broadcast") => {
+            let broadcast = serde_json::from_slice::<BroadcastRequest>(&request.body)?;
+            let filter = broadcast
+                .filter
+                .as_deref()
+                .map(UserPredicate::parse)
+                .transpose()?;
+            let recipients = query(broker, |respond_to| ControlCommand::Broadcast {
+                message: broadcast.message,
+                filter,
+                respond_to,
+            })
+            .await?;
+            (200, json!({ "recipients": recipients }))
+        }
+        ("POST", "/api/reload-words") => {
+            let words = query(broker, |respond_to| ControlCommand::ReloadWordList {
+                respond_to,
+            })
+            .await??;
+            (200, json!({ "words": words }))
+        }
+        (_, path) if ENDPOINTS.contains(&path) => (405, json!({ "error": "method not allowed" })),
+        _ => (404, json!({ "error": "not found" })),
+    })
+}
+
+async fn handle_request(request: Request, token: String, mut broker: EventSender) -> Response {
+    let authorized = request
+        .header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|t| t == token);
+    let (status, body) = if !authorized {
+        (401, json!({ "error": "unauthorized" }))
+    } else {
+        match route(&request, &mut broker).await {
+            Ok(response) => response,
+            Err(e) => (400, json!({ "error": e.to_string() })),
+        }
+    };
+    log::info!(
+        "Admin API {} {} -> {}",
+        request.method,
+        request.path,
+        status
+    );
+    Response::json(status, &body)
+}
+
+/// Serves the operator's HTTP API until shutdown
+pub(crate) async fn serve_loop(
+    config: AdminApiConfig,
+    broker: EventSender,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    if config.token.is_empty() {
+        return Err(anyhow!("Admin API is enabled, but no token is configured"));
+    }
+    log::info!("Admin API listening at {}", &config.bind);
+    let token = config.token;
+    serve(
+        &config.bind,
+        move |request| handle_request(request, token.clone(), broker.clone()),
+        shutdown_recv,
+    )
+    .await?;
+    log::info!("Admin API shutting down");
+    Ok(())
+}
+
+/// Talks to a running server's admin API, e.g. for scripting operator tasks
+pub struct AdminClient {
+    address: String,
+    token: String,
+}
+
+impl AdminClient {
+    pub fn new(address: &str, token: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Value> {
+        self.call("GET", path, Value::Null).await
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> Result<Value> {
+        self.call("POST", path, body).await
+    }
+
+    /// Returns the response document, or the error the server reported
+    async fn call(&self, method: &str, path: &str, body: Value) -> Result<Value> {
+        let authorization = format!("Bearer {}", self.token);
+        let body = if body.is_null() {
+            String::new()
+        } else {
+            body.to_string()
+        };
+        let (status, response) = http::request(
+            &self.address,
+            method,
+            path,
+            &[("Authorization", &authorization)],
+            &body,
+        )
+        .await?;
+        let response: Value = serde_json::from_slice(&response)?;
+        match status {
+            200 => Ok(response),
+            status => Err(anyhow!(
+                "Admin API answered {}: {}",
+                status,
+                response["error"].as_str().unwrap_or("unknown error")
+            )),
+        }
+    }
+}
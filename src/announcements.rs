@@ -0,0 +1,132 @@
+use crate::broker::{Event, EventSender};
+use crate::config::AnnouncementsConfig;
+use crate::server::wait_for_shutdown;
+use anyhow::Result;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{delay_until, Instant};
+
+/// When each configured announcement is due next
+struct Schedule {
+    entries: Vec<(Instant, Duration, String)>,
+}
+
+impl Schedule {
+    fn new(config: &AnnouncementsConfig, now: Instant) -> Self {
+        let mut entries = Vec::new();
+        for announcement in &config.scheduled {
+            if announcement.interval_mins == 0 {
+                log::warn!(
+                    "Ignoring announcement without interval: {}",
+                    announcement.text
+                );
+                continue;
+            }
+            let interval = Duration::from_secs(announcement.interval_mins * 60);
+            entries.push((now + interval, interval, announcement.text.clone()));
+        }
+        Self { entries }
+    }
+
+    /// Returns the announcement that is due first with its time, and schedules its next posting
+    fn next(&mut self) -> Option<(Instant, String)> {
+        let entry = self.entries.iter_mut().min_by_key(|(due, _, _)| *due)?;
+        let due = entry.0;
+        entry.0 = due + entry.1;
+        Some((due, entry.2.clone()))
+    }
+}
+
+/// Hands the configured announcements to the broker whenever they are due, so they are posted
+/// in order with everything else going on in the lobby
+pub async fn schedule_loop(
+    config: AnnouncementsConfig,
+    broker: EventSender,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let schedule = Schedule::new(&config, Instant::now());
+    post_when_due(schedule, broker, shutdown_recv).await?;
+    log::info!("Announcement scheduler shutting down");
+    Ok(())
+}
+
+async fn post_when_due(
+    mut schedule: Schedule,
+    mut broker: EventSender,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    // the watch yields its current value right away, which must not cost an announcement
+    let shutdown = wait_for_shutdown(shutdown_recv);
+    tokio::pin!(shutdown);
+    while let Some((due, text)) = schedule.next() {
+        tokio::select! {
+            _ = delay_until(due) => broker.send(Event::ScheduledAnnouncement { text }).await?,
+            _ = &mut shutdown => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScheduledAnnouncement;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    #[test]
+    fn test_schedule() {
+        let config = AnnouncementsConfig {
+            scheduled: vec![
+                ScheduledAnnouncement {
+                    text: "hourly".to_string(),
+                    interval_mins: 60,
+                },
+                ScheduledAnnouncement {
+                    text: "never".to_string(),
+                    interval_mins: 0,
+                },
+                ScheduledAnnouncement {
+                    text: "half-hourly".to_string(),
+                    interval_mins: 30,
+                },
+            ],
+        };
+        let start = Instant::now();
+        let mut schedule = Schedule::new(&config, start);
+        let mins = |m: u64| start + Duration::from_secs(m * 60);
+        let mut postings = Vec::new();
+        for _ in 0..4 {
+            postings.push(schedule.next().unwrap());
+        }
+        assert_eq!(postings[0], (mins(30), "half-hourly".to_string()));
+        assert_eq!(postings[1].0, mins(60));
+        assert_eq!(postings[2].0, mins(60));
+        assert_eq!(postings[3], (mins(90), "half-hourly".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_first_announcement_is_posted() {
+        let config = AnnouncementsConfig {
+            scheduled: vec![ScheduledAnnouncement {
+                text: "hourly".to_string(),
+                interval_mins: 60,
+            }],
+        };
+        // started an hour ago, so the first posting is due right away
+        let schedule = Schedule::new(&config, Instant::now() - Duration::from_secs(3600));
+        let (broker, mut events) = mpsc::channel(1);
+        let (shutdown_send, shutdown_recv) = watch::channel(false);
+        let posting = tokio::spawn(post_when_due(schedule, broker, shutdown_recv));
+
+        let event = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("no announcement was posted");
+        match event {
+            Some(Event::ScheduledAnnouncement { text }) => assert_eq!(text, "hourly"),
+            other => panic!("unexpected event {:?}", other),
+        }
+        shutdown_send.broadcast(true).unwrap();
+        posting.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::broker::accounts::unix_now;
+use crate::config::AuditLogConfig;
+use crate::rotating_log::json_lines_writer;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::net::Ipv4Addr;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const FILE_NAME: &str = "audit.log";
+
+/// Something that happened in the lobby and that operators may need to look back on
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Login {
+        username: String,
+        ip: Ipv4Addr,
+        build: String,
+    },
+    LoginRejected {
+        username: String,
+        ip: Ipv4Addr,
+        reason: String,
+    },
+    Disconnect {
+        username: String,
+    },
+    JoinChannel {
+        username: String,
+        channel: String,
+    },
+    GameCreated {
+        game: String,
+        host: String,
+    },
+    GameOpened {
+        game: String,
+    },
+    GameJoined {
+        game: String,
+        username: String,
+        spectator: bool,
+    },
+    GameStarted {
+        game: String,
+        players: Vec<String>,
+    },
+    GameClosed {
+        game: String,
+        winner: Option<String>,
+    },
+    Kick {
+        username: String,
+        reason: String,
+    },
+    Ban {
+        username: String,
+    },
+    Unban {
+        username: String,
+    },
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct AuditLogEntry {
+    time: u64,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+/// Appends broker events to rotating log files as JSON lines. Entries are never changed once
+/// written; like the chat log, a writer task keeps the broker from waiting for the disk.
+pub struct AuditLog {
+    writes: Option<mpsc::UnboundedSender<AuditLogEntry>>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        Self { writes: None }
+    }
+
+    /// Opens the log and spawns its writer task.
+    /// The writer finishes once the log is dropped and all pending entries are written.
+    pub fn open(config: &AuditLogConfig) -> Result<(Self, JoinHandle<()>)> {
+        std::fs::create_dir_all(&config.dir).with_context(|| {
+            format!(
+                "Could not create audit log directory {}",
+                config.dir.display()
+            )
+        })?;
+        let (send, recv) = mpsc::unbounded_channel();
+        let handle = crate::server::spawn_and_log_error(
+            json_lines_writer(
+                config.dir.clone(),
+                FILE_NAME,
+                config.max_file_kb * 1024,
+                config.max_files,
+                recv,
+            ),
+            "audit_log_writer",
+        );
+        Ok((Self { writes: Some(send) }, handle))
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(writes) = &self.writes {
+            let entry = AuditLogEntry {
+                time: unix_now(),
+                event,
+            };
+            if writes.send(entry).is_err() {
+                log::error!("Audit log writer is gone, dropping entry");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_flat() {
+        let entry = AuditLogEntry {
+            time: 12,
+            event: AuditEvent::Kick {
+                username: "foo".to_string(),
+                reason: "spam".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"time":12,"kind":"kick","username":"foo","reason":"spam"}"#
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use crate::broker::credentials::{Credentials, Verification};
+use crate::broker::names::canonical;
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DOCUMENT: &str = "accounts";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub username: String,
+    /// Argon2 hash in PHC string format. Documents from before hashing hold the plain
+    /// password under the old name.
+    #[serde(alias = "password")]
+    pub password_hash: String,
+    /// seconds since the Unix epoch
+    pub created_at: u64,
+    /// set while the account is scheduled for deletion
+    pub deletion_requested_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoginCheck {
+    Created,
+    Verified,
+    /// the login cancelled a pending deletion of the account
+    DeletionCancelled,
+    WrongPassword,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// What a password check found, see `PasswordCheck`
+#[derive(Debug)]
+pub enum PasswordOutcome {
+    /// the password matches the account, whose hash is to be replaced if it is outdated
+    Valid {
+        new_hash: Option<String>,
+    },
+    Invalid,
+    /// there is no account yet, so one is to be created with the hashed password
+    Registered {
+        password_hash: String,
+    },
+}
+
+/// Verifies or hashes the password of a login. Argon2 takes its time on purpose, so this runs
+/// on a blocking thread while the broker goes on with other events.
+pub struct PasswordCheck {
+    stored_hash: Option<String>,
+    credentials: Arc<Credentials>,
+}
+
+impl PasswordCheck {
+    pub fn run(self, username: &str, password: &str) -> PasswordOutcome {
+        let stored_hash = match self.stored_hash {
+            Some(stored_hash) => stored_hash,
+            None => {
+                return match self.credentials.hash(password) {
+                    Ok(password_hash) => PasswordOutcome::Registered { password_hash },
+                    Err(e) => {
+                        // refusing the login is better than storing an unusable account
+                        log::error!("Failed to register account {}: {}", username, e);
+                        PasswordOutcome::Invalid
+                    }
+                };
+            }
+        };
+        match self.credentials.verify(&stored_hash, password) {
+            Verification::Invalid => PasswordOutcome::Invalid,
+            Verification::Valid => PasswordOutcome::Valid { new_hash: None },
+            Verification::Outdated => match self.credentials.hash(password) {
+                Ok(hash) => PasswordOutcome::Valid {
+                    new_hash: Some(hash),
+                },
+                Err(e) => {
+                    log::error!("Keeping outdated hash of account {}: {}", username, e);
+                    PasswordOutcome::Valid { new_hash: None }
+                }
+            },
+        }
+    }
+}
+
+/// Registered user accounts. An account is created on a username's first login,
+/// after which the name can only be used with the same password.
+/// All accounts are loaded into memory at startup, so logins never wait for the storage;
+/// only changes are handed to its writer task.
+#[derive(Default)]
+pub struct Accounts {
+    by_name: HashMap<String, Account>,
+    credentials: Arc<Credentials>,
+}
+
+impl Accounts {
+    /// Loads the accounts, hashing any passwords that were stored in plain text
+    pub fn load(storage: &Storage, credentials: Credentials) -> Result<Self> {
+        let mut accounts: Vec<Account> = storage.load(DOCUMENT)?;
+        let mut migrated = 0;
+        for account in &mut accounts {
+            if !Credentials::is_hash(&account.password_hash) {
+                account.password_hash = credentials.hash(&account.password_hash)?;
+                migrated += 1;
+            }
+        }
+        let mut by_name: HashMap<String, Account> = HashMap::new();
+        let mut merged = 0;
+        for account in accounts {
+            // names that only differed by look-alike characters now collide, the older one stays
+            match by_name.entry(canonical(&account.username)) {
+                Entry::Occupied(mut entry) => {
+                    log::warn!(
+                        "Accounts {} and {} have the same name, keeping the older one",
+                        entry.get().username,
+                        account.username
+                    );
+                    if account.created_at < entry.get().created_at {
+                        entry.insert(account);
+                    }
+                    merged += 1;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(account);
+                }
+            }
+        }
+        let accounts = Self {
+            by_name,
+            credentials: Arc::new(credentials),
+        };
+        if migrated > 0 || merged > 0 {
+            log::info!("Hashed the plain text passwords of {} accounts", migrated);
+            accounts.save(storage);
+        }
+        Ok(accounts)
+    }
+
+    fn save(&self, storage: &Storage) {
+        storage.save(DOCUMENT, &self.by_name.values().collect::<Vec<_>>());
+    }
+
+    pub fn count(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.by_name.get(&canonical(username))
+    }
+
+    /// The hashing work a login with the name needs, to be run away from the broker
+    pub fn password_check(&self, username: &str) -> PasswordCheck {
+        PasswordCheck {
+            stored_hash: self.get(username).map(|a| a.password_hash.clone()),
+            credentials: self.credentials.clone(),
+        }
+    }
+
+    pub fn login(&mut self, storage: &Storage, username: &str, password: &str) -> LoginCheck {
+        let outcome = self.password_check(username).run(username, password);
+        self.complete_login(storage, username, outcome)
+    }
+
+    /// Applies the outcome of a password check to the accounts
+    pub fn complete_login(
+        &mut self,
+        storage: &Storage,
+        username: &str,
+        outcome: PasswordOutcome,
+    ) -> LoginCheck {
+        let key = canonical(username);
+        let check = match (outcome, self.by_name.get_mut(&key)) {
+            (PasswordOutcome::Invalid, _) => return LoginCheck::WrongPassword,
+            (PasswordOutcome::Valid { new_hash }, Some(account)) => {
+                let rehashed = new_hash.is_some();
+                if let Some(hash) = new_hash {
+                    log::info!("Upgrading the password hash of account {}", username);
+                    account.password_hash = hash;
+                }
+                match account.deletion_requested_at.take() {
+                    Some(_) => LoginCheck::DeletionCancelled,
+                    None if rehashed => LoginCheck::Verified,
+                    None => return LoginCheck::Verified,
+                }
+            }
+            (PasswordOutcome::Registered { password_hash }, None) => {
+                log::info!("Registering new account {}", username);
+                self.by_name.insert(
+                    key,
+                    Account {
+                        username: username.to_string(),
+                        password_hash,
+                        created_at: unix_now(),
+                        deletion_requested_at: None,
+                    },
+                );
+                LoginCheck::Created
+            }
+            // the account was purged or registered while the password was checked
+            _ => {
+                log::info!("Account {} changed during its login", username);
+                return LoginCheck::WrongPassword;
+            }
+        };
+        self.save(storage);
+        check
+    }
+
+    pub fn request_deletion(&mut self, storage: &Storage, username: &str) {
+        if let Some(account) = self.by_name.get_mut(&canonical(username)) {
+            log::info!("Account {} is scheduled for deletion", username);
+            account.deletion_requested_at = Some(unix_now());
+            self.save(storage);
+        }
+    }
+
+    /// Removes accounts whose deletion grace period has passed and returns their names
+    pub fn purge_deleted(&mut self, storage: &Storage, grace_period: Duration) -> Vec<String> {
+        let now = unix_now();
+        let expired: Vec<String> = self
+            .by_name
+            .iter()
+            .filter(|(_, a)| {
+                a.deletion_requested_at
+                    .is_some_and(|t| now >= t + grace_period.as_secs())
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        let purged: Vec<String> = expired
+            .iter()
+            .filter_map(|key| self.by_name.remove(key))
+            .map(|a| a.username)
+            .collect();
+        if !purged.is_empty() {
+            self.save(storage);
+        }
+        purged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_cancels_deletion() {
+        let storage = Storage::in_memory();
+        let mut accounts = Accounts::default();
+        assert_eq!(accounts.login(&storage, "Foo", "pw"), LoginCheck::Created);
+        assert_eq!(
+            accounts.login(&storage, "foo", "wrong"),
+            LoginCheck::WrongPassword
+        );
+        accounts.request_deletion(&storage, "foo");
+        assert_eq!(
+            accounts.login(&storage, "foo", "pw"),
+            LoginCheck::DeletionCancelled
+        );
+        assert!(accounts
+            .purge_deleted(&storage, Duration::from_secs(0))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_look_alikes_share_the_account() {
+        let storage = Storage::in_memory();
+        let mut accounts = Accounts::default();
+        assert_eq!(accounts.login(&storage, "Oscar", "pw"), LoginCheck::Created);
+        // with a Greek capital omicron
+        assert_eq!(
+            accounts.login(&storage, "\u{039f}scar", "other"),
+            LoginCheck::WrongPassword
+        );
+        assert_eq!(accounts.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_plain_passwords_are_hashed_on_load() {
+        let dir = std::env::temp_dir().join(format!("ie_net_accounts_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let legacy = r#"[{"username": "Foo", "password": "pw", "created_at": 0}]"#;
+        std::fs::write(dir.join("accounts.json"), legacy).unwrap();
+        let (storage, _) = Storage::open(&dir, None).unwrap();
+        let mut accounts = Accounts::load(&storage, Credentials::default()).unwrap();
+        assert!(Credentials::is_hash(
+            &accounts.get("foo").unwrap().password_hash
+        ));
+        assert_eq!(
+            accounts.login(&storage, "foo", "wrong"),
+            LoginCheck::WrongPassword
+        );
+        assert_eq!(accounts.login(&storage, "foo", "pw"), LoginCheck::Verified);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_purge_after_grace_period() {
+        let storage = Storage::in_memory();
+        let mut accounts = Accounts::default();
+        accounts.login(&storage, "Foo", "pw");
+        accounts.request_deletion(&storage, "foo");
+        assert!(accounts
+            .purge_deleted(&storage, Duration::from_secs(3600))
+            .is_empty());
+        assert_eq!(
+            accounts.purge_deleted(&storage, Duration::from_secs(0)),
+            vec!["Foo".to_string()]
+        );
+        assert_eq!(accounts.login(&storage, "foo", "new"), LoginCheck::Created);
+    }
+}
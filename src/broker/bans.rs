@@ -0,0 +1,42 @@
+use crate::broker::names::canonical;
+use crate::storage::Storage;
+use anyhow::Result;
+use std::collections::BTreeSet;
+
+const DOCUMENT: &str = "bans";
+
+/// Usernames that are not allowed to log in, stored by their canonical form so that a
+/// look-alike of a banned name is banned as well
+#[derive(Default)]
+pub struct Bans {
+    usernames: BTreeSet<String>,
+}
+
+impl Bans {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        let usernames: BTreeSet<String> = storage.load(DOCUMENT)?;
+        // documents from before unicode names hold lowercase names
+        Ok(Self {
+            usernames: usernames.iter().map(|u| canonical(u)).collect(),
+        })
+    }
+
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.usernames.contains(&canonical(username))
+    }
+
+    pub fn ban(&mut self, storage: &Storage, username: &str) {
+        if self.usernames.insert(canonical(username)) {
+            storage.save(DOCUMENT, &self.usernames);
+        }
+    }
+
+    /// Lifts a ban, returning whether the user was banned
+    pub fn unban(&mut self, storage: &Storage, username: &str) -> bool {
+        let removed = self.usernames.remove(&canonical(username));
+        if removed {
+            storage.save(DOCUMENT, &self.usernames);
+        }
+        removed
+    }
+}
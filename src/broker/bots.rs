@@ -0,0 +1,154 @@
+use crate::broker::fingerprint::Fingerprint;
+use crate::broker::user::{Location, Role, User};
+use crate::broker::{message_queue, Broker, MessageSender};
+use crate::config::{BotsConfig, VersionsConfig};
+use crate::messages::catalog::Language;
+use crate::protocol_trace::ProtocolTrace;
+use std::net::Ipv4Addr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Whether bots should be present at the given UTC hour
+fn is_active_hour(config: &BotsConfig, hour: u32) -> bool {
+    if config.from_hour <= config.until_hour {
+        config.from_hour <= hour && hour < config.until_hour
+    } else {
+        hour >= config.from_hour || hour < config.until_hour
+    }
+}
+
+fn current_utc_hour() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    ((secs / 3600) % 24) as u32
+}
+
+/// A user without a connection, whose messages go to `send`.
+/// It runs the game version assumed for clients that cannot tell theirs.
+pub(super) fn virtual_user(
+    username: String,
+    location: Location,
+    build: &str,
+    versions: &VersionsConfig,
+    send: MessageSender,
+) -> User {
+    User {
+        id: Uuid::new_v4(),
+        account: username.clone(),
+        username,
+        location,
+        last_channel: None,
+        game_version: versions.fallback(),
+        version_idx: 0,
+        language: Language::English,
+        ip_addr: Ipv4Addr::UNSPECIFIED,
+        region: None,
+        role: Role::Player,
+        connected_at: Instant::now(),
+        last_active: Instant::now(),
+        host_port: None,
+        build: build.to_string(),
+        fingerprint: Fingerprint::default(),
+        bot: true,
+        invisible: false,
+        protocol_trace: ProtocolTrace::default(),
+        send,
+        queued: Default::default(),
+        shard: Default::default(),
+    }
+}
+
+impl Broker {
+    /// Adds or removes bots so that each bot channel has `per_channel` users in total,
+    /// or no bots at all outside of the configured hours
+    pub(super) async fn update_bots(&mut self) {
+        let config = match &self.bots {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let active = is_active_hour(&config, current_utc_hour());
+        for channel_name in &config.channels {
+            let (bots, real_users) = match self.channels.get(channel_name) {
+                Some(channel) => {
+                    let users = self.users.users_in_location(&channel.to_location());
+                    // service bots are neither idle bots nor real users
+                    let bots: Vec<Uuid> = users
+                        .iter()
+                        .filter(|u| u.bot && u.username.starts_with(&config.prefix))
+                        .map(|u| u.id)
+                        .collect();
+                    let real_users = users.iter().filter(|u| !u.bot).count();
+                    (bots, real_users)
+                }
+                None => (Vec::new(), 0),
+            };
+            let wanted = if active {
+                config.per_channel.saturating_sub(real_users)
+            } else {
+                0
+            };
+            for id in bots.iter().skip(wanted) {
+                self.users.remove(*id).await;
+            }
+            if bots.len() < wanted {
+                let location = self
+                    .channels
+                    .get_or_create(&mut self.users, channel_name)
+                    .await
+                    .to_location();
+                for _ in bots.len()..wanted {
+                    self.spawn_bot(&config, location.clone()).await;
+                }
+            }
+        }
+    }
+
+    async fn spawn_bot(&mut self, config: &BotsConfig, location: Location) {
+        let username = match config
+            .names
+            .iter()
+            .map(|name| format!("{}{}", config.prefix, name))
+            .chain((1..).map(|n| format!("{}{}", config.prefix, n)))
+            .find(|username| self.users.by_username(username).is_none())
+        {
+            Some(username) => username,
+            None => return,
+        };
+        log::info!("Spawning bot {} in {}", username, location);
+        // bots never read what is sent to them
+        let (send, mut receiver) = message_queue();
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        self.users
+            .insert(virtual_user(
+                username,
+                location,
+                "bot",
+                &self.versions,
+                send,
+            ))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_active_hour() {
+        let mut config = BotsConfig::default();
+        assert!(is_active_hour(&config, 0));
+        assert!(is_active_hour(&config, 23));
+        config.from_hour = 2;
+        config.until_hour = 8;
+        assert!(!is_active_hour(&config, 1));
+        assert!(is_active_hour(&config, 2));
+        assert!(!is_active_hour(&config, 8));
+        config.from_hour = 22;
+        assert!(is_active_hour(&config, 23));
+        assert!(is_active_hour(&config, 7));
+        assert!(!is_active_hour(&config, 12));
+    }
+}
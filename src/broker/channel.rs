@@ -1,17 +1,33 @@
+use crate::broker::names::Name;
 use crate::broker::user::{Location, User, Users};
 use crate::broker::ArcServerMessage;
-use crate::messages::server_messages::{DropChannelMessage, NewChannelMessage};
-use nom::lib::std::collections::HashMap;
+use crate::config::{ChannelsConfig, PinningConfig};
+use crate::messages::server_messages::{DropChannelMessage, NewChannelMessage, SendMessage};
+use nom::lib::std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Put in front of replayed chat messages, so they are not mistaken for new ones
+const HISTORY_MARKER: &[u8] = b"(earlier) ";
 
 pub struct Channel {
     pub name: String,
+    /// pinned channels are announced before all others
+    pub pinned: bool,
+    /// the most recent chat messages, already marked for replay
+    history: VecDeque<ArcServerMessage>,
+    /// most users allowed in the channel at once; staff may join anyway
+    pub max_users: Option<usize>,
+    /// how long users have to wait between two messages, if slowmode is on
+    slowmode: Option<Duration>,
+    /// when users last spoke, kept only while they still have to wait
+    last_spoke: HashMap<Uuid, Instant>,
 }
 
 pub const DEFAULT_CHANNEL: &str = "General";
-pub const ALLOWED_CHANNEL_NAME_CHARS: &str =
-    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
 
 impl Channel {
     pub fn to_location(&self) -> Location {
@@ -21,26 +37,72 @@ impl Channel {
     }
 
     pub fn to_new_channel_message(&self) -> ArcServerMessage {
-        Arc::new(NewChannelMessage {
-            channel_name: self.name.clone(),
-        })
+        Arc::new(
+            NewChannelMessage {
+                channel_name: self.name.clone(),
+            }
+            .into(),
+        )
     }
 
     pub fn to_drop_channel_message(&self) -> ArcServerMessage {
-        Arc::new(DropChannelMessage {
-            channel_name: self.name.clone(),
-        })
+        Arc::new(
+            DropChannelMessage {
+                channel_name: self.name.clone(),
+            }
+            .into(),
+        )
+    }
+
+    /// Turns slowmode on with the given interval, or off with zero
+    pub fn set_slowmode(&mut self, interval: Duration) {
+        self.slowmode = Some(interval).filter(|i| *i > Duration::from_secs(0));
+        self.last_spoke.clear();
+    }
+
+    /// Returns how long the user still has to wait before speaking again, or remembers that
+    /// they just spoke
+    pub fn check_slowmode(&mut self, user: Uuid, now: Instant) -> Option<Duration> {
+        let interval = self.slowmode?;
+        self.last_spoke
+            .retain(|_, spoke| now.duration_since(*spoke) < interval);
+        if let Some(spoke) = self.last_spoke.get(&user) {
+            return Some(interval - now.duration_since(*spoke));
+        }
+        self.last_spoke.insert(user, now);
+        None
+    }
+
+    /// Recent chat messages to replay to a joining user, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &ArcServerMessage> {
+        self.history.iter()
     }
 }
 
 pub struct Channels {
-    by_name: HashMap<String, Channel>,
+    by_name: HashMap<Name, Channel>,
+    pinned_names: HashSet<Name>,
+    history_size: usize,
+    /// configured limits, by channel name
+    max_users: HashMap<Name, usize>,
 }
 
 impl Channels {
-    pub fn new() -> Self {
+    pub fn new(config: &ChannelsConfig, pinning: &PinningConfig) -> Self {
+        let pinned_names = if pinning.enabled {
+            config.pinned.iter().map(|n| Name::new(n)).collect()
+        } else {
+            HashSet::new()
+        };
         Channels {
             by_name: HashMap::new(),
+            pinned_names,
+            history_size: config.history_size,
+            max_users: config
+                .max_users
+                .iter()
+                .map(|(name, max)| (Name::new(name), *max))
+                .collect(),
         }
     }
 
@@ -49,10 +111,16 @@ impl Channels {
     }
 
     pub async fn get_or_create(&mut self, users: &mut Users, name: &str) -> &Channel {
-        if let Entry::Vacant(e) = self.by_name.entry(name.to_ascii_lowercase()) {
+        let key = Name::new(name);
+        if let Entry::Vacant(e) = self.by_name.entry(key.clone()) {
             log::info!("Creating new channel {}", name);
             let channel = e.insert(Channel {
                 name: name.to_string(),
+                pinned: self.pinned_names.contains(&key),
+                history: VecDeque::with_capacity(self.history_size),
+                max_users: self.max_users.get(&key).copied(),
+                slowmode: None,
+                last_spoke: HashMap::new(),
             });
             users.send_to_all(channel.to_new_channel_message()).await;
         }
@@ -60,14 +128,17 @@ impl Channels {
     }
 
     pub async fn remove(&mut self, users: &mut Users, name: &str) {
-        if let Some(channel) = self.by_name.remove(&name.to_ascii_lowercase()) {
+        if let Some(channel) = self.by_name.remove(&Name::new(name)) {
             log::info!("Removing channel {}", name);
             users.send_to_all(channel.to_drop_channel_message()).await;
         }
     }
 
-    pub async fn check_remove_empty_channels(&mut self, users: &mut Users) {
-        let occupied_locations = users.occupied_locations();
+    pub async fn check_remove_empty_channels(
+        &mut self,
+        users: &mut Users,
+        occupied_locations: &HashSet<Location>,
+    ) {
         let empty_channels: Vec<String> = self
             .by_name
             .values()
@@ -81,12 +152,84 @@ impl Channels {
     }
 
     pub fn get(&self, name: &str) -> Option<&Channel> {
-        self.by_name.get(&name.to_ascii_lowercase())
+        self.by_name.get(&Name::new(name))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Channel> {
+        self.by_name.get_mut(&Name::new(name))
+    }
+
+    /// Remembers a chat message sent to the location if it is a channel
+    pub fn record_message(&mut self, location: &Location, username: &str, message: &[u8]) {
+        let history_size = self.history_size;
+        let channel = match location {
+            Location::Channel { name } => match self.get_mut(name) {
+                Some(channel) => channel,
+                None => return,
+            },
+            _ => return,
+        };
+        if history_size == 0 {
+            return;
+        }
+        if channel.history.len() == history_size {
+            channel.history.pop_front();
+        }
+        channel.history.push_back(Arc::new(
+            SendMessage {
+                username: username.to_string(),
+                message: [HISTORY_MARKER, message].concat(),
+            }
+            .into(),
+        ));
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.by_name.values()
+    }
+
+    /// Announces all channels to the user, pinned ones first
     pub async fn announce_all(&mut self, user: &mut User) {
-        for channel in self.by_name.values() {
+        let mut channels: Vec<&Channel> = self.by_name.values().collect();
+        channels.sort_by_key(|c| !c.pinned);
+        for channel in channels {
             user.send(channel.to_new_channel_message()).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slowmode() {
+        let mut channel = Channel {
+            name: "General".to_string(),
+            pinned: false,
+            history: VecDeque::new(),
+            max_users: None,
+            slowmode: None,
+            last_spoke: HashMap::new(),
+        };
+        let (foo, bar) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert_eq!(channel.check_slowmode(foo, now), None);
+
+        channel.set_slowmode(Duration::from_secs(10));
+        assert_eq!(channel.check_slowmode(foo, now), None);
+        assert_eq!(channel.check_slowmode(bar, now), None);
+        let later = now + Duration::from_secs(4);
+        assert_eq!(
+            channel.check_slowmode(foo, later),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(
+            channel.check_slowmode(foo, now + Duration::from_secs(10)),
+            None
+        );
+
+        channel.set_slowmode(Duration::from_secs(0));
+        assert_eq!(channel.check_slowmode(foo, later), None);
+    }
+}
@@ -0,0 +1,434 @@
+use crate::broker::channel::DEFAULT_CHANNEL;
+use crate::broker::user::User;
+use crate::broker::Broker;
+use crate::messages::client_command::ClanAction;
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::server_messages::{ErrorMessage, InfoMessage};
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DOCUMENT: &str = "clans";
+const MIN_TAG_LENGTH: usize = 2;
+const MAX_TAG_LENGTH: usize = 6;
+const MAX_NAME_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Clan {
+    pub tag: String,
+    pub name: String,
+    pub leader: String,
+    /// usernames as spelled when they joined, including the leader
+    pub members: Vec<String>,
+    /// users who may join, until they do
+    #[serde(default)]
+    pub invited: Vec<String>,
+}
+
+impl Clan {
+    pub fn is_member(&self, username: &str) -> bool {
+        self.members
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(username))
+    }
+
+    pub fn is_leader(&self, username: &str) -> bool {
+        self.leader.eq_ignore_ascii_case(username)
+    }
+
+    fn is_invited(&self, username: &str) -> bool {
+        self.invited
+            .iter()
+            .any(|i| i.eq_ignore_ascii_case(username))
+    }
+
+    /// The channel only members may join
+    pub fn channel(&self) -> String {
+        clan_channel(&self.tag)
+    }
+}
+
+pub fn clan_channel(tag: &str) -> String {
+    format!("Clan_{}", tag)
+}
+
+/// Returns why the tag cannot be used, if it cannot
+fn check_tag(tag: &str) -> Option<String> {
+    if tag.len() < MIN_TAG_LENGTH || tag.len() > MAX_TAG_LENGTH {
+        Some(format!(
+            "Clan tags must be {} to {} characters long",
+            MIN_TAG_LENGTH, MAX_TAG_LENGTH
+        ))
+    } else if !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some("Clan tags may only contain letters and digits".to_string())
+    } else {
+        None
+    }
+}
+
+/// All clans, by lowercase tag
+#[derive(Default)]
+pub struct Clans {
+    by_tag: BTreeMap<String, Clan>,
+}
+
+impl Clans {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        Ok(Self {
+            by_tag: storage.load(DOCUMENT)?,
+        })
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&Clan> {
+        self.by_tag.get(&tag.to_ascii_lowercase())
+    }
+
+    pub fn of_member(&self, username: &str) -> Option<&Clan> {
+        self.by_tag.values().find(|c| c.is_member(username))
+    }
+
+    /// The clan whose channel this is, if it is one
+    pub fn by_channel(&self, channel: &str) -> Option<&Clan> {
+        self.by_tag
+            .values()
+            .find(|c| c.channel().eq_ignore_ascii_case(channel))
+    }
+
+    /// Changes a clan and persists it
+    fn update<T>(
+        &mut self,
+        storage: &Storage,
+        tag: &str,
+        change: impl FnOnce(&mut Clan) -> T,
+    ) -> T {
+        let clan = self
+            .by_tag
+            .get_mut(&tag.to_ascii_lowercase())
+            .expect("updated clan must exist");
+        let result = change(clan);
+        storage.save(DOCUMENT, &self.by_tag);
+        result
+    }
+
+    fn insert(&mut self, storage: &Storage, clan: Clan) {
+        self.by_tag.insert(clan.tag.to_ascii_lowercase(), clan);
+        storage.save(DOCUMENT, &self.by_tag);
+    }
+
+    fn disband(&mut self, storage: &Storage, tag: &str) -> Option<Clan> {
+        let clan = self.by_tag.remove(&tag.to_ascii_lowercase());
+        storage.save(DOCUMENT, &self.by_tag);
+        clan
+    }
+
+    /// Takes a deleted user out of their clan, which is disbanded if they led it
+    pub fn remove_user(&mut self, storage: &Storage, username: &str) {
+        let tag = match self.of_member(username) {
+            Some(clan) if clan.is_leader(username) => {
+                let tag = clan.tag.clone();
+                self.disband(storage, &tag);
+                return;
+            }
+            Some(clan) => clan.tag.clone(),
+            None => return,
+        };
+        self.update(storage, &tag, |c| {
+            c.members.retain(|m| !m.eq_ignore_ascii_case(username))
+        });
+    }
+}
+
+impl Broker {
+    pub(super) async fn clan_command(&mut self, user: User, action: ClanAction) {
+        match action {
+            ClanAction::Create { tag, name } => self.create_clan(user, tag, name).await,
+            ClanAction::Invite { username } => self.invite_to_clan(user, &username).await,
+            ClanAction::Join { tag } => self.join_clan(user, &tag).await,
+            ClanAction::Kick { username } => self.kick_from_clan(user, &username).await,
+            ClanAction::Leave => self.leave_clan(user).await,
+            ClanAction::Info { tag } => self.clan_info(user, tag).await,
+        }
+    }
+
+    /// Returns why the user may not join the channel, if it belongs to a clan they are not in
+    pub(super) fn check_clan_channel(&self, user: &User, channel: &str) -> Option<String> {
+        match self.clans.by_channel(channel) {
+            Some(clan) if !clan.is_member(&user.account) && !user.role.is_staff() => Some(format!(
+                "Only members of [{}] may join #{}",
+                clan.tag, channel
+            )),
+            _ => None,
+        }
+    }
+
+    /// Puts the user's clan tag in front of a chat message
+    pub(super) fn tag_message(&self, username: &str, message: Vec<u8>) -> Vec<u8> {
+        match self.clans.of_member(username) {
+            Some(clan) => [format!("[{}] ", clan.tag).as_bytes(), &message].concat(),
+            None => message,
+        }
+    }
+
+    /// The clan the user leads, or an error telling them they lead none
+    async fn led_clan(&self, user: &mut User) -> Option<Clan> {
+        match self.clans.of_member(&user.account) {
+            Some(clan) if clan.is_leader(&user.account) => Some(clan.clone()),
+            _ => {
+                user.send(ErrorMessage::new_err("Only clan leaders can do that"))
+                    .await;
+                None
+            }
+        }
+    }
+
+    async fn create_clan(&mut self, mut user: User, tag: String, name: String) {
+        let error = if let Some(clan) = self.clans.of_member(&user.account) {
+            Some(format!("You are already a member of [{}]", clan.tag))
+        } else if let Some(reason) = check_tag(&tag) {
+            Some(reason)
+        } else if name.chars().count() > MAX_NAME_LENGTH {
+            Some(format!(
+                "Clan names may be at most {} characters long",
+                MAX_NAME_LENGTH
+            ))
+        } else if self.clans.get(&tag).is_some() {
+            Some(format!("The clan tag {} is taken", tag))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            user.send(ErrorMessage::new_err(&error)).await;
+            return;
+        }
+        log::info!("{} founded clan [{}] {}", user.account, tag, name);
+        let clan = Clan {
+            tag,
+            name,
+            leader: user.account.clone(),
+            members: vec![user.account.clone()],
+            invited: Vec::new(),
+        };
+        user.send(InfoMessage::new_info(&format!(
+            "You founded [{}] {}, invite players with /clan invite <user>",
+            clan.tag, clan.name
+        )))
+        .await;
+        let channel = clan.channel();
+        self.clans.insert(&self.storage, clan);
+        self.join_channel(user, channel).await;
+    }
+
+    async fn invite_to_clan(&mut self, mut user: User, username: &str) {
+        let clan = match self.led_clan(&mut user).await {
+            Some(clan) => clan,
+            None => return,
+        };
+        let invitee = match self.accounts.get(&self.account_of(username)) {
+            Some(account) => account.username.clone(),
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::UserNotFound))
+                    .await;
+                return;
+            }
+        };
+        if let Some(other) = self.clans.of_member(&invitee) {
+            user.send(ErrorMessage::new_err(&format!(
+                "{} is already a member of [{}]",
+                invitee, other.tag
+            )))
+            .await;
+            return;
+        }
+        if !clan.is_invited(&invitee) {
+            self.clans.update(&self.storage, &clan.tag, |c| {
+                c.invited.push(invitee.clone())
+            });
+        }
+        user.send(InfoMessage::new_info(&format!(
+            "{} has been invited to [{}]",
+            invitee, clan.tag
+        )))
+        .await;
+        if let Some(invitee) = self.users.by_account_mut(&invitee) {
+            invitee
+                .send(InfoMessage::new_info(&format!(
+                    "{} invites you to [{}] {}, accept with /clan join {}",
+                    user.username, clan.tag, clan.name, clan.tag
+                )))
+                .await;
+        }
+    }
+
+    async fn join_clan(&mut self, mut user: User, tag: &str) {
+        if let Some(clan) = self.clans.of_member(&user.account) {
+            user.send(ErrorMessage::new_err(&format!(
+                "You are already a member of [{}]",
+                clan.tag
+            )))
+            .await;
+            return;
+        }
+        let clan = match self.clans.get(tag) {
+            Some(clan) if clan.is_invited(&user.account) => clan.clone(),
+            _ => {
+                user.send(ErrorMessage::new_err(&format!(
+                    "You have not been invited to {}",
+                    tag
+                )))
+                .await;
+                return;
+            }
+        };
+        self.clans.update(&self.storage, &clan.tag, |c| {
+            c.invited.retain(|i| !i.eq_ignore_ascii_case(&user.account));
+            c.members.push(user.account.clone());
+        });
+        self.send_to_clan(
+            &clan.tag,
+            &format!("{} joined [{}]", user.account, clan.tag),
+        )
+        .await;
+        self.join_channel(user, clan.channel()).await;
+    }
+
+    async fn kick_from_clan(&mut self, mut user: User, username: &str) {
+        let clan = match self.led_clan(&mut user).await {
+            Some(clan) => clan,
+            None => return,
+        };
+        let username = &self.account_of(username);
+        if clan.is_leader(username) {
+            user.send(ErrorMessage::new_err(
+                "Clan leaders cannot kick themselves, use /clan leave",
+            ))
+            .await;
+            return;
+        }
+        if !clan.is_member(username) {
+            user.send(ErrorMessage::new_err(&format!(
+                "{} is not a member of [{}]",
+                username, clan.tag
+            )))
+            .await;
+            return;
+        }
+        self.clans.update(&self.storage, &clan.tag, |c| {
+            c.members.retain(|m| !m.eq_ignore_ascii_case(username))
+        });
+        self.send_to_clan(
+            &clan.tag,
+            &format!("{} was kicked from [{}]", username, clan.tag),
+        )
+        .await;
+        if let Some(kicked) = self.users.by_account_mut(username) {
+            kicked
+                .send(InfoMessage::new_info(&format!(
+                    "You were kicked from [{}]",
+                    clan.tag
+                )))
+                .await;
+        }
+        self.leave_clan_channel(username, &clan).await;
+    }
+
+    /// Leaves the user's clan, which is disbanded if they lead it
+    async fn leave_clan(&mut self, mut user: User) {
+        let clan = match self.clans.of_member(&user.account) {
+            Some(clan) => clan.clone(),
+            None => {
+                user.send(ErrorMessage::new_err("You are not a member of a clan"))
+                    .await;
+                return;
+            }
+        };
+        if clan.is_leader(&user.account) {
+            log::info!("{} disbanded clan [{}]", user.account, clan.tag);
+            self.send_to_clan(&clan.tag, &format!("[{}] has been disbanded", clan.tag))
+                .await;
+            self.clans.disband(&self.storage, &clan.tag);
+            for member in &clan.members {
+                self.leave_clan_channel(member, &clan).await;
+            }
+        } else {
+            self.clans.update(&self.storage, &clan.tag, |c| {
+                c.members.retain(|m| !m.eq_ignore_ascii_case(&user.account))
+            });
+            self.send_to_clan(&clan.tag, &format!("{} left [{}]", user.account, clan.tag))
+                .await;
+            user.send(InfoMessage::new_info(&format!("You left [{}]", clan.tag)))
+                .await;
+            self.leave_clan_channel(&user.account, &clan).await;
+        }
+    }
+
+    async fn clan_info(&mut self, mut user: User, tag: Option<String>) {
+        let clan = match &tag {
+            Some(tag) => self.clans.get(tag),
+            None => self.clans.of_member(&user.account),
+        };
+        let lines = match (clan, tag) {
+            (Some(clan), _) => vec![
+                format!("[{}] {}, led by {}", clan.tag, clan.name, clan.leader),
+                format!("Members: {}", clan.members.join(", ")),
+            ],
+            (None, Some(tag)) => {
+                user.send(ErrorMessage::new_err(&format!("There is no clan {}", tag)))
+                    .await;
+                return;
+            }
+            (None, None) => vec![
+                "You are not a member of a clan, found one with /clan create <tag> <name>"
+                    .to_string(),
+            ],
+        };
+        for line in lines {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
+
+    /// Tells every online member of the clan
+    async fn send_to_clan(&mut self, tag: &str, text: &str) {
+        let members = match self.clans.get(tag) {
+            Some(clan) => clan.members.clone(),
+            None => return,
+        };
+        let message = InfoMessage::new_info(text);
+        let prepared = Arc::default();
+        for member in members {
+            if let Some(member) = self.users.by_account_mut(&member) {
+                member.send_shared(message.clone(), &prepared).await;
+            }
+        }
+    }
+
+    /// Sends a former member who is in the clan's channel back to the default channel
+    async fn leave_clan_channel(&mut self, account: &str, clan: &Clan) {
+        let member = match self.users.by_account(account) {
+            Some(member) => member.clone(),
+            None => return,
+        };
+        let in_channel = self
+            .channels
+            .get(&clan.channel())
+            .is_some_and(|c| c.to_location() == member.location);
+        if in_channel {
+            self.join_channel(member, DEFAULT_CHANNEL.to_string()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_tag() {
+        assert_eq!(check_tag("UCS"), None);
+        assert_eq!(check_tag("ed2150"), None);
+        assert!(check_tag("X").is_some());
+        assert!(check_tag("TOOLONG").is_some());
+        assert!(check_tag("[UC]").is_some());
+    }
+}
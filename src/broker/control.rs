@@ -0,0 +1,102 @@
+use crate::broker::daily::DailyEntry;
+use crate::broker::fingerprint::{BuildStats, Fingerprint};
+use crate::broker::predicate::UserPredicate;
+use crate::broker::snapshot::LobbySnapshot;
+use crate::broker::user::Role;
+use anyhow::Result;
+use serde::Serialize;
+use std::net::Ipv4Addr;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+/// Commands issued by the server operator rather than by a game client.
+/// Each command carries the channel on which the broker delivers its answer.
+#[derive(Debug)]
+pub enum ControlCommand {
+    DumpState {
+        respond_to: oneshot::Sender<LobbySnapshot>,
+    },
+    /// Re-reads the content filter's word list, answering with the number of words
+    ReloadWordList {
+        respond_to: oneshot::Sender<Result<usize>>,
+    },
+    ListUsers {
+        respond_to: oneshot::Sender<Vec<UserInfo>>,
+    },
+    ListChannels {
+        respond_to: oneshot::Sender<Vec<ChannelInfo>>,
+    },
+    ListGames {
+        respond_to: oneshot::Sender<Vec<GameInfo>>,
+    },
+    /// Counts logins and online users per client build
+    ListBuilds {
+        respond_to: oneshot::Sender<Vec<BuildStats>>,
+    },
+    /// Activity per day, oldest first
+    DailyStats {
+        respond_to: oneshot::Sender<Vec<DailyEntry>>,
+    },
+    /// Disconnects a user, answering whether they were online
+    Kick {
+        username: String,
+        respond_to: oneshot::Sender<bool>,
+    },
+    /// Keeps a user from logging in again and disconnects them if they are online
+    Ban {
+        username: String,
+        respond_to: oneshot::Sender<()>,
+    },
+    /// Lifts a ban, answering whether the user was banned
+    Unban {
+        username: String,
+        respond_to: oneshot::Sender<bool>,
+    },
+    /// Switches dumping the frames of a user's connection to the protocol log on or off,
+    /// answering whether the user was online
+    TraceProtocol {
+        username: String,
+        enabled: bool,
+        respond_to: oneshot::Sender<bool>,
+    },
+    /// Sends an announcement to every user matching the filter, or to everyone without one,
+    /// answering with the number of recipients
+    Broadcast {
+        message: String,
+        filter: Option<UserPredicate>,
+        respond_to: oneshot::Sender<usize>,
+    },
+}
+
+/// A connected user as seen by the operator
+#[derive(Debug, Clone, Serialize)]
+pub struct UserInfo {
+    pub id: Uuid,
+    pub username: String,
+    pub role: Role,
+    pub location: String,
+    pub ip_addr: Ipv4Addr,
+    pub game_version: Uuid,
+    pub build: String,
+    pub bot: bool,
+    pub invisible: bool,
+    pub fingerprint: Fingerprint,
+    pub connected_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub users: usize,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GameInfo {
+    pub name: String,
+    pub host: String,
+    pub status: String,
+    pub players: u32,
+    pub max_players: u32,
+    pub pinned: bool,
+}
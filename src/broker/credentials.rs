@@ -0,0 +1,124 @@
+use crate::config::PasswordHashConfig;
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use password_hash::rand_core::OsRng;
+use password_hash::SaltString;
+use std::convert::TryFrom;
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Valid,
+    /// the password is right, but its hash should be replaced by one with the current parameters
+    Outdated,
+    Invalid,
+}
+
+/// Hashes passwords with Argon2id and a random salt per password, in the PHC string format
+/// that records the parameters along with the hash. Raising the parameters upgrades each
+/// stored hash on its user's next login.
+pub struct Credentials {
+    argon2: Argon2<'static>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::new(&PasswordHashConfig::default()).expect("default parameters are valid")
+    }
+}
+
+impl Credentials {
+    pub fn new(config: &PasswordHashConfig) -> Result<Self> {
+        let params = Params::new(
+            config.memory_kib,
+            config.iterations,
+            config.parallelism,
+            None,
+        )
+        .map_err(|e| anyhow!("Invalid password hashing parameters: {}", e))?;
+        Ok(Self {
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        })
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self
+            .argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+        Ok(hash.to_string())
+    }
+
+    /// Whether the stored value is a hash rather than a password from before hashing
+    pub fn is_hash(stored: &str) -> bool {
+        PasswordHash::new(stored).is_ok()
+    }
+
+    /// Checks a password against its stored hash in constant time
+    pub fn verify(&self, stored: &str, password: &str) -> Verification {
+        let hash = match PasswordHash::new(stored) {
+            Ok(hash) => hash,
+            Err(_) => return Verification::Invalid,
+        };
+        if self
+            .argon2
+            .verify_password(password.as_bytes(), &hash)
+            .is_err()
+        {
+            return Verification::Invalid;
+        }
+        if self.is_current(&hash) {
+            Verification::Valid
+        } else {
+            Verification::Outdated
+        }
+    }
+
+    fn is_current(&self, hash: &PasswordHash) -> bool {
+        let current = self.argon2.params();
+        hash.algorithm == Algorithm::Argon2id.ident()
+            && hash.version == Some(Version::V0x13.into())
+            && Params::try_from(hash).is_ok_and(|params| {
+                params.m_cost() == current.m_cost()
+                    && params.t_cost() == current.t_cost()
+                    && params.p_cost() == current.p_cost()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(iterations: u32) -> Credentials {
+        Credentials::new(&PasswordHashConfig {
+            memory_kib: 1024,
+            iterations,
+            parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let credentials = credentials(1);
+        let hash = credentials.hash("secret").unwrap();
+        assert!(Credentials::is_hash(&hash));
+        assert!(!hash.contains("secret"));
+        assert_ne!(hash, credentials.hash("secret").unwrap());
+        assert_eq!(credentials.verify(&hash, "secret"), Verification::Valid);
+        assert_eq!(credentials.verify(&hash, "wrong"), Verification::Invalid);
+        assert_eq!(
+            credentials.verify("secret", "secret"),
+            Verification::Invalid
+        );
+    }
+
+    #[test]
+    fn test_changed_parameters_outdate_hashes() {
+        let hash = credentials(1).hash("secret").unwrap();
+        let upgraded = credentials(2);
+        assert_eq!(upgraded.verify(&hash, "secret"), Verification::Outdated);
+        assert_eq!(upgraded.verify(&hash, "wrong"), Verification::Invalid);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::broker::accounts::unix_now;
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DOCUMENT: &str = "daily";
+
+/// Activity on one day, UTC
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DayStats {
+    pub logins: u64,
+    /// chat and private messages
+    pub messages: u64,
+    /// started games that were closed, with or without a result
+    pub games: u64,
+}
+
+/// A day's activity as handed to operators
+#[derive(Debug, Clone, Serialize)]
+pub struct DailyEntry {
+    /// YYYY-MM-DD
+    pub date: String,
+    #[serde(flatten)]
+    pub stats: DayStats,
+}
+
+/// Activity per day, for charting. Since messages are counted, changes are persisted in
+/// batches by housekeeping and on shutdown rather than each time.
+pub struct DailyStats {
+    by_date: BTreeMap<String, DayStats>,
+    dirty: bool,
+}
+
+impl DailyStats {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        Ok(Self {
+            by_date: storage.load(DOCUMENT)?,
+            dirty: false,
+        })
+    }
+
+    fn today(&mut self) -> &mut DayStats {
+        self.dirty = true;
+        self.by_date.entry(date_of(unix_now())).or_default()
+    }
+
+    pub fn record_login(&mut self) {
+        self.today().logins += 1;
+    }
+
+    pub fn record_message(&mut self) {
+        self.today().messages += 1;
+    }
+
+    pub fn record_game(&mut self) {
+        self.today().games += 1;
+    }
+
+    /// All days with any activity, oldest first
+    pub fn entries(&self) -> Vec<DailyEntry> {
+        self.by_date
+            .iter()
+            .map(|(date, stats)| DailyEntry {
+                date: date.clone(),
+                stats: stats.clone(),
+            })
+            .collect()
+    }
+
+    pub fn flush(&mut self, storage: &Storage) {
+        if self.dirty {
+            storage.save(DOCUMENT, &self.by_date);
+            self.dirty = false;
+        }
+    }
+}
+
+/// Formats the UTC date of a Unix timestamp as YYYY-MM-DD
+fn date_of(unix_secs: u64) -> String {
+    // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    let days = (unix_secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_of() {
+        assert_eq!(date_of(0), "1970-01-01");
+        assert_eq!(date_of(951_782_400), "2000-02-29");
+        assert_eq!(date_of(1_704_067_199), "2023-12-31");
+        assert_eq!(date_of(1_704_067_200), "2024-01-01");
+    }
+}
@@ -0,0 +1,804 @@
+use crate::broker::channel::DEFAULT_CHANNEL;
+use crate::broker::game::GameStatus::Open;
+use crate::broker::user::{Location, Role, User};
+use crate::broker::{ArcServerMessage, Broker};
+use crate::federation::{PeerLocation, PeerMessage, PeerSender};
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::server_messages::{
+    DropChannelMessage, DropGameMessage, ErrorMessage, JoinChannelMessage, JoinGameMessage,
+    NewChannelMessage, NewGameMessage, PrivateMessage, SendMessage, UserJoinedMessage,
+    UserLeftMessage,
+};
+use crate::util::bytevec_to_str;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// How remote users, channels and games are presented to local users
+pub fn tag(name: &str, server: &str) -> String {
+    format!("{}@{}", name, server)
+}
+
+/// Splits a tagged name into name and server
+pub fn split_tag(tagged: &str) -> Option<(&str, &str)> {
+    let (name, server) = tagged.rsplit_once('@')?;
+    if name.is_empty() || server.is_empty() {
+        None
+    } else {
+        Some((name, server))
+    }
+}
+
+/// Translates a local location into what the given peer understands, if it is visible to it
+pub fn to_peer_location(location: &Location, peer: &str) -> Option<PeerLocation> {
+    let (name, on_receiver) = match location {
+        Location::Channel { name } => (format!("#{}", name), false),
+        Location::Game { name } => (format!("${}", name), false),
+        Location::RemoteChannel { server, name } if server == peer => (format!("#{}", name), true),
+        Location::RemoteGame { server, name } if server == peer => (format!("${}", name), true),
+        _ => return None,
+    };
+    Some(PeerLocation { name, on_receiver })
+}
+
+/// Translates a location received from the given peer into a local location
+pub fn from_peer_location(location: &PeerLocation, peer: &str) -> Option<Location> {
+    let name = location.name.get(1..)?.to_string();
+    let server = peer.to_string();
+    Some(match (location.name.get(0..1)?, location.on_receiver) {
+        ("#", true) => Location::Channel { name },
+        ("$", true) => Location::Game { name },
+        ("#", false) => Location::RemoteChannel { server, name },
+        ("$", false) => Location::RemoteGame { server, name },
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteUser {
+    pub username: String,
+    /// from the local server's point of view
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteGame {
+    pub name: String,
+    pub id: Uuid,
+    pub host_ip: Ipv4Addr,
+    pub port: Option<u16>,
+    pub password: Vec<u8>,
+    pub players: u32,
+    pub max_players: u32,
+}
+
+/// What a peer has been told about the local server
+#[derive(Debug, Default, PartialEq)]
+pub struct PeerView {
+    pub users: BTreeMap<String, PeerLocation>,
+    pub channels: BTreeSet<String>,
+    pub games: BTreeMap<String, RemoteGame>,
+}
+
+impl PeerView {
+    /// The messages that bring a peer from the previous view to this one
+    pub fn diff(&self, previous: &PeerView) -> Vec<PeerMessage> {
+        let mut messages = Vec::new();
+        for name in previous.channels.difference(&self.channels) {
+            messages.push(PeerMessage::ChannelDropped { name: name.clone() });
+        }
+        for name in self.channels.difference(&previous.channels) {
+            messages.push(PeerMessage::ChannelCreated { name: name.clone() });
+        }
+        for (username, location) in &self.users {
+            if previous.users.get(username) != Some(location) {
+                messages.push(PeerMessage::UserPresent {
+                    username: username.clone(),
+                    location: location.clone(),
+                });
+            }
+        }
+        for username in previous.users.keys() {
+            if !self.users.contains_key(username) {
+                messages.push(PeerMessage::UserGone {
+                    username: username.clone(),
+                });
+            }
+        }
+        for (name, game) in &previous.games {
+            if self.games.get(name) != Some(game) {
+                messages.push(PeerMessage::GameDropped { name: name.clone() });
+            }
+        }
+        for (name, game) in &self.games {
+            if previous.games.get(name) != Some(game) {
+                messages.push(PeerMessage::GameOpened {
+                    name: game.name.clone(),
+                    id: game.id,
+                    host_ip: game.host_ip,
+                    port: game.port,
+                    password: game.password.clone(),
+                    players: game.players,
+                    max_players: game.max_players,
+                });
+            }
+        }
+        messages
+    }
+}
+
+pub struct Peer {
+    /// distinguishes this link from later ones to the same server
+    pub link: Uuid,
+    pub send: PeerSender,
+    pub users: HashMap<String, RemoteUser>,
+    pub channels: BTreeMap<String, String>,
+    pub games: HashMap<String, RemoteGame>,
+    pub sent: PeerView,
+}
+
+impl Peer {
+    pub async fn send(&mut self, message: PeerMessage) {
+        if self.send.send(message).await.is_err() {
+            log::warn!("Failed to send message to federation peer");
+        }
+    }
+
+    pub fn to_new_game_message(&self, game: &RemoteGame, server: &str) -> ArcServerMessage {
+        Arc::new(
+            NewGameMessage {
+                game_name: tag(&game.name, server),
+                id: game.id,
+                players: game.players,
+                max_players: game.max_players,
+                spectators_only: false,
+                tag: None,
+            }
+            .into(),
+        )
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&String> {
+        self.channels.get(&name.to_ascii_lowercase())
+    }
+
+    pub fn game(&self, name: &str) -> Option<&RemoteGame> {
+        self.games.get(&name.to_ascii_lowercase())
+    }
+}
+
+/// State of all federation links, as far as the broker is concerned
+#[derive(Default)]
+pub struct Federation {
+    peers: HashMap<String, Peer>,
+}
+
+impl Federation {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers a new link, unless one to the same server already exists
+    pub fn connect(&mut self, server: &str, link: Uuid, send: PeerSender) -> bool {
+        if self.peers.contains_key(server) {
+            return false;
+        }
+        self.peers.insert(
+            server.to_string(),
+            Peer {
+                link,
+                send,
+                users: HashMap::new(),
+                channels: BTreeMap::new(),
+                games: HashMap::new(),
+                sent: PeerView::default(),
+            },
+        );
+        true
+    }
+
+    /// Forgets the given link, returning its state if it was the active one
+    pub fn disconnect(&mut self, server: &str, link: Uuid) -> Option<Peer> {
+        if self.peers.get(server)?.link != link {
+            return None;
+        }
+        self.peers.remove(server)
+    }
+
+    pub fn get(&self, server: &str) -> Option<&Peer> {
+        self.peers.get(server)
+    }
+
+    pub fn get_mut(&mut self, server: &str) -> Option<&mut Peer> {
+        self.peers.get_mut(server)
+    }
+
+    pub fn servers(&self) -> Vec<String> {
+        self.peers.keys().cloned().collect()
+    }
+
+    /// Looks up a remote user by tagged name, returning its username and server
+    pub fn find_user(&self, tagged: &str) -> Option<(String, String)> {
+        let (name, server) = split_tag(tagged)?;
+        let user = self
+            .peers
+            .get(server)?
+            .users
+            .get(&name.to_ascii_lowercase())?;
+        Some((user.username.clone(), server.to_string()))
+    }
+
+    /// Remote users by tagged name, with their location
+    pub fn users(&self) -> Vec<(String, Location)> {
+        self.peers
+            .iter()
+            .flat_map(|(server, peer)| {
+                peer.users
+                    .values()
+                    .map(move |u| (tag(&u.username, server), u.location.clone()))
+            })
+            .collect()
+    }
+
+    pub fn channels(&self) -> Vec<String> {
+        self.peers
+            .iter()
+            .flat_map(|(server, peer)| peer.channels.values().map(move |c| tag(c, server)))
+            .collect()
+    }
+
+    pub fn games(&self) -> Vec<String> {
+        self.peers
+            .iter()
+            .flat_map(|(server, peer)| peer.games.values().map(move |g| tag(&g.name, server)))
+            .collect()
+    }
+
+    /// Announces all remote channels and open games to the user
+    pub async fn announce_all(&self, user: &mut User) {
+        for (server, peer) in &self.peers {
+            for channel in peer.channels.values() {
+                user.send(Arc::new(
+                    NewChannelMessage {
+                        channel_name: tag(channel, server),
+                    }
+                    .into(),
+                ))
+                .await;
+            }
+            for game in peer.games.values() {
+                user.send(peer.to_new_game_message(game, server)).await;
+            }
+        }
+    }
+
+    /// Tagged names of the remote users in a location
+    pub fn users_in_location(&self, location: &Location) -> Vec<String> {
+        self.peers
+            .iter()
+            .flat_map(|(server, peer)| {
+                peer.users
+                    .values()
+                    .filter(move |u| u.location == *location)
+                    .map(move |u| tag(&u.username, server))
+            })
+            .collect()
+    }
+
+    pub fn occupied_locations(&self) -> HashSet<Location> {
+        self.peers
+            .values()
+            .flat_map(|peer| peer.users.values().map(|u| u.location.clone()))
+            .collect()
+    }
+
+    /// Servers with remote users in the given location
+    pub fn servers_with_users_in(&self, location: &Location) -> Vec<String> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.users.values().any(|u| u.location == *location))
+            .map(|(server, _)| server.clone())
+            .collect()
+    }
+}
+
+impl Broker {
+    pub(super) fn peer_connected(&mut self, server: String, link: Uuid, send: PeerSender) {
+        // dropping the sender closes the redundant link
+        if !self.federation.connect(&server, link, send) {
+            log::warn!("Already linked to {}, dropping new link", server);
+        }
+    }
+
+    pub(super) async fn peer_disconnected(&mut self, server: &str, link: Uuid) {
+        let peer = match self.federation.disconnect(server, link) {
+            Some(peer) => peer,
+            None => return,
+        };
+        for user in peer.users.values() {
+            self.users
+                .send_to_location(
+                    user.location.clone(),
+                    Arc::new(
+                        UserLeftMessage {
+                            username: tag(&user.username, server),
+                            destination: None,
+                        }
+                        .into(),
+                    ),
+                )
+                .await;
+        }
+        for channel in peer.channels.values() {
+            self.users
+                .send_to_all(Arc::new(
+                    DropChannelMessage {
+                        channel_name: tag(channel, server),
+                    }
+                    .into(),
+                ))
+                .await;
+        }
+        for game in peer.games.values() {
+            self.users
+                .send_to_all(Arc::new(
+                    DropGameMessage {
+                        game_name: tag(&game.name, server),
+                    }
+                    .into(),
+                ))
+                .await;
+        }
+        self.evict_users(|location| match location {
+            Location::RemoteChannel { server: s, .. } | Location::RemoteGame { server: s, .. } => {
+                s == server
+            }
+            _ => false,
+        })
+        .await;
+    }
+
+    /// Moves local users whose location went away back to the default channel
+    async fn evict_users(&mut self, evicted: impl Fn(&Location) -> bool) {
+        let users: Vec<User> = self
+            .users
+            .iter()
+            .filter(|u| evicted(&u.location))
+            .cloned()
+            .collect();
+        for user in users {
+            self.join_channel(user, DEFAULT_CHANNEL.to_string()).await;
+        }
+    }
+
+    pub(super) async fn join_remote_channel(&mut self, mut user: User, name: &str, server: &str) {
+        let name = match self.federation.get(server).and_then(|p| p.channel(name)) {
+            Some(name) => name.clone(),
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::ChannelNotFound))
+                    .await;
+                return;
+            }
+        };
+        let location = Location::RemoteChannel {
+            server: server.to_string(),
+            name: name.clone(),
+        };
+        if location == user.location {
+            return;
+        }
+        self.users.catch_up(&user).await;
+        user.send(Arc::new(
+            JoinChannelMessage {
+                channel_name: tag(&name, server),
+            }
+            .into(),
+        ))
+        .await;
+        self.send_users_in_location(&mut user, &location).await;
+        user.location = location;
+        self.users.update(user).await;
+    }
+
+    pub(super) async fn join_remote_game(
+        &mut self,
+        mut user: User,
+        name: &str,
+        server: &str,
+        password: Vec<u8>,
+    ) {
+        let game = match self.federation.get(server).and_then(|p| p.game(name)) {
+            Some(game) => game.clone(),
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::GameNotFound))
+                    .await;
+                return;
+            }
+        };
+        if let Ok(id) = Uuid::parse_str(&bytevec_to_str(&password)) {
+            if id == game.id {
+                log::info!("Client {} has joined remote game {}", user.id, game.name);
+                user.location = Location::RemoteGame {
+                    server: server.to_string(),
+                    name: game.name,
+                };
+                self.users.update(user).await;
+            }
+        } else if game.players >= game.max_players {
+            user.send(ErrorMessage::new_coded(LobbyError::GameFull))
+                .await;
+        } else if password == game.password {
+            user.send(Arc::new(
+                JoinGameMessage {
+                    version: user.game_version,
+                    game_name: tag(&game.name, server),
+                    password,
+                    id: game.id,
+                    ip_addr: game.host_ip,
+                    port: game.port,
+                }
+                .into(),
+            ))
+            .await;
+        } else {
+            user.send(ErrorMessage::new_coded(LobbyError::InvalidPassword))
+                .await;
+        }
+    }
+
+    /// Sends a public message to the peers with users in the sender's location
+    pub(super) async fn forward_public(
+        &mut self,
+        username: &str,
+        location: &Location,
+        message: &[u8],
+    ) {
+        let servers = match location {
+            Location::RemoteChannel { server, .. } | Location::RemoteGame { server, .. } => {
+                vec![server.clone()]
+            }
+            _ => self.federation.servers_with_users_in(location),
+        };
+        for server in servers {
+            let location = to_peer_location(location, &server);
+            if let (Some(peer), Some(location)) = (self.federation.get_mut(&server), location) {
+                peer.send(PeerMessage::Public {
+                    username: username.to_string(),
+                    location,
+                    message: message.to_vec(),
+                })
+                .await;
+            }
+        }
+    }
+
+    pub(super) async fn handle_peer_message(&mut self, server: &str, message: PeerMessage) {
+        if self.federation.get(server).is_none() {
+            log::debug!("Ignoring message from unlinked server {}", server);
+            return;
+        }
+        match message {
+            PeerMessage::Hello { .. } | PeerMessage::Proof { .. } => {
+                log::warn!("Unexpected handshake message from {}", server)
+            }
+            PeerMessage::UserPresent { username, location } => {
+                self.remote_user_present(server, username, location).await
+            }
+            PeerMessage::UserGone { username } => self.remote_user_gone(server, &username).await,
+            PeerMessage::ChannelCreated { name } => {
+                if !self.names.is_valid_channel_name(&name) {
+                    return;
+                }
+                let peer = self.federation.get_mut(server).unwrap();
+                if peer
+                    .channels
+                    .insert(name.to_ascii_lowercase(), name.clone())
+                    .is_none()
+                {
+                    self.users
+                        .send_to_all(Arc::new(
+                            NewChannelMessage {
+                                channel_name: tag(&name, server),
+                            }
+                            .into(),
+                        ))
+                        .await;
+                }
+            }
+            PeerMessage::ChannelDropped { name } => {
+                let peer = self.federation.get_mut(server).unwrap();
+                if let Some(name) = peer.channels.remove(&name.to_ascii_lowercase()) {
+                    self.users
+                        .send_to_all(Arc::new(
+                            DropChannelMessage {
+                                channel_name: tag(&name, server),
+                            }
+                            .into(),
+                        ))
+                        .await;
+                    let location = Location::RemoteChannel {
+                        server: server.to_string(),
+                        name,
+                    };
+                    self.evict_users(|l| *l == location).await;
+                }
+            }
+            PeerMessage::GameOpened {
+                name,
+                id,
+                host_ip,
+                port,
+                password,
+                players,
+                max_players,
+            } => {
+                if !self.names.is_valid_game_name(&name) {
+                    return;
+                }
+                let peer = self.federation.get_mut(server).unwrap();
+                let game = RemoteGame {
+                    name,
+                    id,
+                    host_ip,
+                    port,
+                    password,
+                    players,
+                    max_players,
+                };
+                let message = peer.to_new_game_message(&game, server);
+                peer.games.insert(game.name.to_ascii_lowercase(), game);
+                self.users.send_to_all(message).await;
+            }
+            PeerMessage::GameDropped { name } => {
+                let peer = self.federation.get_mut(server).unwrap();
+                if let Some(game) = peer.games.remove(&name.to_ascii_lowercase()) {
+                    self.users
+                        .send_to_all(Arc::new(
+                            DropGameMessage {
+                                game_name: tag(&game.name, server),
+                            }
+                            .into(),
+                        ))
+                        .await;
+                }
+            }
+            PeerMessage::Public {
+                username,
+                location,
+                message,
+            } => {
+                if let Some(location) = from_peer_location(&location, server) {
+                    let message = self.filter.apply(&location, Role::Player, message);
+                    let username = tag(&username, server);
+                    self.channels.record_message(&location, &username, &message);
+                    self.users
+                        .send_to_location(
+                            location,
+                            Arc::new(SendMessage { username, message }.into()),
+                        )
+                        .await;
+                }
+            }
+            PeerMessage::Private { from, to, message } => {
+                let message = self.filter.apply(&Location::Nowhere, Role::Player, message);
+                let origin = self
+                    .federation
+                    .get(server)
+                    .and_then(|p| p.users.get(&from.to_ascii_lowercase()))
+                    .map(|u| u.location.to_string())
+                    .unwrap_or_default();
+                if let Some(recipient) = self.users.by_username_mut(&to) {
+                    recipient
+                        .send(Arc::new(
+                            PrivateMessage {
+                                from: tag(&from, server),
+                                to: recipient.username.clone(),
+                                location: origin,
+                                message,
+                            }
+                            .into(),
+                        ))
+                        .await;
+                }
+            }
+        }
+    }
+
+    async fn remote_user_present(
+        &mut self,
+        server: &str,
+        username: String,
+        location: PeerLocation,
+    ) {
+        let location = match from_peer_location(&location, server) {
+            Some(location) => location,
+            None => return,
+        };
+        if let Location::Channel { name } = &location {
+            if !self.names.is_valid_channel_name(name) {
+                return;
+            }
+            // the channel may have been dropped before the peer learned about it
+            self.channels.get_or_create(&mut self.users, name).await;
+        }
+        let tagged = tag(&username, server);
+        let peer = self.federation.get_mut(server).unwrap();
+        let previous = peer.users.insert(
+            username.to_ascii_lowercase(),
+            RemoteUser {
+                username,
+                location: location.clone(),
+            },
+        );
+        let origin = match previous {
+            Some(previous) if previous.location == location => return,
+            Some(previous) => {
+                self.users
+                    .send_to_location(
+                        previous.location.clone(),
+                        Arc::new(
+                            UserLeftMessage {
+                                username: tagged.clone(),
+                                destination: Some(location.to_string()),
+                            }
+                            .into(),
+                        ),
+                    )
+                    .await;
+                Some(previous.location.to_string())
+            }
+            None => None,
+        };
+        self.users
+            .send_to_location(
+                location,
+                Arc::new(
+                    UserJoinedMessage {
+                        username: tagged,
+                        origin,
+                        version_idx: 0,
+                    }
+                    .into(),
+                ),
+            )
+            .await;
+    }
+
+    async fn remote_user_gone(&mut self, server: &str, username: &str) {
+        let peer = self.federation.get_mut(server).unwrap();
+        if let Some(user) = peer.users.remove(&username.to_ascii_lowercase()) {
+            self.users
+                .send_to_location(
+                    user.location,
+                    Arc::new(
+                        UserLeftMessage {
+                            username: tag(&user.username, server),
+                            destination: None,
+                        }
+                        .into(),
+                    ),
+                )
+                .await;
+        }
+    }
+
+    /// What the given peer should currently know about this server
+    fn peer_view(&self, server: &str) -> PeerView {
+        PeerView {
+            users: self
+                .users
+                .iter()
+                .filter(|u| !u.invisible)
+                .filter_map(|u| Some((u.username.clone(), to_peer_location(&u.location, server)?)))
+                .collect(),
+            channels: self.channels.iter().map(|c| c.name.clone()).collect(),
+            games: self
+                .games
+                .iter()
+                .filter(|g| g.status == Open)
+                // peers hand out addresses without asking the host
+                .filter(|g| !self.privacy.hide_host_ips || g.relay.is_some())
+                .map(|g| {
+                    (
+                        g.name.to_ascii_lowercase(),
+                        RemoteGame {
+                            name: g.name.clone(),
+                            id: g.id,
+                            host_ip: g.connect_ip(),
+                            port: self.games.custom_port(g),
+                            password: g.password.clone(),
+                            players: g.player_count(),
+                            max_players: g.max_players,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Tells every peer what changed since it was last updated
+    pub(super) async fn sync_peers(&mut self) {
+        for server in self.federation.servers() {
+            let view = self.peer_view(&server);
+            if let Some(peer) = self.federation.get_mut(&server) {
+                let messages = view.diff(&peer.sent);
+                peer.sent = view;
+                for message in messages {
+                    peer.send(message).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_translation_is_symmetric() {
+        let local = Location::Channel {
+            name: "General".to_string(),
+        };
+        let sent = to_peer_location(&local, "b").unwrap();
+        // on the receiving side, the channel lives on the sending server "a"
+        assert_eq!(
+            from_peer_location(&sent, "a"),
+            Some(Location::RemoteChannel {
+                server: "a".to_string(),
+                name: "General".to_string()
+            })
+        );
+
+        let remote = Location::RemoteChannel {
+            server: "b".to_string(),
+            name: "General".to_string(),
+        };
+        assert_eq!(to_peer_location(&remote, "c"), None);
+        let sent = to_peer_location(&remote, "b").unwrap();
+        assert_eq!(
+            from_peer_location(&sent, "a"),
+            Some(Location::Channel {
+                name: "General".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_view_diff() {
+        let mut previous = PeerView::default();
+        previous.channels.insert("General".to_string());
+        previous.users.insert(
+            "foo".to_string(),
+            PeerLocation {
+                name: "#General".to_string(),
+                on_receiver: false,
+            },
+        );
+        let mut current = PeerView::default();
+        current.channels.insert("Other".to_string());
+
+        assert_eq!(
+            current.diff(&previous),
+            vec![
+                PeerMessage::ChannelDropped {
+                    name: "General".to_string()
+                },
+                PeerMessage::ChannelCreated {
+                    name: "Other".to_string()
+                },
+                PeerMessage::UserGone {
+                    username: "foo".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_tag() {
+        assert_eq!(split_tag("foo@server"), Some(("foo", "server")));
+        assert_eq!(split_tag("foo"), None);
+        assert_eq!(split_tag("@server"), None);
+    }
+}
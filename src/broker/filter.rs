@@ -0,0 +1,122 @@
+use crate::broker::user::{Location, Role};
+use crate::config::FilterConfig;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Masks words from a configurable list in chat messages.
+/// Words are matched case-insensitively and only as whole words.
+#[derive(Default)]
+pub struct ContentFilter {
+    word_list: Option<PathBuf>,
+    words: HashSet<Vec<u8>>,
+    exempt_channels: HashSet<String>,
+}
+
+impl ContentFilter {
+    pub fn new(config: &FilterConfig) -> Result<Self> {
+        let mut filter = Self {
+            word_list: config.word_list.clone(),
+            words: HashSet::new(),
+            exempt_channels: config
+                .exempt_channels
+                .iter()
+                .map(|c| c.to_ascii_lowercase())
+                .collect(),
+        };
+        filter.reload()?;
+        Ok(filter)
+    }
+
+    /// Re-reads the word list from disk and returns the number of words now filtered
+    pub fn reload(&mut self) -> Result<usize> {
+        if let Some(path) = &self.word_list {
+            let contents = std::fs::read(path)
+                .with_context(|| format!("Could not read word list {}", path.display()))?;
+            self.words = contents
+                .split(|b| *b == b'\n')
+                .map(|w| w.trim_ascii().to_ascii_lowercase())
+                .filter(|w| !w.is_empty() && !w.starts_with(b"#"))
+                .collect();
+            log::info!("Loaded {} filtered words", self.words.len());
+        }
+        Ok(self.words.len())
+    }
+
+    fn is_exempt(&self, location: &Location, role: Role) -> bool {
+        if role.is_staff() {
+            return true;
+        }
+        match location {
+            Location::Channel { name } => self.exempt_channels.contains(&name.to_ascii_lowercase()),
+            _ => false,
+        }
+    }
+
+    /// Masks filtered words in a message sent by a user with the given role to the given location
+    pub fn apply(&self, location: &Location, role: Role, message: Vec<u8>) -> Vec<u8> {
+        if self.words.is_empty() || self.is_exempt(location, role) {
+            return message;
+        }
+
+        let mut result = message;
+        let mut start = 0;
+        while start < result.len() {
+            let len = result[start..]
+                .iter()
+                .position(|b| !b.is_ascii_alphanumeric())
+                .unwrap_or(result.len() - start);
+            if len > 0
+                && self
+                    .words
+                    .contains(&result[start..start + len].to_ascii_lowercase())
+            {
+                for b in &mut result[start..start + len] {
+                    *b = b'*';
+                }
+            }
+            start += len + 1;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> ContentFilter {
+        ContentFilter {
+            word_list: None,
+            words: vec![b"darn".to_vec()].into_iter().collect(),
+            exempt_channels: vec!["adults".to_string()].into_iter().collect(),
+        }
+    }
+
+    fn channel(name: &str) -> Location {
+        Location::Channel {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_masks_whole_words_only() {
+        assert_eq!(
+            filter().apply(&channel("General"), Role::Player, b"Darn, darnit!".to_vec()),
+            b"****, darnit!".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_exemptions() {
+        let message = b"darn".to_vec();
+        assert_eq!(
+            filter().apply(&channel("Adults"), Role::Player, message.clone()),
+            message
+        );
+        assert_eq!(
+            filter().apply(&channel("General"), Role::Moderator, message.clone()),
+            message
+        );
+    }
+}
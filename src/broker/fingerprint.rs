@@ -0,0 +1,175 @@
+use crate::config::BuildRule;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Game,
+    WebSocket,
+}
+
+/// Details of a client's login handshake that differ between community patches and builds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Fingerprint {
+    pub transport: Transport,
+    pub language: String,
+    /// size of the compressed ident frame, which depends on the build's zlib settings
+    pub ident_size: usize,
+    /// bytes following the known fields of the ident and login messages
+    pub ident_extra: usize,
+    pub login_extra: usize,
+    /// time from connecting until the ident arrived
+    pub ident_delay_ms: u64,
+    /// time from our ident answer until the login arrived
+    pub login_delay_ms: u64,
+}
+
+impl Fingerprint {
+    pub fn websocket() -> Self {
+        Self {
+            transport: Transport::WebSocket,
+            ..Self::default()
+        }
+    }
+
+    /// The stable part of the fingerprint, leaving out timing
+    pub fn signature(&self) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.language, self.ident_size, self.ident_extra, self.login_extra
+        )
+    }
+
+    fn matches(&self, rule: &BuildRule) -> bool {
+        rule.language.as_ref().is_none_or(|l| *l == self.language)
+            && rule.ident_size.is_none_or(|s| s == self.ident_size)
+            && rule.ident_extra.is_none_or(|e| e == self.ident_extra)
+            && rule.login_extra.is_none_or(|e| e == self.login_extra)
+            && rule
+                .max_ident_delay_ms
+                .is_none_or(|d| self.ident_delay_ms <= d)
+    }
+}
+
+/// Login and online counts for one build
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BuildStats {
+    pub build: String,
+    pub online: usize,
+    /// logins since the server started
+    pub logins: u64,
+}
+
+/// Names client builds by their fingerprint and counts their logins
+pub struct Builds {
+    rules: Vec<BuildRule>,
+    logins: BTreeMap<String, u64>,
+}
+
+impl Builds {
+    pub fn new(rules: Vec<BuildRule>) -> Self {
+        Self {
+            rules,
+            logins: BTreeMap::new(),
+        }
+    }
+
+    /// Unrecognized game clients are named after their signature so that operators can
+    /// tell them apart and add a rule
+    pub fn classify(&self, fingerprint: &Fingerprint) -> String {
+        if fingerprint.transport == Transport::WebSocket {
+            return "websocket".to_string();
+        }
+        match self.rules.iter().find(|r| fingerprint.matches(r)) {
+            Some(rule) => rule.name.clone(),
+            None => format!("unknown:{}", fingerprint.signature()),
+        }
+    }
+
+    pub fn record_login(&mut self, build: &str) {
+        *self.logins.entry(build.to_string()).or_default() += 1;
+    }
+
+    pub fn stats<'a>(&self, online_builds: impl Iterator<Item = &'a str>) -> Vec<BuildStats> {
+        let mut online = BTreeMap::new();
+        for build in online_builds {
+            *online.entry(build).or_insert(0) += 1;
+        }
+        self.logins
+            .iter()
+            .map(|(build, logins)| BuildStats {
+                build: build.clone(),
+                online: online.get(build.as_str()).copied().unwrap_or(0),
+                logins: *logins,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint() -> Fingerprint {
+        Fingerprint {
+            language: "en".to_string(),
+            ident_size: 40,
+            ident_delay_ms: 250,
+            ..Fingerprint::default()
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let builds = Builds::new(vec![
+            BuildRule {
+                name: "scripted".to_string(),
+                max_ident_delay_ms: Some(10),
+                ..BuildRule::default()
+            },
+            BuildRule {
+                name: "tmp2.2".to_string(),
+                language: Some("en".to_string()),
+                ident_size: Some(40),
+                ..BuildRule::default()
+            },
+        ]);
+        assert_eq!(builds.classify(&fingerprint()), "tmp2.2");
+        let german = Fingerprint {
+            language: "de".to_string(),
+            ..fingerprint()
+        };
+        assert_eq!(builds.classify(&german), "unknown:de/40/0/0");
+        let scripted = Fingerprint {
+            ident_delay_ms: 2,
+            ..fingerprint()
+        };
+        assert_eq!(builds.classify(&scripted), "scripted");
+        assert_eq!(builds.classify(&Fingerprint::websocket()), "websocket");
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut builds = Builds::new(Vec::new());
+        builds.record_login("a");
+        builds.record_login("a");
+        builds.record_login("b");
+        assert_eq!(
+            builds.stats(vec!["a"].into_iter()),
+            vec![
+                BuildStats {
+                    build: "a".to_string(),
+                    online: 1,
+                    logins: 2
+                },
+                BuildStats {
+                    build: "b".to_string(),
+                    online: 0,
+                    logins: 1
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::JoinFloodConfig;
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct ChannelJoins {
+    recent: VecDeque<Instant>,
+    flooded_until: Option<Instant>,
+    last_join_by_ip: HashMap<Ipv4Addr, Instant>,
+}
+
+impl ChannelJoins {
+    fn is_flooded(&self, now: Instant) -> bool {
+        self.flooded_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Detects rapid join/part cycling in a channel. While a channel is flooded, every IP
+/// has to wait for a cooldown after joining it before it may join it again.
+pub struct JoinFloodGuard {
+    max_joins: usize,
+    window: Duration,
+    cooldown: Duration,
+    channels: HashMap<String, ChannelJoins>,
+}
+
+impl JoinFloodGuard {
+    pub fn new(config: &JoinFloodConfig) -> Self {
+        Self {
+            max_joins: config.max_joins,
+            window: Duration::from_secs(config.window_secs),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Returns how long the IP still has to wait if it may not join the channel right now
+    pub fn check(&self, channel: &str, ip: Ipv4Addr, now: Instant) -> Option<Duration> {
+        let joins = self.channels.get(&channel.to_ascii_lowercase())?;
+        if !joins.is_flooded(now) {
+            return None;
+        }
+        let last_join = joins.last_join_by_ip.get(&ip)?;
+        self.cooldown
+            .checked_sub(now.duration_since(*last_join))
+            .filter(|remaining| *remaining > Duration::from_secs(0))
+    }
+
+    /// Records a join, returning true if it pushed the channel into the flooded state
+    pub fn record(&mut self, channel: &str, ip: Ipv4Addr, now: Instant) -> bool {
+        let window = self.window;
+        let joins = self
+            .channels
+            .entry(channel.to_ascii_lowercase())
+            .or_default();
+        joins.last_join_by_ip.insert(ip, now);
+        joins.recent.push_back(now);
+        while joins
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > window)
+        {
+            joins.recent.pop_front();
+        }
+        if joins.recent.len() <= self.max_joins {
+            return false;
+        }
+        let was_flooded = joins.is_flooded(now);
+        joins.flooded_until = Some(now + self.cooldown);
+        !was_flooded
+    }
+
+    /// Forgets joins that can no longer influence any decision
+    pub fn prune(&mut self, now: Instant) {
+        let horizon = self.window.max(self.cooldown);
+        self.channels.retain(|_, joins| {
+            joins
+                .last_join_by_ip
+                .retain(|_, t| now.duration_since(*t) <= horizon);
+            joins.recent.retain(|t| now.duration_since(*t) <= horizon);
+            !joins.last_join_by_ip.is_empty() || joins.is_flooded(now)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> JoinFloodGuard {
+        JoinFloodGuard::new(&JoinFloodConfig {
+            enabled: true,
+            max_joins: 3,
+            window_secs: 10,
+            cooldown_secs: 30,
+        })
+    }
+
+    #[test]
+    fn test_cooldown_only_while_flooded() {
+        let mut guard = guard();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let start = Instant::now();
+        for i in 0..3 {
+            assert_eq!(guard.check("General", ip, start), None);
+            assert!(!guard.record("General", ip, start + Duration::from_secs(i)));
+        }
+        assert!(guard.record("general", ip, start + Duration::from_secs(3)));
+
+        let now = start + Duration::from_secs(4);
+        assert_eq!(
+            guard.check("General", ip, now),
+            Some(Duration::from_secs(29))
+        );
+        // IPs that did not take part may still join, and other channels are unaffected
+        assert_eq!(
+            guard.check("General", Ipv4Addr::new(10, 0, 0, 2), now),
+            None
+        );
+        assert_eq!(guard.check("Other", ip, now), None);
+        assert_eq!(
+            guard.check("General", ip, start + Duration::from_secs(40)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_slow_joins_are_not_a_flood() {
+        let mut guard = guard();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let start = Instant::now();
+        for i in 0..10 {
+            assert!(!guard.record("General", ip, start + Duration::from_secs(i * 5)));
+        }
+        guard.prune(start + Duration::from_secs(200));
+        assert!(guard.channels.is_empty());
+    }
+}
@@ -1,16 +1,18 @@
 use crate::broker::game::GameStatus::{Open, Requested, Started};
+use crate::broker::names::Name;
 use crate::broker::user::{Location, User, Users};
 use crate::broker::ArcServerMessage;
+use crate::config::GamesConfig;
 use crate::messages::server_messages::{CreateGameMessage, DropGameMessage, NewGameMessage};
-use nom::lib::std::collections::HashMap;
+use crate::relay::RelayAllocation;
+use nom::lib::std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 use tokio::time::Duration;
 use uuid::Uuid;
 
-pub const ALLOWED_GAME_NAME_CHARS: &str =
-    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_+.| ";
+const MAX_TAG_LENGTH: usize = 8;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum GameStatus {
@@ -22,12 +24,31 @@ pub enum GameStatus {
 pub struct Game {
     pub hosted_by: Uuid,
     pub host_ip: Ipv4Addr,
+    pub host_port: u16,
+    /// the host's region, if known
+    pub region: Option<String>,
+    /// region or locale the host labelled the game with
+    pub tag: Option<String>,
+    /// channel the host requested the game from
+    pub origin: Option<String>,
     pub id: Uuid,
     pub game_version: Uuid,
     pub name: String,
     pub password: Vec<u8>,
     pub status: GameStatus,
     pub created_at: Instant,
+    pub players: HashSet<Uuid>,
+    pub max_players: u32,
+    pub started_at: Option<SystemTime>,
+    /// accounts of the players at the time the game was started
+    pub roster: Vec<String>,
+    pub relay: Option<RelayAllocation>,
+    /// pinned games are announced before all others
+    pub pinned: bool,
+    /// users waiting for the host to approve them joining
+    pub knocks: HashSet<Uuid>,
+    /// users watching the game, who do not count as players
+    pub spectators: HashSet<Uuid>,
 }
 
 impl Game {
@@ -38,30 +59,148 @@ impl Game {
     }
 
     pub fn to_new_game_message(&self) -> ArcServerMessage {
-        Arc::new(NewGameMessage {
-            id: self.id,
-            game_name: self.name.clone(),
-        })
+        Arc::new(
+            NewGameMessage {
+                id: self.id,
+                game_name: self.name.clone(),
+                players: self.player_count(),
+                max_players: self.max_players,
+                spectators_only: self.status == Started,
+                tag: self.tag.clone(),
+            }
+            .into(),
+        )
+    }
+
+    /// The address joiners should connect to
+    pub fn connect_ip(&self) -> Ipv4Addr {
+        match &self.relay {
+            Some(relay) => relay.public_ip,
+            None => self.host_ip,
+        }
+    }
+
+    /// The port joiners should connect to
+    pub fn connect_port(&self) -> u16 {
+        match &self.relay {
+            Some(relay) => relay.port,
+            None => self.host_port,
+        }
+    }
+
+    pub fn player_count(&self) -> u32 {
+        self.players.len() as u32
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.player_count() >= self.max_players
     }
 
     pub fn to_drop_game_message(&self) -> ArcServerMessage {
-        Arc::new(DropGameMessage {
-            game_name: self.name.clone(),
-        })
+        Arc::new(
+            DropGameMessage {
+                game_name: self.name.clone(),
+            }
+            .into(),
+        )
+    }
+
+    /// Games are only shown to users of the same game version, and to bots, which watch them all
+    pub fn is_visible_to(&self, user: &User) -> bool {
+        user.bot || user.game_version == self.game_version
+    }
+
+    async fn send_to_visible(&self, users: &mut Users, message: ArcServerMessage) {
+        users
+            .send_to_matching(|u| self.is_visible_to(u), message)
+            .await;
     }
 }
 
+/// Orders games pinned ones first, then those hosted in the region, then those with the closest
+/// hosts, and by name after that
+pub fn sort_by_proximity(
+    games: &mut [&Game],
+    region: Option<&str>,
+    latency: impl Fn(&Game) -> Option<Duration>,
+) {
+    let elsewhere = |game: &Game| region.is_none() || game.region.as_deref() != region;
+    // hosts that do not answer probes go last
+    let distance = |game: &Game| latency(game).unwrap_or(Duration::MAX);
+    games.sort_by(|a, b| {
+        b.pinned
+            .cmp(&a.pinned)
+            .then_with(|| elsewhere(a).cmp(&elsewhere(b)))
+            .then_with(|| distance(a).cmp(&distance(b)))
+            .then_with(|| {
+                a.name
+                    .to_ascii_lowercase()
+                    .cmp(&b.name.to_ascii_lowercase())
+            })
+    });
+}
+
+/// Tags are short labels like EU, NA-east or de, which are compared ignoring case
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let valid = (1..=MAX_TAG_LENGTH).contains(&tag.len())
+        && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    Some(tag.to_ascii_uppercase()).filter(|_| valid)
+}
+
 pub struct Games {
-    by_name: HashMap<String, Game>,
+    by_name: HashMap<Name, Game>,
+    max_players: u32,
+    default_port: u16,
+    /// started games stay listed for spectators to join
+    allow_spectators: bool,
+    requested_timeout: Duration,
+    /// how long started games may stay around, if limited
+    max_started: Option<Duration>,
+    /// started games left this soon after the start were aborted, if detected at all
+    aborted_within: Option<Duration>,
+    /// announce games close to a user first rather than in no particular order
+    nearby_first: bool,
 }
 
 impl Games {
-    pub fn new() -> Self {
+    pub fn new(config: &GamesConfig) -> Self {
         Self {
             by_name: HashMap::new(),
+            max_players: config.max_players,
+            default_port: config.default_port,
+            allow_spectators: config.spectators,
+            requested_timeout: Duration::from_secs(config.requested_timeout_secs),
+            max_started: Some(Duration::from_secs(config.max_started_secs))
+                .filter(|max| *max > Duration::from_secs(0)),
+            aborted_within: Some(Duration::from_secs(config.aborted_within_secs))
+                .filter(|within| *within > Duration::from_secs(0)),
+            nearby_first: config.nearby_first,
         }
     }
 
+    pub fn allows_spectators(&self) -> bool {
+        self.allow_spectators
+    }
+
+    /// Whether the game shows up in the clients' game lists
+    fn is_listed(&self, game: &Game) -> bool {
+        match game.status {
+            Requested => false,
+            Open => true,
+            Started => self.allow_spectators,
+        }
+    }
+
+    /// Games shown in the clients' game lists
+    pub fn listed(&self) -> impl Iterator<Item = &Game> {
+        self.by_name.values().filter(move |g| self.is_listed(g))
+    }
+
+    /// The port to tell joiners about, if it is not the one clients use anyway
+    pub fn custom_port(&self, game: &Game) -> Option<u16> {
+        Some(game.connect_port()).filter(|port| *port != self.default_port)
+    }
+
     pub fn count(&self) -> u32 {
         self.by_name.len() as u32
     }
@@ -70,15 +209,28 @@ impl Games {
         self.by_name.values().filter(|g| g.status == Open).count() as u32
     }
 
+    pub fn count_started(&self) -> u32 {
+        self.by_name
+            .values()
+            .filter(|g| g.status == Started)
+            .count() as u32
+    }
+
     pub fn get(&self, name: &str) -> Option<&Game> {
-        self.by_name.get(&name.to_ascii_lowercase())
+        self.by_name.get(&Name::new(name))
     }
 
     pub fn get_mut(&mut self, name: &str) -> Option<&mut Game> {
-        self.by_name.get_mut(&name.to_ascii_lowercase())
+        self.by_name.get_mut(&Name::new(name))
     }
 
-    pub async fn create_game(&mut self, user: &mut User, name: &str, password: &[u8]) {
+    pub async fn create_game(
+        &mut self,
+        user: &mut User,
+        name: &str,
+        password: &[u8],
+        tag: Option<String>,
+    ) {
         log::info!(
             "User {} has requested to host new game {}",
             user.username,
@@ -87,57 +239,118 @@ impl Games {
         let game = Game {
             hosted_by: user.id,
             host_ip: user.ip_addr,
+            host_port: user.host_port.unwrap_or(self.default_port),
+            region: user.region.clone(),
+            tag,
+            origin: match &user.location {
+                Location::Channel { name } => Some(name.clone()),
+                _ => None,
+            },
             name: name.to_string(),
             password: password.to_vec(),
             status: Requested,
             id: Uuid::from_u128(0),
             game_version: user.game_version,
             created_at: Instant::now(),
+            players: HashSet::new(),
+            max_players: self.max_players,
+            started_at: None,
+            roster: Vec::new(),
+            relay: None,
+            pinned: false,
+            knocks: HashSet::new(),
+            spectators: HashSet::new(),
         };
-        user.send(Arc::new(CreateGameMessage {
-            game_name: game.name.clone(),
-            password: game.password.clone(),
-            version: game.game_version,
-            id: Uuid::new_v4(),
-        }))
+        user.send(Arc::new(
+            CreateGameMessage {
+                game_name: game.name.clone(),
+                password: game.password.clone(),
+                version: game.game_version,
+                id: Uuid::new_v4(),
+            }
+            .into(),
+        ))
         .await;
-        self.by_name.insert(name.to_ascii_lowercase(), game);
+        self.by_name.insert(Name::new(name), game);
     }
 
-    pub async fn open_game(&mut self, users: &mut Users, name: &str, id: Uuid) {
+    pub async fn open_game(
+        &mut self,
+        users: &mut Users,
+        name: &str,
+        id: Uuid,
+        relay: Option<RelayAllocation>,
+    ) {
         if let Some(game) = self.get_mut(name) {
             log::info!("Game {} is now open", name);
+            if let Some(relay) = &relay {
+                log::info!("Game {} is relayed via port {}", name, relay.port);
+            }
             game.id = id;
             game.status = Open;
-            users.send_to_all(game.to_new_game_message()).await;
+            game.relay = relay;
+            game.players.insert(game.hosted_by);
+            game.send_to_visible(users, game.to_new_game_message())
+                .await;
         }
     }
 
     pub async fn start_game(&mut self, users: &mut Users, name: &str) {
+        let allow_spectators = self.allow_spectators;
         if let Some(game) = self.get_mut(name) {
             log::info!("Game {} has started", name);
             game.status = Started;
-            users.send_to_all(game.to_drop_game_message()).await;
+            game.started_at = Some(SystemTime::now());
+            game.roster = game
+                .players
+                .iter()
+                .filter_map(|id| users.by_user_id(id))
+                .map(|u| u.account.clone())
+                .collect();
+            game.roster.sort();
+            if allow_spectators {
+                // replaces the listing with one marked for spectators
+                game.send_to_visible(users, game.to_new_game_message())
+                    .await;
+            } else {
+                game.send_to_visible(users, game.to_drop_game_message())
+                    .await;
+            }
         }
     }
 
-    pub async fn remove(&mut self, users: &mut Users, name: &str) {
-        if let Some(game) = self.by_name.remove(&name.to_ascii_lowercase()) {
-            log::info!("Removing game {}", name);
-            if game.status == Open {
-                users.send_to_all(game.to_drop_game_message()).await;
-            }
+    pub async fn remove(&mut self, users: &mut Users, name: &str) -> Option<Game> {
+        let game = self.by_name.remove(&Name::new(name))?;
+        log::info!("Removing game {}", name);
+        if self.is_listed(&game) {
+            game.send_to_visible(users, game.to_drop_game_message())
+                .await;
         }
+        Some(game)
     }
 
-    pub async fn check_remove_empty_games(&mut self, users: &mut Users) {
-        let occupied_locations = users.occupied_locations();
+    /// Forgets players that are no longer located in their game
+    pub fn update_players(&mut self, users: &Users) {
+        for game in self.by_name.values_mut() {
+            let location = game.to_location();
+            let in_game = |id: &Uuid| users.by_user_id(id).is_some_and(|u| u.location == location);
+            game.players.retain(in_game);
+            game.spectators.retain(in_game);
+        }
+    }
+
+    /// Removes games that were abandoned and returns them
+    pub async fn check_remove_empty_games(
+        &mut self,
+        users: &mut Users,
+        occupied_locations: &HashSet<Location>,
+    ) -> Vec<Game> {
         let empty_games: Vec<String> = self
             .by_name
             .values()
             .filter(|g| {
                 if g.status == Requested {
-                    g.created_at.elapsed() > Duration::new(30, 0)
+                    g.created_at.elapsed() > self.requested_timeout
                 } else {
                     !occupied_locations.contains(&g.to_location())
                 }
@@ -145,14 +358,118 @@ impl Games {
             .map(|g| g.name.clone())
             .collect();
 
+        let mut removed = Vec::new();
         for game in empty_games {
-            self.remove(users, &game).await;
+            removed.extend(self.remove(users, &game).await);
+        }
+        removed
+    }
+
+    /// Started games that have been running for longer than allowed
+    pub fn stale(&self) -> impl Iterator<Item = &Game> {
+        let max_started = self.max_started;
+        self.by_name.values().filter(move |g| {
+            let age = g.started_at.and_then(|started| started.elapsed().ok());
+            match (age, max_started) {
+                (Some(age), Some(max_started)) => g.status == Started && age > max_started,
+                _ => false,
+            }
+        })
+    }
+
+    /// Whether a started game is still so young that the match cannot have been played yet
+    pub fn just_started(&self, game: &Game) -> bool {
+        let age = game.started_at.and_then(|started| started.elapsed().ok());
+        match (age, self.aborted_within) {
+            (Some(age), Some(within)) => game.status == Started && age < within,
+            _ => false,
         }
     }
 
-    pub async fn announce_open(&self, user: &mut User) {
-        for game in self.by_name.values().filter(|g| g.status == Open) {
+    pub fn started_by_host(&self, host: Uuid) -> Option<&Game> {
+        self.by_name
+            .values()
+            .find(|g| g.hosted_by == host && g.status == Started)
+    }
+
+    pub fn open_by_host_mut(&mut self, host: Uuid) -> Option<&mut Game> {
+        self.by_name
+            .values_mut()
+            .find(|g| g.hosted_by == host && g.status == Open)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Game> {
+        self.by_name.values()
+    }
+
+    /// Announces all listed games of the user's version to them, pinned ones first, and unless
+    /// configured otherwise, then those closest to the user
+    pub async fn announce_listed(
+        &self,
+        user: &mut User,
+        latency: impl Fn(&Game) -> Option<Duration>,
+    ) {
+        let mut games: Vec<&Game> = self.listed().filter(|g| g.is_visible_to(user)).collect();
+        if self.nearby_first {
+            sort_by_proximity(&mut games, user.region.as_deref(), latency);
+        } else {
+            games.sort_by_key(|g| !g.pinned);
+        }
+        for game in games {
             user.send(game.to_new_game_message()).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(name: &str, region: Option<&str>, pinned: bool) -> Game {
+        Game {
+            hosted_by: Uuid::new_v4(),
+            host_ip: Ipv4Addr::LOCALHOST,
+            host_port: 17173,
+            region: region.map(str::to_string),
+            tag: None,
+            origin: None,
+            id: Uuid::new_v4(),
+            game_version: Uuid::nil(),
+            name: name.to_string(),
+            password: Vec::new(),
+            status: Open,
+            created_at: Instant::now(),
+            players: HashSet::new(),
+            max_players: 8,
+            started_at: None,
+            roster: Vec::new(),
+            relay: None,
+            pinned,
+            knocks: HashSet::new(),
+            spectators: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn test_sort_by_proximity() {
+        let far = game("far", Some("NA"), false);
+        let near = game("near", Some("EU"), false);
+        let unknown = game("unknown", None, false);
+        let fast = game("fast", Some("EU"), false);
+        let pinned = game("pinned", Some("NA"), true);
+        let latency = |game: &Game| match game.name.as_str() {
+            "fast" => Some(Duration::from_millis(20)),
+            "near" => Some(Duration::from_millis(60)),
+            _ => None,
+        };
+        let mut games = vec![&far, &near, &unknown, &fast, &pinned];
+        sort_by_proximity(&mut games, Some("EU"), latency);
+        let names: Vec<&str> = games.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, vec!["pinned", "fast", "near", "far", "unknown"]);
+
+        // without a region of their own, users only go by latency
+        sort_by_proximity(&mut games, None, latency);
+        let names: Vec<&str> = games.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, vec!["pinned", "fast", "near", "far", "unknown"]);
+    }
+}
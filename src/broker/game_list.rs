@@ -0,0 +1,103 @@
+use crate::broker::game::{sort_by_proximity, Game, GameStatus};
+use crate::broker::names::normalize;
+use crate::broker::user::User;
+use crate::broker::Broker;
+use crate::messages::client_command::GameFilter;
+use crate::messages::server_messages::InfoMessage;
+
+/// How many games /games lists at most, so that a lobby full of games does not flood the chat
+const MAX_LISTED_GAMES: usize = 20;
+
+fn matches(filter: &GameFilter, game: &Game, user: &User) -> bool {
+    game.status == GameStatus::Open
+        && (!filter.same_version || game.game_version == user.game_version)
+        // games and users of unknown regions are not close to anyone
+        && (!filter.same_region || (game.region.is_some() && game.region == user.region))
+        && filter.tag.as_ref().is_none_or(|tag| {
+            game.tag
+                .as_ref()
+                .is_some_and(|own| own.eq_ignore_ascii_case(tag))
+        })
+        && (!filter.without_password || game.password.is_empty())
+        && (!filter.with_free_slots || !game.is_full())
+        && filter
+            .name
+            .as_ref()
+            .is_none_or(|name| normalize(&game.name).contains(&normalize(name)))
+}
+
+impl Broker {
+    /// Lists every channel with its user count, pinned ones first, followed by the open games
+    pub(super) async fn list(&mut self, mut user: User) {
+        let mut channels: Vec<(bool, String, usize)> = self
+            .channels
+            .iter()
+            .map(|c| {
+                let users = self.users.visible_in_location(&c.to_location()).len();
+                (c.pinned, c.name.clone(), users)
+            })
+            .collect();
+        channels.sort_by(|a, b| {
+            b.0.cmp(&a.0)
+                .then_with(|| a.1.to_ascii_lowercase().cmp(&b.1.to_ascii_lowercase()))
+        });
+        for (_, name, users) in channels {
+            let plural = if users == 1 { "" } else { "s" };
+            let line = format!("#{} ({} user{})", name, users, plural);
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+        self.list_games(user, GameFilter::default()).await;
+    }
+
+    /// Lists the open games that match the filter as info lines, pinned ones first, then those
+    /// closest to the user
+    pub(super) async fn list_games(&mut self, mut user: User, filter: GameFilter) {
+        let mut games: Vec<&Game> = self
+            .games
+            .iter()
+            .filter(|g| matches(&filter, g, &user))
+            .collect();
+        sort_by_proximity(&mut games, user.region.as_deref(), |g| self.host_latency(g));
+        if games.is_empty() {
+            let info = if filter == GameFilter::default() {
+                "No open games"
+            } else {
+                "No open games match"
+            };
+            user.send(InfoMessage::new_info(info)).await;
+            return;
+        }
+        for game in games.iter().take(MAX_LISTED_GAMES) {
+            let tag = match &game.tag {
+                Some(tag) => format!(", {}", tag),
+                None => String::new(),
+            };
+            let password = if game.password.is_empty() {
+                ""
+            } else {
+                ", password"
+            };
+            let latency = match self.host_latency(game) {
+                Some(latency) => format!(", {} ms", latency.as_millis()),
+                None => String::new(),
+            };
+            let line = format!(
+                "${} ({}/{}{}{}{})",
+                game.name,
+                game.player_count(),
+                game.max_players,
+                tag,
+                password,
+                latency
+            );
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+        if games.len() > MAX_LISTED_GAMES {
+            user.send(InfoMessage::new_info(&format!(
+                "... and {} more, narrow down the list with a filter",
+                games.len() - MAX_LISTED_GAMES
+            )))
+            .await;
+        }
+    }
+}
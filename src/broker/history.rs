@@ -0,0 +1,75 @@
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const DOCUMENT: &str = "match_history";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchRecord {
+    pub game_name: String,
+    pub host: String,
+    pub players: Vec<String>,
+    pub winner: Option<String>,
+    /// seconds since the Unix epoch
+    pub started_at: u64,
+    pub duration_secs: u64,
+    /// all players disconnected shortly after the start
+    #[serde(default)]
+    pub aborted: bool,
+}
+
+impl MatchRecord {
+    pub fn summary(&self) -> String {
+        if self.aborted {
+            return format!(
+                "{} ({} min): {} - aborted",
+                self.game_name,
+                self.duration_secs / 60,
+                self.players.join(", ")
+            );
+        }
+        format!(
+            "{} ({} min): {} - winner: {}",
+            self.game_name,
+            self.duration_secs / 60,
+            self.players.join(", "),
+            self.winner.as_deref().unwrap_or("unknown")
+        )
+    }
+
+    fn involves(&self, username: &str) -> bool {
+        self.players
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(username))
+    }
+}
+
+/// Record of all finished matches, oldest first
+#[derive(Default)]
+pub struct MatchHistory {
+    records: Vec<MatchRecord>,
+}
+
+impl MatchHistory {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        Ok(Self {
+            records: storage.load(DOCUMENT)?,
+        })
+    }
+
+    pub fn record(&mut self, storage: &Storage, record: MatchRecord) {
+        log::info!("Recording match result: {}", record.summary());
+        self.records.push(record);
+        storage.save(DOCUMENT, &self.records);
+    }
+
+    /// The most recent matches, newest first, optionally only those a user took part in
+    pub fn recent(&self, username: Option<&str>, limit: usize) -> Vec<&MatchRecord> {
+        self.records
+            .iter()
+            .rev()
+            .filter(|r| username.is_none_or(|u| r.involves(u)))
+            .take(limit)
+            .collect()
+    }
+}
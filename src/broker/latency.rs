@@ -0,0 +1,76 @@
+use crate::broker::game::{Game, GameStatus};
+use crate::broker::user::User;
+use crate::broker::Broker;
+use crate::ipv6::is_synthesized;
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::server_messages::{ErrorMessage, InfoMessage};
+use std::time::Duration;
+
+impl Broker {
+    /// Has the pinger probe the hosts of all open games and the users online
+    pub(super) fn update_ping_hosts(&self) {
+        if let Some(pinger) = &self.pinger {
+            let users = self
+                .users
+                .iter()
+                .filter(|u| !u.bot && !is_synthesized(u.ip_addr))
+                .map(|u| u.ip_addr);
+            pinger.set_hosts(
+                self.games
+                    .iter()
+                    .filter(|g| g.status == GameStatus::Open)
+                    .map(|g| g.host_ip)
+                    .chain(users)
+                    .collect(),
+            );
+        }
+    }
+
+    /// The last measured round trip to the game's host
+    pub(super) fn host_latency(&self, game: &Game) -> Option<Duration> {
+        self.pinger.as_ref()?.latency(game.host_ip)
+    }
+
+    /// The last measured round trip to the user's machine
+    pub(super) fn user_latency(&self, user: &User) -> Option<Duration> {
+        self.pinger.as_ref()?.latency(user.ip_addr)
+    }
+
+    pub(super) async fn ping_self(&mut self, mut user: User) {
+        if self.pinger.is_none() {
+            user.send(ErrorMessage::new_err("Latency probing is disabled"))
+                .await;
+            return;
+        }
+        let text = match self.user_latency(&user) {
+            Some(latency) => format!("You are {} ms away from the server", latency.as_millis()),
+            None => "Your connection does not answer probes".to_string(),
+        };
+        user.send(InfoMessage::new_info(&text)).await;
+    }
+
+    pub(super) async fn ping_game(&mut self, mut user: User, game_name: &str) {
+        if self.pinger.is_none() {
+            user.send(ErrorMessage::new_err("Latency probing is disabled"))
+                .await;
+            return;
+        }
+        let game_name = game_name.strip_prefix('$').unwrap_or(game_name);
+        let text = match self.games.get(game_name) {
+            Some(game) if game.status == GameStatus::Open => match self.host_latency(game) {
+                Some(latency) => format!(
+                    "The host of {} is {} ms away from the server",
+                    game.name,
+                    latency.as_millis()
+                ),
+                None => format!("The host of {} does not answer probes", game.name),
+            },
+            _ => {
+                user.send(ErrorMessage::new_coded(LobbyError::GameNotFound))
+                    .await;
+                return;
+            }
+        };
+        user.send(InfoMessage::new_info(&text)).await;
+    }
+}
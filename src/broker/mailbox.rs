@@ -0,0 +1,204 @@
+use crate::broker::accounts::unix_now;
+use crate::broker::names::canonical;
+use crate::broker::user::User;
+use crate::broker::Broker;
+use crate::messages::client_command::MailAction;
+use crate::messages::server_messages::{ErrorMessage, InfoMessage, PrivateMessage};
+use crate::storage::Storage;
+use crate::util::bytevec_to_str;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DOCUMENT: &str = "mailbox";
+
+/// A private message that was sent while its recipient was offline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub from: String,
+    /// seconds since the Unix epoch
+    pub sent_at: u64,
+    pub message: Vec<u8>,
+    /// whether it was delivered on a login already
+    #[serde(default)]
+    pub delivered: bool,
+}
+
+impl Mail {
+    fn summary(&self, now: u64) -> String {
+        let minutes = now.saturating_sub(self.sent_at) / 60;
+        let age = match minutes {
+            0..=59 => format!("{} min", minutes),
+            60..=1439 => format!("{} h", minutes / 60),
+            _ => format!("{} d", minutes / (24 * 60)),
+        };
+        format!(
+            "{} ago from {}: {}",
+            age,
+            self.from,
+            bytevec_to_str(&self.message)
+        )
+    }
+}
+
+/// Mail of all users, by canonical recipient name
+#[derive(Default)]
+pub struct Mailbox {
+    by_name: BTreeMap<String, Vec<Mail>>,
+}
+
+impl Mailbox {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        let stored: BTreeMap<String, Vec<Mail>> = storage.load(DOCUMENT)?;
+        // documents from before unicode names are keyed by lowercase names
+        let mut by_name: BTreeMap<String, Vec<Mail>> = BTreeMap::new();
+        for (username, mails) in stored {
+            by_name
+                .entry(canonical(&username))
+                .or_default()
+                .extend(mails);
+        }
+        Ok(Self { by_name })
+    }
+
+    pub fn get(&self, username: &str) -> &[Mail] {
+        self.by_name
+            .get(&canonical(username))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn add(&mut self, storage: &Storage, username: &str, mail: Mail) {
+        self.by_name
+            .entry(canonical(username))
+            .or_default()
+            .push(mail);
+        storage.save(DOCUMENT, &self.by_name);
+    }
+
+    /// Returns the mail that was not delivered yet, marking it as delivered
+    pub fn take_undelivered(&mut self, storage: &Storage, username: &str) -> Vec<Mail> {
+        let mails = match self.by_name.get_mut(&canonical(username)) {
+            Some(mails) => mails,
+            None => return Vec::new(),
+        };
+        let undelivered: Vec<Mail> = mails.iter().filter(|m| !m.delivered).cloned().collect();
+        if !undelivered.is_empty() {
+            mails.iter_mut().for_each(|m| m.delivered = true);
+            storage.save(DOCUMENT, &self.by_name);
+        }
+        undelivered
+    }
+
+    pub fn remove(&mut self, storage: &Storage, username: &str) {
+        if self.by_name.remove(&canonical(username)).is_some() {
+            storage.save(DOCUMENT, &self.by_name);
+        }
+    }
+}
+
+impl Broker {
+    /// Keeps a private message for a registered user who is offline.
+    /// Returns false if the user has no account.
+    pub(super) async fn send_mail(
+        &mut self,
+        user: &mut User,
+        recipient: &str,
+        message: Vec<u8>,
+    ) -> bool {
+        let recipient = match self.accounts.get(recipient) {
+            Some(account) => account.username.clone(),
+            None => return false,
+        };
+        if self.mailbox.get(&recipient).len() >= self.mail.max_per_user {
+            user.send(ErrorMessage::new_err(&format!(
+                "The mailbox of {} is full",
+                recipient
+            )))
+            .await;
+            return true;
+        }
+        let mail = Mail {
+            from: user.account.clone(),
+            sent_at: unix_now(),
+            message,
+            delivered: false,
+        };
+        self.mailbox.add(&self.storage, &recipient, mail);
+        user.send(InfoMessage::new_info(&format!(
+            "{} is offline and will get your message on their next login",
+            recipient
+        )))
+        .await;
+        true
+    }
+
+    /// Hands a user who just logged in the mail that arrived while they were away
+    pub(super) async fn deliver_mail(&mut self, user: &mut User) {
+        let mails = self.mailbox.take_undelivered(&self.storage, &user.account);
+        if mails.is_empty() {
+            return;
+        }
+        user.send(InfoMessage::new_info(&format!(
+            "You got {} messages while you were away, review them with /mail",
+            mails.len()
+        )))
+        .await;
+        for mail in mails {
+            user.send(Arc::new(
+                PrivateMessage {
+                    from: mail.from,
+                    to: user.username.clone(),
+                    location: "mail".to_string(),
+                    message: mail.message,
+                }
+                .into(),
+            ))
+            .await;
+        }
+    }
+
+    pub(super) async fn mail_command(&mut self, mut user: User, action: MailAction) {
+        match action {
+            MailAction::Read => {
+                let now = unix_now();
+                let lines: Vec<String> = self
+                    .mailbox
+                    .get(&user.account)
+                    .iter()
+                    .map(|m| m.summary(now))
+                    .collect();
+                if lines.is_empty() {
+                    user.send(InfoMessage::new_info("You have no mail")).await;
+                }
+                for line in lines {
+                    user.send(InfoMessage::new_info(&line)).await;
+                }
+            }
+            MailAction::Clear => {
+                self.mailbox.remove(&self.storage, &user.account);
+                user.send(InfoMessage::new_info("Your mail has been deleted"))
+                    .await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mail_summary() {
+        let mail = Mail {
+            from: "foo".to_string(),
+            sent_at: 1000,
+            message: b"gg".to_vec(),
+            delivered: true,
+        };
+        assert_eq!(mail.summary(1000 + 5 * 60), "5 min ago from foo: gg");
+        assert_eq!(mail.summary(1000 + 3 * 3600), "3 h ago from foo: gg");
+        assert_eq!(mail.summary(1000 + 2 * 86400), "2 d ago from foo: gg");
+    }
+}
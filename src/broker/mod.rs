@@ -1,51 +1,258 @@
+pub mod accounts;
+mod bans;
+mod bots;
 mod channel;
+mod clans;
+pub mod control;
+mod credentials;
+pub mod daily;
+mod federation;
+mod filter;
+pub mod fingerprint;
+mod flood;
 mod game;
+mod game_list;
+pub mod history;
+mod latency;
+mod mailbox;
+mod names;
+pub mod plugin;
+pub mod predicate;
+mod preferences;
+mod profile;
+pub mod ranking;
+mod sanitize;
+mod send_queue;
+pub mod service_bot;
+mod sessions;
+mod shard;
+pub mod snapshot;
+pub mod status;
+mod throttle;
+pub mod totals;
+mod tournaments;
 pub mod user;
 
+use crate::audit_log::{AuditEvent, AuditLog};
+use crate::broker::accounts::{unix_now, Accounts, LoginCheck, PasswordOutcome};
+use crate::broker::bans::Bans;
 use crate::broker::channel::Channels;
-use crate::broker::game::{Games, ALLOWED_GAME_NAME_CHARS};
+use crate::broker::clans::Clans;
+use crate::broker::control::{ChannelInfo, ControlCommand, GameInfo, UserInfo};
+use crate::broker::credentials::Credentials;
+use crate::broker::daily::DailyStats;
+use crate::broker::federation::{split_tag, tag, Federation};
+use crate::broker::filter::ContentFilter;
+use crate::broker::fingerprint::{Builds, Fingerprint};
+use crate::broker::flood::JoinFloodGuard;
+use crate::broker::game::{normalize_tag, Game, Games};
+use crate::broker::history::{MatchHistory, MatchRecord};
+use crate::broker::mailbox::Mailbox;
+use crate::broker::names::Name;
+use crate::broker::plugin::{check_plugins, BrokerPlugin, Verdict};
+use crate::broker::preferences::Preferences;
+use crate::broker::ranking::Rankings;
+use crate::broker::sanitize::Sanitizer;
+use crate::broker::send_queue::CrowdedQueues;
+pub use crate::broker::send_queue::{message_queue, MessageReceiver, MessageSender};
+use crate::broker::service_bot::{HelpBot, ServiceEventSender};
+use crate::broker::sessions::{Session, Sessions};
+use crate::broker::snapshot::LobbySnapshot;
+use crate::broker::status::{ChannelStatus, OpenGameStatus, ServerStatus};
+use crate::broker::throttle::LoginThrottle;
+use crate::broker::totals::ServerTotals;
+use crate::broker::tournaments::Tournaments;
 use crate::broker::user::Users;
-use crate::messages::client_command::ClientCommand;
-use crate::messages::login_server::WelcomeServerMessage;
+use crate::chat_log::ChatLog;
+use crate::config::{BotsConfig, Config, MacrosConfig, MailConfig, PrivacyConfig, VersionsConfig};
+use crate::federation::{PeerMessage, PeerSender};
+use crate::geoip::GeoIp;
+use crate::ipv6::is_synthesized;
+use crate::messages::catalog::Language;
+use crate::messages::client_command::{ClientCommand, MacroAction};
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::login_server::{RejectServerMessage, WelcomeServerMessage};
 use crate::messages::server_messages::{
-    ErrorMessage, JoinChannelMessage, JoinGameMessage, PrivateMessage, SendMessage,
-    SentPrivateMessage, SyncStatsMessage,
+    AnnouncementMessage, ErrorMessage, InfoMessage, JoinChannelMessage, JoinGameMessage,
+    MatchSummaryMessage, NewUserMessage, NoticeMessage, PrivateMessage, SendMessage,
+    SentPrivateMessage, SyncStatsMessage, SERVER_NAME,
 };
-use crate::messages::ServerMessage;
-use crate::util::{bytevec_to_str, only_allowed_chars_not_empty};
+use crate::messages::{ServerMessage, WireMessage};
+use crate::metrics::Metrics;
+use crate::ping::Pinger;
+use crate::protocol_trace::ProtocolTrace;
+use crate::relay::Relay;
+use crate::replication::ReplicationFeed;
+use crate::server::wait_for_shutdown;
+use crate::storage::Storage;
+use crate::trace::{self, TraceContext, Tracer};
+use crate::util::{bytevec_to_str, format_duration, only_allowed_chars_not_empty};
+use crate::validation::NameValidator;
+use crate::version::build_info;
 use anyhow::Result;
-use channel::{ALLOWED_CHANNEL_NAME_CHARS, DEFAULT_CHANNEL};
+use channel::DEFAULT_CHANNEL;
+use game::GameStatus::Open;
 use game::GameStatus::Requested;
 use game::GameStatus::Started;
-use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::stream::StreamExt;
-use tokio::sync::{mpsc, watch};
-use user::{Location, User};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task;
+use user::{Location, Role, User};
 use uuid::Uuid;
 
-pub type ArcServerMessage = Arc<dyn ServerMessage>;
-pub type MessageSender = mpsc::Sender<ArcServerMessage>;
-pub type MessageReceiver = mpsc::Receiver<ArcServerMessage>;
+pub type ArcServerMessage = Arc<ServerMessage>;
 pub type EventSender = mpsc::Sender<Event>;
 pub type EventReceiver = mpsc::Receiver<Event>;
 
+/// How many messages a client's send queue holds before further ones wait in its backlog
+pub const SEND_QUEUE_CAPACITY: usize = 64;
+
+/// How many messages may wait in a client's backlog before the client is disconnected
+pub const BACKLOG_CAPACITY: usize = 1024;
+
+/// A message queued for delivery to a client, together with the trace it belongs to
+/// and the language the client runs the game in
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub message: ArcServerMessage,
+    pub trace: Option<TraceContext>,
+    pub language: Language,
+    /// shared by all recipients of a message sent to many users
+    pub prepared: Option<Arc<PreparedBytes>>,
+    /// counts the message as pending for its recipient until the writer is done with it
+    pub queued: Option<QueuedMessage>,
+}
+
+/// Holds a place in a client's queue depth while alive
+#[derive(Debug)]
+pub struct QueuedMessage(Arc<AtomicUsize>);
+
+impl QueuedMessage {
+    pub fn new(depth: &Arc<AtomicUsize>) -> Self {
+        depth.fetch_add(1, Ordering::Relaxed);
+        Self(depth.clone())
+    }
+}
+
+impl Clone for QueuedMessage {
+    fn clone(&self) -> Self {
+        Self::new(&self.0)
+    }
+}
+
+impl Drop for QueuedMessage {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl OutgoingMessage {
+    pub fn new(message: ArcServerMessage) -> Self {
+        Self::localized(message, Language::English)
+    }
+
+    pub fn localized(message: ArcServerMessage, language: Language) -> Self {
+        Self {
+            message,
+            trace: trace::current(),
+            language,
+            prepared: None,
+            queued: None,
+        }
+    }
+
+    /// The bytes to send, serialized only once per language for messages sent to many users
+    pub fn prepare(&self) -> Result<Arc<[u8]>> {
+        match &self.prepared {
+            Some(prepared) => prepared.get(&self.message, self.language),
+            None => Ok(self.message.prepare_localized(self.language)?.into()),
+        }
+    }
+}
+
+/// The serialized forms of a message sent to many users, which every recipient's writer
+/// would otherwise prepare on its own
+#[derive(Debug, Default)]
+pub struct PreparedBytes {
+    by_language: Mutex<HashMap<Language, Arc<[u8]>>>,
+}
+
+impl PreparedBytes {
+    fn get(&self, message: &ServerMessage, language: Language) -> Result<Arc<[u8]>> {
+        // the first writer prepares the message while the others wait for it
+        let mut by_language = self.by_language.lock().unwrap();
+        if let Some(bytes) = by_language.get(&language) {
+            return Ok(bytes.clone());
+        }
+        let bytes: Arc<[u8]> = message.prepare_localized(language)?.into();
+        by_language.insert(language, bytes.clone());
+        Ok(bytes)
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
     NewUser {
         id: Uuid,
         username: String,
+        password: String,
         game_version: Uuid,
         ip_addr: Ipv4Addr,
+        fingerprint: Fingerprint,
+        protocol_trace: ProtocolTrace,
         send: MessageSender,
     },
     Command {
         id: Uuid,
         command: ClientCommand,
+        trace: Option<TraceContext>,
     },
     DropClient {
         id: Uuid,
     },
+    /// a client was turned away for identifying with a game version that is not accepted
+    FailedIdent {
+        ip_addr: Ipv4Addr,
+    },
+    Control {
+        command: ControlCommand,
+    },
+    /// Asks for the public summary of the lobby
+    QueryState {
+        respond_to: oneshot::Sender<ServerStatus>,
+    },
+    /// a federation link to another server has been established
+    PeerConnected {
+        server: String,
+        link: Uuid,
+        send: PeerSender,
+    },
+    PeerMessage {
+        server: String,
+        message: PeerMessage,
+    },
+    PeerDisconnected {
+        server: String,
+        link: Uuid,
+    },
+    /// the password of a new user has been checked
+    LoginChecked {
+        user: Box<User>,
+        blank_password: bool,
+        outcome: PasswordOutcome,
+    },
+    /// a configured announcement is due in the default channel
+    ScheduledAnnouncement {
+        text: String,
+    },
+    /// Stops the broker once every event sent before it has been handled, no matter how many
+    /// senders are still around
+    Shutdown,
 }
 
 #[derive(PartialEq)]
@@ -62,14 +269,86 @@ struct Broker {
     channels: Channels,
     games: Games,
     stats: Stats,
+    started_at: Instant,
+    metrics: Metrics,
+    storage: Storage,
+    chat_log: ChatLog,
+    audit_log: AuditLog,
+    history: MatchHistory,
+    rankings: Rankings,
+    accounts: Accounts,
+    bans: Bans,
+    preferences: Preferences,
+    mailbox: Mailbox,
+    mail: MailConfig,
+    clans: Clans,
+    tournaments: Tournaments,
+    totals: ServerTotals,
+    daily: DailyStats,
+    /// where pairings and results are announced
+    tournament_channel: String,
+    macros: MacrosConfig,
+    privacy: PrivacyConfig,
+    /// confirmation tokens for /deleteaccount, by user id
+    deletion_tokens: HashMap<Uuid, (String, Instant)>,
+    deletion_grace_period: Duration,
+    sanitizer: Sanitizer,
+    names: NameValidator,
+    filter: ContentFilter,
+    builds: Builds,
+    join_flood: Option<JoinFloodGuard>,
+    login_throttle: Option<LoginThrottle>,
+    /// what recently disconnected users left behind
+    sessions: Sessions,
+    /// the token each user was given on login to resume their session with, by user id
+    resume_tokens: HashMap<Uuid, String>,
+    /// how long a client may leave its send queue full before it is disconnected
+    evict_after: Duration,
+    /// how long a client's send queue may stay nearly full before it is logged
+    warn_crowded_after: Duration,
+    crowded_queues: CrowdedQueues,
+    /// started games everyone left right after the start, until it is clear whether their
+    /// match is being played or fell apart
+    launched: Vec<Game>,
+    max_users: Option<usize>,
+    bots: Option<BotsConfig>,
+    plugins: Vec<Box<dyn BrokerPlugin>>,
+    /// events the broker sends itself: commands issued by service bots and checked passwords
+    service_events: ServiceEventSender,
+    /// names of the users whose password is being checked, by user id
+    pending_logins: HashMap<Uuid, Name>,
+    /// normalized names of the service bots and configured reserved names, which users may not
+    /// log in with
+    reserved_names: Vec<String>,
+    relay: Option<Relay>,
+    pinger: Option<Pinger>,
+    pinning: bool,
+    geoip: GeoIp,
+    versions: VersionsConfig,
+    federation: Federation,
+    admins: Vec<String>,
+    moderators: Vec<String>,
 }
 
 impl Broker {
-    fn new() -> Self {
-        Self {
+    fn new(
+        config: &Config,
+        metrics: Metrics,
+        storage: Storage,
+        chat_log: ChatLog,
+        audit_log: AuditLog,
+        plugins: Vec<Box<dyn BrokerPlugin>>,
+        service_events: ServiceEventSender,
+    ) -> Result<Self> {
+        let accounts = Accounts::load(
+            &storage,
+            Credentials::new(&config.accounts.password_hashing)?,
+        )?;
+        let totals = ServerTotals::load(&storage, accounts.count())?;
+        Ok(Self {
             users: Users::new(),
-            channels: Channels::new(),
-            games: Games::new(),
+            channels: Channels::new(&config.channels, &config.pinning),
+            games: Games::new(&config.games),
             stats: Stats {
                 users_total: 0,
                 users_online: 0,
@@ -77,14 +356,125 @@ impl Broker {
                 games_total: 0,
                 games_open: 0,
             },
+            started_at: Instant::now(),
+            metrics,
+            history: MatchHistory::load(&storage)?,
+            rankings: Rankings::load(&storage)?,
+            accounts,
+            bans: Bans::load(&storage)?,
+            preferences: Preferences::load(&storage)?,
+            mailbox: Mailbox::load(&storage)?,
+            clans: Clans::load(&storage)?,
+            tournaments: Tournaments::load(&storage)?,
+            totals,
+            daily: DailyStats::load(&storage)?,
+            tournament_channel: config.tournaments.channel.clone(),
+            mail: config.mail.clone(),
+            macros: config.macros.clone(),
+            privacy: config.privacy.clone(),
+            deletion_tokens: HashMap::new(),
+            pending_logins: HashMap::new(),
+            deletion_grace_period: Duration::from_secs(
+                config.accounts.deletion_grace_days * 24 * 60 * 60,
+            ),
+            storage,
+            chat_log,
+            audit_log,
+            sanitizer: Sanitizer::new(&config.sanitize),
+            names: NameValidator::new(&config.names),
+            filter: ContentFilter::new(&config.filter)?,
+            builds: Builds::new(config.builds.rules.clone()),
+            bots: Some(config.bots.clone()).filter(|bots| bots.enabled),
+            plugins,
+            service_events,
+            reserved_names: config
+                .accounts
+                .reserved_names
+                .iter()
+                .filter(|name| {
+                    let mut staff = config.roles.admins.iter().chain(&config.roles.moderators);
+                    !staff.any(|staff| staff.eq_ignore_ascii_case(name))
+                })
+                .map(|name| names::normalize(name))
+                .collect(),
+            join_flood: if config.join_flood.enabled {
+                Some(JoinFloodGuard::new(&config.join_flood))
+            } else {
+                None
+            },
+            login_throttle: if config.login_throttle.enabled {
+                Some(LoginThrottle::new(&config.login_throttle))
+            } else {
+                None
+            },
+            sessions: Sessions::new(&config.sessions),
+            resume_tokens: HashMap::new(),
+            evict_after: Duration::from_secs(config.slow_clients.evict_after_secs),
+            warn_crowded_after: Duration::from_secs(config.slow_clients.warn_after_secs),
+            crowded_queues: CrowdedQueues::default(),
+            launched: Vec::new(),
+            max_users: config.limits.max_users,
+            relay: Relay::new(&config.relay),
+            pinger: Pinger::new(&config.ping),
+            pinning: config.pinning.enabled,
+            geoip: GeoIp::new(&config.geoip)?,
+            versions: config.versions.clone(),
+            federation: Federation::new(),
+            admins: config.roles.admins.clone(),
+            moderators: config.roles.moderators.clone(),
+        })
+    }
+
+    fn role_for(&self, username: &str) -> Role {
+        let matches = |names: &Vec<String>| names.iter().any(|n| n.eq_ignore_ascii_case(username));
+        if matches(&self.admins) {
+            Role::Admin
+        } else if matches(&self.moderators) {
+            Role::Moderator
+        } else {
+            Role::Player
         }
     }
 
-    async fn public_message(&mut self, user: User, message: Vec<u8>) {
-        let send_msg = Arc::new(SendMessage {
-            username: user.username,
-            message,
-        });
+    async fn public_message(&mut self, mut user: User, message: Vec<u8>) {
+        let message = match self.sanitizer.apply(&message) {
+            Ok(message) => message,
+            Err(reason) => {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
+        };
+        if let Some(wait) = self.check_slowmode(&user) {
+            let secs = (wait.as_millis() as u64).div_ceil(1000);
+            user.send(ErrorMessage::new_err(&format!(
+                "Slowmode is on in {}, wait another {} seconds",
+                user.location, secs
+            )))
+            .await;
+            return;
+        }
+        let mut message = self.filter.apply(&user.location, user.role, message);
+        if let Verdict::Veto(reason) =
+            check_plugins(&mut self.plugins, |p| p.on_message(&user, &mut message))
+        {
+            user.send(ErrorMessage::new_err(&reason)).await;
+            return;
+        }
+        let message = self.tag_message(&user.account, message);
+        self.forward_public(&user.username, &user.location, &message)
+            .await;
+        self.channels
+            .record_message(&user.location, &user.username, &message);
+        self.chat_log
+            .public_message(&user.location, &user.username, &message);
+        self.daily.record_message();
+        let send_msg = Arc::new(
+            SendMessage {
+                username: user.username,
+                message,
+            }
+            .into(),
+        );
         self.users
             .send_to_location(user.location.clone(), send_msg)
             .await;
@@ -92,76 +482,132 @@ impl Broker {
 
     async fn private_message_channel(&mut self, mut user: User, channel: &str, message: Vec<u8>) {
         if let Some(channel) = self.channels.get(channel) {
-            user.send(Arc::new(SentPrivateMessage {
-                to: format!("#{}", channel.name),
-                message: message.clone(),
-            }))
+            let message = self
+                .filter
+                .apply(&channel.to_location(), user.role, message);
+            user.send(Arc::new(
+                SentPrivateMessage {
+                    to: format!("#{}", channel.name),
+                    message: message.clone(),
+                }
+                .into(),
+            ))
             .await;
             self.users
                 .send_to_location(
                     channel.to_location(),
-                    Arc::new(PrivateMessage {
-                        from: user.username.clone(),
-                        to: format!("#{}", channel.name),
-                        location: user.location.to_string(),
-                        message,
-                    }),
+                    Arc::new(
+                        PrivateMessage {
+                            from: user.username.clone(),
+                            to: format!("#{}", channel.name),
+                            location: user.location.to_string(),
+                            message,
+                        }
+                        .into(),
+                    ),
                 )
                 .await;
         } else {
-            user.send(ErrorMessage::new_err("Channel does not exist"))
+            user.send(ErrorMessage::new_coded(LobbyError::ChannelNotFound))
                 .await;
         }
     }
 
     async fn private_message_game(&mut self, mut user: User, game: &str, message: Vec<u8>) {
         if let Some(game) = self.games.get(game) {
-            user.send(Arc::new(SentPrivateMessage {
-                to: format!("${}", game.name),
-                message: message.clone(),
-            }))
+            let message = self.filter.apply(&game.to_location(), user.role, message);
+            user.send(Arc::new(
+                SentPrivateMessage {
+                    to: format!("${}", game.name),
+                    message: message.clone(),
+                }
+                .into(),
+            ))
             .await;
             self.users
                 .send_to_location(
                     Location::Game {
                         name: game.name.clone(),
                     },
-                    Arc::new(PrivateMessage {
-                        from: user.username.clone(),
-                        to: format!("${}", game.name),
-                        location: user.location.to_string(),
-                        message,
-                    }),
+                    Arc::new(
+                        PrivateMessage {
+                            from: user.username.clone(),
+                            to: format!("${}", game.name),
+                            location: user.location.to_string(),
+                            message,
+                        }
+                        .into(),
+                    ),
                 )
                 .await;
         } else {
-            user.send(ErrorMessage::new_err("Game does not exist"))
+            user.send(ErrorMessage::new_coded(LobbyError::GameNotFound))
                 .await;
         }
     }
 
     async fn private_message_user(&mut self, mut user: User, recipient: &str, message: Vec<u8>) {
-        if let Some(recipient) = self.users.by_username_mut(recipient) {
-            user.send(Arc::new(SentPrivateMessage {
-                to: recipient.username.clone(),
-                message: message.clone(),
-            }))
+        let message = self.filter.apply(&Location::Nowhere, user.role, message);
+        let online = self
+            .users
+            .visible_to(recipient, &user)
+            .or_else(|| self.users.by_account(recipient))
+            .filter(|r| r.is_visible_to(&user))
+            .map(|r| r.id);
+        if let Some(recipient) = online.and_then(|id| self.users.by_user_id_mut(&id)) {
+            user.send(Arc::new(
+                SentPrivateMessage {
+                    to: recipient.username.clone(),
+                    message: message.clone(),
+                }
+                .into(),
+            ))
             .await;
             recipient
-                .send(Arc::new(PrivateMessage {
+                .send(Arc::new(
+                    PrivateMessage {
+                        from: user.username.clone(),
+                        to: recipient.username.clone(),
+                        location: user.location.to_string(),
+                        message,
+                    }
+                    .into(),
+                ))
+                .await;
+        } else if let Some((name, server)) = self.federation.find_user(recipient) {
+            user.send(Arc::new(
+                SentPrivateMessage {
+                    to: tag(&name, &server),
+                    message: message.clone(),
+                }
+                .into(),
+            ))
+            .await;
+            if let Some(peer) = self.federation.get_mut(&server) {
+                peer.send(PeerMessage::Private {
                     from: user.username.clone(),
-                    to: recipient.username.clone(),
-                    location: user.location.to_string(),
+                    to: name,
                     message,
-                }))
+                })
                 .await;
-        } else {
-            user.send(ErrorMessage::new_err("User does not exist"))
+            }
+        } else if !self.send_mail(&mut user, recipient, message).await {
+            user.send(ErrorMessage::new_coded(LobbyError::UserNotFound))
                 .await;
         }
     }
 
-    async fn private_message(&mut self, user: User, target: String, message: Vec<u8>) {
+    async fn private_message(&mut self, mut user: User, target: String, message: Vec<u8>) {
+        let message = match self.sanitizer.apply(&message) {
+            Ok(message) => message,
+            Err(reason) => {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
+        };
+        self.daily.record_message();
+        self.chat_log
+            .private_message(&user.username, &target, &message);
         match &target[0..1] {
             "#" => {
                 self.private_message_channel(user, &target[1..], message)
@@ -172,257 +618,2003 @@ impl Broker {
         }
     }
 
-    async fn join_channel(&mut self, mut user: User, channel_name: String) {
-        if !only_allowed_chars_not_empty(&channel_name, ALLOWED_CHANNEL_NAME_CHARS) {
-            user.send(Arc::new(ErrorMessage {
-                error: "Invalid channel name".to_string(),
-            }))
+    /// Sends the client the list of local and remote users in a location
+    async fn send_users_in_location(&self, user: &mut User, location: &Location) {
+        for u in self.users.users_in_location(location) {
+            if !u.invisible || u.id == user.id {
+                user.send(u.to_new_user_message()).await;
+            }
+        }
+        for username in self.federation.users_in_location(location) {
+            user.send(Arc::new(
+                NewUserMessage {
+                    username,
+                    version_idx: 0,
+                }
+                .into(),
+            ))
             .await;
+        }
+    }
+
+    async fn join_channel(&mut self, mut user: User, channel_name: String) {
+        if let Some((name, server)) = split_tag(&channel_name) {
+            self.join_remote_channel(user, name, server).await;
+            return;
+        }
+        if !self.names.is_valid_channel_name(&channel_name) {
+            user.send(ErrorMessage::new_coded(LobbyError::InvalidChannelName))
+                .await;
             return;
         }
 
+        if let Some(reason) = self.check_clan_channel(&user, &channel_name) {
+            user.send(ErrorMessage::new_err(&reason)).await;
+            return;
+        }
+        // users logging in always have to end up in a channel
+        if user.location != Location::Nowhere {
+            if let Verdict::Veto(reason) = check_plugins(&mut self.plugins, |p| {
+                p.on_join_channel(&user, &channel_name)
+            }) {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
+        }
+        if user.location != Location::Nowhere && !user.role.is_staff() {
+            let remaining = self
+                .join_flood
+                .as_ref()
+                .and_then(|guard| guard.check(&channel_name, user.ip_addr, Instant::now()));
+            if let Some(remaining) = remaining {
+                user.send(ErrorMessage::new_err(&format!(
+                    "Channel is being flooded, please wait {} seconds before rejoining",
+                    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+                )))
+                .await;
+                return;
+            }
+        }
+
+        if user.location != Location::Nowhere && !user.role.is_staff() {
+            if let Some(reason) = self.check_channel_full(&user, &channel_name) {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
+        }
+
         let channel = self
             .channels
             .get_or_create(&mut self.users, &channel_name)
             .await;
-        if channel.to_location() == user.location {
+        let location = channel.to_location();
+        if location == user.location {
             log::debug!("User is already in requested channel, nothing to do");
             return;
         }
+        let channel_name = channel.name.clone();
 
+        self.users.catch_up(&user).await;
         // send join message and list of users in new channel
-        user.send(Arc::new(JoinChannelMessage {
-            channel_name: channel.name.clone(),
-        }))
+        user.send(Arc::new(
+            JoinChannelMessage {
+                channel_name: channel_name.clone(),
+            }
+            .into(),
+        ))
         .await;
-        for u in self.users.users_in_location(&channel.to_location()) {
-            user.send(u.to_new_user_message()).await;
+        self.send_users_in_location(&mut user, &location).await;
+        let history: Vec<ArcServerMessage> = self
+            .channels
+            .get(&channel_name)
+            .map(|c| c.history().cloned().collect())
+            .unwrap_or_default();
+        for message in history {
+            user.send(message).await;
         }
 
+        if let Some(guard) = &mut self.join_flood {
+            if guard.record(&channel_name, user.ip_addr, Instant::now()) {
+                log::warn!("Join flood detected in channel {}", channel_name);
+                self.users
+                    .send_to_staff(InfoMessage::new_info(&format!(
+                        "Join flood detected in #{}, rejoins are throttled",
+                        channel_name
+                    )))
+                    .await;
+            }
+        }
+
+        self.audit_log.record(AuditEvent::JoinChannel {
+            username: user.username.clone(),
+            channel: channel_name.clone(),
+        });
         // update channel information for client
-        user.location = channel.to_location();
+        user.location = location;
+        user.last_channel = Some(channel_name);
         self.users.update(user).await;
     }
 
-    async fn host_game(&mut self, mut user: User, game_name: String, password_or_guid: Vec<u8>) {
-        if !only_allowed_chars_not_empty(&game_name, ALLOWED_GAME_NAME_CHARS) {
-            user.send(ErrorMessage::new_err("Invalid game name")).await;
+    /// Takes a user from their game back to the default channel. The game is cleaned up after
+    /// the event once nobody is left in it.
+    async fn leave_game(&mut self, mut user: User) {
+        if !matches!(user.location, Location::Game { .. }) {
+            user.send(ErrorMessage::new_coded(LobbyError::NotInGame))
+                .await;
+            return;
+        }
+        // leaving must not be refused like joining a full or flooded channel
+        user.location = Location::Nowhere;
+        self.join_channel(user, DEFAULT_CHANNEL.to_string()).await;
+    }
+
+    async fn host_game(
+        &mut self,
+        mut user: User,
+        game_name: String,
+        password_or_guid: Vec<u8>,
+        tag: Option<String>,
+    ) {
+        if !self.names.is_valid_game_name(&game_name) {
+            user.send(ErrorMessage::new_coded(LobbyError::InvalidGameName))
+                .await;
+            return;
+        }
+        let tag = match tag.map(|tag| normalize_tag(&tag)) {
+            Some(None) => {
+                user.send(ErrorMessage::new_err(
+                    "Game tags may only have up to 8 letters, digits or dashes",
+                ))
+                .await;
+                return;
+            }
+            Some(tag) => tag,
+            None => None,
+        };
+        if is_synthesized(user.ip_addr) {
+            user.send(ErrorMessage::new_coded(LobbyError::HostingNeedsIpv4))
+                .await;
+            return;
+        }
+        if let Some(reason) = self.check_tournament_game(&user, &game_name) {
+            user.send(ErrorMessage::new_err(&reason)).await;
             return;
         }
 
         if let Some(game) = self.games.get(&game_name) {
             let maybe_guid = Uuid::parse_str(&String::from_utf8_lossy(&password_or_guid));
             if game.status == Started || game.hosted_by != user.id || maybe_guid.is_err() {
-                user.send(ErrorMessage::new_err("Game already exists."))
+                user.send(ErrorMessage::new_coded(LobbyError::GameExists))
                     .await;
                 return;
             }
             let status = game.status;
             if status == Requested {
                 user.location = game.to_location();
+                let relay = match &self.relay {
+                    Some(relay) => {
+                        relay
+                            .allocate(SocketAddrV4::new(game.host_ip, game.host_port))
+                            .await
+                    }
+                    None => None,
+                };
                 self.games
-                    .open_game(&mut self.users, &game_name, maybe_guid.unwrap())
+                    .open_game(&mut self.users, &game_name, maybe_guid.unwrap(), relay)
                     .await;
+                self.audit_log.record(AuditEvent::GameOpened {
+                    game: game_name.clone(),
+                });
+                self.update_ping_hosts();
                 self.users.update(user).await;
             } else {
                 self.games.start_game(&mut self.users, &game_name).await;
+                if let Some(game) = self.games.get(&game_name) {
+                    self.audit_log.record(AuditEvent::GameStarted {
+                        game: game.name.clone(),
+                        players: game.roster.clone(),
+                    });
+                }
             }
         } else {
+            if let Verdict::Veto(reason) =
+                check_plugins(&mut self.plugins, |p| p.on_game_created(&user, &game_name))
+            {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
             self.games
-                .create_game(&mut user, &game_name, &password_or_guid)
+                .create_game(&mut user, &game_name, &password_or_guid, tag)
                 .await;
+            self.audit_log.record(AuditEvent::GameCreated {
+                game: game_name,
+                host: user.username.clone(),
+            });
+            self.totals.record_game_hosted(&self.storage);
         }
     }
 
-    async fn join_game(&mut self, mut user: User, game_name: String, password: Vec<u8>) {
+    async fn join_game(
+        &mut self,
+        mut user: User,
+        game_name: String,
+        password: Vec<u8>,
+        spectator: bool,
+    ) {
+        if let Some((name, server)) = split_tag(&game_name) {
+            self.join_remote_game(user, name, server, password).await;
+            return;
+        }
+        if spectator && !self.games.allows_spectators() {
+            user.send(ErrorMessage::new_coded(LobbyError::SpectatorsNotAllowed))
+                .await;
+            return;
+        }
+        // anyone may watch a tournament match
+        if let Some(reason) = self
+            .check_tournament_game(&user, &game_name)
+            .filter(|_| !spectator)
+        {
+            user.send(ErrorMessage::new_err(&reason)).await;
+            return;
+        }
         if let Some(game) = self.games.get(&game_name) {
+            if !game.is_visible_to(&user) {
+                let version = self
+                    .versions
+                    .name_of(game.game_version)
+                    .unwrap_or("unknown");
+                user.send(ErrorMessage::new_err(&format!(
+                    "{} needs game version {}",
+                    game.name, version
+                )))
+                .await;
+                return;
+            }
             let game_version = user.game_version;
             if let Ok(id) = Uuid::parse_str(&bytevec_to_str(&password)) {
                 if id == game.id {
-                    log::info!("Client {} has joined game {}", user.id, game.name);
+                    if !spectator && game.is_full() && !game.players.contains(&user.id) {
+                        user.send(ErrorMessage::new_coded(LobbyError::GameFull))
+                            .await;
+                        return;
+                    }
+                    if spectator {
+                        log::info!("Client {} is watching game {}", user.id, game.name);
+                    } else {
+                        log::info!("Client {} has joined game {}", user.id, game.name);
+                    }
+                    self.audit_log.record(AuditEvent::GameJoined {
+                        game: game.name.clone(),
+                        username: user.username.clone(),
+                        spectator,
+                    });
                     user.location = game.to_location();
+                    if let Some(game) = self.games.get_mut(&game_name) {
+                        if spectator {
+                            game.spectators.insert(user.id);
+                        } else {
+                            game.players.insert(user.id);
+                        }
+                    }
                     self.users.update(user).await;
                 }
+            } else if !spectator && game.status == Started {
+                user.send(ErrorMessage::new_coded(LobbyError::GameStarted))
+                    .await;
+            } else if !spectator && game.is_full() {
+                user.send(ErrorMessage::new_coded(LobbyError::GameFull))
+                    .await;
             } else if password == game.password {
-                user.send(Arc::new(JoinGameMessage {
-                    version: game_version,
-                    game_name: game.name.clone(),
-                    password,
-                    id: game.id,
-                    ip_addr: game.host_ip,
-                }))
-                .await;
+                // relayed games do not reveal the host's address
+                if game.relay.is_none() && !self.is_trusted(&user) {
+                    self.knock(user, &game_name).await;
+                } else {
+                    user.send(self.join_info(game, game_version)).await;
+                }
             } else {
-                user.send(Arc::new(ErrorMessage {
-                    error: "Invalid password".to_string(),
-                }))
-                .await;
+                user.send(ErrorMessage::new_coded(LobbyError::InvalidPassword))
+                    .await;
             }
         } else {
-            user.send(Arc::new(ErrorMessage {
-                error: "Game does not exist".to_string(),
-            }))
+            user.send(ErrorMessage::new_coded(LobbyError::GameNotFound))
+                .await;
+        }
+    }
+
+    fn match_record(
+        &self,
+        game: &Game,
+        winner: Option<String>,
+        aborted: bool,
+    ) -> Option<MatchRecord> {
+        let started_at = game.started_at?;
+        let host = self
+            .users
+            .by_user_id(&game.hosted_by)
+            .map(|u| u.account.clone())
+            .unwrap_or_default();
+        Some(MatchRecord {
+            game_name: game.name.clone(),
+            host,
+            players: game.roster.clone(),
+            winner,
+            started_at: started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration_secs: SystemTime::now()
+                .duration_since(started_at)
+                .unwrap_or_default()
+                .as_secs(),
+            aborted,
+        })
+    }
+
+    /// Records the match and tells the default channel how it went
+    async fn record_match(&mut self, game: &Game, winner: Option<String>) {
+        let record = match self.match_record(game, winner, false) {
+            Some(record) => record,
+            None => return,
+        };
+        let summary = MatchSummaryMessage {
+            game_name: record.game_name.clone(),
+            duration_secs: record.duration_secs,
+            players: record.players.clone(),
+            winner: record.winner.clone(),
+        };
+        self.users
+            .send_to_location(
+                Location::Channel {
+                    name: DEFAULT_CHANNEL.to_string(),
+                },
+                Arc::new(summary.into()),
+            )
             .await;
+        self.rankings.update(&self.storage, &record);
+        self.history.record(&self.storage, record);
+        self.totals.record_game_completed(&self.storage);
+        self.daily.record_game();
+    }
+
+    /// Keeps a match that fell apart in the history, without it counting as played
+    fn record_aborted_match(&mut self, game: &Game) {
+        if let Some(record) = self.match_record(game, None, true) {
+            self.history.record(&self.storage, record);
         }
     }
 
-    async fn handle_client_command(&mut self, id: Uuid, command: ClientCommand) {
-        let mut user = match self.users.by_user_id(&id) {
-            Some(user) => user.clone(),
+    async fn game_result(&mut self, mut user: User, winner: String) {
+        let (game_name, roster) = match self.games.started_by_host(user.id) {
+            Some(game) => (game.name.clone(), game.roster.clone()),
             None => {
-                log::info!("Received message for {}, but client does not exist", id);
+                user.send(ErrorMessage::new_coded(LobbyError::NotHostingRunningGame))
+                    .await;
                 return;
             }
         };
-        match command {
-            ClientCommand::Send { message } => self.public_message(user, message).await,
-            ClientCommand::PrivateMessage { target, message } => {
-                self.private_message(user, target, message).await
-            }
-            ClientCommand::Join { channel } => self.join_channel(user, channel).await,
-            ClientCommand::HostGame {
-                game_name,
-                password_or_guid,
-            } => self.host_game(user, game_name, password_or_guid).await,
-            ClientCommand::JoinGame {
-                game_name,
-                password,
-            } => self.join_game(user, game_name, password).await,
-            ClientCommand::NoOp => (),
-            ClientCommand::Malformed { reason } => {
-                user.send(Arc::new(ErrorMessage { error: reason })).await
-            }
-            ClientCommand::Unknown { command } => {
-                user.send(Arc::new(ErrorMessage {
-                    error: format!("Unknown command: {}", command),
-                }))
+        // results are kept under the winner's account, also if they go by another name
+        let account = Name::new(&self.account_of(&winner));
+        let winner = match roster.into_iter().find(|p| Name::new(p) == account) {
+            Some(player) => player,
+            None => {
+                user.send(ErrorMessage::new_err(&format!(
+                    "{} did not play in {}",
+                    winner, game_name
+                )))
                 .await;
+                return;
             }
+        };
+        if let Some(reason) = self.check_tournament_winner(&game_name, &winner) {
+            user.send(ErrorMessage::new_err(&reason)).await;
+            return;
+        }
+        if let Some(game) = self.games.remove(&mut self.users, &game_name).await {
+            self.audit_log.record(AuditEvent::GameClosed {
+                game: game.name.clone(),
+                winner: Some(winner.clone()),
+            });
+            let location = game.to_location();
+            let text = format!("{} is over, {} won", location, winner);
+            self.evacuate_game(&location, &text).await;
+            self.record_match(&game, Some(winner.clone())).await;
+            user.send(InfoMessage::new_info(&format!(
+                "Result for {} has been recorded",
+                game.name
+            )))
+            .await;
+            self.decide_match(&game_name, &winner).await;
         }
     }
 
-    async fn handle_new_user(
-        &mut self,
-        id: Uuid,
-        username: String,
-        game_version: Uuid,
-        ip_addr: Ipv4Addr,
-        send: MessageSender,
-    ) {
-        let mut user = User {
-            id,
-            username,
-            location: Location::Nowhere,
-            game_version,
-            ip_addr,
-            send,
+    async fn history(&mut self, mut user: User, username: Option<String>) {
+        const MAX_ENTRIES: usize = 10;
+        let username = username.map(|u| self.account_of(&u));
+        let records = self.history.recent(username.as_deref(), MAX_ENTRIES);
+        if records.is_empty() {
+            user.send(InfoMessage::new_info("No matches recorded"))
+                .await;
+            return;
+        }
+        let lines: Vec<String> = records.iter().map(|r| r.summary()).collect();
+        for line in lines {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
+
+    async fn rank(&mut self, mut user: User, username: Option<String>) {
+        let username = match username {
+            Some(username) => self.account_of(&username),
+            None => user.account.clone(),
+        };
+        let text = match (
+            self.rankings.get(&username),
+            self.rankings.position(&username),
+        ) {
+            (Some(rating), Some(position)) => format!("#{} {}", position, rating.summary()),
+            _ => format!("{} has no rated games yet", username),
         };
+        user.send(InfoMessage::new_info(&text)).await;
+    }
 
-        if self.users.by_username(&user.username).is_some() {
-            log::info!(
-                "A client with username {} is already logged in, dropping client",
-                user.username
-            );
-            return;
+    async fn top10(&mut self, mut user: User) {
+        let lines: Vec<String> = self
+            .rankings
+            .top(10)
+            .iter()
+            .enumerate()
+            .map(|(i, r)| format!("#{} {}", i + 1, r.summary()))
+            .collect();
+        if lines.is_empty() {
+            user.send(InfoMessage::new_info("No rated games yet")).await;
         }
+        for line in lines {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
 
-        log::info!(
-            "User {} has successfully logged in as {}",
-            user.id,
-            user.username
+    async fn server_stats(&mut self, mut user: User) {
+        let line = format!(
+            "{} users online, {} open and {} running games, up for {}, version {}",
+            self.stats.users_online,
+            self.stats.games_open,
+            self.games.count_started(),
+            format_duration(self.started_at.elapsed()),
+            build_info()
         );
-        user.send(Arc::new(WelcomeServerMessage {
-            server_ident: "IE::Net".to_string(),
-            welcome_message: "Welcome to IE::Net, a community-operated EarthNet server".to_string(),
-            players_total: 0,
-            players_online: 0,
-            channels_total: 0,
-            games_total: 0,
-            games_running: 0,
-            games_available: 0,
-            game_versions: vec!["tmp2.2".to_string()],
-            initial_channel: DEFAULT_CHANNEL.to_string(),
-        }))
-        .await;
+        user.send(InfoMessage::new_info(&line)).await;
+    }
 
-        self.channels.announce_all(&mut user).await;
-        self.games.announce_open(&mut user).await;
+    /// Tells what a client is dealing with, for debugging mismatched community builds
+    async fn server_version(&mut self, mut user: User) {
+        let mut lines = vec![format!("{} {}", SERVER_NAME, build_info())];
+        for version in &self.versions.accepted {
+            let yours = if version.id == user.game_version {
+                " (yours)"
+            } else {
+                ""
+            };
+            lines.push(format!(
+                "Game version {}: {}{}",
+                version.name, version.id, yours
+            ));
+        }
+        let mut extensions = vec!["/hostport", "game tags"];
+        if self.games.allows_spectators() {
+            extensions.push("spectators");
+        }
+        if self.relay.is_some() {
+            extensions.push("game relay");
+        }
+        if self.pinger.is_some() {
+            extensions.push("latency probing");
+        }
+        let peers = self.federation.servers();
+        let federation = format!("federation with {}", peers.join(", "));
+        if !peers.is_empty() {
+            extensions.push(&federation);
+        }
+        lines.push(format!("Extensions: {}", extensions.join(", ")));
+        for line in lines {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
 
-        self.users.insert(user).await;
-        self.join_channel(
-            self.users.by_user_id(&id).unwrap().clone(),
-            DEFAULT_CHANNEL.to_string(),
-        )
-        .await;
+    /// Returns how long the user has to wait before speaking in their channel again
+    fn check_slowmode(&mut self, user: &User) -> Option<Duration> {
+        match &user.location {
+            Location::Channel { name } if !user.role.is_staff() => self
+                .channels
+                .get_mut(name)
+                .and_then(|c| c.check_slowmode(user.id, Instant::now())),
+            _ => None,
+        }
     }
 
-    async fn update_stats(&mut self) {
-        let stats = Stats {
-            users_total: self.users.count(),
-            users_online: self.users.count(),
-            channels_total: self.channels.count(),
-            games_total: self.games.count(),
-            games_open: self.games.count_open(),
-        };
-        if stats != self.stats {
-            self.stats = stats;
-            self.users
-                .send_to_all(Arc::new(SyncStatsMessage {
-                    users_total: self.stats.users_total,
-                    users_online: self.stats.users_online,
-                    channels_total: self.stats.channels_total,
-                    games_total: self.stats.games_total,
-                    games_open: self.stats.games_open,
-                }))
+    async fn announce(&mut self, mut user: User, text: Vec<u8>) {
+        if user.role != Role::Admin {
+            user.send(ErrorMessage::new_err("Only admins can make announcements"))
                 .await;
+            return;
         }
+        let text = match self.sanitizer.apply(&text) {
+            Ok(text) => text,
+            Err(reason) => {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
+        };
+        log::info!(
+            "Announcement from {}: {}",
+            user.username,
+            bytevec_to_str(&text)
+        );
+        self.users
+            .send_to_all(Arc::new(AnnouncementMessage { text }.into()))
+            .await;
     }
 
-    async fn handle_event(&mut self, event: Event) -> Result<()> {
-        match event {
-            Event::NewUser {
-                id,
-                username,
-                game_version,
-                ip_addr,
-                send,
-            } => {
-                self.handle_new_user(id, username, game_version, ip_addr, send)
-                    .await
+    async fn notice(&mut self, mut user: User, username: String, text: Vec<u8>) {
+        if user.role != Role::Admin {
+            user.send(ErrorMessage::new_err("Only admins can send notices"))
+                .await;
+            return;
+        }
+        let text = match self.sanitizer.apply(&text) {
+            Ok(text) => text,
+            Err(reason) => {
+                user.send(ErrorMessage::new_err(&reason)).await;
+                return;
+            }
+        };
+        let target = match self.users.by_username_mut(&username) {
+            Some(target) => target,
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::UserNotOnline))
+                    .await;
+                return;
+            }
+        };
+        log::info!(
+            "Notice from {} to {}: {}",
+            user.username,
+            target.username,
+            bytevec_to_str(&text)
+        );
+        let confirmation = format!("Notice sent to {}", target.username);
+        target.send(Arc::new(NoticeMessage { text }.into())).await;
+        user.send(InfoMessage::new_info(&confirmation)).await;
+    }
+
+    /// Returns why a user cannot join the channel, if it is full
+    fn check_channel_full(&self, user: &User, channel_name: &str) -> Option<String> {
+        let channel = self.channels.get(channel_name)?;
+        let max_users = channel.max_users?;
+        let location = channel.to_location();
+        if location == user.location {
+            return None;
+        }
+        let users = self
+            .users
+            .users_in_location(&location)
+            .iter()
+            .filter(|u| !u.bot)
+            .count();
+        if users < max_users {
+            return None;
+        }
+        Some(if channel.name.eq_ignore_ascii_case(DEFAULT_CHANNEL) {
+            format!("#{} is full", channel.name)
+        } else {
+            format!(
+                "#{} is full, try #{} instead",
+                channel.name, DEFAULT_CHANNEL
+            )
+        })
+    }
+
+    async fn set_invisible(&mut self, mut user: User, enabled: bool) {
+        if user.role != Role::Admin {
+            user.send(ErrorMessage::new_err("Only admins can be invisible"))
+                .await;
+            return;
+        }
+        self.preferences
+            .update(&self.storage, &user.account, |p| p.invisible = enabled);
+        self.users.set_invisible(user.id, enabled).await;
+        log::info!("{} set invisible to {}", user.username, enabled);
+        let text = if enabled {
+            "You are invisible now, also on your next logins"
+        } else {
+            "You are visible again"
+        };
+        user.send(InfoMessage::new_info(text)).await;
+    }
+
+    async fn set_max_users(&mut self, mut user: User, max_users: Option<usize>) {
+        if !user.role.is_staff() {
+            user.send(ErrorMessage::new_err(
+                "Only admins and moderators can limit channels",
+            ))
+            .await;
+            return;
+        }
+        let channel = match &user.location {
+            Location::Channel { name } => self.channels.get_mut(name),
+            _ => None,
+        };
+        let channel = match channel {
+            Some(channel) => channel,
+            None => {
+                user.send(ErrorMessage::new_err("Only channels can be limited"))
+                    .await;
+                return;
+            }
+        };
+        channel.max_users = max_users;
+        log::info!(
+            "{} set the user limit of #{} to {:?}",
+            user.username,
+            channel.name,
+            max_users
+        );
+        let text = match max_users {
+            Some(max_users) => format!("#{} now takes at most {} users", channel.name, max_users),
+            None => format!("#{} is no longer limited", channel.name),
+        };
+        user.send(InfoMessage::new_info(&text)).await;
+    }
+
+    async fn slowmode(&mut self, mut user: User, secs: u64) {
+        if !user.role.is_staff() {
+            user.send(ErrorMessage::new_err(
+                "Only admins and moderators can set slowmode",
+            ))
+            .await;
+            return;
+        }
+        let channel = match &user.location {
+            Location::Channel { name } => self.channels.get_mut(name),
+            _ => None,
+        };
+        let channel = match channel {
+            Some(channel) => channel,
+            None => {
+                user.send(ErrorMessage::new_err(
+                    "Slowmode can only be set in a channel",
+                ))
+                .await;
+                return;
+            }
+        };
+        channel.set_slowmode(Duration::from_secs(secs));
+        log::info!(
+            "{} set slowmode of #{} to {} seconds",
+            user.username,
+            channel.name,
+            secs
+        );
+        let text = match secs {
+            0 => format!("Slowmode is off in #{}", channel.name),
+            secs => format!(
+                "Slowmode is on in #{}, one message every {} seconds",
+                channel.name, secs
+            ),
+        };
+        self.users
+            .send_to_location(user.location.clone(), InfoMessage::new_info(&text))
+            .await;
+    }
+
+    async fn pin(&mut self, mut user: User, target: String, pinned: bool) {
+        if !self.pinning {
+            user.send(ErrorMessage::new_err("Pinning is disabled"))
+                .await;
+            return;
+        }
+        if user.role != Role::Admin {
+            user.send(ErrorMessage::new_err(
+                "Only admins can pin channels and games",
+            ))
+            .await;
+            return;
+        }
+        let found = match (target.get(0..1), target.get(1..)) {
+            (Some("#"), Some(name)) => self.channels.get_mut(name).map(|c| c.pinned = pinned),
+            (Some("$"), Some(name)) => self.games.get_mut(name).map(|g| g.pinned = pinned),
+            _ => None,
+        };
+        match found {
+            Some(()) => {
+                log::info!("{} set pinned={} for {}", user.username, pinned, target);
+                user.send(InfoMessage::new_info(&format!(
+                    "{} is now {}",
+                    target,
+                    if pinned { "pinned" } else { "unpinned" }
+                )))
+                .await
+            }
+            None => {
+                user.send(ErrorMessage::new_err(
+                    "Expected an existing #channel or $game",
+                ))
+                .await
+            }
+        }
+    }
+
+    async fn delete_account(&mut self, mut user: User, token: Option<String>) {
+        const TOKEN_VALIDITY: Duration = Duration::from_secs(5 * 60);
+        let pending = self
+            .deletion_tokens
+            .remove(&user.id)
+            .filter(|(_, issued)| issued.elapsed() < TOKEN_VALIDITY);
+        match (token, pending) {
+            (Some(token), Some((expected, _))) if token == expected => {
+                self.accounts.request_deletion(&self.storage, &user.account);
+                user.send(InfoMessage::new_info(&format!(
+                    "Your account will be deleted in {} days. Log in again before then to cancel.",
+                    self.deletion_grace_period.as_secs() / (24 * 60 * 60)
+                )))
+                .await;
+            }
+            (Some(_), _) => {
+                user.send(ErrorMessage::new_err(
+                    "Invalid or expired confirmation token",
+                ))
+                .await;
+            }
+            (None, _) => {
+                let token = Uuid::new_v4().to_simple().to_string()[..8].to_string();
+                user.send(InfoMessage::new_info(&format!(
+                    "To delete your account, type /deleteaccount {} within 5 minutes",
+                    token
+                )))
+                .await;
+                self.deletion_tokens
+                    .insert(user.id, (token, Instant::now()));
+            }
+        }
+    }
+
+    /// Sends everyone still in a game that has been running for too long back to the default
+    /// channel, after which the game is removed like any abandoned one
+    async fn drop_stale_games(&mut self) {
+        let stale: Vec<Location> = self.games.stale().map(|g| g.to_location()).collect();
+        for location in stale {
+            log::info!("Dropping {}, which has been running for too long", location);
+            let text = format!("{} has been running for too long and was dropped", location);
+            self.evacuate_game(&location, &text).await;
+        }
+    }
+
+    /// Tells everyone in a game why they have to leave it and sends them to the default channel
+    async fn evacuate_game(&mut self, location: &Location, text: &str) {
+        self.users
+            .send_to_location(location.clone(), InfoMessage::new_info(text))
+            .await;
+        let users: Vec<User> = self
+            .users
+            .users_in_location(location)
+            .into_iter()
+            .cloned()
+            .collect();
+        for mut user in users {
+            // like after a login, nothing may keep the user from landing in the channel
+            user.location = Location::Nowhere;
+            self.join_channel(user, DEFAULT_CHANNEL.to_string()).await;
+        }
+    }
+
+    /// Drops the games a leaving host had not started yet right away, rather than leaving them
+    /// listed with connection info nobody answers at until the game is found empty
+    async fn drop_hosted_games(&mut self, host: Uuid) {
+        let unstarted: Vec<String> = self
+            .games
+            .iter()
+            .filter(|g| g.hosted_by == host && g.status != Started)
+            .map(|g| g.name.clone())
+            .collect();
+        for game_name in unstarted {
+            let game = match self.games.remove(&mut self.users, &game_name).await {
+                Some(game) => game,
+                None => continue,
+            };
+            log::info!("Dropping game {}, whose host has left", game.name);
+            self.audit_log.record(AuditEvent::GameClosed {
+                game: game.name.clone(),
+                winner: None,
+            });
+            let location = game.to_location();
+            let text = format!("The host has left, {} was dropped", location);
+            self.evacuate_game(&location, &text).await;
+        }
+    }
+
+    /// Settles the matches of games everyone left right after the start. Players also leave for
+    /// the match itself, as the game client drops the lobby connection when launching it, so the
+    /// match only fell apart if one of them comes back early; otherwise it is recorded once the
+    /// window for that has passed.
+    async fn settle_launched_games(&mut self) {
+        for game in std::mem::take(&mut self.launched) {
+            if !self.games.just_started(&game) {
+                // the host never reported a result, but the match still happened
+                self.record_match(&game, None).await;
+            } else if game
+                .roster
+                .iter()
+                .any(|player| self.users.by_account(player).is_some())
+            {
+                self.abort_match(&game).await;
+            } else {
+                self.launched.push(game);
+            }
+        }
+    }
+
+    /// Keeps a match that fell apart in the history and tells the channel it was set up in
+    async fn abort_match(&mut self, game: &Game) {
+        log::info!("The match {} fell apart right after the start", game.name);
+        self.record_aborted_match(game);
+        if let Some(origin) = &game.origin {
+            let text = format!(
+                "The match {} fell apart, its players came back right after the start",
+                game.name
+            );
+            self.users
+                .send_to_location(
+                    Location::Channel {
+                        name: origin.clone(),
+                    },
+                    InfoMessage::new_info(&text),
+                )
+                .await;
+        }
+    }
+
+    /// Periodic maintenance that is not triggered by any event
+    async fn housekeeping(&mut self) {
+        if let Some(guard) = &mut self.join_flood {
+            guard.prune(Instant::now());
+        }
+        if let Some(throttle) = &mut self.login_throttle {
+            throttle.prune(Instant::now());
+        }
+        self.sessions.prune(Instant::now());
+        self.daily.flush(&self.storage);
+        for username in self
+            .accounts
+            .purge_deleted(&self.storage, self.deletion_grace_period)
+        {
+            log::info!("Purging deleted account {}", username);
+            self.rankings.remove(&self.storage, &username);
+            self.preferences.remove(&self.storage, &username);
+            self.mailbox.remove(&self.storage, &username);
+            self.clans.remove_user(&self.storage, &username);
+        }
+        self.update_ping_hosts();
+        self.update_bots().await;
+    }
+
+    fn join_info(&self, game: &Game, version: Uuid) -> ArcServerMessage {
+        Arc::new(
+            JoinGameMessage {
+                version,
+                game_name: game.name.clone(),
+                password: game.password.clone(),
+                id: game.id,
+                ip_addr: game.connect_ip(),
+                port: self.games.custom_port(game),
+            }
+            .into(),
+        )
+    }
+
+    /// Whether the user may learn host addresses without the host's approval
+    fn is_trusted(&self, user: &User) -> bool {
+        if !self.privacy.hide_host_ips || user.role.is_staff() {
+            return true;
+        }
+        let min_age = self.privacy.trusted_account_days * 24 * 60 * 60;
+        self.accounts
+            .get(&user.account)
+            .is_some_and(|a| unix_now().saturating_sub(a.created_at) >= min_age)
+    }
+
+    async fn knock(&mut self, mut user: User, game_name: &str) {
+        let game = match self.games.get_mut(game_name) {
+            Some(game) => game,
+            None => return,
+        };
+        let first_knock = game.knocks.insert(user.id);
+        let host_id = game.hosted_by;
+        user.send(InfoMessage::new_info(
+            "The host has been asked to let you join",
+        ))
+        .await;
+        if first_knock {
+            if let Some(host) = self.users.by_user_id_mut(&host_id) {
+                host.send(InfoMessage::new_info(&format!(
+                    "{} would like to join your game, type /approve {} to let them in",
+                    user.username, user.username
+                )))
+                .await;
+            }
+        }
+    }
+
+    async fn approve(&mut self, mut user: User, username: String) {
+        let (target_id, target_version) = match self.users.by_username(&username) {
+            Some(target) => (target.id, target.game_version),
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::UserNotOnline))
+                    .await;
+                return;
+            }
+        };
+        let game = match self.games.open_by_host_mut(user.id) {
+            Some(game) => game,
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::NotHostingOpenGame))
+                    .await;
+                return;
+            }
+        };
+        if !game.knocks.remove(&target_id) {
+            user.send(ErrorMessage::new_err(&format!(
+                "{} has not asked to join your game",
+                username
+            )))
+            .await;
+            return;
+        }
+        let game_name = game.name.clone();
+        let join_info = match self.games.get(&game_name) {
+            Some(game) => self.join_info(game, target_version),
+            None => return,
+        };
+        log::info!(
+            "{} approved {} joining {}",
+            user.username,
+            username,
+            game_name
+        );
+        if let Some(target) = self.users.by_user_id_mut(&target_id) {
+            target.send(join_info).await;
+        }
+        user.send(InfoMessage::new_info(&format!(
+            "{} may now join your game",
+            username
+        )))
+        .await;
+    }
+
+    async fn host_port(&mut self, mut user: User, port: Option<u16>) {
+        const MIN_PORT: u16 = 1024;
+        match port {
+            Some(port) if port < MIN_PORT => {
+                user.send(ErrorMessage::new_err(&format!(
+                    "Ports below {} cannot be used for games",
+                    MIN_PORT
+                )))
+                .await;
+                return;
+            }
+            Some(port) => {
+                user.send(InfoMessage::new_info(&format!(
+                    "Games you host from now on will be joined on port {}",
+                    port
+                )))
+                .await
+            }
+            None => {
+                user.send(InfoMessage::new_info(
+                    "Games you host from now on will be joined on the default port",
+                ))
+                .await
+            }
+        }
+        user.host_port = port;
+        self.users.update(user).await;
+    }
+
+    /// Puts a user who logged in again back where they were before losing the connection
+    async fn resume_session(&mut self, mut user: User, token: &str) {
+        let session = match self.sessions.resume(token, &user.account, Instant::now()) {
+            Some(session) => session,
+            None => {
+                user.send(ErrorMessage::new_err("Invalid or expired resume token"))
+                    .await;
+                return;
+            }
+        };
+        let id = user.id;
+        user.host_port = session.host_port;
+        self.users.update(user.clone()).await;
+        if let Some(channel) = session.channel {
+            if !matches!(&user.location, Location::Channel { name } if *name == channel) {
+                self.join_channel(user, channel.clone()).await;
+            }
+            let mut user = self.users.by_user_id(&id).unwrap().clone();
+            if matches!(&user.location, Location::Channel { name } if *name == channel) {
+                user.send(InfoMessage::new_info(&format!(
+                    "Welcome back, you are in {} again",
+                    user.location
+                )))
+                .await;
+            }
+        } else {
+            user.send(InfoMessage::new_info("Welcome back")).await;
+        }
+    }
+
+    async fn macro_command(&mut self, mut user: User, action: MacroAction) {
+        let macros = self
+            .preferences
+            .get(&user.account)
+            .map(|p| p.macros.clone())
+            .unwrap_or_default();
+        match action {
+            MacroAction::Set { name, text } => {
+                let name = name.to_ascii_lowercase();
+                if !only_allowed_chars_not_empty(&name, "abcdefghijklmnopqrstuvwxyz0123456789") {
+                    user.send(ErrorMessage::new_err(
+                        "Macro names may only contain letters and digits",
+                    ))
+                    .await;
+                } else if text.len() > self.macros.max_length {
+                    user.send(ErrorMessage::new_err(&format!(
+                        "Macros may be at most {} characters long",
+                        self.macros.max_length
+                    )))
+                    .await;
+                } else if !macros.contains_key(&name) && macros.len() >= self.macros.max_count {
+                    user.send(ErrorMessage::new_err(&format!(
+                        "You cannot have more than {} macros",
+                        self.macros.max_count
+                    )))
+                    .await;
+                } else {
+                    self.preferences.update(&self.storage, &user.account, |p| {
+                        p.macros.insert(name.clone(), text)
+                    });
+                    user.send(InfoMessage::new_info(&format!(
+                        "Macro {} saved, use it with /g {}",
+                        name, name
+                    )))
+                    .await;
+                }
+            }
+            MacroAction::Remove { name } => {
+                let removed = self.preferences.update(&self.storage, &user.account, |p| {
+                    p.macros.remove(&name.to_ascii_lowercase())
+                });
+                match removed {
+                    Some(_) => {
+                        user.send(InfoMessage::new_info(&format!("Macro {} removed", name)))
+                            .await
+                    }
+                    None => {
+                        user.send(ErrorMessage::new_err(&format!("No macro named {}", name)))
+                            .await
+                    }
+                }
+            }
+            MacroAction::List => {
+                if macros.is_empty() {
+                    user.send(InfoMessage::new_info("You have no macros")).await;
+                }
+                for (name, text) in macros {
+                    user.send(InfoMessage::new_info(&format!(
+                        "{}: {}",
+                        name,
+                        bytevec_to_str(&text)
+                    )))
+                    .await;
+                }
+            }
+        }
+    }
+
+    /// Rewrites a command before it is dispatched, returning None if nothing is left to do
+    async fn preprocess_command(
+        &mut self,
+        user: &mut User,
+        command: ClientCommand,
+    ) -> Option<ClientCommand> {
+        match command {
+            ClientCommand::ExpandMacro { name } => {
+                let text = self
+                    .preferences
+                    .get(&user.account)
+                    .and_then(|p| p.macros.get(&name.to_ascii_lowercase()))
+                    .cloned();
+                match text {
+                    Some(message) => Some(ClientCommand::Send { message }),
+                    None => {
+                        user.send(ErrorMessage::new_err(&format!("No macro named {}", name)))
+                            .await;
+                        None
+                    }
+                }
+            }
+            command => Some(command),
+        }
+    }
+
+    async fn handle_client_command(&mut self, id: Uuid, command: ClientCommand) {
+        let mut user = match self.users.by_user_id_mut(&id) {
+            Some(user) => {
+                user.last_active = Instant::now();
+                user.clone()
+            }
+            None => {
+                log::info!("Received message for {}, but client does not exist", id);
+                return;
+            }
+        };
+        let command = match self.preprocess_command(&mut user, command).await {
+            Some(command) => command,
+            None => return,
+        };
+        match command {
+            ClientCommand::Send { message } => self.public_message(user, message).await,
+            ClientCommand::PrivateMessage { target, message } => {
+                self.private_message(user, target, message).await
+            }
+            ClientCommand::Join { channel } => self.join_channel(user, channel).await,
+            ClientCommand::HostGame {
+                game_name,
+                password_or_guid,
+                tag,
+            } => self.host_game(user, game_name, password_or_guid, tag).await,
+            ClientCommand::JoinGame {
+                game_name,
+                password,
+                spectator,
+            } => self.join_game(user, game_name, password, spectator).await,
+            ClientCommand::GameResult { winner } => self.game_result(user, winner).await,
+            ClientCommand::History { username } => self.history(user, username).await,
+            ClientCommand::Rank { username } => self.rank(user, username).await,
+            ClientCommand::Top10 => self.top10(user).await,
+            ClientCommand::Stats => self.server_stats(user).await,
+            ClientCommand::Version => self.server_version(user).await,
+            ClientCommand::DeleteAccount { token } => self.delete_account(user, token).await,
+            ClientCommand::Pin { target } => self.pin(user, target, true).await,
+            ClientCommand::Unpin { target } => self.pin(user, target, false).await,
+            ClientCommand::Macro { action } => self.macro_command(user, action).await,
+            ClientCommand::HostPort { port } => self.host_port(user, port).await,
+            ClientCommand::Resume { token } => self.resume_session(user, &token).await,
+            ClientCommand::Approve { username } => self.approve(user, username).await,
+            ClientCommand::Games { filter } => self.list_games(user, filter).await,
+            ClientCommand::List => self.list(user).await,
+            ClientCommand::Ping { game_name } => match game_name {
+                Some(game_name) => self.ping_game(user, &game_name).await,
+                None => self.ping_self(user).await,
+            },
+            ClientCommand::Find { term } => self.find(user, &term).await,
+            ClientCommand::LeaveGame => self.leave_game(user).await,
+            ClientCommand::Nick { new_name } => self.nick(user, new_name).await,
+            ClientCommand::Invisible { enabled } => self.set_invisible(user, enabled).await,
+            ClientCommand::Slowmode { secs } => self.slowmode(user, secs).await,
+            ClientCommand::MaxUsers { max_users } => self.set_max_users(user, max_users).await,
+            ClientCommand::Announce { text } => self.announce(user, text).await,
+            ClientCommand::Notice { username, text } => self.notice(user, username, text).await,
+            ClientCommand::Mail { action } => self.mail_command(user, action).await,
+            ClientCommand::SetInfo { field, value } => self.set_info(user, field, value).await,
+            ClientCommand::Finger { username } => self.finger(user, username).await,
+            ClientCommand::Clan { action } => self.clan_command(user, action).await,
+            ClientCommand::Tournament { action } => self.tournament_command(user, action).await,
+            ClientCommand::SignUp { tournament } => self.sign_up(user, tournament).await,
+            // replaced by preprocess_command
+            ClientCommand::ExpandMacro { .. } => (),
+            ClientCommand::NoOp => (),
+            ClientCommand::Malformed { reason } => user.send(ErrorMessage::new_err(&reason)).await,
+            ClientCommand::Unknown { command, params } => {
+                let reply = self
+                    .plugins
+                    .iter_mut()
+                    .find_map(|p| p.on_command(&user, &command, &params));
+                match reply {
+                    Some(reply) => user.send(InfoMessage::new_info(&reply)).await,
+                    None => {
+                        user.send(ErrorMessage::new_err(&format!(
+                            "Unknown command: {}",
+                            command
+                        )))
+                        .await
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_new_user(&mut self, user: User, password: String) {
+        // a second login for someone online is turned away before their password is checked,
+        // so that it can neither count as a failed login nor create anything
+        let name = Name::new(&user.username);
+        if self.users.by_username(&user.username).is_some()
+            || self.users.by_account(&user.username).is_some()
+            || self.pending_logins.values().any(|pending| *pending == name)
+        {
+            log::info!(
+                "A client with username {} is already logged in, dropping client",
+                user.username
+            );
+            self.reject_login(user, "Already logged in".to_string())
+                .await;
+            return;
+        }
+
+        if self.bans.is_banned(&user.username) {
+            log::info!("Rejecting banned user {}", user.username);
+            self.reject_login(user, "You are banned from this server".to_string())
+                .await;
+            return;
+        }
+
+        if let Some(max_users) = self.max_users {
+            let online = self.users.iter().filter(|u| !u.bot).count();
+            if online + self.pending_logins.len() >= max_users {
+                log::info!("Rejecting {}, the server is full", user.username);
+                self.reject_login(user, "The server is full".to_string())
+                    .await;
+                return;
+            }
+        }
+
+        if let Some(reason) = self.check_reserved_name(&user.username) {
+            log::info!("Rejecting reserved username {}", user.username);
+            self.reject_login(user, reason).await;
+            return;
+        }
+
+        let wait = self
+            .login_throttle
+            .as_ref()
+            .and_then(|throttle| throttle.check(user.ip_addr, &user.username, Instant::now()));
+        if let Some(wait) = wait {
+            let secs = (wait.as_millis() as u64).div_ceil(1000);
+            log::info!(
+                "Throttling login of {} from {} for another {} seconds",
+                user.username,
+                user.ip_addr,
+                secs
+            );
+            let reason = format!("Too many failed logins, try again in {} seconds", secs);
+            self.reject_login(user, reason).await;
+            return;
+        }
+
+        self.pending_logins.insert(user.id, name);
+        let check = self.accounts.password_check(&user.username);
+        let events = self.service_events.clone();
+        task::spawn_blocking(move || {
+            let outcome = check.run(&user.username, &password);
+            let event = Event::LoginChecked {
+                user: Box::new(user),
+                blank_password: password.is_empty(),
+                outcome,
+            };
+            // the broker may have shut down in the meantime
+            let _ = events.send(event);
+        });
+    }
+
+    /// Goes on with a login once its password has been checked
+    async fn handle_checked_login(
+        &mut self,
+        mut user: User,
+        blank_password: bool,
+        outcome: PasswordOutcome,
+    ) {
+        if self.pending_logins.remove(&user.id).is_none() {
+            log::info!("{} disconnected while logging in", user.username);
+            return;
+        }
+        let login_check = self
+            .accounts
+            .complete_login(&self.storage, &user.username, outcome);
+        if let Some(throttle) = &mut self.login_throttle {
+            if login_check == LoginCheck::WrongPassword {
+                throttle.record_failed_login(user.ip_addr, &user.username, Instant::now());
+            } else {
+                throttle.record_login(user.ip_addr, &user.username);
+            }
+        }
+        if login_check == LoginCheck::Created {
+            self.totals.record_account(&self.storage);
+        }
+        if login_check == LoginCheck::WrongPassword {
+            log::info!("Wrong password for account {}", user.username);
+            let reason = if blank_password {
+                format!(
+                    "The name {} is registered, log in with its password",
+                    user.username
+                )
+            } else {
+                "Wrong password".to_string()
+            };
+            self.reject_login(user, reason).await;
+            return;
+        }
+
+        if let Verdict::Veto(reason) = check_plugins(&mut self.plugins, |p| p.on_login(&user)) {
+            self.reject_login(user, reason).await;
+            return;
+        }
+
+        log::info!(
+            "User {} has successfully logged in as {} using build {}",
+            user.id,
+            user.username,
+            user.build
+        );
+        self.builds.record_login(&user.build);
+        self.totals.record_login(&self.storage);
+        self.daily.record_login();
+        self.audit_log.record(AuditEvent::Login {
+            username: user.username.clone(),
+            ip: user.ip_addr,
+            build: user.build.clone(),
+        });
+        user.invisible = user.role == Role::Admin
+            && self
+                .preferences
+                .get(&user.account)
+                .is_some_and(|p| p.invisible);
+        let channel = DEFAULT_CHANNEL.to_string();
+        user.send(Arc::new(
+            WelcomeServerMessage {
+                server_ident: format!("IE::Net {}", build_info()),
+                welcome_message: "Welcome to IE::Net, a community-operated EarthNet server"
+                    .to_string(),
+                players_total: self.totals.get().accounts as u32,
+                players_online: 0,
+                channels_total: 0,
+                games_total: 0,
+                games_running: 0,
+                games_available: 0,
+                game_versions: self.versions.names(),
+                initial_channel: channel.clone(),
+            }
+            .into(),
+        ))
+        .await;
+
+        if login_check == LoginCheck::DeletionCancelled {
+            user.send(InfoMessage::new_info(
+                "The pending deletion of your account has been cancelled",
+            ))
+            .await;
+        }
+
+        self.channels.announce_all(&mut user).await;
+        self.games
+            .announce_listed(&mut user, |g| self.host_latency(g))
+            .await;
+        self.federation.announce_all(&mut user).await;
+
+        let id = user.id;
+        self.users.insert(user).await;
+        self.update_ping_hosts();
+        self.join_channel(self.users.by_user_id(&id).unwrap().clone(), channel)
+            .await;
+        let mut user = self.users.by_user_id(&id).unwrap().clone();
+        if let Some(keep_for) = self.sessions.keep_for().filter(|_| !user.bot) {
+            let token = Sessions::new_token();
+            user.send(InfoMessage::new_info(&format!(
+                "If you get disconnected, log in again and type /resume {} within {} minutes to \
+                 return where you left off",
+                token,
+                keep_for.as_secs().div_ceil(60)
+            )))
+            .await;
+            self.resume_tokens.insert(id, token);
+        }
+        if user.invisible {
+            user.send(InfoMessage::new_info(
+                "You are invisible, type /invisible off to be seen",
+            ))
+            .await;
+        }
+        self.deliver_mail(&mut user).await;
+    }
+
+    /// Tells the client why it may not log in and drops it, which closes the connection once
+    /// the rejection is written
+    async fn reject_login(&self, mut user: User, reason: String) {
+        self.audit_log.record(AuditEvent::LoginRejected {
+            username: user.username.clone(),
+            ip: user.ip_addr,
+            reason: reason.clone(),
+        });
+        user.send(Arc::new(RejectServerMessage { reason }.into()))
+            .await;
+    }
+
+    fn snapshot(&self) -> LobbySnapshot {
+        LobbySnapshot {
+            users: self
+                .users
+                .iter()
+                .filter(|u| !u.invisible)
+                .map(|u| (u.username.clone(), u.location.clone()))
+                .chain(self.federation.users())
+                .collect(),
+            channels: self
+                .channels
+                .iter()
+                .map(|c| c.name.clone())
+                .chain(self.federation.channels())
+                .collect(),
+            open_games: self
+                .games
+                .listed()
+                .map(|g| g.name.clone())
+                .chain(self.federation.games())
+                .collect(),
+            pinned: self
+                .channels
+                .iter()
+                .filter(|c| c.pinned)
+                .map(|c| c.to_location().to_string())
+                .chain(
+                    self.games
+                        .iter()
+                        .filter(|g| g.pinned)
+                        .map(|g| g.to_location().to_string()),
+                )
+                .collect(),
+        }
+    }
+
+    fn status(&self) -> ServerStatus {
+        ServerStatus {
+            version: build_info(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            users_online: self.users.iter().filter(|u| !u.bot && !u.invisible).count() as u32,
+            channels: self
+                .channels
+                .iter()
+                .map(|c| ChannelStatus {
+                    name: c.name.clone(),
+                    users: self
+                        .users
+                        .visible_in_location(&c.to_location())
+                        .iter()
+                        .filter(|u| !u.bot)
+                        .count()
+                        + self.federation.users_in_location(&c.to_location()).len(),
+                })
+                .collect(),
+            open_games: self
+                .games
+                .iter()
+                .filter(|g| g.status == Open)
+                .map(|g| OpenGameStatus {
+                    name: g.name.clone(),
+                    host: self
+                        .users
+                        .by_user_id(&g.hosted_by)
+                        .map(|u| u.username.clone())
+                        .unwrap_or_default(),
+                    players: g.player_count(),
+                    max_players: g.max_players,
+                })
+                .collect(),
+            totals: self.totals.get().clone(),
+        }
+    }
+
+    fn user_infos(&self) -> Vec<UserInfo> {
+        self.users
+            .iter()
+            .map(|u| UserInfo {
+                id: u.id,
+                username: u.username.clone(),
+                role: u.role,
+                location: u.location.to_string(),
+                ip_addr: u.ip_addr,
+                game_version: u.game_version,
+                build: u.build.clone(),
+                bot: u.bot,
+                invisible: u.invisible,
+                fingerprint: u.fingerprint.clone(),
+                connected_secs: u.connected_at.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    fn channel_infos(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .iter()
+            .map(|c| ChannelInfo {
+                name: c.name.clone(),
+                users: self.users.users_in_location(&c.to_location()).len(),
+                pinned: c.pinned,
+            })
+            .collect()
+    }
+
+    fn game_infos(&self) -> Vec<GameInfo> {
+        self.games
+            .iter()
+            .map(|g| GameInfo {
+                name: g.name.clone(),
+                host: self
+                    .users
+                    .by_user_id(&g.hosted_by)
+                    .map(|u| u.username.clone())
+                    .unwrap_or_default(),
+                status: match g.status {
+                    Requested => "requested",
+                    Open => "open",
+                    Started => "started",
+                }
+                .to_string(),
+                players: g.player_count(),
+                max_players: g.max_players,
+                pinned: g.pinned,
+            })
+            .collect()
+    }
+
+    /// Disconnects clients that have not been able to take any messages for too long, or that
+    /// fell so far behind that their backlog ran full
+    async fn evict_stalled_clients(&mut self) {
+        let now = Instant::now();
+        let stalled: Vec<String> = self
+            .users
+            .iter()
+            .filter(|u| {
+                u.send.overflowed() || u.stalled_for(now).is_some_and(|d| d >= self.evict_after)
+            })
+            .map(|u| u.username.clone())
+            .collect();
+        for username in stalled {
+            log::warn!(
+                "Evicting {}, who does not keep up with their messages",
+                username
+            );
+            self.metrics.increment("clients.evicted");
+            self.kick(&username, "Disconnected for not keeping up with the server")
+                .await;
+        }
+    }
+
+    /// Disconnects a user by closing their send queue, returning whether they were online
+    async fn kick(&mut self, username: &str, reason: &str) -> bool {
+        let id = match self.users.by_username_mut(username) {
+            Some(user) => {
+                user.send(InfoMessage::new_info(reason)).await;
+                // shards may still hold a sender, which must not keep the connection open
+                user.send.close();
+                user.id
+            }
+            None => return false,
+        };
+        log::info!("Disconnecting {}: {}", username, reason);
+        self.audit_log.record(AuditEvent::Kick {
+            username: username.to_string(),
+            reason: reason.to_string(),
+        });
+        self.deletion_tokens.remove(&id);
+        self.resume_tokens.remove(&id);
+        self.users.remove(id).await;
+        true
+    }
+
+    async fn handle_control_command(&mut self, command: ControlCommand) {
+        match command {
+            ControlCommand::DumpState { respond_to } => respond(respond_to, self.snapshot()),
+            ControlCommand::ReloadWordList { respond_to } => {
+                let result = self.filter.reload();
+                if let Err(e) = &result {
+                    log::error!("Failed to reload word list: {}", e);
+                }
+                respond(respond_to, result);
+            }
+            ControlCommand::ListUsers { respond_to } => respond(respond_to, self.user_infos()),
+            ControlCommand::ListChannels { respond_to } => {
+                respond(respond_to, self.channel_infos())
+            }
+            ControlCommand::ListGames { respond_to } => respond(respond_to, self.game_infos()),
+            ControlCommand::DailyStats { respond_to } => respond(respond_to, self.daily.entries()),
+            ControlCommand::ListBuilds { respond_to } => {
+                let stats = self
+                    .builds
+                    .stats(self.users.iter().map(|u| u.build.as_str()));
+                respond(respond_to, stats)
+            }
+            ControlCommand::Kick {
+                username,
+                respond_to,
+            } => {
+                let kicked = self
+                    .kick(&username, "You have been disconnected by an administrator")
+                    .await;
+                respond(respond_to, kicked);
+            }
+            ControlCommand::TraceProtocol {
+                username,
+                enabled,
+                respond_to,
+            } => {
+                let online = match self.users.by_username(&username) {
+                    Some(user) if !user.bot => {
+                        user.protocol_trace.set_enabled(enabled);
+                        true
+                    }
+                    _ => false,
+                };
+                if online {
+                    log::info!(
+                        "Protocol trace for {} switched {}",
+                        username,
+                        if enabled { "on" } else { "off" }
+                    );
+                }
+                respond(respond_to, online);
+            }
+            ControlCommand::Ban {
+                username,
+                respond_to,
+            } => {
+                log::info!("Banning {}", username);
+                self.audit_log.record(AuditEvent::Ban {
+                    username: username.clone(),
+                });
+                // the ban holds the account, also if its owner goes by another name
+                let account = self.account_of(&username);
+                self.bans.ban(&self.storage, &account);
+                let online = self
+                    .users
+                    .by_username(&username)
+                    .or_else(|| self.users.by_account(&account))
+                    .map(|u| u.username.clone());
+                if let Some(online) = online {
+                    self.kick(&online, "You have been banned from this server")
+                        .await;
+                }
+                respond(respond_to, ());
+            }
+            ControlCommand::Unban {
+                username,
+                respond_to,
+            } => {
+                log::info!("Unbanning {}", username);
+                self.audit_log.record(AuditEvent::Unban {
+                    username: username.clone(),
+                });
+                respond(respond_to, self.bans.unban(&self.storage, &username));
+            }
+            ControlCommand::Broadcast {
+                message,
+                filter,
+                respond_to,
+            } => {
+                log::info!("Broadcasting announcement: {}", message);
+                let now = Instant::now();
+                let recipients = self
+                    .users
+                    .send_to_matching(
+                        |u| filter.as_ref().is_none_or(|f| f.matches(u, now)),
+                        InfoMessage::new_info(&message),
+                    )
+                    .await;
+                respond(respond_to, recipients);
+            }
+        }
+    }
+
+    async fn update_stats(&mut self) {
+        let stats = Stats {
+            users_total: self.users.visible_count(),
+            users_online: self.users.visible_count(),
+            channels_total: self.channels.count(),
+            games_total: self.games.count(),
+            games_open: self.games.count_open(),
+        };
+        self.metrics
+            .gauge("users_online", stats.users_online as i64);
+        let real_users = self.users.iter().filter(|u| !u.bot && !u.invisible).count();
+        self.totals
+            .record_users_online(&self.storage, real_users as u32);
+        self.metrics.gauge("channels", stats.channels_total as i64);
+        self.metrics.gauge("games_total", stats.games_total as i64);
+        self.metrics.gauge("games_open", stats.games_open as i64);
+        if stats != self.stats {
+            self.stats = stats;
+            self.users
+                .send_to_all(Arc::new(
+                    SyncStatsMessage {
+                        users_total: self.stats.users_total,
+                        users_online: self.stats.users_online,
+                        channels_total: self.stats.channels_total,
+                        games_total: self.stats.games_total,
+                        games_open: self.stats.games_open,
+                    }
+                    .into(),
+                ))
+                .await;
+        }
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        // a player coming back once the window has passed must not abort the match
+        self.settle_launched_games().await;
+        match event {
+            Event::NewUser {
+                id,
+                username,
+                password,
+                game_version,
+                ip_addr,
+                fingerprint,
+                protocol_trace,
+                send,
+            } => {
+                self.metrics.increment("events.new_user");
+                let user = User {
+                    id,
+                    role: self.role_for(&username),
+                    account: username.clone(),
+                    username,
+                    location: Location::Nowhere,
+                    last_channel: None,
+                    game_version,
+                    version_idx: self.versions.index_of(game_version).unwrap_or(0),
+                    language: Language::from_client(&fingerprint.language),
+                    region: self.geoip.region(ip_addr),
+                    ip_addr,
+                    connected_at: Instant::now(),
+                    last_active: Instant::now(),
+                    host_port: None,
+                    build: self.builds.classify(&fingerprint),
+                    fingerprint,
+                    bot: false,
+                    invisible: false,
+                    protocol_trace,
+                    send,
+                    queued: Default::default(),
+                    shard: Default::default(),
+                };
+                self.handle_new_user(user, password).await
+            }
+            Event::LoginChecked {
+                user,
+                blank_password,
+                outcome,
+            } => {
+                self.metrics.increment("events.login_checked");
+                self.handle_checked_login(*user, blank_password, outcome)
+                    .await
+            }
+            Event::Command { id, command, .. } => {
+                self.metrics.increment("events.command");
+                let metric = command.metric_name();
+                let start = Instant::now();
+                self.handle_client_command(id, command).await;
+                self.metrics.record_since(metric, start);
             }
-            Event::Command { id, command } => self.handle_client_command(id, command).await,
             Event::DropClient { id } => {
+                self.metrics.increment("events.drop_client");
                 log::info!("Client {} disconnected, dropping", id);
+                self.pending_logins.remove(&id);
+                if let Some(user) = self.users.by_user_id(&id) {
+                    self.audit_log.record(AuditEvent::Disconnect {
+                        username: user.username.clone(),
+                    });
+                    if let Some(token) = self.resume_tokens.remove(&id) {
+                        let session = Session::of(user, Instant::now());
+                        self.sessions.suspend(token, &user.account, session);
+                    }
+                }
+                self.deletion_tokens.remove(&id);
                 self.users.remove(id).await;
+                self.drop_hosted_games(id).await;
+            }
+            Event::FailedIdent { ip_addr } => {
+                self.metrics.increment("events.failed_ident");
+                if let Some(throttle) = &mut self.login_throttle {
+                    throttle.record_failed_ident(ip_addr, Instant::now());
+                }
+            }
+            Event::Control { command } => {
+                self.metrics.increment("events.control");
+                self.handle_control_command(command).await
+            }
+            Event::QueryState { respond_to } => {
+                self.metrics.increment("events.query_state");
+                respond(respond_to, self.status())
+            }
+            Event::PeerConnected { server, link, send } => {
+                self.metrics.increment("events.peer_connected");
+                self.peer_connected(server, link, send)
+            }
+            Event::PeerMessage { server, message } => {
+                self.metrics.increment("events.peer_message");
+                self.handle_peer_message(&server, message).await
+            }
+            Event::PeerDisconnected { server, link } => {
+                self.metrics.increment("events.peer_disconnected");
+                self.peer_disconnected(&server, link).await
+            }
+            // the main loop stops on it before it gets here
+            Event::Shutdown => (),
+            Event::ScheduledAnnouncement { text } => {
+                self.metrics.increment("events.scheduled_announcement");
+                let location = Location::Channel {
+                    name: DEFAULT_CHANNEL.to_string(),
+                };
+                self.users
+                    .send_to_location(location, InfoMessage::new_info(&text))
+                    .await;
             }
         }
 
+        self.update_bots().await;
+        self.drop_stale_games().await;
+        let mut occupied_locations = self.users.occupied_locations();
+        occupied_locations.extend(self.federation.occupied_locations());
         self.channels
-            .check_remove_empty_channels(&mut self.users)
+            .check_remove_empty_channels(&mut self.users, &occupied_locations)
             .await;
-        self.games.check_remove_empty_games(&mut self.users).await;
+        self.games.update_players(&self.users);
+        for game in self
+            .games
+            .check_remove_empty_games(&mut self.users, &occupied_locations)
+            .await
+        {
+            self.audit_log.record(AuditEvent::GameClosed {
+                game: game.name.clone(),
+                winner: None,
+            });
+            self.launched.push(game);
+        }
+        self.settle_launched_games().await;
         self.update_stats().await;
+        self.sync_peers().await;
         Ok(())
     }
 }
 
+fn respond<T>(respond_to: oneshot::Sender<T>, value: T) {
+    if respond_to.send(value).is_err() {
+        log::warn!("Requester of control command went away");
+    }
+}
+
 pub async fn broker_loop(
     mut events: EventReceiver,
-    mut shutdown_recv: watch::Receiver<bool>,
+    shutdown_recv: watch::Receiver<bool>,
+    metrics: Metrics,
+    config: Config,
+    tracer: Tracer,
+    plugins: Vec<Box<dyn BrokerPlugin>>,
+    replication: Option<ReplicationFeed>,
 ) -> Result<()> {
-    let mut broker = Broker::new();
+    let (storage, storage_handle) = match &config.storage.data_dir {
+        Some(dir) => {
+            let (storage, handle) = Storage::open(dir, replication)?;
+            (storage, Some(handle))
+        }
+        None => (Storage::in_memory(), None),
+    };
+    let (chat_log, chat_log_handle) = if config.chat_log.enabled {
+        let (chat_log, handle) = ChatLog::open(&config.chat_log)?;
+        (chat_log, Some(handle))
+    } else {
+        (ChatLog::disabled(), None)
+    };
+    let (audit_log, audit_log_handle) = if config.audit_log.enabled {
+        let (audit_log, handle) = AuditLog::open(&config.audit_log)?;
+        (audit_log, Some(handle))
+    } else {
+        (AuditLog::disabled(), None)
+    };
+    let (service_events, mut service_events_recv) = mpsc::unbounded_channel();
+    let mut broker = Broker::new(
+        &config,
+        metrics,
+        storage,
+        chat_log,
+        audit_log,
+        plugins,
+        service_events,
+    )?;
     log::info!("Main server loop starting up");
+    if config.help_bot.enabled {
+        broker
+            .spawn_service_bot(Box::new(HelpBot::new(config.help_bot.clone())))
+            .await;
+    }
 
+    // sending to a client that stopped reading blocks the broker until its queue drains,
+    // which must not keep the server from shutting down
+    let shutdown = wait_for_shutdown(shutdown_recv);
+    tokio::pin!(shutdown);
+    let mut housekeeping = tokio::time::interval(Duration::from_secs(60));
+    let mut stall_check = tokio::time::interval(Duration::from_secs(1));
     loop {
         tokio::select! {
+            _ = housekeeping.tick() => tokio::select! {
+                _ = broker.housekeeping() => (),
+                _ = &mut shutdown => break,
+            },
+            _ = stall_check.tick() => {
+                broker.watch_send_queues();
+                broker.evict_stalled_clients().await;
+            },
             maybe_event = events.next() => match maybe_event {
-                Some(event) => broker.handle_event(event).await?,
+                Some(Event::Shutdown) => break,
+                Some(event) => {
+                    let parent = match &event {
+                        Event::Command { trace, .. } => *trace,
+                        _ => None,
+                    };
+                    let span = tracer.start_span("broker.handle_event", parent);
+                    let context = span.as_ref().map(|s| s.context());
+                    tokio::select! {
+                        result = trace::scope(context, broker.handle_event(event)) => result?,
+                        _ = &mut shutdown => break,
+                    }
+                }
                 None => break,
             },
-            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            Some(event) = service_events_recv.recv() => tokio::select! {
+                result = broker.handle_event(event) => result?,
+                _ = &mut shutdown => break,
+            },
+            _ = &mut shutdown => break,
         }
     }
 
     log::info!("Main server loop shutting down");
+    broker.daily.flush(&broker.storage);
+    // dropping the broker closes the storage and logs, so their writers can flush and finish
+    drop(broker);
+    if let Some(storage_handle) = storage_handle {
+        storage_handle.await?;
+    }
+    if let Some(chat_log_handle) = chat_log_handle {
+        chat_log_handle.await?;
+    }
+    if let Some(audit_log_handle) = audit_log_handle {
+        audit_log_handle.await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::server_messages::InfoMessage;
+
+    #[test]
+    fn test_shared_message_is_prepared_once_per_language() {
+        let message: ArcServerMessage = Arc::new(
+            InfoMessage {
+                text: "Server restarts in 5 minutes".to_string(),
+            }
+            .into(),
+        );
+        let prepared = Arc::new(PreparedBytes::default());
+        let prepare = |language| {
+            let mut outgoing = OutgoingMessage::localized(message.clone(), language);
+            outgoing.prepared = Some(prepared.clone());
+            outgoing.prepare().unwrap()
+        };
+        let english = prepare(Language::English);
+        let german = prepare(Language::German);
+        assert!(Arc::ptr_eq(&english, &prepare(Language::English)));
+        assert!(Arc::ptr_eq(&german, &prepare(Language::German)));
+        assert!(!Arc::ptr_eq(&english, &german));
+
+        let unshared = OutgoingMessage::new(message.clone()).prepare().unwrap();
+        assert!(!Arc::ptr_eq(&english, &unshared));
+        assert_eq!(english, unshared);
+    }
+}
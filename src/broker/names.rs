@@ -0,0 +1,360 @@
+use crate::broker::game::GameStatus;
+use crate::broker::user::{Location, User};
+use crate::broker::{ArcServerMessage, Broker};
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::server_messages::{ErrorMessage, InfoMessage};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters that only separate words in names, like in `eRtH_2150-FFA`
+const SEPARATORS: &str = " _-.|";
+
+/// How many matches /find lists at most
+const MAX_FIND_RESULTS: usize = 10;
+
+/// Folds a name for comparisons that should not depend on case, diacritics or separators,
+/// so that `eRtH_2150-FFA` and `Erth 2150 FFA` are the same
+pub fn normalize(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.nfc() {
+        if SEPARATORS.contains(c) {
+            continue;
+        }
+        match fold_char(c) {
+            Some(folded) => result.push_str(folded),
+            None => result.extend(c.to_lowercase().map(fold_confusable)),
+        }
+    }
+    result
+}
+
+/// Folds a username into the key that tells online users apart: composed the same way,
+/// ignoring case and with look-alikes from other scripts replaced, so that `Αdmin` with a
+/// Greek alpha is the same as `Admin`. Unlike `normalize`, diacritics and separators count.
+pub fn canonical(name: &str) -> String {
+    name.nfc()
+        .flat_map(char::to_lowercase)
+        .map(fold_confusable)
+        .collect()
+}
+
+/// A user, channel or game name as it was given, compared and hashed by its canonical form,
+/// so that maps keyed by it find `General` when asked for `general`
+#[derive(Debug, Clone)]
+pub struct Name {
+    key: String,
+    display: String,
+}
+
+impl Name {
+    pub fn new(display: &str) -> Self {
+        Self {
+            key: canonical(display),
+            display: display.to_string(),
+        }
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.display)
+    }
+}
+
+/// Replaces lowercase letters that are hard to tell from a Latin letter by that letter
+fn fold_confusable(c: char) -> char {
+    match c {
+        // fullwidth forms of ASCII characters
+        '\u{ff01}'..='\u{ff5e}' => {
+            std::char::from_u32(c as u32 - 0xfee0).map_or(c, |c| c.to_ascii_lowercase())
+        }
+        // Greek
+        'α' => 'a',
+        'β' => 'b',
+        'ε' => 'e',
+        'ζ' => 'z',
+        'η' => 'h',
+        'ι' => 'i',
+        'κ' => 'k',
+        'μ' => 'm',
+        'ν' => 'v',
+        'ο' => 'o',
+        'ρ' => 'p',
+        'τ' => 't',
+        'υ' => 'u',
+        'χ' => 'x',
+        // Cyrillic
+        'а' => 'a',
+        'в' => 'b',
+        'е' => 'e',
+        'һ' | 'н' => 'h',
+        'і' => 'i',
+        'ј' => 'j',
+        'к' => 'k',
+        'м' => 'm',
+        'о' => 'o',
+        'р' => 'p',
+        'с' => 'c',
+        'ѕ' => 's',
+        'т' => 't',
+        'у' => 'y',
+        'х' => 'x',
+        'ԁ' => 'd',
+        'ԛ' => 'q',
+        'ԝ' => 'w',
+        _ => c,
+    }
+}
+
+/// Latin letters with diacritics as found in European player names
+fn fold_char(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ą' | 'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ą' => {
+            "a"
+        }
+        'ç' | 'ć' | 'č' | 'Ç' | 'Ć' | 'Č' => "c",
+        'ď' | 'Ď' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ę' | 'ě' | 'È' | 'É' | 'Ê' | 'Ë' | 'Ę' | 'Ě' => "e",
+        'ì' | 'í' | 'î' | 'ï' | 'Ì' | 'Í' | 'Î' | 'Ï' => "i",
+        'ł' | 'Ł' => "l",
+        'ñ' | 'ń' | 'ň' | 'Ñ' | 'Ń' | 'Ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' => "o",
+        'ř' | 'Ř' => "r",
+        'ś' | 'š' | 'Ś' | 'Š' => "s",
+        'ť' | 'Ť' => "t",
+        'ù' | 'ú' | 'û' | 'ü' | 'ů' | 'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ů' => "u",
+        'ý' | 'ÿ' | 'Ý' => "y",
+        'ź' | 'ż' | 'ž' | 'Ź' | 'Ż' | 'Ž' => "z",
+        'ß' => "ss",
+        'æ' | 'Æ' => "ae",
+        'œ' | 'Œ' => "oe",
+        _ => return None,
+    })
+}
+
+/// How well a name matches a search term, better matches compare lower
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchQuality {
+    Exact,
+    Prefix,
+    Substring,
+    /// all characters of the term appear in order
+    Scattered,
+}
+
+pub fn match_quality(term: &str, name: &str) -> Option<MatchQuality> {
+    let term = normalize(term);
+    let name = normalize(name);
+    if term.is_empty() {
+        None
+    } else if name == term {
+        Some(MatchQuality::Exact)
+    } else if name.starts_with(&term) {
+        Some(MatchQuality::Prefix)
+    } else if name.contains(&term) {
+        Some(MatchQuality::Substring)
+    } else {
+        let mut name_chars = name.chars();
+        if term.chars().all(|t| name_chars.any(|n| n == t)) {
+            Some(MatchQuality::Scattered)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the items whose names match the term, best and then shortest matches first
+pub fn search<T>(term: &str, items: impl Iterator<Item = T>, name: impl Fn(&T) -> &str) -> Vec<T> {
+    let mut matches: Vec<(MatchQuality, usize, T)> = items
+        .filter_map(|item| {
+            let quality = match_quality(term, name(&item))?;
+            Some((quality, name(&item).len(), item))
+        })
+        .collect();
+    matches.sort_by_key(|(quality, len, _)| (*quality, *len));
+    matches.into_iter().map(|(_, _, item)| item).collect()
+}
+
+impl Broker {
+    /// Returns why a username may not be used, if it is taken by the server itself
+    pub(super) fn check_reserved_name(&self, username: &str) -> Option<String> {
+        let name = normalize(username);
+        if let Some(bots) = &self.bots {
+            if name.starts_with(&normalize(&bots.prefix)) {
+                return Some(format!("Names starting with {} are reserved", bots.prefix));
+            }
+        }
+        if self.reserved_names.contains(&name) {
+            return Some(format!("The name {} is reserved", username));
+        }
+        None
+    }
+
+    /// Returns the error telling a logged-in user why they may not take the name, if they may not
+    fn check_new_name(&self, user: &User, new_name: &str) -> Option<ArcServerMessage> {
+        if !self.names.is_valid_username(new_name) {
+            return Some(ErrorMessage::new_err(&format!(
+                "{} contains characters not allowed in names",
+                new_name
+            )));
+        }
+        if let Location::Game { .. } | Location::RemoteGame { .. } = user.location {
+            return Some(ErrorMessage::new_err(
+                "You cannot change your name inside a game",
+            ));
+        }
+        if let Some(reason) = self.check_reserved_name(new_name) {
+            return Some(ErrorMessage::new_err(&reason));
+        }
+        // changing the case of one's own name is fine, anything else must be unclaimed
+        if new_name.eq_ignore_ascii_case(&user.username) {
+            return None;
+        }
+        // going back to the login name is always allowed
+        if new_name.eq_ignore_ascii_case(&user.account) {
+            return None;
+        }
+        if self.users.visible_to(new_name, user).is_some() {
+            return Some(ErrorMessage::new_err(&format!(
+                "{} is already online",
+                new_name
+            )));
+        }
+        // an invisible admin's name is refused like that of anyone offline
+        if self.users.by_username(new_name).is_some()
+            || self.accounts.get(new_name).is_some()
+            || self.bans.is_banned(new_name)
+            || self.role_for(new_name).is_staff()
+        {
+            return Some(ErrorMessage::new_coded(LobbyError::NameTaken(
+                new_name.to_string(),
+            )));
+        }
+        None
+    }
+
+    pub(super) async fn nick(&mut self, mut user: User, new_name: String) {
+        if let Some(error) = self.check_new_name(&user, &new_name) {
+            user.send(error).await;
+            return;
+        }
+        log::info!("{} is now known as {}", user.username, new_name);
+        self.users.rename(user.id, new_name.clone()).await;
+        user.send(InfoMessage::new_info(&format!(
+            "You are now known as {}",
+            new_name
+        )))
+        .await;
+    }
+
+    /// Resolves the name a user goes by to the account their data is kept under, which is the
+    /// name itself unless someone online took it with /nick
+    pub(super) fn account_of(&self, name: &str) -> String {
+        self.users
+            .by_username(name)
+            .map(|u| u.account.clone())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    /// Lists the channels and open games whose names match the term, as `#channel` and `$game`
+    pub(super) async fn find(&mut self, mut user: User, term: &str) {
+        let channels = self
+            .channels
+            .iter()
+            .map(|c| (c.name.clone(), format!("#{}", c.name)));
+        let games = self
+            .games
+            .iter()
+            .filter(|g| g.status == GameStatus::Open)
+            .map(|g| {
+                let line = format!("${} ({}/{})", g.name, g.player_count(), g.max_players);
+                (g.name.clone(), line)
+            });
+        let candidates: Vec<(String, String)> = channels.chain(games).collect();
+        let matches = search(term, candidates.into_iter(), |(name, _)| name);
+        if matches.is_empty() {
+            user.send(InfoMessage::new_info(&format!(
+                "No channels or games match {}",
+                term
+            )))
+            .await;
+        }
+        for (_, line) in matches.into_iter().take(MAX_FIND_RESULTS) {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("eRtH_2150-FFA"), "erth2150ffa");
+        assert_eq!(normalize("Érth 2150 FFA"), "erth2150ffa");
+        assert_eq!(normalize("Łódź.Clan|Wars"), "lodzclanwars");
+        assert_eq!(normalize("[bot] Scout"), "[bot]scout");
+        assert_eq!(normalize("\u{0391}dmin"), "admin");
+        assert_eq!(normalize("E\u{0301}rth"), "erth");
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(canonical("Admin"), "admin");
+        assert_eq!(canonical("\u{0391}dmin"), "admin");
+        assert_eq!(canonical("\u{0410}\u{0434}min"), "a\u{0434}min");
+        assert_eq!(canonical("\u{ff21}dmin"), "admin");
+        assert_eq!(canonical("Jo\u{0308}rg"), "jörg");
+        assert_ne!(canonical("Jörg"), canonical("Jorg"));
+        assert_ne!(canonical("foo_bar"), canonical("foobar"));
+    }
+
+    #[test]
+    fn test_name() {
+        let name = Name::new("General");
+        assert_eq!(name, Name::new("gENERAL"));
+        assert_ne!(name, Name::new("Genera1"));
+        assert_eq!(name.to_string(), "General");
+        let mut by_name = std::collections::HashMap::new();
+        by_name.insert(name, 1);
+        assert_eq!(by_name.get(&Name::new("general")), Some(&1));
+    }
+
+    #[test]
+    fn test_search() {
+        let names = [
+            "General",
+            "eRtH_2150-FFA",
+            "Earth",
+            "ffa_only",
+            "Tournament",
+        ];
+        assert_eq!(
+            search("erth", names.iter(), |n| n),
+            vec![&"eRtH_2150-FFA", &"Earth"]
+        );
+        assert_eq!(
+            search("FFA", names.iter(), |n| n),
+            vec![&"ffa_only", &"eRtH_2150-FFA"]
+        );
+        assert_eq!(search("trnmt", names.iter(), |n| n), vec![&"Tournament"]);
+        assert!(search("", names.iter(), |n| n).is_empty());
+        assert!(search("xyz", names.iter(), |n| n).is_empty());
+    }
+}
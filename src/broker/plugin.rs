@@ -0,0 +1,58 @@
+use crate::broker::user::User;
+
+/// A plugin's decision about an event
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// stops the event, telling the user why
+    Veto(String),
+}
+
+/// Extends the broker with custom behavior. Hooks run inside the broker loop, in the order the
+/// plugins were registered, and must not block; plugins that need I/O should hand it off to a
+/// task of their own. The first veto stops an event before later plugins see it.
+pub trait BrokerPlugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// A user has authenticated and is about to enter the lobby
+    fn on_login(&mut self, _user: &User) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A user is about to join a channel on this server. Not called for the channel users
+    /// enter right after logging in, which `on_login` covers.
+    fn on_join_channel(&mut self, _user: &User, _channel: &str) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A public chat message is about to be sent, after the content filter was applied.
+    /// The message may be changed.
+    fn on_message(&mut self, _user: &User, _message: &mut Vec<u8>) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A user asked to host a new game
+    fn on_game_created(&mut self, _host: &User, _game_name: &str) -> Verdict {
+        Verdict::Allow
+    }
+
+    /// A command the server does not know. Returns the text to answer with if the plugin
+    /// handled it, later plugins are not asked then.
+    fn on_command(&mut self, _user: &User, _command: &str, _params: &[Vec<u8>]) -> Option<String> {
+        None
+    }
+}
+
+/// Runs a hook on every plugin until one vetoes
+pub(super) fn check_plugins(
+    plugins: &mut [Box<dyn BrokerPlugin>],
+    mut hook: impl FnMut(&mut dyn BrokerPlugin) -> Verdict,
+) -> Verdict {
+    for plugin in plugins {
+        if let Verdict::Veto(reason) = hook(plugin.as_mut()) {
+            log::info!("Plugin {} vetoed: {}", plugin.name(), reason);
+            return Verdict::Veto(reason);
+        }
+    }
+    Verdict::Allow
+}
@@ -0,0 +1,269 @@
+use crate::broker::user::{Location, User};
+use anyhow::{anyhow, Result};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case, take_till, take_while1};
+use nom::character::complete::{char, digit1, multispace0, multispace1, one_of};
+use nom::combinator::{all_consuming, map, map_opt, map_res, opt, value};
+use nom::multi::fold_many0;
+use nom::sequence::{delimited, pair, preceded, tuple};
+use nom::IResult;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextField {
+    Username,
+    Build,
+    Version,
+    Channel,
+    Game,
+    Role,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextOp {
+    Equals,
+    NotEquals,
+    /// case-insensitive substring match
+    Contains,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Selects users by their properties, e.g. `build ~ tmp2.1 and not role = admin` or
+/// `channel = "Clan Wars" or idle > 30m`
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserPredicate {
+    And(Box<UserPredicate>, Box<UserPredicate>),
+    Or(Box<UserPredicate>, Box<UserPredicate>),
+    Not(Box<UserPredicate>),
+    Text {
+        field: TextField,
+        op: TextOp,
+        value: String,
+    },
+    /// seconds since the user's last command
+    Idle {
+        op: CompareOp,
+        secs: u64,
+    },
+}
+
+impl UserPredicate {
+    pub fn parse(input: &str) -> Result<Self> {
+        match all_consuming(delimited(multispace0, parsers::or_expr, multispace0))(input) {
+            Ok((_, predicate)) => Ok(predicate),
+            Err(nom::Err::Error((rest, _))) | Err(nom::Err::Failure((rest, _))) => {
+                Err(anyhow!("Invalid user filter near '{}'", rest))
+            }
+            Err(nom::Err::Incomplete(_)) => Err(anyhow!("Incomplete user filter")),
+        }
+    }
+
+    pub fn matches(&self, user: &User, now: Instant) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(user, now) && b.matches(user, now),
+            Self::Or(a, b) => a.matches(user, now) || b.matches(user, now),
+            Self::Not(a) => !a.matches(user, now),
+            Self::Text { field, op, value } => {
+                let actual = match field {
+                    TextField::Username => user.username.clone(),
+                    TextField::Build => user.build.clone(),
+                    TextField::Version => user.game_version.to_string(),
+                    TextField::Channel => match &user.location {
+                        Location::Channel { name } => name.clone(),
+                        _ => String::new(),
+                    },
+                    TextField::Game => match &user.location {
+                        Location::Game { name } => name.clone(),
+                        _ => String::new(),
+                    },
+                    TextField::Role => format!("{:?}", user.role),
+                }
+                .to_lowercase();
+                let value = value.to_lowercase();
+                match op {
+                    TextOp::Equals => actual == value,
+                    TextOp::NotEquals => actual != value,
+                    TextOp::Contains => actual.contains(&value),
+                }
+            }
+            Self::Idle { op, secs } => {
+                let idle = now.saturating_duration_since(user.last_active).as_secs();
+                match op {
+                    CompareOp::Less => idle < *secs,
+                    CompareOp::LessOrEqual => idle <= *secs,
+                    CompareOp::Greater => idle > *secs,
+                    CompareOp::GreaterOrEqual => idle >= *secs,
+                }
+            }
+        }
+    }
+}
+
+mod parsers {
+    use super::*;
+
+    fn keyword<'a>(word: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+        delimited(multispace0, tag_no_case(word), multispace1)
+    }
+
+    fn text_field(input: &str) -> IResult<&str, TextField> {
+        alt((
+            value(TextField::Username, tag_no_case("username")),
+            value(TextField::Build, tag_no_case("build")),
+            value(TextField::Version, tag_no_case("version")),
+            value(TextField::Channel, tag_no_case("channel")),
+            value(TextField::Game, tag_no_case("game")),
+            value(TextField::Role, tag_no_case("role")),
+        ))(input)
+    }
+
+    fn text_op(input: &str) -> IResult<&str, TextOp> {
+        alt((
+            value(TextOp::NotEquals, tag("!=")),
+            value(TextOp::Equals, tag("=")),
+            value(TextOp::Contains, tag("~")),
+        ))(input)
+    }
+
+    fn compare_op(input: &str) -> IResult<&str, CompareOp> {
+        alt((
+            value(CompareOp::LessOrEqual, tag("<=")),
+            value(CompareOp::GreaterOrEqual, tag(">=")),
+            value(CompareOp::Less, tag("<")),
+            value(CompareOp::Greater, tag(">")),
+        ))(input)
+    }
+
+    fn text_value(input: &str) -> IResult<&str, String> {
+        map(
+            alt((
+                delimited(char('"'), take_till(|c| c == '"'), char('"')),
+                take_while1(|c: char| c.is_alphanumeric() || "._-|[]{}".contains(c)),
+            )),
+            str::to_string,
+        )(input)
+    }
+
+    /// a number of seconds, or of minutes or hours with an `m` or `h` suffix;
+    /// amounts that do not fit into a number of seconds are rejected
+    fn duration(input: &str) -> IResult<&str, u64> {
+        map_opt(
+            pair(map_res(digit1, str::parse::<u64>), opt(one_of("smh"))),
+            |(amount, unit)| match unit {
+                Some('m') => amount.checked_mul(60),
+                Some('h') => amount.checked_mul(3600),
+                _ => Some(amount),
+            },
+        )(input)
+    }
+
+    fn comparison(input: &str) -> IResult<&str, UserPredicate> {
+        alt((
+            map(
+                tuple((
+                    preceded(multispace0, tag_no_case("idle")),
+                    preceded(multispace0, compare_op),
+                    preceded(multispace0, duration),
+                )),
+                |(_, op, secs)| UserPredicate::Idle { op, secs },
+            ),
+            map(
+                tuple((
+                    preceded(multispace0, text_field),
+                    preceded(multispace0, text_op),
+                    preceded(multispace0, text_value),
+                )),
+                |(field, op, value)| UserPredicate::Text { field, op, value },
+            ),
+        ))(input)
+    }
+
+    fn not_expr(input: &str) -> IResult<&str, UserPredicate> {
+        alt((
+            map(preceded(keyword("not"), not_expr), |p| {
+                UserPredicate::Not(Box::new(p))
+            }),
+            delimited(
+                preceded(multispace0, char('(')),
+                or_expr,
+                preceded(multispace0, char(')')),
+            ),
+            comparison,
+        ))(input)
+    }
+
+    fn and_expr(input: &str) -> IResult<&str, UserPredicate> {
+        let (input, first) = not_expr(input)?;
+        fold_many0(preceded(keyword("and"), not_expr), first, |a, b| {
+            UserPredicate::And(Box::new(a), Box::new(b))
+        })(input)
+    }
+
+    pub(super) fn or_expr(input: &str) -> IResult<&str, UserPredicate> {
+        let (input, first) = and_expr(input)?;
+        fold_many0(preceded(keyword("or"), and_expr), first, |a, b| {
+            UserPredicate::Or(Box::new(a), Box::new(b))
+        })(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(field: TextField, op: TextOp, value: &str) -> UserPredicate {
+        UserPredicate::Text {
+            field,
+            op,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            UserPredicate::parse("build ~ tmp2.1").unwrap(),
+            text(TextField::Build, TextOp::Contains, "tmp2.1")
+        );
+        assert_eq!(
+            UserPredicate::parse("channel = \"Clan Wars\" or idle > 30m").unwrap(),
+            UserPredicate::Or(
+                Box::new(text(TextField::Channel, TextOp::Equals, "Clan Wars")),
+                Box::new(UserPredicate::Idle {
+                    op: CompareOp::Greater,
+                    secs: 1800
+                })
+            )
+        );
+        assert_eq!(
+            UserPredicate::parse("not role=admin and (game != x or idle<=10)").unwrap(),
+            UserPredicate::And(
+                Box::new(UserPredicate::Not(Box::new(text(
+                    TextField::Role,
+                    TextOp::Equals,
+                    "admin"
+                )))),
+                Box::new(UserPredicate::Or(
+                    Box::new(text(TextField::Game, TextOp::NotEquals, "x")),
+                    Box::new(UserPredicate::Idle {
+                        op: CompareOp::LessOrEqual,
+                        secs: 10
+                    })
+                ))
+            )
+        );
+        assert!(UserPredicate::parse("").is_err());
+        assert!(UserPredicate::parse("build").is_err());
+        assert!(UserPredicate::parse("color = red").is_err());
+        assert!(UserPredicate::parse("build = a and").is_err());
+        assert!(UserPredicate::parse("idle > 18446744073709551615h").is_err());
+        assert!(UserPredicate::parse("idle > 18446744073709551615m").is_err());
+    }
+}
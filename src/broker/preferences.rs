@@ -0,0 +1,60 @@
+use crate::broker::names::canonical;
+use crate::broker::profile::Profile;
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DOCUMENT: &str = "preferences";
+
+/// Settings a user keeps across sessions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserPreferences {
+    /// chat macros by lowercase name
+    pub macros: BTreeMap<String, Vec<u8>>,
+    pub profile: Profile,
+    /// admins only: log in without being seen by other users
+    pub invisible: bool,
+}
+
+/// Preferences of all users, by canonical username
+#[derive(Default)]
+pub struct Preferences {
+    by_name: BTreeMap<String, UserPreferences>,
+}
+
+impl Preferences {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        let stored: BTreeMap<String, UserPreferences> = storage.load(DOCUMENT)?;
+        // documents from before unicode names are keyed by lowercase names
+        let mut by_name = BTreeMap::new();
+        for (username, preferences) in stored {
+            by_name.entry(canonical(&username)).or_insert(preferences);
+        }
+        Ok(Self { by_name })
+    }
+
+    pub fn get(&self, username: &str) -> Option<&UserPreferences> {
+        self.by_name.get(&canonical(username))
+    }
+
+    /// Changes a user's preferences and persists them
+    pub fn update<T>(
+        &mut self,
+        storage: &Storage,
+        username: &str,
+        change: impl FnOnce(&mut UserPreferences) -> T,
+    ) -> T {
+        let preferences = self.by_name.entry(canonical(username)).or_default();
+        let result = change(preferences);
+        storage.save(DOCUMENT, &self.by_name);
+        result
+    }
+
+    pub fn remove(&mut self, storage: &Storage, username: &str) {
+        if self.by_name.remove(&canonical(username)).is_some() {
+            storage.save(DOCUMENT, &self.by_name);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::broker::user::{Role, User};
+use crate::broker::{Broker, SEND_QUEUE_CAPACITY};
+use crate::messages::client_command::ProfileField;
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::server_messages::{ErrorMessage, InfoMessage};
+use serde::{Deserialize, Serialize};
+
+const MAX_FIELD_LENGTH: usize = 24;
+
+/// What a user tells others about themselves, shown by /finger
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub country: Option<String>,
+    pub faction: Option<String>,
+}
+
+impl Profile {
+    fn field_mut(&mut self, field: ProfileField) -> &mut Option<String> {
+        match field {
+            ProfileField::Country => &mut self.country,
+            ProfileField::Faction => &mut self.faction,
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(country) = &self.country {
+            lines.push(format!("Country: {}", country));
+        }
+        if let Some(faction) = &self.faction {
+            lines.push(format!("Favorite faction: {}", faction));
+        }
+        lines
+    }
+}
+
+/// Returns why the value cannot be used for a profile entry, if it cannot
+fn check_field(value: &str) -> Option<String> {
+    if value.chars().count() > MAX_FIELD_LENGTH {
+        Some(format!(
+            "Profile entries may be at most {} characters long",
+            MAX_FIELD_LENGTH
+        ))
+    } else {
+        None
+    }
+}
+
+impl Broker {
+    /// Sets or, without a value, clears an entry of the user's profile
+    pub(super) async fn set_info(
+        &mut self,
+        mut user: User,
+        field: ProfileField,
+        value: Option<String>,
+    ) {
+        let value = value
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        if let Some(reason) = value.as_deref().and_then(check_field) {
+            user.send(ErrorMessage::new_err(&reason)).await;
+            return;
+        }
+        let text = match &value {
+            Some(value) => format!("Your {} is now {}", field, value),
+            None => format!("Your {} has been removed", field),
+        };
+        self.preferences.update(&self.storage, &user.account, |p| {
+            *p.profile.field_mut(field) = value
+        });
+        user.send(InfoMessage::new_info(&text)).await;
+    }
+
+    /// Tells the user where another user is and what their profile says
+    pub(super) async fn finger(&mut self, mut user: User, username: String) {
+        let account = match self.accounts.get(&self.account_of(&username)) {
+            Some(account) => account.username.clone(),
+            None => {
+                user.send(ErrorMessage::new_coded(LobbyError::UserNotFound))
+                    .await;
+                return;
+            }
+        };
+        let online = self
+            .users
+            .by_account(&account)
+            .filter(|other| other.is_visible_to(&user));
+        let presence = match online {
+            Some(other) => match self.user_latency(other) {
+                Some(latency) => format!(
+                    "{} is online in {}, {} ms away from the server",
+                    account,
+                    other.location,
+                    latency.as_millis()
+                ),
+                None => format!("{} is online in {}", account, other.location),
+            },
+            None => format!("{} is offline", account),
+        };
+        // how well the user keeps up helps admins tell a slow connection from a stuck one
+        let queue = match self.users.by_account(&account) {
+            Some(other) if user.role == Role::Admin => Some(format!(
+                "Send queue: {} of {} messages",
+                other.queue_depth(),
+                SEND_QUEUE_CAPACITY
+            )),
+            _ => None,
+        };
+        let mut profile = self
+            .preferences
+            .get(&account)
+            .map(|p| p.profile.lines())
+            .unwrap_or_default();
+        if let Some(clan) = self.clans.of_member(&account) {
+            profile.push(format!("Clan: [{}] {}", clan.tag, clan.name));
+        }
+        user.send(InfoMessage::new_info(&presence)).await;
+        let region = online.and_then(|other| other.region.clone());
+        if let Some(region) = region {
+            user.send(InfoMessage::new_info(&format!("Region: {}", region)))
+                .await;
+        }
+        if let Some(queue) = queue {
+            user.send(InfoMessage::new_info(&queue)).await;
+        }
+        if profile.is_empty() {
+            user.send(InfoMessage::new_info(&format!(
+                "{} has not filled in a profile",
+                account
+            )))
+            .await;
+        }
+        for line in profile {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_field() {
+        assert_eq!(check_field("Österreich"), None);
+        assert_eq!(check_field(&"ü".repeat(24)), None);
+        assert!(check_field(&"x".repeat(25)).is_some());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::broker::history::MatchRecord;
+use crate::broker::names::canonical;
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DOCUMENT: &str = "ratings";
+const INITIAL_RATING: f64 = 1500.0;
+const K_FACTOR: f64 = 32.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rating {
+    pub username: String,
+    pub rating: f64,
+    pub games: u32,
+    pub wins: u32,
+}
+
+impl Rating {
+    fn new(username: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            rating: INITIAL_RATING,
+            games: 0,
+            wins: 0,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{}: {:.0} ({} games, {} wins)",
+            self.username, self.rating, self.games, self.wins
+        )
+    }
+}
+
+fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Elo ratings of all players that took part in a match with a known winner.
+/// In matches with more than two players, the winner is rated as having beaten each
+/// of the other players, while the others are only rated against the winner.
+#[derive(Default)]
+pub struct Rankings {
+    by_name: HashMap<String, Rating>,
+}
+
+impl Rankings {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        let ratings: Vec<Rating> = storage.load(DOCUMENT)?;
+        Ok(Self {
+            by_name: ratings
+                .into_iter()
+                .map(|r| (canonical(&r.username), r))
+                .collect(),
+        })
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Rating> {
+        self.by_name.get(&canonical(username))
+    }
+
+    fn rating_of(&self, username: &str) -> f64 {
+        self.get(username).map_or(INITIAL_RATING, |r| r.rating)
+    }
+
+    pub fn update(&mut self, storage: &Storage, record: &MatchRecord) {
+        let winner = match &record.winner {
+            Some(winner)
+                if record
+                    .players
+                    .iter()
+                    .any(|p| canonical(p) == canonical(winner)) =>
+            {
+                winner
+            }
+            _ => return,
+        };
+        let losers: Vec<&String> = record
+            .players
+            .iter()
+            .filter(|p| canonical(p) != canonical(winner))
+            .collect();
+        if losers.is_empty() {
+            return;
+        }
+
+        // all deltas are computed from the ratings before the match
+        let winner_rating = self.rating_of(winner);
+        let mut winner_delta = 0.0;
+        let mut loser_deltas = Vec::new();
+        for loser in &losers {
+            let loser_rating = self.rating_of(loser);
+            winner_delta += K_FACTOR * (1.0 - expected_score(winner_rating, loser_rating));
+            loser_deltas.push(K_FACTOR * (0.0 - expected_score(loser_rating, winner_rating)));
+        }
+
+        self.apply(winner, winner_delta, true);
+        for (loser, delta) in losers.into_iter().zip(loser_deltas) {
+            self.apply(loser, delta, false);
+        }
+        storage.save(DOCUMENT, &self.by_name.values().collect::<Vec<_>>());
+    }
+
+    fn apply(&mut self, username: &str, delta: f64, won: bool) {
+        let rating = self
+            .by_name
+            .entry(canonical(username))
+            .or_insert_with(|| Rating::new(username));
+        rating.rating += delta;
+        rating.games += 1;
+        if won {
+            rating.wins += 1;
+        }
+    }
+
+    pub fn remove(&mut self, storage: &Storage, username: &str) {
+        if self.by_name.remove(&canonical(username)).is_some() {
+            storage.save(DOCUMENT, &self.by_name.values().collect::<Vec<_>>());
+        }
+    }
+
+    /// Position of the user in the leaderboard, starting at 1
+    pub fn position(&self, username: &str) -> Option<usize> {
+        let rating = self.get(username)?.rating;
+        Some(self.by_name.values().filter(|r| r.rating > rating).count() + 1)
+    }
+
+    pub fn top(&self, limit: usize) -> Vec<&Rating> {
+        let mut ratings: Vec<&Rating> = self.by_name.values().collect();
+        ratings.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        ratings.truncate(limit);
+        ratings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(players: &[&str], winner: &str) -> MatchRecord {
+        MatchRecord {
+            game_name: "Game".to_string(),
+            host: players[0].to_string(),
+            players: players.iter().map(|p| p.to_string()).collect(),
+            winner: Some(winner.to_string()),
+            started_at: 0,
+            duration_secs: 0,
+            aborted: false,
+        }
+    }
+
+    #[test]
+    fn test_winner_gains_what_loser_loses() {
+        let mut rankings = Rankings::default();
+        rankings.update(&Storage::in_memory(), &record(&["a", "b"], "a"));
+        assert_eq!(rankings.get("a").unwrap().rating, 1516.0);
+        assert_eq!(rankings.get("b").unwrap().rating, 1484.0);
+        assert_eq!(rankings.position("a"), Some(1));
+        assert_eq!(rankings.position("b"), Some(2));
+    }
+
+    #[test]
+    fn test_unknown_winner_is_ignored() {
+        let mut rankings = Rankings::default();
+        rankings.update(&Storage::in_memory(), &record(&["a", "b"], "c"));
+        assert!(rankings.top(10).is_empty());
+    }
+}
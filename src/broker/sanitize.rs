@@ -0,0 +1,90 @@
+use crate::config::SanitizeConfig;
+
+/// Cleans up chat messages before they are sent to anyone, so that every recipient, log and
+/// plugin sees the same text
+pub struct Sanitizer {
+    config: SanitizeConfig,
+}
+
+impl Sanitizer {
+    pub fn new(config: &SanitizeConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Returns the cleaned message, or why it may not be sent
+    pub fn apply(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let mut result = Vec::with_capacity(message.len());
+        for &b in message {
+            let b = match b {
+                b'\t' | b'\r' | b'\n' if self.config.normalize_whitespace => b' ',
+                b if (b < 0x20 || b == 0x7f) && self.config.strip_control_chars => continue,
+                b => b,
+            };
+            if self.config.normalize_whitespace && b == b' ' && result.last() == Some(&b' ') {
+                continue;
+            }
+            result.push(b);
+        }
+        if self.config.normalize_whitespace {
+            let end = result.iter().rposition(|b| *b != b' ').map_or(0, |i| i + 1);
+            result.truncate(end);
+            let start = result.iter().position(|b| *b != b' ').unwrap_or(0);
+            result.drain(..start);
+        }
+        if result.is_empty() {
+            return Err("Message is empty".to_string());
+        }
+        if result.len() > self.config.max_message_bytes {
+            return Err(format!(
+                "Messages may be at most {} characters long",
+                self.config.max_message_bytes
+            ));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cleanup() {
+        let sanitizer = Sanitizer::new(&SanitizeConfig::default());
+        assert_eq!(
+            sanitizer.apply(b"  gg\t\twp \x07all\r\n").unwrap(),
+            b"gg wp all".to_vec()
+        );
+        // characters of the game's code pages are left alone
+        assert_eq!(
+            sanitizer.apply(b"Gr\xfc\xdfe").unwrap(),
+            b"Gr\xfc\xdfe".to_vec()
+        );
+        assert!(sanitizer.apply(b" \x01 ").is_err());
+    }
+
+    #[test]
+    fn test_length_is_checked_after_cleanup() {
+        let sanitizer = Sanitizer::new(&SanitizeConfig {
+            max_message_bytes: 5,
+            ..SanitizeConfig::default()
+        });
+        assert_eq!(sanitizer.apply(b"a    b   c").unwrap(), b"a b c".to_vec());
+        assert_eq!(
+            sanitizer.apply(b"abcdef"),
+            Err("Messages may be at most 5 characters long".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rules_can_be_disabled() {
+        let sanitizer = Sanitizer::new(&SanitizeConfig {
+            max_message_bytes: 100,
+            strip_control_chars: false,
+            normalize_whitespace: false,
+        });
+        assert_eq!(sanitizer.apply(b" a\x07 ").unwrap(), b" a\x07 ".to_vec());
+    }
+}
@@ -0,0 +1,343 @@
+use crate::broker::{Broker, OutgoingMessage, BACKLOG_CAPACITY, SEND_QUEUE_CAPACITY};
+use crate::server::wait_for_shutdown;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+/// A send queue at least this full is close to holding back messages
+const CROWDED_DEPTH: usize = SEND_QUEUE_CAPACITY * 3 / 4;
+
+/// Creates the queue of messages to a client. Sending never waits for the client, which would
+/// hold up the whole lobby: whatever does not fit into the queue waits in a backlog, which the
+/// receiver works through in order once it catches up. A client whose backlog overflows is
+/// cut off, as is one the broker closes the queue of.
+pub fn message_queue() -> (MessageSender, MessageReceiver) {
+    let (send, receive) = mpsc::channel(SEND_QUEUE_CAPACITY);
+    let backlog = Arc::new(Mutex::new(Backlog::default()));
+    let (close, closed) = watch::channel(false);
+    (
+        MessageSender {
+            send,
+            backlog: backlog.clone(),
+            close: Arc::new(close),
+        },
+        MessageReceiver {
+            receive,
+            backlog,
+            closed,
+        },
+    )
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    messages: VecDeque<OutgoingMessage>,
+    /// since when messages have been waiting in the backlog
+    since: Option<Instant>,
+    /// no more messages are taken, and the receiver ends once it has the ones before
+    closed: bool,
+    /// the backlog ran full, so the client is to be disconnected
+    overflowed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSender {
+    send: mpsc::Sender<OutgoingMessage>,
+    backlog: Arc<Mutex<Backlog>>,
+    close: Arc<watch::Sender<bool>>,
+}
+
+/// Where a message ended up
+#[derive(Debug, PartialEq)]
+pub enum Queued {
+    Sent,
+    /// the queue is full, so the message waits behind the others in the backlog
+    Backlogged,
+    /// the message is the first one the queue had no room for
+    BacklogStarted,
+    /// the backlog is full, so the message is dropped and the queue closed
+    Overflowed,
+    /// the client is gone
+    Closed,
+}
+
+impl MessageSender {
+    pub fn push(&mut self, message: OutgoingMessage) -> Queued {
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.closed {
+            return Queued::Closed;
+        }
+        if backlog.messages.len() >= BACKLOG_CAPACITY {
+            backlog.overflowed = true;
+            self.close_locked(&mut backlog);
+            return Queued::Overflowed;
+        }
+        // nothing may overtake the messages that are already waiting
+        if !backlog.messages.is_empty() {
+            backlog.messages.push_back(message);
+            return Queued::Backlogged;
+        }
+        match self.send.try_send(message) {
+            Ok(()) => Queued::Sent,
+            Err(TrySendError::Full(message)) => {
+                backlog.messages.push_back(message);
+                backlog.since = Some(Instant::now());
+                Queued::BacklogStarted
+            }
+            Err(TrySendError::Closed(_)) => Queued::Closed,
+        }
+    }
+
+    /// How long messages have been waiting for the client to catch up
+    pub fn backlogged_for(&self, now: Instant) -> Option<Duration> {
+        self.backlog
+            .lock()
+            .unwrap()
+            .since
+            .map(|since| now.duration_since(since))
+    }
+
+    /// Whether the client fell so far behind that its backlog ran full
+    pub fn overflowed(&self) -> bool {
+        self.backlog.lock().unwrap().overflowed
+    }
+
+    /// Stops taking messages, so that the client's connection is closed once the ones that
+    /// are queued are written, also while other senders are still around
+    pub fn close(&self) {
+        self.close_locked(&mut self.backlog.lock().unwrap());
+    }
+
+    fn close_locked(&self, backlog: &mut Backlog) {
+        backlog.closed = true;
+        // the receiver may be gone already, which is what closing is about
+        let _ = self.close.broadcast(true);
+    }
+}
+
+pub struct MessageReceiver {
+    receive: mpsc::Receiver<OutgoingMessage>,
+    backlog: Arc<Mutex<Backlog>>,
+    closed: watch::Receiver<bool>,
+}
+
+impl MessageReceiver {
+    /// The next message in the order they were sent, or None once every sender is gone or the
+    /// queue was closed, and all messages from before have been received
+    pub async fn recv(&mut self) -> Option<OutgoingMessage> {
+        loop {
+            {
+                // senders only add to the backlog once the queue is full, so the queue holds the
+                // older messages, and nothing can be sent while the lock is held
+                let mut backlog = self.backlog.lock().unwrap();
+                if let Ok(message) = self.receive.try_recv() {
+                    return Some(message);
+                }
+                if let Some(message) = backlog.messages.pop_front() {
+                    if backlog.messages.is_empty() {
+                        backlog.since = None;
+                    }
+                    return Some(message);
+                }
+                if backlog.closed {
+                    return None;
+                }
+            }
+            tokio::select! {
+                message = self.receive.recv() => return message,
+                // looks at the queue again, for messages that came in before it was closed
+                _ = wait_for_shutdown(self.closed.clone()) => (),
+            }
+        }
+    }
+
+    /// Completes once the queue is closed, for writers to stop waiting on a client that does
+    /// not read what was queued last
+    pub fn closed(&self) -> impl Future<Output = ()> {
+        wait_for_shutdown(self.closed.clone())
+    }
+}
+
+/// Clients whose send queue has been close to full, and since when
+#[derive(Default)]
+pub(super) struct CrowdedQueues {
+    since: HashMap<Uuid, Crowded>,
+}
+
+struct Crowded {
+    since: Instant,
+    warned: bool,
+}
+
+impl CrowdedQueues {
+    /// Answers how long the queue has been crowded once it has been for `warn_after`, but only
+    /// once per stretch, so that a client that does not catch up is not logged every second
+    fn update(
+        &mut self,
+        id: Uuid,
+        depth: usize,
+        now: Instant,
+        warn_after: Duration,
+    ) -> Option<Duration> {
+        if depth < CROWDED_DEPTH {
+            self.since.remove(&id);
+            return None;
+        }
+        let crowded = self.since.entry(id).or_insert(Crowded {
+            since: now,
+            warned: false,
+        });
+        let duration = now.duration_since(crowded.since);
+        if crowded.warned || duration < warn_after {
+            return None;
+        }
+        crowded.warned = true;
+        Some(duration)
+    }
+}
+
+impl Broker {
+    /// Exports how full the send queues are and warns about clients that stay close to
+    /// stalling, who will be evicted if they do not catch up
+    pub(super) fn watch_send_queues(&mut self) {
+        let now = Instant::now();
+        let mut deepest = 0;
+        let mut crowded = 0;
+        for user in self.users.iter() {
+            let depth = user.queue_depth();
+            deepest = deepest.max(depth);
+            if depth >= CROWDED_DEPTH {
+                crowded += 1;
+            }
+            if let Some(duration) =
+                self.crowded_queues
+                    .update(user.id, depth, now, self.warn_crowded_after)
+            {
+                log::warn!(
+                    "Send queue of user {} has been nearly full for {} seconds ({} of {} messages)",
+                    user.username,
+                    duration.as_secs(),
+                    depth,
+                    SEND_QUEUE_CAPACITY
+                );
+            }
+        }
+        let users = &self.users;
+        self.crowded_queues
+            .since
+            .retain(|id, _| users.by_user_id(id).is_some());
+        self.metrics.gauge("send_queue.deepest", deepest as i64);
+        self.metrics.gauge("send_queue.crowded", crowded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::server_messages::InfoMessage;
+    use crate::messages::ServerMessage;
+
+    fn info_text(message: &OutgoingMessage) -> String {
+        match &*message.message {
+            ServerMessage::Info(info) => info.text.clone(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_keeps_the_order() {
+        let (mut send, mut receive) = message_queue();
+        let start = Instant::now();
+        let mut pushed = Vec::new();
+        for i in 0..SEND_QUEUE_CAPACITY + 10 {
+            pushed.push(send.push(OutgoingMessage::new(InfoMessage::new_info(&i.to_string()))));
+        }
+        assert_eq!(pushed[SEND_QUEUE_CAPACITY - 1], Queued::Sent);
+        assert_eq!(pushed[SEND_QUEUE_CAPACITY], Queued::BacklogStarted);
+        assert_eq!(pushed[SEND_QUEUE_CAPACITY + 1], Queued::Backlogged);
+        assert!(send.backlogged_for(start).is_some());
+        // taking one message makes room, but the backlog still goes first
+        assert_eq!(info_text(&receive.recv().await.unwrap()), "0");
+        send.push(OutgoingMessage::new(InfoMessage::new_info("last")));
+        drop(send);
+        let mut received = Vec::new();
+        while let Some(message) = receive.recv().await {
+            received.push(info_text(&message));
+        }
+        let expected: Vec<String> = (1..SEND_QUEUE_CAPACITY + 10)
+            .map(|i| i.to_string())
+            .chain(Some("last".to_string()))
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_closed_queue_ends_after_what_was_queued() {
+        let (mut send, mut receive) = message_queue();
+        // like a shard that has yet to hear that the user left
+        let mut other = send.clone();
+        send.push(OutgoingMessage::new(InfoMessage::new_info("bye")));
+        send.close();
+        assert_eq!(
+            other.push(OutgoingMessage::new(InfoMessage::new_info("late"))),
+            Queued::Closed
+        );
+        assert_eq!(info_text(&receive.recv().await.unwrap()), "bye");
+        assert!(receive.recv().await.is_none());
+        receive.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_backlog_is_bounded() {
+        let (mut send, mut receive) = message_queue();
+        for i in 0..SEND_QUEUE_CAPACITY + BACKLOG_CAPACITY {
+            assert_ne!(
+                send.push(OutgoingMessage::new(InfoMessage::new_info(&i.to_string()))),
+                Queued::Overflowed
+            );
+        }
+        assert!(!send.overflowed());
+        assert_eq!(
+            send.push(OutgoingMessage::new(InfoMessage::new_info("one too many"))),
+            Queued::Overflowed
+        );
+        assert!(send.overflowed());
+        assert_eq!(
+            send.push(OutgoingMessage::new(InfoMessage::new_info("after"))),
+            Queued::Closed
+        );
+        // the client still gets what fit in, and then the queue ends
+        let mut received = 0;
+        while receive.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, SEND_QUEUE_CAPACITY + BACKLOG_CAPACITY);
+    }
+
+    #[test]
+    fn test_crowded_queues() {
+        let mut queues = CrowdedQueues::default();
+        let id = Uuid::new_v4();
+        let start = Instant::now();
+        let warn_after = Duration::from_secs(5);
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(queues.update(id, CROWDED_DEPTH, at(0), warn_after), None);
+        assert_eq!(queues.update(id, CROWDED_DEPTH, at(4), warn_after), None);
+        assert_eq!(
+            queues.update(id, SEND_QUEUE_CAPACITY, at(5), warn_after),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(queues.update(id, CROWDED_DEPTH, at(6), warn_after), None);
+        // catching up starts a new stretch
+        assert_eq!(queues.update(id, 0, at(7), warn_after), None);
+        assert_eq!(queues.update(id, CROWDED_DEPTH, at(8), warn_after), None);
+        assert_eq!(
+            queues.update(id, CROWDED_DEPTH, at(13), warn_after),
+            Some(Duration::from_secs(5))
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::broker::bots::virtual_user;
+use crate::broker::names::normalize;
+use crate::broker::{message_queue, Broker, Event, MessageReceiver};
+use crate::config::HelpBotConfig;
+use crate::messages::client_command::ClientCommand;
+use crate::messages::ServerMessage;
+use crate::util::bytevec_to_str;
+use std::collections::BTreeMap;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+pub type ServiceEventSender = mpsc::UnboundedSender<Event>;
+
+/// A bot that lives in the lobby as a regular user. It receives everything sent to that user
+/// and acts through the same commands a client would send.
+pub trait ServiceBot: Send {
+    fn username(&self) -> &str;
+
+    /// The channel the bot stays in
+    fn channel(&self) -> &str;
+
+    /// Reacts to a message sent to the bot with the commands to issue.
+    /// The bot's own chat messages are not passed in.
+    fn on_message(&mut self, message: &ServerMessage) -> Vec<ClientCommand>;
+}
+
+/// Answers `!topic` questions from its configuration and announces new games
+pub struct HelpBot {
+    config: HelpBotConfig,
+    topics: BTreeMap<String, String>,
+}
+
+impl HelpBot {
+    pub fn new(config: HelpBotConfig) -> Self {
+        let topics = config
+            .topics
+            .iter()
+            .map(|(topic, answer)| (topic.to_lowercase(), answer.clone()))
+            .collect();
+        Self { config, topics }
+    }
+
+    fn answer(&self, question: &str) -> String {
+        let topic = question.trim().to_lowercase();
+        if topic.is_empty() || topic == "help" {
+            let topics: Vec<String> = self.topics.keys().map(|t| format!("!{}", t)).collect();
+            return if topics.is_empty() {
+                "I have nothing to tell yet".to_string()
+            } else {
+                format!("Ask me about {}", topics.join(", "))
+            };
+        }
+        match self.topics.get(&topic) {
+            Some(answer) => answer.clone(),
+            None => format!("I don't know about {}, try !help", topic),
+        }
+    }
+}
+
+impl ServiceBot for HelpBot {
+    fn username(&self) -> &str {
+        &self.config.name
+    }
+
+    fn channel(&self) -> &str {
+        &self.config.channel
+    }
+
+    fn on_message(&mut self, message: &ServerMessage) -> Vec<ClientCommand> {
+        let text = match message {
+            ServerMessage::Send(chat) => match bytevec_to_str(&chat.message).strip_prefix('!') {
+                Some(question) => self.answer(question),
+                None => return Vec::new(),
+            },
+            ServerMessage::NewGame(game) if self.config.announce_games && !game.spectators_only => {
+                let tag = match &game.tag {
+                    Some(tag) => format!(", {}", tag),
+                    None => String::new(),
+                };
+                format!(
+                    "New game: {} ({}/{}{})",
+                    game.game_name, game.players, game.max_players, tag
+                )
+            }
+            _ => return Vec::new(),
+        };
+        vec![ClientCommand::Send {
+            message: text.into_bytes(),
+        }]
+    }
+}
+
+/// Feeds the bot's messages to it and hands its commands back to the broker
+async fn service_bot_loop(
+    id: Uuid,
+    mut bot: Box<dyn ServiceBot>,
+    mut messages: MessageReceiver,
+    events: ServiceEventSender,
+) {
+    while let Some(msg) = messages.recv().await {
+        if let ServerMessage::Send(chat) = &*msg.message {
+            if chat.username == bot.username() {
+                continue;
+            }
+        }
+        for command in bot.on_message(&msg.message) {
+            let event = Event::Command {
+                id,
+                command,
+                trace: None,
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+    log::info!("Service bot {} finished", bot.username());
+}
+
+impl Broker {
+    pub(super) async fn spawn_service_bot(&mut self, bot: Box<dyn ServiceBot>) {
+        if self.users.by_username(bot.username()).is_some() {
+            log::warn!("Service bot {} is already online", bot.username());
+            return;
+        }
+        log::info!(
+            "Spawning service bot {} in {}",
+            bot.username(),
+            bot.channel()
+        );
+        let location = self
+            .channels
+            .get_or_create(&mut self.users, bot.channel())
+            .await
+            .to_location();
+        self.reserved_names.push(normalize(bot.username()));
+        let (send, receiver) = message_queue();
+        let user = virtual_user(
+            bot.username().to_string(),
+            location,
+            "service",
+            &self.versions,
+            send,
+        );
+        tokio::spawn(service_bot_loop(
+            user.id,
+            bot,
+            receiver,
+            self.service_events.clone(),
+        ));
+        self.users.insert(user).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::server_messages::{NewGameMessage, SendMessage};
+
+    #[test]
+    fn test_help_bot() {
+        let mut config = HelpBotConfig::default();
+        config
+            .topics
+            .insert("Rules".to_string(), "Be nice".to_string());
+        let mut bot = HelpBot::new(config);
+        let ask = |bot: &mut HelpBot, text: &str| {
+            bot.on_message(
+                &SendMessage {
+                    username: "foo".to_string(),
+                    message: text.as_bytes().to_vec(),
+                }
+                .into(),
+            )
+        };
+        let said = |commands: Vec<ClientCommand>| match commands.as_slice() {
+            [ClientCommand::Send { message }] => bytevec_to_str(message),
+            _ => panic!("expected a single chat message"),
+        };
+        assert!(ask(&mut bot, "hello").is_empty());
+        assert_eq!(said(ask(&mut bot, "!rules")), "Be nice");
+        assert_eq!(said(ask(&mut bot, "!help")), "Ask me about !rules");
+        assert_eq!(
+            said(ask(&mut bot, "!maps")),
+            "I don't know about maps, try !help"
+        );
+        let announcement = bot.on_message(
+            &NewGameMessage {
+                game_name: "FFA".to_string(),
+                id: Uuid::new_v4(),
+                players: 1,
+                max_players: 8,
+                spectators_only: false,
+                tag: None,
+            }
+            .into(),
+        );
+        assert_eq!(said(announcement), "New game: FFA (1/8)");
+        let announcement = bot.on_message(
+            &NewGameMessage {
+                game_name: "FFA".to_string(),
+                id: Uuid::new_v4(),
+                players: 1,
+                max_players: 8,
+                spectators_only: false,
+                tag: Some("EU".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(said(announcement), "New game: FFA (1/8, EU)");
+    }
+}
@@ -0,0 +1,113 @@
+use crate::broker::names::canonical;
+use crate::broker::user::{Location, User};
+use crate::config::SessionsConfig;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// What a user leaves behind on disconnecting, to pick up again when they return
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// the channel the user was in, or the one they went into a game from
+    pub channel: Option<String>,
+    pub host_port: Option<u16>,
+    pub left_at: Instant,
+}
+
+impl Session {
+    pub fn of(user: &User, now: Instant) -> Self {
+        let channel = match &user.location {
+            Location::Channel { name } => Some(name.clone()),
+            _ => user.last_channel.clone(),
+        };
+        Self {
+            channel,
+            host_port: user.host_port,
+            left_at: now,
+        }
+    }
+}
+
+/// Keeps the sessions of users who left recently, by the resume token they were given on
+/// login. The game client closes its lobby connection when a match starts, so returning users
+/// log in as usual and hand the token back with `/resume`.
+pub struct Sessions {
+    by_token: HashMap<String, (String, Session)>,
+    keep_for: Duration,
+}
+
+impl Sessions {
+    pub fn new(config: &SessionsConfig) -> Self {
+        Self {
+            by_token: HashMap::new(),
+            keep_for: Duration::from_secs(config.resume_secs),
+        }
+    }
+
+    pub fn keep_for(&self) -> Option<Duration> {
+        Some(self.keep_for).filter(|d| *d > Duration::from_secs(0))
+    }
+
+    /// A token that is hard to guess, for a user to resume their session with
+    pub fn new_token() -> String {
+        Uuid::new_v4().to_simple().to_string()
+    }
+
+    /// Remembers the session of a user who disconnected
+    pub fn suspend(&mut self, token: String, account: &str, session: Session) {
+        if self.keep_for().is_some() {
+            self.by_token.insert(token, (canonical(account), session));
+        }
+    }
+
+    /// Hands out the session a returning user left behind, if the token was issued to the same
+    /// account and has not expired
+    pub fn resume(&mut self, token: &str, account: &str, now: Instant) -> Option<Session> {
+        let (owner, _) = self.by_token.get(token)?;
+        if *owner != canonical(account) {
+            return None;
+        }
+        let (_, session) = self.by_token.remove(token)?;
+        Some(session).filter(|s| now.duration_since(s.left_at) <= self.keep_for)
+    }
+
+    /// Forgets sessions nobody came back for
+    pub fn prune(&mut self, now: Instant) {
+        let keep_for = self.keep_for;
+        self.by_token
+            .retain(|_, (_, s)| now.duration_since(s.left_at) <= keep_for);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let mut sessions = Sessions::new(&SessionsConfig { resume_secs: 60 });
+        let now = Instant::now();
+        let session = Session {
+            channel: Some("Lounge".to_string()),
+            host_port: Some(2300),
+            left_at: now,
+        };
+        sessions.suspend("token".to_string(), "Foo", session.clone());
+
+        assert_eq!(sessions.resume("guess", "foo", now), None);
+        assert_eq!(sessions.resume("token", "foo", now), Some(session.clone()));
+        // a session is only resumed once
+        assert_eq!(sessions.resume("token", "foo", now), None);
+        // and only by the account it was issued to, without others using it up
+        sessions.suspend("token".to_string(), "Foo", session.clone());
+        assert_eq!(sessions.resume("token", "bar", now), None);
+        assert_eq!(sessions.resume("token", "foo", now), Some(session.clone()));
+
+        let later = now + Duration::from_secs(61);
+        sessions.suspend("token".to_string(), "Foo", session.clone());
+        assert_eq!(sessions.resume("token", "foo", later), None);
+        sessions.suspend("token".to_string(), "Foo", session);
+        sessions.prune(later);
+        assert!(sessions.by_token.is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::broker::user::{deliver, Location, User};
+use crate::broker::{ArcServerMessage, MessageSender, OutgoingMessage, PreparedBytes};
+use crate::messages::catalog::Language;
+use crate::trace::{self, TraceContext};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+/// What a shard needs to know about a user to send them messages
+struct Member {
+    username: String,
+    language: Language,
+    send: MessageSender,
+    queued: Arc<AtomicUsize>,
+}
+
+enum ShardCommand {
+    Join(Uuid, Member),
+    Leave(Uuid, oneshot::Sender<()>),
+    Rename(Uuid, String),
+    Flush(oneshot::Sender<()>),
+    Broadcast {
+        message: ArcServerMessage,
+        prepared: Arc<PreparedBytes>,
+        trace: Option<TraceContext>,
+        recipients: Recipients,
+    },
+}
+
+/// Who at a location is sent a broadcast
+pub(super) enum Recipients {
+    All,
+    AllExcept(Uuid),
+    Only(HashSet<Uuid>),
+}
+
+impl Recipients {
+    fn contains(&self, id: &Uuid) -> bool {
+        match self {
+            Self::All => true,
+            Self::AllExcept(except) => except != id,
+            Self::Only(ids) => ids.contains(id),
+        }
+    }
+}
+
+/// A task of its own for a channel or game, which keeps the list of users there and fans out
+/// the messages sent to them. The broker loop only hands a message over, so sending to a
+/// crowded location does not hold up everything else, and locations send concurrently.
+/// The state of the location itself, like its topic or who hosts a game, stays with the broker.
+#[derive(Clone)]
+pub(super) struct LocationShard {
+    commands: mpsc::UnboundedSender<ShardCommand>,
+    /// broadcasts handed over that the task has yet to send
+    underway: Arc<AtomicUsize>,
+}
+
+impl LocationShard {
+    pub fn spawn(location: Location) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let underway = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run_shard(location, receiver, underway.clone()));
+        Self { commands, underway }
+    }
+
+    pub fn join(&self, user: &User) {
+        let member = Member {
+            username: user.username.clone(),
+            language: user.language,
+            send: user.send.clone(),
+            queued: user.queued.clone(),
+        };
+        self.command(ShardCommand::Join(user.id, member));
+    }
+
+    /// Returns once everything sent to the location before has gone out to the user, so that
+    /// nothing from here arrives after what the user is sent at their next location
+    pub async fn leave(&self, id: Uuid) {
+        let (done, left) = oneshot::channel();
+        self.command(ShardCommand::Leave(id, done));
+        let _ = left.await;
+    }
+
+    /// Keeps the name the shard logs the user by up to date after a /nick
+    pub fn rename(&self, id: Uuid, username: String) {
+        self.command(ShardCommand::Rename(id, username));
+    }
+
+    /// Returns once everything sent to the location before has gone out
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        self.command(ShardCommand::Flush(done));
+        let _ = flushed.await;
+    }
+
+    /// Messages that go to several locations at once share the same prepared bytes, so they
+    /// are serialized only once however many shards send them
+    pub fn broadcast(
+        &self,
+        message: ArcServerMessage,
+        prepared: Arc<PreparedBytes>,
+        recipients: Recipients,
+    ) {
+        self.underway.fetch_add(1, Ordering::Relaxed);
+        self.command(ShardCommand::Broadcast {
+            message,
+            prepared,
+            trace: trace::current(),
+            recipients,
+        });
+    }
+
+    fn command(&self, command: ShardCommand) {
+        // the task only ends once every handle is dropped
+        let _ = self.commands.send(command);
+    }
+}
+
+/// The shard of the location a user is at, shared by every copy of the user. Messages sent to
+/// the user directly wait for the broadcasts to the location that are still underway, which
+/// they would otherwise overtake.
+#[derive(Clone, Default)]
+pub(super) struct ShardLink(Arc<Mutex<Option<LocationShard>>>);
+
+impl ShardLink {
+    pub fn set(&self, shard: Option<LocationShard>) {
+        *self.0.lock().unwrap() = shard;
+    }
+
+    /// Returns once every broadcast handed to the user's location so far has gone out
+    pub async fn catch_up(&self) {
+        let shard = self.0.lock().unwrap().clone();
+        if let Some(shard) = shard {
+            if shard.underway.load(Ordering::Relaxed) > 0 {
+                shard.flush().await;
+            }
+        }
+    }
+}
+
+async fn run_shard(
+    location: Location,
+    mut commands: mpsc::UnboundedReceiver<ShardCommand>,
+    underway: Arc<AtomicUsize>,
+) {
+    log::debug!("Shard for {} started", location);
+    let mut members: HashMap<Uuid, Member> = HashMap::new();
+    while let Some(command) = commands.recv().await {
+        match command {
+            ShardCommand::Join(id, member) => {
+                members.insert(id, member);
+            }
+            ShardCommand::Leave(id, done) => {
+                members.remove(&id);
+                let _ = done.send(());
+            }
+            ShardCommand::Rename(id, username) => {
+                if let Some(member) = members.get_mut(&id) {
+                    member.username = username;
+                }
+            }
+            ShardCommand::Flush(done) => {
+                let _ = done.send(());
+            }
+            ShardCommand::Broadcast {
+                message,
+                prepared,
+                trace,
+                recipients,
+            } => {
+                for (id, member) in members.iter_mut().filter(|(id, _)| recipients.contains(id)) {
+                    let mut outgoing = OutgoingMessage::localized(message.clone(), member.language);
+                    outgoing.trace = trace;
+                    outgoing.prepared = Some(Arc::clone(&prepared));
+                    deliver(
+                        *id,
+                        &member.username,
+                        &mut member.send,
+                        &member.queued,
+                        outgoing,
+                    );
+                }
+                underway.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+    log::debug!("Shard for {} finished", location);
+}
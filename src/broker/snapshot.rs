@@ -0,0 +1,186 @@
+use crate::broker::federation::split_tag;
+use crate::broker::user::Location;
+use crate::broker::ArcServerMessage;
+use crate::messages::ServerMessage;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// The broker's authoritative view of the lobby at a point in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LobbySnapshot {
+    pub users: BTreeMap<String, Location>,
+    pub channels: BTreeSet<String>,
+    pub open_games: BTreeSet<String>,
+    /// pinned channels and games, as `#channel` and `$game`
+    pub pinned: BTreeSet<String>,
+}
+
+impl LobbySnapshot {
+    pub fn users_in_location(&self, location: &Location) -> BTreeSet<String> {
+        self.users
+            .iter()
+            .filter(|(_, l)| *l == location)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// The view of the lobby a client derives from the messages it has received.
+/// A client only learns about the users in its own location, so that is all
+/// it can be compared against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientView {
+    pub location: Location,
+    pub users: BTreeSet<String>,
+    pub channels: BTreeSet<String>,
+    pub games: BTreeSet<String>,
+}
+
+impl Default for ClientView {
+    fn default() -> Self {
+        Self {
+            location: Location::Nowhere,
+            users: BTreeSet::new(),
+            channels: BTreeSet::new(),
+            games: BTreeSet::new(),
+        }
+    }
+}
+
+impl ClientView {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Updates the view with a message the client received from the server
+    pub fn apply(&mut self, message: &ArcServerMessage) {
+        match &**message {
+            ServerMessage::JoinChannel(join) => {
+                self.location = match split_tag(&join.channel_name) {
+                    Some((name, server)) => Location::RemoteChannel {
+                        server: server.to_string(),
+                        name: name.to_string(),
+                    },
+                    None => Location::Channel {
+                        name: join.channel_name.clone(),
+                    },
+                };
+                self.users.clear();
+            }
+            ServerMessage::NewUser(newuser) => {
+                self.users.insert(newuser.username.clone());
+            }
+            ServerMessage::UserJoined(newuser) => {
+                self.users.insert(newuser.username.clone());
+            }
+            ServerMessage::UserLeft(dropuser) => {
+                self.users.remove(&dropuser.username);
+            }
+            ServerMessage::NewChannel(newchannel) => {
+                self.channels.insert(newchannel.channel_name.clone());
+            }
+            ServerMessage::DropChannel(dropchannel) => {
+                self.channels.remove(&dropchannel.channel_name);
+            }
+            ServerMessage::NewGame(newgame) => {
+                self.games.insert(newgame.game_name.clone());
+            }
+            ServerMessage::DropGame(dropgame) => {
+                self.games.remove(&dropgame.game_name);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    WrongLocation {
+        expected: Location,
+        actual: Location,
+    },
+    MissingUser(String),
+    PhantomUser(String),
+    MissingChannel(String),
+    PhantomChannel(String),
+    MissingGame(String),
+    PhantomGame(String),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongLocation { expected, actual } => {
+                write!(
+                    f,
+                    "client is in {} but server has it in {}",
+                    actual, expected
+                )
+            }
+            Self::MissingUser(name) => write!(f, "missing user {}", name),
+            Self::PhantomUser(name) => write!(f, "phantom user {}", name),
+            Self::MissingChannel(name) => write!(f, "missing channel #{}", name),
+            Self::PhantomChannel(name) => write!(f, "phantom channel #{}", name),
+            Self::MissingGame(name) => write!(f, "missing game ${}", name),
+            Self::PhantomGame(name) => write!(f, "phantom game ${}", name),
+        }
+    }
+}
+
+fn diff_sets<F, G>(
+    expected: &BTreeSet<String>,
+    actual: &BTreeSet<String>,
+    missing: F,
+    phantom: G,
+    result: &mut Vec<Mismatch>,
+) where
+    F: Fn(String) -> Mismatch,
+    G: Fn(String) -> Mismatch,
+{
+    result.extend(expected.difference(actual).cloned().map(missing));
+    result.extend(actual.difference(expected).cloned().map(phantom));
+}
+
+/// Compares what a client believes against the broker's state for the given user.
+/// Returns an empty list if the client is in sync.
+pub fn diff(server: &LobbySnapshot, username: &str, client: &ClientView) -> Vec<Mismatch> {
+    let mut result = Vec::new();
+
+    let expected_location = server
+        .users
+        .get(username)
+        .cloned()
+        .unwrap_or(Location::Nowhere);
+    if expected_location != client.location {
+        result.push(Mismatch::WrongLocation {
+            expected: expected_location.clone(),
+            actual: client.location.clone(),
+        });
+    }
+
+    // clients are not told about themselves, so leave the user out
+    let mut expected_users = server.users_in_location(&expected_location);
+    expected_users.remove(username);
+    diff_sets(
+        &expected_users,
+        &client.users,
+        Mismatch::MissingUser,
+        Mismatch::PhantomUser,
+        &mut result,
+    );
+    diff_sets(
+        &server.channels,
+        &client.channels,
+        Mismatch::MissingChannel,
+        Mismatch::PhantomChannel,
+        &mut result,
+    );
+    diff_sets(
+        &server.open_games,
+        &client.games,
+        Mismatch::MissingGame,
+        Mismatch::PhantomGame,
+        &mut result,
+    );
+    result
+}
@@ -0,0 +1,28 @@
+use crate::broker::totals::Totals;
+use serde::Serialize;
+
+/// Public summary of the lobby for community websites
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServerStatus {
+    /// crate version and commit of the running build
+    pub version: String,
+    pub uptime_secs: u64,
+    pub users_online: u32,
+    pub channels: Vec<ChannelStatus>,
+    pub open_games: Vec<OpenGameStatus>,
+    pub totals: Totals,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStatus {
+    pub name: String,
+    pub users: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenGameStatus {
+    pub name: String,
+    pub host: String,
+    pub players: u32,
+    pub max_players: u32,
+}
@@ -0,0 +1,176 @@
+use crate::config::LoginThrottleConfig;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.blocked_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|remaining| *remaining > Duration::from_secs(0))
+    }
+}
+
+/// Slows down password guessing. Failed logins count against both the IP and the username as
+/// tried from that IP, failed idents only against the IP. Past the free attempts, each failure
+/// doubles the wait before the next attempt, until enough failures lock the IP or the username
+/// out entirely. A username is only ever locked out for the IP that failed to log in with it,
+/// so that nobody can lock others out of their accounts.
+pub struct LoginThrottle {
+    config: LoginThrottleConfig,
+    by_ip: HashMap<Ipv4Addr, Failures>,
+    by_login: HashMap<(Ipv4Addr, String), Failures>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &LoginThrottleConfig) -> Self {
+        Self {
+            config: config.clone(),
+            by_ip: HashMap::new(),
+            by_login: HashMap::new(),
+        }
+    }
+
+    /// Returns how long the login still has to wait if it may not be attempted right now
+    pub fn check(&self, ip: Ipv4Addr, username: &str, now: Instant) -> Option<Duration> {
+        let by_ip = self.by_ip.get(&ip).and_then(|f| f.remaining(now));
+        let by_login = self
+            .by_login
+            .get(&(ip, username.to_ascii_lowercase()))
+            .and_then(|f| f.remaining(now));
+        by_ip.max(by_login)
+    }
+
+    pub fn record_failed_ident(&mut self, ip: Ipv4Addr, now: Instant) {
+        if record(&self.config, &mut self.by_ip, ip, now) {
+            log::warn!("Locking out {} after repeated failed idents", ip);
+        }
+    }
+
+    pub fn record_failed_login(&mut self, ip: Ipv4Addr, username: &str, now: Instant) {
+        if record(&self.config, &mut self.by_ip, ip, now) {
+            log::warn!("Locking out {} after repeated failed logins", ip);
+        }
+        let key = (ip, username.to_ascii_lowercase());
+        if record(&self.config, &mut self.by_login, key, now) {
+            log::warn!(
+                "Locking out username {} for {} after repeated failed logins",
+                username,
+                ip
+            );
+        }
+    }
+
+    /// Forgets the failures of a username from an IP once it got the password right
+    pub fn record_login(&mut self, ip: Ipv4Addr, username: &str) {
+        self.by_login.remove(&(ip, username.to_ascii_lowercase()));
+    }
+
+    /// Forgets failures that no longer hold anyone back
+    pub fn prune(&mut self, now: Instant) {
+        let reset = Duration::from_secs(self.config.reset_secs);
+        let keep = |f: &Failures| now.duration_since(f.last) <= reset || f.remaining(now).is_some();
+        self.by_ip.retain(|_, f| keep(f));
+        self.by_login.retain(|_, f| keep(f));
+    }
+}
+
+/// Records a failure, returning true if it started a lockout
+fn record<K: Hash + Eq>(
+    config: &LoginThrottleConfig,
+    failures: &mut HashMap<K, Failures>,
+    key: K,
+    now: Instant,
+) -> bool {
+    let failures = failures.entry(key).or_insert(Failures {
+        count: 0,
+        last: now,
+        blocked_until: None,
+    });
+    if now.duration_since(failures.last) > Duration::from_secs(config.reset_secs) {
+        failures.count = 0;
+    }
+    failures.count += 1;
+    failures.last = now;
+    failures.blocked_until = penalty(config, failures.count).map(|wait| now + wait);
+    failures.count == config.lockout_attempts
+}
+
+/// How long to wait after the given number of failures in a row
+fn penalty(config: &LoginThrottleConfig, count: u32) -> Option<Duration> {
+    if count >= config.lockout_attempts {
+        return Some(Duration::from_secs(config.lockout_secs));
+    }
+    let doublings = count.checked_sub(config.free_attempts + 1)?;
+    let backoff = config
+        .backoff_secs
+        .saturating_mul(2u64.saturating_pow(doublings));
+    Some(Duration::from_secs(backoff.min(config.max_backoff_secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(&LoginThrottleConfig {
+            enabled: true,
+            free_attempts: 2,
+            backoff_secs: 1,
+            max_backoff_secs: 4,
+            lockout_attempts: 6,
+            lockout_secs: 60,
+            reset_secs: 600,
+        })
+    }
+
+    #[test]
+    fn test_backoff_doubles_until_lockout() {
+        let mut throttle = throttle();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let other_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let now = Instant::now();
+        let mut waits = Vec::new();
+        for _ in 0..6 {
+            throttle.record_failed_login(ip, "Foo", now);
+            waits.push(throttle.check(ip, "bar", now).map(|d| d.as_secs()));
+        }
+        assert_eq!(waits, vec![None, None, Some(1), Some(2), Some(4), Some(60)]);
+        // the username is only locked out for the IP that failed with it
+        assert_eq!(throttle.check(other_ip, "foo", now), None);
+        throttle.record_login(ip, "foo");
+        assert_eq!(
+            throttle.check(ip, "foo", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            throttle.check(ip, "bar", now + Duration::from_secs(61)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_failures_are_forgotten() {
+        let mut throttle = throttle();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.record_failed_ident(ip, start);
+        }
+        assert!(throttle.check(ip, "foo", start).is_some());
+
+        let later = start + Duration::from_secs(601);
+        throttle.record_failed_ident(ip, later);
+        assert_eq!(throttle.check(ip, "foo", later), None);
+
+        throttle.prune(later + Duration::from_secs(601));
+        assert!(throttle.by_ip.is_empty());
+    }
+}
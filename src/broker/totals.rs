@@ -0,0 +1,74 @@
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const DOCUMENT: &str = "totals";
+
+/// Counters kept over the whole lifetime of the server, across restarts
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Totals {
+    pub logins: u64,
+    /// accounts ever registered, including deleted ones
+    pub accounts: u64,
+    pub games_hosted: u64,
+    /// started games that were closed, with or without a result
+    pub games_completed: u64,
+    /// most users online at the same time
+    pub peak_users: u32,
+}
+
+/// Keeps the totals and persists them whenever they change
+pub struct ServerTotals {
+    totals: Totals,
+}
+
+impl ServerTotals {
+    /// Loads the totals. Servers that kept accounts before counting totals start out with
+    /// the accounts they already have.
+    pub fn load(storage: &Storage, existing_accounts: usize) -> Result<Self> {
+        let totals = match storage.load::<Option<Totals>>(DOCUMENT)? {
+            Some(totals) => totals,
+            None => {
+                let totals = Totals {
+                    accounts: existing_accounts as u64,
+                    ..Totals::default()
+                };
+                storage.save(DOCUMENT, &totals);
+                totals
+            }
+        };
+        Ok(Self { totals })
+    }
+
+    pub fn get(&self) -> &Totals {
+        &self.totals
+    }
+
+    pub fn record_login(&mut self, storage: &Storage) {
+        self.totals.logins += 1;
+        storage.save(DOCUMENT, &self.totals);
+    }
+
+    pub fn record_account(&mut self, storage: &Storage) {
+        self.totals.accounts += 1;
+        storage.save(DOCUMENT, &self.totals);
+    }
+
+    pub fn record_game_hosted(&mut self, storage: &Storage) {
+        self.totals.games_hosted += 1;
+        storage.save(DOCUMENT, &self.totals);
+    }
+
+    pub fn record_game_completed(&mut self, storage: &Storage) {
+        self.totals.games_completed += 1;
+        storage.save(DOCUMENT, &self.totals);
+    }
+
+    pub fn record_users_online(&mut self, storage: &Storage, users_online: u32) {
+        if users_online > self.totals.peak_users {
+            self.totals.peak_users = users_online;
+            storage.save(DOCUMENT, &self.totals);
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::broker::channel::DEFAULT_CHANNEL;
+use crate::broker::user::{Location, Role, User};
+use crate::broker::Broker;
+use crate::messages::client_command::TournamentAction;
+use crate::messages::server_messages::{ErrorMessage, InfoMessage};
+use crate::storage::Storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DOCUMENT: &str = "tournaments";
+const MIN_PLAYERS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentStatus {
+    SignUp,
+    Running,
+    Finished,
+}
+
+/// Two players meeting in a round, or a single one advancing without a match
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pairing {
+    /// the game the players have to host and join for their match
+    pub game_name: String,
+    pub players: Vec<String>,
+    pub winner: Option<String>,
+}
+
+impl Pairing {
+    fn involves(&self, username: &str) -> bool {
+        self.players
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(username))
+    }
+
+    fn summary(&self) -> String {
+        let players = match self.players.as_slice() {
+            [player] => format!("{} advances without a match", player),
+            players => format!("{} in ${}", players.join(" vs "), self.game_name),
+        };
+        match &self.winner {
+            Some(winner) if self.players.len() > 1 => format!("{}, won by {}", players, winner),
+            _ => players,
+        }
+    }
+}
+
+/// A single elimination bracket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tournament {
+    pub name: String,
+    pub status: TournamentStatus,
+    /// in the order they signed up, which seeds the first round
+    pub players: Vec<String>,
+    pub rounds: Vec<Vec<Pairing>>,
+}
+
+impl Tournament {
+    fn is_signed_up(&self, username: &str) -> bool {
+        self.players
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(username))
+    }
+
+    /// Pairs the players in order, the last one advances on their own if there is an odd number
+    fn pair(&mut self, players: &[String]) {
+        let round = self.rounds.len() + 1;
+        let pairings = players
+            .chunks(2)
+            .enumerate()
+            .map(|(i, players)| Pairing {
+                game_name: format!("{} R{} M{}", self.name, round, i + 1),
+                players: players.to_vec(),
+                winner: match players {
+                    [player] => Some(player.clone()),
+                    _ => None,
+                },
+            })
+            .collect();
+        self.rounds.push(pairings);
+    }
+
+    fn current_round(&self) -> Option<&Vec<Pairing>> {
+        match self.status {
+            TournamentStatus::Running => self.rounds.last(),
+            _ => None,
+        }
+    }
+
+    /// The match of the current round that is played in the game, if it is still open
+    fn open_pairing(&self, game_name: &str) -> Option<&Pairing> {
+        self.current_round()?
+            .iter()
+            .find(|p| p.winner.is_none() && p.game_name.eq_ignore_ascii_case(game_name))
+    }
+
+    /// Records the winner of a match. Once every match of the round is decided, the winners
+    /// are paired for the next round, or the last one left wins the tournament.
+    fn decide(&mut self, game_name: &str, winner: &str) {
+        let round = match self.rounds.last_mut() {
+            Some(round) => round,
+            None => return,
+        };
+        if let Some(pairing) = round
+            .iter_mut()
+            .find(|p| p.game_name.eq_ignore_ascii_case(game_name))
+        {
+            pairing.winner = pairing
+                .players
+                .iter()
+                .find(|p| p.eq_ignore_ascii_case(winner))
+                .cloned();
+        }
+        let winners: Option<Vec<String>> = round.iter().map(|p| p.winner.clone()).collect();
+        match winners {
+            Some(winners) if winners.len() == 1 => self.status = TournamentStatus::Finished,
+            Some(winners) => self.pair(&winners),
+            None => (),
+        }
+    }
+
+    pub fn champion(&self) -> Option<&str> {
+        match (self.status, self.rounds.last().map(|r| r.as_slice())) {
+            (TournamentStatus::Finished, Some([last])) => last.winner.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// All tournaments, by lowercase name
+#[derive(Default)]
+pub struct Tournaments {
+    by_name: BTreeMap<String, Tournament>,
+}
+
+impl Tournaments {
+    pub fn load(storage: &Storage) -> Result<Self> {
+        Ok(Self {
+            by_name: storage.load(DOCUMENT)?,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tournament> {
+        self.by_name.get(&name.to_ascii_lowercase())
+    }
+
+    /// Changes a tournament and persists it
+    fn update<T>(
+        &mut self,
+        storage: &Storage,
+        name: &str,
+        change: impl FnOnce(&mut Tournament) -> T,
+    ) -> Option<T> {
+        let result = change(self.by_name.get_mut(&name.to_ascii_lowercase())?);
+        storage.save(DOCUMENT, &self.by_name);
+        Some(result)
+    }
+
+    fn insert(&mut self, storage: &Storage, tournament: Tournament) {
+        self.by_name
+            .insert(tournament.name.to_ascii_lowercase(), tournament);
+        storage.save(DOCUMENT, &self.by_name);
+    }
+
+    fn remove(&mut self, storage: &Storage, name: &str) -> Option<Tournament> {
+        let removed = self.by_name.remove(&name.to_ascii_lowercase());
+        storage.save(DOCUMENT, &self.by_name);
+        removed
+    }
+
+    /// The tournament and match that is to be played in the game, if any
+    fn by_game(&self, game_name: &str) -> Option<(&Tournament, &Pairing)> {
+        self.by_name
+            .values()
+            .find_map(|t| t.open_pairing(game_name).map(|p| (t, p)))
+    }
+}
+
+impl Broker {
+    pub(super) async fn tournament_command(&mut self, mut user: User, action: TournamentAction) {
+        if let TournamentAction::Status { name } = action {
+            self.tournament_status(user, name).await;
+            return;
+        }
+        if user.role != Role::Admin {
+            user.send(ErrorMessage::new_err("Only admins can run tournaments"))
+                .await;
+            return;
+        }
+        match action {
+            TournamentAction::Create { name } => self.create_tournament(user, name).await,
+            TournamentAction::Start { name } => self.start_tournament(user, &name).await,
+            TournamentAction::Advance { game_name, winner } => {
+                match self.tournaments.by_game(&game_name) {
+                    Some((_, pairing)) if pairing.involves(&winner) => {
+                        self.decide_match(&game_name, &winner).await
+                    }
+                    _ => {
+                        user.send(ErrorMessage::new_err(&format!(
+                            "{} is not playing an open tournament match in {}",
+                            winner, game_name
+                        )))
+                        .await
+                    }
+                }
+            }
+            TournamentAction::Cancel { name } => {
+                match self.tournaments.remove(&self.storage, &name) {
+                    Some(tournament) => {
+                        self.announce_tournament(&format!(
+                            "The tournament {} has been cancelled",
+                            tournament.name
+                        ))
+                        .await
+                    }
+                    None => {
+                        user.send(ErrorMessage::new_err("Tournament does not exist"))
+                            .await
+                    }
+                }
+            }
+            TournamentAction::Status { .. } => (),
+        }
+    }
+
+    pub(super) async fn sign_up(&mut self, mut user: User, name: String) {
+        let status = self.tournaments.get(&name).map(|t| t.status);
+        match status {
+            Some(TournamentStatus::SignUp) => (),
+            Some(_) => {
+                user.send(ErrorMessage::new_err(
+                    "The sign-up for this tournament is closed",
+                ))
+                .await;
+                return;
+            }
+            None => {
+                user.send(ErrorMessage::new_err("Tournament does not exist"))
+                    .await;
+                return;
+            }
+        }
+        let username = user.account.clone();
+        let added = self.tournaments.update(&self.storage, &name, |t| {
+            if t.is_signed_up(&username) {
+                return None;
+            }
+            t.players.push(username.clone());
+            Some(t.name.clone())
+        });
+        match added.flatten() {
+            Some(tournament) => {
+                user.send(InfoMessage::new_info(&format!(
+                    "You signed up for {}",
+                    tournament
+                )))
+                .await
+            }
+            None => {
+                user.send(ErrorMessage::new_err("You have already signed up"))
+                    .await
+            }
+        }
+    }
+
+    /// Returns why the user may not host or join the game, if it is reserved for a match
+    pub(super) fn check_tournament_game(&self, user: &User, game_name: &str) -> Option<String> {
+        match self.tournaments.by_game(game_name) {
+            Some((_, pairing)) if !pairing.involves(&user.account) => Some(format!(
+                "{} is reserved for the tournament match {}",
+                game_name,
+                pairing.players.join(" vs ")
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns why the winner cannot be reported for the game, if it is a tournament match
+    /// they did not play in
+    pub(super) fn check_tournament_winner(&self, game_name: &str, winner: &str) -> Option<String> {
+        match self.tournaments.by_game(game_name) {
+            Some((_, pairing)) if !pairing.involves(winner) => Some(format!(
+                "The winner of {} must be one of {}",
+                game_name,
+                pairing.players.join(", ")
+            )),
+            _ => None,
+        }
+    }
+
+    /// Advances the bracket if the game was a tournament match
+    pub(super) async fn decide_match(&mut self, game_name: &str, winner: &str) {
+        let name = match self.tournaments.by_game(game_name) {
+            Some((tournament, _)) => tournament.name.clone(),
+            None => return,
+        };
+        let rounds_before = self.tournaments.get(&name).map_or(0, |t| t.rounds.len());
+        self.tournaments
+            .update(&self.storage, &name, |t| t.decide(game_name, winner));
+        let tournament = match self.tournaments.get(&name) {
+            Some(tournament) => tournament.clone(),
+            None => return,
+        };
+        self.announce_tournament(&format!("{}: {} won {}", name, winner, game_name))
+            .await;
+        if let Some(champion) = tournament.champion() {
+            log::info!("{} won the tournament {}", champion, name);
+            self.announce_tournament(&format!("{} won the tournament {}!", champion, name))
+                .await;
+        } else if tournament.rounds.len() > rounds_before {
+            self.announce_round(&tournament).await;
+        }
+    }
+
+    async fn create_tournament(&mut self, mut user: User, name: String) {
+        if !self.names.is_valid_channel_name(&name) {
+            user.send(ErrorMessage::new_err("Invalid tournament name"))
+                .await;
+            return;
+        }
+        if self.tournaments.get(&name).is_some() {
+            user.send(ErrorMessage::new_err("Tournament already exists"))
+                .await;
+            return;
+        }
+        log::info!("{} created the tournament {}", user.username, name);
+        self.tournaments.insert(
+            &self.storage,
+            Tournament {
+                name: name.clone(),
+                status: TournamentStatus::SignUp,
+                players: Vec::new(),
+                rounds: Vec::new(),
+            },
+        );
+        self.announce_tournament(&format!(
+            "The tournament {} is open, sign up with /signup {}",
+            name, name
+        ))
+        .await;
+    }
+
+    async fn start_tournament(&mut self, mut user: User, name: &str) {
+        let error = match self.tournaments.get(name) {
+            None => Some("Tournament does not exist".to_string()),
+            Some(t) if t.status != TournamentStatus::SignUp => {
+                Some("Tournament has already started".to_string())
+            }
+            Some(t) if t.players.len() < MIN_PLAYERS => Some(format!(
+                "A tournament needs at least {} players",
+                MIN_PLAYERS
+            )),
+            Some(_) => None,
+        };
+        if let Some(error) = error {
+            user.send(ErrorMessage::new_err(&error)).await;
+            return;
+        }
+        self.tournaments.update(&self.storage, name, |t| {
+            t.status = TournamentStatus::Running;
+            let players = t.players.clone();
+            t.pair(&players);
+        });
+        if let Some(tournament) = self.tournaments.get(name).cloned() {
+            log::info!("Tournament {} started", tournament.name);
+            self.announce_round(&tournament).await;
+        }
+    }
+
+    async fn tournament_status(&mut self, mut user: User, name: String) {
+        let tournament = match self.tournaments.get(&name) {
+            Some(tournament) => tournament,
+            None => {
+                user.send(ErrorMessage::new_err("Tournament does not exist"))
+                    .await;
+                return;
+            }
+        };
+        let mut lines = vec![match tournament.status {
+            TournamentStatus::SignUp => format!(
+                "{} is open for sign-up, players: {}",
+                tournament.name,
+                tournament.players.join(", ")
+            ),
+            TournamentStatus::Running => {
+                format!("{}, round {}", tournament.name, tournament.rounds.len())
+            }
+            TournamentStatus::Finished => format!(
+                "{} was won by {}",
+                tournament.name,
+                tournament.champion().unwrap_or("nobody")
+            ),
+        }];
+        if let Some(round) = tournament.current_round() {
+            lines.extend(round.iter().map(|p| p.summary()));
+        }
+        for line in lines {
+            user.send(InfoMessage::new_info(&line)).await;
+        }
+    }
+
+    async fn announce_round(&mut self, tournament: &Tournament) {
+        let round = match tournament.rounds.last() {
+            Some(round) => round,
+            None => return,
+        };
+        self.announce_tournament(&format!(
+            "{}, round {}:",
+            tournament.name,
+            tournament.rounds.len()
+        ))
+        .await;
+        for pairing in round {
+            self.announce_tournament(&pairing.summary()).await;
+        }
+    }
+
+    /// Announces in the tournament channel, or in the default channel while nobody is in there
+    async fn announce_tournament(&mut self, text: &str) {
+        let location = match self.channels.get(&self.tournament_channel) {
+            Some(channel) => channel.to_location(),
+            None => Location::Channel {
+                name: DEFAULT_CHANNEL.to_string(),
+            },
+        };
+        self.users
+            .send_to_location(location, InfoMessage::new_info(text))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tournament(players: &[&str]) -> Tournament {
+        let mut tournament = Tournament {
+            name: "Cup".to_string(),
+            status: TournamentStatus::Running,
+            players: players.iter().map(|p| p.to_string()).collect(),
+            rounds: Vec::new(),
+        };
+        let players = tournament.players.clone();
+        tournament.pair(&players);
+        tournament
+    }
+
+    #[test]
+    fn test_bracket() {
+        let mut cup = tournament(&["a", "b", "c"]);
+        assert_eq!(cup.rounds[0].len(), 2);
+        assert_eq!(cup.rounds[0][0].game_name, "Cup R1 M1");
+        assert_eq!(cup.rounds[0][1].winner.as_deref(), Some("c"));
+        assert!(cup.open_pairing("cup r1 m1").is_some());
+        assert!(cup.open_pairing("Cup R1 M2").is_none());
+
+        cup.decide("Cup R1 M1", "B");
+        assert_eq!(cup.rounds.len(), 2);
+        assert_eq!(cup.rounds[1][0].players, vec!["b", "c"]);
+        assert_eq!(cup.champion(), None);
+
+        cup.decide("Cup R2 M1", "c");
+        assert_eq!(cup.status, TournamentStatus::Finished);
+        assert_eq!(cup.champion(), Some("c"));
+        assert!(cup.open_pairing("Cup R2 M1").is_none());
+    }
+}
@@ -1,58 +1,212 @@
-use crate::broker::{ArcServerMessage, MessageSender};
+use crate::broker::fingerprint::Fingerprint;
+use crate::broker::names::Name;
+use crate::broker::send_queue::Queued;
+use crate::broker::shard::{LocationShard, Recipients, ShardLink};
+use crate::broker::{
+    ArcServerMessage, MessageSender, OutgoingMessage, PreparedBytes, QueuedMessage,
+};
+use crate::messages::catalog::Language;
 use crate::messages::server_messages::{NewUserMessage, UserJoinedMessage, UserLeftMessage};
+use crate::protocol_trace::ProtocolTrace;
 use nom::lib::std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use std::fmt;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 #[derive(Clone, PartialEq, Hash, Eq, Debug)]
 pub enum Location {
-    Channel { name: String },
-    Game { name: String },
+    Channel {
+        name: String,
+    },
+    Game {
+        name: String,
+    },
+    /// a channel on a federated server
+    RemoteChannel {
+        server: String,
+        name: String,
+    },
+    /// a game on a federated server
+    RemoteGame {
+        server: String,
+        name: String,
+    },
     Nowhere,
 }
 
-impl ToString for Location {
-    fn to_string(&self) -> String {
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Channel { name } => format!("#{}", name),
-            Self::Game { name } => format!("${}", name),
-            Self::Nowhere => "[nowhere]".to_string(),
+            Self::Channel { name } => write!(f, "#{}", name),
+            Self::Game { name } => write!(f, "${}", name),
+            Self::RemoteChannel { server, name } => write!(f, "#{}@{}", name, server),
+            Self::RemoteGame { server, name } => write!(f, "${}@{}", name, server),
+            Self::Nowhere => write!(f, "[nowhere]"),
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn is_staff(self) -> bool {
+        self != Role::Player
+    }
+}
+
 #[derive(Clone)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
+    /// the name the user logged in with, which their account, clan, mail, rankings and
+    /// preferences stay under when they take another name with /nick
+    pub account: String,
     pub location: Location,
+    /// the channel the user was in last, which they keep while in a game
+    pub last_channel: Option<String>,
     pub game_version: Uuid,
+    /// position of the game version in the list the clients got with their welcome
+    pub version_idx: u32,
+    /// server texts are translated to it where possible
+    pub language: Language,
     pub ip_addr: Ipv4Addr,
+    /// continent the user connects from, if known
+    pub region: Option<String>,
+    pub role: Role,
+    pub connected_at: Instant,
+    /// when the user last sent a command
+    pub last_active: Instant,
+    /// port declared with /hostport for the games this user hosts
+    pub host_port: Option<u16>,
+    /// client build recognized from the handshake fingerprint
+    pub build: String,
+    pub fingerprint: Fingerprint,
+    /// idle presence spawned by the server rather than a connected client
+    pub bot: bool,
+    /// an admin observing the lobby, whom other users neither see come and go nor count
+    pub invisible: bool,
+    /// dumps the frames of the user's connection while enabled
+    pub protocol_trace: ProtocolTrace,
     pub send: MessageSender,
+    /// messages waiting in the send queue or being written to the client
+    pub queued: Arc<AtomicUsize>,
+    /// the shard of the user's location, which direct messages wait for
+    pub(super) shard: ShardLink,
+}
+
+/// Only names the user, the rest is either secret or of no use in logs
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .finish()
+    }
 }
 
 impl User {
     pub async fn send(&mut self, message: ArcServerMessage) {
-        if self.send.send(message).await.is_err() {
+        self.send_outgoing(OutgoingMessage::localized(message, self.language))
+            .await;
+    }
+
+    /// Sends a message that goes to many users, sharing its serialized form with the others
+    pub async fn send_shared(&mut self, message: ArcServerMessage, prepared: &Arc<PreparedBytes>) {
+        let mut message = OutgoingMessage::localized(message, self.language);
+        message.prepared = Some(prepared.clone());
+        self.send_outgoing(message).await;
+    }
+
+    async fn send_outgoing(&mut self, message: OutgoingMessage) {
+        self.shard.catch_up().await;
+        deliver(
+            self.id,
+            &self.username,
+            &mut self.send,
+            &self.queued,
+            message,
+        );
+    }
+
+    /// Invisible admins count as offline to everyone but admins and themselves
+    pub fn is_visible_to(&self, viewer: &User) -> bool {
+        !self.invisible || viewer.role == Role::Admin || self.id == viewer.id
+    }
+
+    /// How many messages are still on their way to the client
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// How long messages to the user have been held back because their queue is full
+    pub fn stalled_for(&self, now: Instant) -> Option<Duration> {
+        self.send.backlogged_for(now)
+    }
+
+    pub fn to_new_user_message(&self) -> ArcServerMessage {
+        Arc::new(
+            NewUserMessage {
+                username: self.username.clone(),
+                version_idx: self.version_idx,
+            }
+            .into(),
+        )
+    }
+}
+
+/// Never waits for the client, which would hold up the whole lobby. Messages that do not fit
+/// into a full queue wait in its backlog, and the broker disconnects clients that stay stalled
+/// or whose backlog runs full.
+pub(super) fn deliver(
+    id: Uuid,
+    username: &str,
+    send: &mut MessageSender,
+    queued: &Arc<AtomicUsize>,
+    mut message: OutgoingMessage,
+) {
+    // a message that does not make it into the queue gives its place back when dropped
+    message.queued = Some(QueuedMessage::new(queued));
+    match send.push(message) {
+        Queued::Sent | Queued::Backlogged => (),
+        Queued::BacklogStarted => {
+            log::warn!(
+                "Send queue of user {} is full, holding back messages",
+                username
+            );
+        }
+        Queued::Overflowed => {
+            log::warn!("Backlog of user {} is full, disconnecting them", username);
+        }
+        Queued::Closed => {
             // if this happens, it means that the user's receiver was closed
             // this should trigger an event being sent to the broker that the
             // client went away, so we'll just log and ignore the error here
-            log::warn!("Failed to send message to user {}", self.id);
+            log::warn!("Failed to send message to user {}", id);
         }
     }
-
-    pub fn to_new_user_message(&self) -> ArcServerMessage {
-        Arc::new(NewUserMessage {
-            username: self.username.clone(),
-        })
-    }
 }
 
 #[derive(Default)]
 pub struct Users {
     by_id: HashMap<Uuid, User>,
-    by_name: HashMap<String, Uuid>,
+    /// by canonical name, so that look-alike names count as taken
+    by_name: HashMap<Name, Uuid>,
+    /// by the canonical name of the account they logged in with
+    by_account: HashMap<Name, Uuid>,
+    /// who is where, so that messages to a location do not have to look at every user
+    by_location: HashMap<Location, HashSet<Uuid>>,
+    /// deliver the messages to each occupied location
+    shards: HashMap<Location, LocationShard>,
 }
 
 impl Users {
@@ -64,27 +218,63 @@ impl Users {
         self.by_id.len() as u32
     }
 
+    /// Counts the users others can see, leaving out invisible admins
+    pub fn visible_count(&self) -> u32 {
+        self.by_id.values().filter(|u| !u.invisible).count() as u32
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.by_id.values()
+    }
+
     pub fn users_in_location(&self, location: &Location) -> Vec<&User> {
-        self.by_id
-            .values()
-            .filter(|u| u.location == *location)
-            .collect()
+        match self.by_location.get(location) {
+            Some(ids) => ids.iter().filter_map(|id| self.by_id.get(id)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn visible_in_location(&self, location: &Location) -> Vec<&User> {
+        let mut users = self.users_in_location(location);
+        users.retain(|u| !u.invisible);
+        users
     }
 
     pub fn occupied_locations(&self) -> HashSet<Location> {
-        self.by_id.values().map(|u| u.location.clone()).collect()
+        self.by_location.keys().cloned().collect()
     }
 
     pub fn by_username(&self, username: &str) -> Option<&User> {
-        if let Some(id) = self.by_name.get(&username.to_ascii_lowercase()) {
+        if let Some(id) = self.by_name.get(&Name::new(username)) {
             self.by_id.get(id)
         } else {
             None
         }
     }
 
+    /// Finds an online user as the viewer may know them; invisible admins count as offline
+    /// to everyone but admins
+    pub fn visible_to(&self, username: &str, viewer: &User) -> Option<&User> {
+        self.by_username(username)
+            .filter(|u| u.is_visible_to(viewer))
+    }
+
+    /// Finds the user logged in with an account, whatever name they go by now
+    pub fn by_account(&self, account: &str) -> Option<&User> {
+        self.by_account
+            .get(&Name::new(account))
+            .and_then(|id| self.by_id.get(id))
+    }
+
+    pub fn by_account_mut(&mut self, account: &str) -> Option<&mut User> {
+        match self.by_account.get(&Name::new(account)) {
+            Some(id) => self.by_id.get_mut(id),
+            None => None,
+        }
+    }
+
     pub fn by_username_mut(&mut self, username: &str) -> Option<&mut User> {
-        if let Some(id) = self.by_name.get(&username.to_ascii_lowercase()) {
+        if let Some(id) = self.by_name.get(&Name::new(username)) {
             self.by_id.get_mut(id)
         } else {
             None
@@ -95,34 +285,123 @@ impl Users {
         self.by_id.get(id)
     }
 
+    pub fn by_user_id_mut(&mut self, id: &Uuid) -> Option<&mut User> {
+        self.by_id.get_mut(id)
+    }
+
+    /// Every user is somewhere, so the shards of all occupied locations reach everyone. They
+    /// send concurrently instead of the broker going through the users one by one.
     pub async fn send_to_all(&mut self, message: ArcServerMessage) {
-        for user in self.by_id.values_mut() {
-            user.send(message.clone()).await;
+        let prepared = Arc::default();
+        for shard in self.shards.values() {
+            shard.broadcast(message.clone(), Arc::clone(&prepared), Recipients::All);
+        }
+    }
+
+    /// Answers with the number of recipients
+    pub async fn send_to_matching(
+        &mut self,
+        predicate: impl Fn(&User) -> bool,
+        message: ArcServerMessage,
+    ) -> usize {
+        let mut by_location: HashMap<&Location, HashSet<Uuid>> = HashMap::new();
+        for user in self.by_id.values().filter(|user| predicate(user)) {
+            by_location
+                .entry(&user.location)
+                .or_default()
+                .insert(user.id);
+        }
+        let prepared = Arc::default();
+        let mut recipients = 0;
+        for (location, ids) in by_location {
+            if let Some(shard) = self.shards.get(location) {
+                recipients += ids.len();
+                shard.broadcast(
+                    message.clone(),
+                    Arc::clone(&prepared),
+                    Recipients::Only(ids),
+                );
+            }
         }
+        recipients
     }
 
+    pub async fn send_to_staff(&mut self, message: ArcServerMessage) {
+        self.send_to_matching(|user| user.role.is_staff(), message)
+            .await;
+    }
+
+    /// Hands the message to the location's shard, which sends it on its own time
     pub async fn send_to_location(&mut self, location: Location, message: ArcServerMessage) {
-        for user in self.by_id.values_mut() {
-            if user.location == location {
-                user.send(message.clone()).await;
+        if let Some(shard) = self.shards.get(&location) {
+            shard.broadcast(message, Arc::default(), Recipients::All);
+        }
+    }
+
+    /// Waits until the user has been sent everything that went to their location so far. A
+    /// user who moves has to be caught up before being sent anything about the new location,
+    /// which would otherwise overtake what is still underway from the old one.
+    pub async fn catch_up(&self, user: &User) {
+        // the caller may already have moved the user away from where the lobby last saw them
+        let location = match self.by_id.get(&user.id) {
+            Some(known) => &known.location,
+            None => &user.location,
+        };
+        if let Some(shard) = self.shards.get(location) {
+            shard.flush().await;
+        }
+    }
+
+    fn add_to_location(&mut self, user: &User) {
+        self.by_location
+            .entry(user.location.clone())
+            .or_default()
+            .insert(user.id);
+        let shard = self
+            .shards
+            .entry(user.location.clone())
+            .or_insert_with(|| LocationShard::spawn(user.location.clone()));
+        shard.join(user);
+        user.shard.set(Some(shard.clone()));
+    }
+
+    async fn remove_from_location(&mut self, user: &User) {
+        if let Some(ids) = self.by_location.get_mut(&user.location) {
+            ids.remove(&user.id);
+            if ids.is_empty() {
+                self.by_location.remove(&user.location);
+            }
+        }
+        user.shard.set(None);
+        if let Some(shard) = self.shards.get(&user.location) {
+            shard.leave(user.id).await;
+            if !self.by_location.contains_key(&user.location) {
+                // the shard's task finishes once its handles are gone
+                self.shards.remove(&user.location);
             }
         }
     }
 
     pub async fn insert(&mut self, user: User) {
         // inform existing users at location of new user
-        self.send_to_location(
-            user.location.clone(),
-            Arc::new(UserJoinedMessage {
-                username: user.username.clone(),
-                origin: None,
-                version_idx: 0,
-            }),
-        )
-        .await;
+        if !user.invisible {
+            self.send_to_location(
+                user.location.clone(),
+                Arc::new(
+                    UserJoinedMessage {
+                        username: user.username.clone(),
+                        origin: None,
+                        version_idx: user.version_idx,
+                    }
+                    .into(),
+                ),
+            )
+            .await;
+        }
 
-        self.by_name
-            .insert(user.username.to_ascii_lowercase(), user.id);
+        self.by_name.insert(Name::new(&user.username), user.id);
+        self.by_account.insert(Name::new(&user.account), user.id);
+        self.add_to_location(&user);
         self.by_id.insert(user.id, user);
     }
 
@@ -133,43 +412,186 @@ impl Users {
         }
 
         let prev = self.by_id.remove(&user.id).unwrap();
-        if prev.location != user.location {
+        if prev.location != user.location && user.invisible {
+            self.remove_from_location(&prev).await;
+            self.add_to_location(&user);
+        } else if prev.location != user.location {
+            self.remove_from_location(&prev).await;
             // inform users at new location of new user
             self.send_to_location(
                 user.location.clone(),
-                Arc::new(UserJoinedMessage {
-                    username: user.username.clone(),
-                    origin: Some(prev.location.to_string()),
-                    version_idx: 0,
-                }),
+                Arc::new(
+                    UserJoinedMessage {
+                        username: user.username.clone(),
+                        origin: Some(prev.location.to_string()),
+                        version_idx: user.version_idx,
+                    }
+                    .into(),
+                ),
             )
             .await;
 
             // inform users at previous location of user leaving
             self.send_to_location(
                 prev.location.clone(),
-                Arc::new(UserLeftMessage {
-                    username: user.username.clone(),
-                    destination: Some(user.location.to_string()),
-                }),
+                Arc::new(
+                    UserLeftMessage {
+                        username: user.username.clone(),
+                        destination: Some(user.location.to_string()),
+                    }
+                    .into(),
+                ),
             )
             .await;
+            self.add_to_location(&user);
         }
 
         self.by_id.insert(user.id, user);
     }
 
+    /// Changes a user's name; everyone at the user's location sees the old name leave and
+    /// the new one join
+    pub async fn rename(&mut self, id: Uuid, new_name: String) {
+        let user = match self.by_id.get_mut(&id) {
+            Some(user) => user,
+            None => return,
+        };
+        let old_name = std::mem::replace(&mut user.username, new_name.clone());
+        let location = user.location.clone();
+        let version_idx = user.version_idx;
+        let invisible = user.invisible;
+        self.by_name.remove(&Name::new(&old_name));
+        self.by_name.insert(Name::new(&new_name), id);
+        if let Some(shard) = self.shards.get(&location) {
+            shard.rename(id, new_name.clone());
+        }
+        if invisible {
+            return;
+        }
+        self.send_to_location(
+            location.clone(),
+            Arc::new(
+                UserLeftMessage {
+                    username: old_name,
+                    destination: None,
+                }
+                .into(),
+            ),
+        )
+        .await;
+        self.send_to_location(
+            location,
+            Arc::new(
+                UserJoinedMessage {
+                    username: new_name,
+                    origin: None,
+                    version_idx,
+                }
+                .into(),
+            ),
+        )
+        .await;
+    }
+
+    /// Hides a user from everyone at their location, or shows them again
+    pub async fn set_invisible(&mut self, id: Uuid, invisible: bool) {
+        let user = match self.by_id.get_mut(&id) {
+            Some(user) if user.invisible != invisible => user,
+            _ => return,
+        };
+        user.invisible = invisible;
+        let location = user.location.clone();
+        let username = user.username.clone();
+        let version_idx = user.version_idx;
+        let message: ArcServerMessage = if invisible {
+            Arc::new(
+                UserLeftMessage {
+                    username,
+                    destination: None,
+                }
+                .into(),
+            )
+        } else {
+            Arc::new(
+                UserJoinedMessage {
+                    username,
+                    origin: None,
+                    version_idx,
+                }
+                .into(),
+            )
+        };
+        if let Some(shard) = self.shards.get(&location) {
+            shard.broadcast(message, Arc::default(), Recipients::AllExcept(id));
+        }
+    }
+
     pub async fn remove(&mut self, id: Uuid) {
         if let Some(user) = self.by_id.remove(&id) {
-            self.by_name.remove(&user.username.to_ascii_lowercase());
+            self.by_name.remove(&Name::new(&user.username));
+            self.by_account.remove(&Name::new(&user.account));
+            self.remove_from_location(&user).await;
+            if user.invisible {
+                return;
+            }
             self.send_to_location(
                 user.location,
-                Arc::new(UserLeftMessage {
-                    username: user.username,
-                    destination: None,
-                }),
+                Arc::new(
+                    UserLeftMessage {
+                        username: user.username,
+                        destination: None,
+                    }
+                    .into(),
+                ),
             )
             .await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::bots::virtual_user;
+    use crate::broker::message_queue;
+    use crate::config::VersionsConfig;
+    use crate::messages::server_messages::InfoMessage;
+    use crate::messages::ServerMessage;
+
+    fn info_text(message: &OutgoingMessage) -> String {
+        match &*message.message {
+            ServerMessage::Info(info) => info.text.clone(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_waits_for_earlier_broadcast() {
+        let (send, mut receive) = message_queue();
+        let location = Location::Channel {
+            name: "Lounge".to_string(),
+        };
+        let user = virtual_user(
+            "Foo".to_string(),
+            location.clone(),
+            "",
+            &VersionsConfig::default(),
+            send,
+        );
+        let id = user.id;
+        let mut users = Users::default();
+        users.insert(user).await;
+
+        users
+            .send_to_location(location, InfoMessage::new_info("broadcast"))
+            .await;
+        users
+            .by_user_id_mut(&id)
+            .unwrap()
+            .send(InfoMessage::new_info("direct"))
+            .await;
+
+        assert_eq!(info_text(&receive.recv().await.unwrap()), "broadcast");
+        assert_eq!(info_text(&receive.recv().await.unwrap()), "direct");
+    }
+}
@@ -0,0 +1,110 @@
+use crate::protocol_trace::{hex_dump, Direction};
+use crate::server::spawn_and_log_error;
+use crate::version::build_info;
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Bytes as they came in or went out, with the time since the connection was made
+pub(crate) struct Chunk {
+    pub(crate) direction: Direction,
+    pub(crate) elapsed: Duration,
+    pub(crate) bytes: Vec<u8>,
+}
+
+/// Records everything a connection receives and sends to a file of its own, chunk by chunk
+/// as read from and written to the socket. Chunks are handed to a writer task, so capturing
+/// never makes the connection wait for the disk.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    writes: mpsc::UnboundedSender<Chunk>,
+    started_at: Instant,
+}
+
+impl Capture {
+    /// Starts capturing to `<unix time>-<client id>.txt` in the directory.
+    /// The writer finishes once all clones of the capture are dropped.
+    pub fn start(dir: &Path, client_id: Uuid, peer: SocketAddr) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = dir.join(format!("{}-{}.txt", now, client_id));
+        let header = format!(
+            "# client {} from {}, connected at {}, server {}\n",
+            client_id,
+            peer,
+            now,
+            build_info()
+        );
+        let (writes, chunks) = mpsc::unbounded_channel();
+        spawn_and_log_error(capture_writer(path, header, chunks), "capture_writer");
+        Self {
+            writes,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        let chunk = Chunk {
+            direction,
+            elapsed: self.started_at.elapsed(),
+            bytes: bytes.to_vec(),
+        };
+        // a failed writer has logged why already
+        let _ = self.writes.send(chunk);
+    }
+}
+
+fn format_chunk(chunk: &Chunk) -> String {
+    let arrow = match chunk.direction {
+        Direction::Received => "<<",
+        Direction::Sent => ">>",
+    };
+    format!(
+        "{:.3} {} {} bytes\n{}",
+        chunk.elapsed.as_secs_f64(),
+        arrow,
+        chunk.bytes.len(),
+        hex_dump(&chunk.bytes)
+    )
+}
+
+async fn capture_writer(
+    path: PathBuf,
+    header: String,
+    mut chunks: mpsc::UnboundedReceiver<Chunk>,
+) -> Result<()> {
+    let mut file = File::create(&path)
+        .await
+        .with_context(|| format!("Could not create capture file {}", path.display()))?;
+    file.write_all(header.as_bytes()).await?;
+    while let Some(chunk) = chunks.recv().await {
+        file.write_all(format_chunk(&chunk).as_bytes()).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_chunk() {
+        let chunk = Chunk {
+            direction: Direction::Received,
+            elapsed: Duration::from_millis(1500),
+            bytes: b"/nop\x00".to_vec(),
+        };
+        assert_eq!(
+            format_chunk(&chunk),
+            "1.500 << 5 bytes\n00000000  2f 6e 6f 70 00                                   |/nop.|\n"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::broker::accounts::unix_now;
+use crate::broker::user::Location;
+use crate::config::ChatLogConfig;
+use crate::rotating_log::json_lines_writer;
+use crate::util::bytevec_to_str;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const FILE_NAME: &str = "chat.log";
+
+/// One line of the chat log
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ChatLogEntry {
+    Public {
+        time: u64,
+        channel: String,
+        username: String,
+        message: String,
+    },
+    /// only who wrote to whom, the text of private messages is never logged
+    Private {
+        time: u64,
+        from: String,
+        to: String,
+        length: usize,
+    },
+}
+
+/// Appends chat to rotating log files as JSON lines. Like `Storage`, entries are handed to a
+/// writer task, so logging never makes the broker wait for the disk.
+pub struct ChatLog {
+    writes: Option<mpsc::UnboundedSender<ChatLogEntry>>,
+    /// lowercase names of the channels to log, all if empty
+    channels: HashSet<String>,
+    skip_channels: HashSet<String>,
+    private_messages: bool,
+}
+
+impl ChatLog {
+    pub fn disabled() -> Self {
+        Self {
+            writes: None,
+            channels: HashSet::new(),
+            skip_channels: HashSet::new(),
+            private_messages: false,
+        }
+    }
+
+    /// Opens the log and spawns its writer task.
+    /// The writer finishes once the log is dropped and all pending entries are written.
+    pub fn open(config: &ChatLogConfig) -> Result<(Self, JoinHandle<()>)> {
+        std::fs::create_dir_all(&config.dir).with_context(|| {
+            format!(
+                "Could not create chat log directory {}",
+                config.dir.display()
+            )
+        })?;
+        let (send, recv) = mpsc::unbounded_channel();
+        let handle = crate::server::spawn_and_log_error(
+            json_lines_writer(
+                config.dir.clone(),
+                FILE_NAME,
+                config.max_file_kb * 1024,
+                config.max_files,
+                recv,
+            ),
+            "chat_log_writer",
+        );
+        let lowercase = |names: &[String]| names.iter().map(|n| n.to_ascii_lowercase()).collect();
+        Ok((
+            Self {
+                writes: Some(send),
+                channels: lowercase(&config.channels),
+                skip_channels: lowercase(&config.skip_channels),
+                private_messages: config.private_messages,
+            },
+            handle,
+        ))
+    }
+
+    fn logs_channel(&self, channel: &str) -> bool {
+        let channel = channel.to_ascii_lowercase();
+        (self.channels.is_empty() || self.channels.contains(&channel))
+            && !self.skip_channels.contains(&channel)
+    }
+
+    /// Logs a chat message if it was sent to a channel that is logged
+    pub fn public_message(&self, location: &Location, username: &str, message: &[u8]) {
+        match location {
+            Location::Channel { name } if self.logs_channel(name) => {
+                self.write(ChatLogEntry::Public {
+                    time: unix_now(),
+                    channel: name.clone(),
+                    username: username.to_string(),
+                    message: bytevec_to_str(message),
+                })
+            }
+            _ => (),
+        }
+    }
+
+    pub fn private_message(&self, from: &str, to: &str, message: &[u8]) {
+        if self.private_messages {
+            self.write(ChatLogEntry::Private {
+                time: unix_now(),
+                from: from.to_string(),
+                to: to.to_string(),
+                length: message.len(),
+            })
+        }
+    }
+
+    fn write(&self, entry: ChatLogEntry) {
+        if let Some(writes) = &self.writes {
+            if writes.send(entry).is_err() {
+                log::error!("Chat log writer is gone, dropping entry");
+            }
+        }
+    }
+}
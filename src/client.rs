@@ -1,240 +1,427 @@
-use crate::broker::{Event, EventSender, MessageReceiver, MessageSender};
+use crate::broker::fingerprint::{Fingerprint, Transport};
+use crate::broker::{
+    message_queue, Event, EventSender, MessageReceiver, MessageSender, OutgoingMessage,
+};
+use crate::capture::Capture;
 use crate::client::LoginStatus::LoggedIn;
+use crate::config::VersionsConfig;
+use crate::ipv6::client_ipv4;
+use crate::messages::catalog::Language;
 use crate::messages::client_command::ClientCommand;
-use crate::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use crate::messages::codec::{ClientFrame, EarthNetCodec, IdentFrame, Stage};
+use crate::messages::login_client::LoginClientMessage;
 use crate::messages::login_server::{IdentServerMessage, RejectServerMessage};
-use crate::messages::ServerMessage;
-use crate::server::spawn_and_log_error;
-use crate::util::{bytevec_to_str, only_allowed_chars_not_empty};
-use anyhow::Result;
-use std::net::{IpAddr, Ipv4Addr};
+use crate::metrics::Metrics;
+use crate::protocol_trace::{Direction, ProtocolTrace};
+use crate::server::{spawn_and_log_error, wait_for_shutdown, ClientSettings};
+use crate::trace::Tracer;
+use crate::util::bytevec_to_str;
+use crate::validation::NameValidator;
+use anyhow::{anyhow, Result};
+use futures::SinkExt;
+use std::future::Future;
+use std::io;
+use std::net::Ipv4Addr;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
 use tokio::stream::StreamExt;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite};
 use uuid::Uuid;
 use LoginStatus::{Connected, Greeted};
 
+/// How long writers get on shutdown to hand their queued messages to the client
+pub(crate) const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Version 2.2, the game's final official patch
+pub(crate) fn default_game_version() -> Uuid {
+    Uuid::parse_str("534ba248-a87c-4ce9-8bee-bc376aae6134").unwrap()
+}
+
+/// What the client revealed about itself before logging in
+#[derive(Debug)]
+struct Handshake {
+    ip_addr: Ipv4Addr,
+    game_version: Uuid,
+    fingerprint: Fingerprint,
+    greeted_at: Instant,
+}
+
 #[derive(Debug)]
 enum LoginStatus {
     Connected {
         send: MessageSender,
+        connected_at: Instant,
+        /// game versions the client may identify with
+        versions: Arc<VersionsConfig>,
+        names: Arc<NameValidator>,
     },
     Greeted {
         send: MessageSender,
-        game_version: Uuid,
+        handshake: Handshake,
+        names: Arc<NameValidator>,
     },
     LoggedIn,
 }
 
-pub async fn client_handler(stream: TcpStream, mut broker: EventSender) -> Result<()> {
-    let ip_addr = match stream.peer_addr()?.ip() {
-        IpAddr::V4(ipv4) => ipv4,
-        IpAddr::V6(_) => {
-            return Err(anyhow::anyhow!(
-                "IPv6 connections are incompatible with the game"
-            ))
-        }
-    };
-    let (mut stream_read, stream_write) = stream.into_split();
-    let (client_sender, client_receiver) = mpsc::channel(64);
+pub async fn client_handler(
+    stream: TcpStream,
+    mut broker: EventSender,
+    metrics: Metrics,
+    tracer: Tracer,
+    clients: Arc<ClientSettings>,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let peer = peer_addr.ip();
+    // clients without an IPv4 address are told so once they reveal their language
+    let ip_addr = client_ipv4(peer, &clients.ipv6);
+    if ip_addr.is_none() {
+        log::info!("IPv6 client {} has no IPv4 address to go by", peer);
+    }
+    let (stream_read, stream_write) = stream.into_split();
+    let (client_sender, client_receiver) = message_queue();
     let (write_shutdown_send, mut write_shutdown_recv) = mpsc::channel(1);
     let client_id = Uuid::new_v4();
+    let protocol_trace = match &clients.capture_dir {
+        Some(dir) => ProtocolTrace::with_capture(Capture::start(dir, client_id, peer_addr)),
+        None => ProtocolTrace::default(),
+    };
     spawn_and_log_error(
         client_write_loop(
             client_id,
             stream_write,
             client_receiver,
             write_shutdown_send,
+            metrics,
+            tracer.clone(),
+            protocol_trace.clone(),
+            shutdown_recv.clone(),
         ),
         "client_write_loop",
     );
     let mut login_status = Connected {
         send: client_sender,
+        connected_at: Instant::now(),
+        versions: clients.versions.clone(),
+        names: clients.names.clone(),
     };
 
-    let mut received = Vec::with_capacity(1024);
+    let mut frames = FramedRead::new(
+        CapturingReader {
+            inner: stream_read,
+            protocol_trace: protocol_trace.clone(),
+        },
+        EarthNetCodec::new(client_id, protocol_trace.clone()),
+    );
 
     log::info!("Starting handler for new client with id {}", client_id);
 
+    let shutdown = wait_for_shutdown(shutdown_recv);
+    tokio::pin!(shutdown);
     loop {
-        tokio::select! {
-            conn_alive = read_from_client(client_id, &mut stream_read, &mut received) =>
-                if !conn_alive { break },
+        let frame = tokio::select! {
+            frame = frames.next() => match frame {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    log::error!("Error receiving from client {}: {}", client_id, e);
+                    break
+                },
+                None => {
+                    log::info!("Client {} closed the connection", client_id);
+                    break
+                },
+            },
             _ = write_shutdown_recv.recv() => {
                 log::info!("Writer for client {} shut down, stopping read handler", client_id);
                 break
             },
-        }
-        login_status = match process_messages(
+            _ = &mut shutdown => {
+                // the broker is going away as well, so there is no one left to tell
+                log::info!("Client handler for client {} shutting down", client_id);
+                return Ok(());
+            },
+        };
+        login_status = match process_frame(
             client_id,
-            &ip_addr,
-            &mut received,
+            ip_addr,
+            frame,
             &mut broker,
+            &tracer,
+            &protocol_trace,
             login_status,
         )
         .await
         {
             Ok(status) => status,
             Err(e) => {
-                log::error!("Error parsing message from client {}: {}", client_id, e);
+                log::error!("Error handling message from client {}: {}", client_id, e);
                 break;
             }
         };
+        frames.decoder_mut().set_stage(login_status.stage());
     }
     log::info!("Client handler finished for client {}", client_id);
     broker.send(Event::DropClient { id: client_id }).await?;
     Ok(())
 }
 
-async fn process_messages(
+impl LoginStatus {
+    /// The frames the client is expected to send next
+    fn stage(&self) -> Stage {
+        match self {
+            Connected { .. } => Stage::Ident,
+            Greeted { .. } => Stage::Login,
+            LoggedIn => Stage::Commands,
+        }
+    }
+}
+
+async fn process_frame(
     client_id: Uuid,
-    ip_addr: &Ipv4Addr,
-    received: &mut Vec<u8>,
+    ip_addr: Option<Ipv4Addr>,
+    frame: ClientFrame,
     broker: &mut EventSender,
-    mut login_status: LoginStatus,
+    tracer: &Tracer,
+    protocol_trace: &ProtocolTrace,
+    login_status: LoginStatus,
 ) -> Result<LoginStatus> {
-    while !received.is_empty() {
-        let initially_available = received.len();
-        login_status = match login_status {
-            Connected { send } => process_ident(received, send).await?,
-            Greeted { send, game_version } => {
-                process_login(client_id, ip_addr, received, broker, send, game_version).await?
-            }
-            LoggedIn => process_commands(client_id, received, broker).await?,
-        };
-        if received.len() == initially_available {
-            // no data was consumed, so need to wait for more data
-            break;
+    match (login_status, frame) {
+        (
+            Connected {
+                send,
+                connected_at,
+                versions,
+                names,
+            },
+            ClientFrame::Ident(ident),
+        ) => process_ident(ip_addr, connected_at, versions, names, ident, broker, send).await,
+        (
+            Greeted {
+                send,
+                handshake,
+                names,
+            },
+            ClientFrame::Login(login),
+        ) => {
+            let trace = protocol_trace.clone();
+            process_login(client_id, login, broker, send, handshake, names, trace).await
+        }
+        (LoggedIn, ClientFrame::Command(command)) => {
+            process_command(client_id, command, broker, tracer).await
         }
+        (_, frame) => Err(anyhow!("Unexpected {:?}", frame)),
     }
-
-    Ok(login_status)
 }
 
-async fn process_commands(
+async fn process_command(
     client_id: Uuid,
-    received: &mut Vec<u8>,
+    command: ClientCommand,
     broker: &mut EventSender,
+    tracer: &Tracer,
 ) -> Result<LoginStatus> {
-    match ClientCommand::try_parse(received)? {
-        Some(msg) => {
-            broker
-                .send(Event::Command {
-                    id: client_id,
-                    command: msg,
-                })
-                .await?;
-            Ok(LoggedIn)
-        }
-        None => Ok(LoggedIn),
-    }
+    let span = tracer.start_trace("client.parse_command");
+    broker
+        .send(Event::Command {
+            id: client_id,
+            command,
+            trace: span.as_ref().map(|s| s.context()),
+        })
+        .await?;
+    Ok(LoggedIn)
 }
 
 async fn process_login(
     client_id: Uuid,
-    ip_addr: &Ipv4Addr,
-    received: &mut Vec<u8>,
+    login: LoginClientMessage,
     broker: &mut EventSender,
     mut send: MessageSender,
-    game_version: Uuid,
+    mut handshake: Handshake,
+    names: Arc<NameValidator>,
+    protocol_trace: ProtocolTrace,
 ) -> Result<LoginStatus> {
-    const ALLOWED_USERNAME_CHARS: &str =
-        "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_.|()[]{}";
-    match LoginClientMessage::try_parse(received)? {
-        Some(login) => {
-            let username = bytevec_to_str(&login.username);
-            if only_allowed_chars_not_empty(&username, ALLOWED_USERNAME_CHARS) {
-                broker
-                    .send(Event::NewUser {
-                        id: client_id,
-                        game_version,
-                        send,
-                        ip_addr: *ip_addr,
-                        username,
-                    })
-                    .await?;
-                Ok(LoggedIn)
-            } else {
-                send.send(Arc::new(RejectServerMessage {
-                    reason: "translateInvalidCharactersInName".to_string(),
-                }))
-                .await?;
-                Ok(Greeted { send, game_version })
+    let username = bytevec_to_str(&login.username);
+    if names.is_valid_username(&username) {
+        let fingerprint = &mut handshake.fingerprint;
+        fingerprint.login_extra = login.extra_bytes;
+        fingerprint.login_delay_ms = handshake.greeted_at.elapsed().as_millis() as u64;
+        broker
+            .send(Event::NewUser {
+                id: client_id,
+                game_version: handshake.game_version,
+                send,
+                ip_addr: handshake.ip_addr,
+                fingerprint: handshake.fingerprint,
+                protocol_trace,
+                username,
+                password: bytevec_to_str(&login.password),
+            })
+            .await?;
+        Ok(LoggedIn)
+    } else {
+        send.push(OutgoingMessage::new(Arc::new(
+            RejectServerMessage {
+                reason: "translateInvalidCharactersInName".to_string(),
             }
-        }
-        None => Ok(Greeted { send, game_version }),
+            .into(),
+        )));
+        Ok(Greeted {
+            send,
+            handshake,
+            names,
+        })
     }
 }
 
-async fn process_ident(received: &mut Vec<u8>, mut send: MessageSender) -> Result<LoginStatus> {
-    let allowed_game_version: Uuid =
-        Uuid::parse_str("534ba248-a87c-4ce9-8bee-bc376aae6134").unwrap();
-    match IdentClientMessage::try_parse(received)? {
-        Some(ident) => {
-            if ident.game_version == allowed_game_version {
-                send.send(Arc::new(IdentServerMessage {})).await?;
-                Ok(Greeted {
-                    send,
-                    game_version: ident.game_version,
-                })
-            } else {
-                send.send(Arc::new(RejectServerMessage {
-                    reason: "Wrong game version. Please install version 2.2".to_string(),
-                }))
-                .await?;
-                Ok(Connected { send })
-            }
+async fn process_ident(
+    ip_addr: Option<Ipv4Addr>,
+    connected_at: Instant,
+    versions: Arc<VersionsConfig>,
+    names: Arc<NameValidator>,
+    frame: IdentFrame,
+    broker: &mut EventSender,
+    mut send: MessageSender,
+) -> Result<LoginStatus> {
+    let ident = frame.message;
+    let language = Language::from_client(&bytevec_to_str(&ident.language));
+    let ip_addr = match ip_addr {
+        Some(ip_addr) => ip_addr,
+        None => {
+            let reject = Arc::new(
+                RejectServerMessage {
+                    reason: "IPv6 connections are not supported, please connect over IPv4"
+                        .to_string(),
+                }
+                .into(),
+            );
+            send.push(OutgoingMessage::localized(reject, language));
+            return Ok(Connected {
+                send,
+                connected_at,
+                versions,
+                names,
+            });
         }
-        None => Ok(Connected { send }),
+    };
+    if versions.index_of(ident.game_version).is_some() {
+        let fingerprint = Fingerprint {
+            transport: Transport::Game,
+            language: bytevec_to_str(&ident.language),
+            ident_size: frame.size,
+            ident_extra: ident.extra_bytes,
+            login_extra: 0,
+            ident_delay_ms: connected_at.elapsed().as_millis() as u64,
+            login_delay_ms: 0,
+        };
+        send.push(OutgoingMessage::new(Arc::new(IdentServerMessage {}.into())));
+        Ok(Greeted {
+            send,
+            handshake: Handshake {
+                ip_addr,
+                game_version: ident.game_version,
+                fingerprint,
+                greeted_at: Instant::now(),
+            },
+            names,
+        })
+    } else {
+        let reject = Arc::new(
+            RejectServerMessage {
+                reason: format!(
+                    "Wrong game version. Please install one of: {}",
+                    versions.names().join(", ")
+                ),
+            }
+            .into(),
+        );
+        send.push(OutgoingMessage::localized(reject, language));
+        broker.send(Event::FailedIdent { ip_addr }).await?;
+        Ok(Connected {
+            send,
+            connected_at,
+            versions,
+            names,
+        })
     }
 }
 
-async fn read_from_client(
-    client_id: Uuid,
-    reader: &mut (impl AsyncRead + Unpin),
-    received: &mut Vec<u8>,
-) -> bool {
-    let mut read_buf = [0u8; 256];
-    let num_read = match reader.read(&mut read_buf).await {
-        Ok(0) => {
-            log::info!("Client {} closed the connection", client_id);
-            return false;
-        }
-        Ok(n) => n,
-        Err(e) if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::WouldBlock => {
-            return true
-        }
-        Err(e) => {
-            log::warn!("Error when reading from client {}: {}", client_id, e);
-            return false;
+/// Hands everything read from the client to the session's capture, exactly as it came off
+/// the socket, before the codec frames it
+struct CapturingReader<R> {
+    inner: R,
+    protocol_trace: ProtocolTrace,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CapturingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.protocol_trace.capture(Direction::Received, &buf[..n]);
         }
-    };
-    received.extend_from_slice(&read_buf[..num_read]);
-    true
+        result
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn client_write_loop(
     client_id: Uuid,
-    mut stream: OwnedWriteHalf,
+    stream: OwnedWriteHalf,
     mut messages: MessageReceiver,
     _shutdown_send: mpsc::Sender<()>,
+    metrics: Metrics,
+    tracer: Tracer,
+    protocol_trace: ProtocolTrace,
+    shutdown_recv: watch::Receiver<bool>,
 ) -> Result<()> {
-    while let Some(msg) = messages.next().await {
-        log::debug!("Sending message to client {}: {:?}", client_id, msg);
-        send_message(&*msg, &mut stream).await?;
+    let mut frames = FramedWrite::new(
+        stream,
+        EarthNetCodec::new(client_id, protocol_trace.clone()),
+    );
+    let closed = messages.closed();
+    let write_all = async {
+        while let Some(msg) = messages.recv().await {
+            log::debug!("Sending message to client {}: {:?}", client_id, msg.message);
+            let _span = tracer.start_span("client.send_message", msg.trace);
+            let bytes = msg.prepare()?;
+            metrics.increment(msg.message.metric_name());
+            protocol_trace.log_frame(client_id, Direction::Sent, &bytes);
+            protocol_trace.capture(Direction::Sent, &bytes);
+            frames.send(bytes).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::pin!(write_all);
+    tokio::select! {
+        result = &mut write_all => result?,
+        _ = stop_writing(closed, shutdown_recv) => {
+            // a client that stopped reading would otherwise keep the writer blocked forever
+            if let Ok(result) = timeout(FLUSH_TIMEOUT, write_all).await {
+                result?;
+            }
+        }
     }
     log::info!("Writer for client {} is finished", client_id);
     Ok(())
 }
 
-async fn send_message(
-    message: &dyn ServerMessage,
-    writer: &mut (impl AsyncWrite + Unpin),
-) -> Result<()> {
-    let bytes = message.prepare_message()?;
-    writer.write_all(&bytes).await?;
-    Ok(())
+/// Completes once the server shuts down or the broker closed the client's queue, after which
+/// the writer only gets `FLUSH_TIMEOUT` for the messages that are left
+pub(crate) async fn stop_writing(
+    closed: impl Future<Output = ()>,
+    shutdown_recv: watch::Receiver<bool>,
+) {
+    tokio::select! {
+        _ = closed => (),
+        _ = wait_for_shutdown(shutdown_recv) => (),
+    }
 }
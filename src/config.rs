@@ -0,0 +1,1052 @@
+use crate::client::default_game_version;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Server configuration, read from a TOML file.
+/// Every setting has a sensible default, so an empty file is a valid configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub shutdown: ShutdownConfig,
+    pub limits: LimitsConfig,
+    pub sockets: SocketsConfig,
+    pub storage: StorageConfig,
+    pub versions: VersionsConfig,
+    pub ipv6: Ipv6Config,
+    pub geoip: GeoIpConfig,
+    pub replication: ReplicationConfig,
+    pub chat_log: ChatLogConfig,
+    pub audit_log: AuditLogConfig,
+    pub accounts: AccountsConfig,
+    pub names: NamesConfig,
+    pub roles: RolesConfig,
+    pub filter: FilterConfig,
+    pub sanitize: SanitizeConfig,
+    pub announcements: AnnouncementsConfig,
+    pub channels: ChannelsConfig,
+    pub pinning: PinningConfig,
+    pub macros: MacrosConfig,
+    pub mail: MailConfig,
+    pub tournaments: TournamentsConfig,
+    pub join_flood: JoinFloodConfig,
+    pub login_throttle: LoginThrottleConfig,
+    pub slow_clients: SlowClientsConfig,
+    pub bots: BotsConfig,
+    pub help_bot: HelpBotConfig,
+    pub games: GamesConfig,
+    pub sessions: SessionsConfig,
+    pub privacy: PrivacyConfig,
+    pub builds: BuildsConfig,
+    pub relay: RelayConfig,
+    pub ping: PingConfig,
+    pub master: MasterConfig,
+    pub federation: FederationConfig,
+    pub admin_api: AdminApiConfig,
+    pub console: ConsoleConfig,
+    pub status: StatusApiConfig,
+    pub websocket: WebSocketConfig,
+    pub metrics: MetricsConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub tracing: TracingConfig,
+    pub capture: CaptureConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ListenConfig {
+    /// host:port addresses to accept game clients on, e.g. one per network interface
+    pub bind: Vec<String>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec!["0.0.0.0:17171".to_string()],
+        }
+    }
+}
+
+/// What happens between the shutdown signal and the server going down
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// how long users are warned ahead; new connections are refused in the meantime
+    pub notice_secs: u64,
+    /// keep going after the notice until every open game has started or been closed
+    pub wait_for_games: bool,
+    /// how long to wait for open games at most
+    pub max_wait_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            notice_secs: 0,
+            wait_for_games: false,
+            max_wait_secs: 5 * 60,
+        }
+    }
+}
+
+/// Caps that keep a small host from being overrun; unlimited if unset
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// game client connections open at once, counted over all listen addresses
+    pub max_clients: Option<usize>,
+    /// users logged in at once, not counting bots
+    pub max_users: Option<usize>,
+    /// leave new connections waiting while `max_clients` is reached instead of closing them
+    pub pause_accepting: bool,
+}
+
+/// What user, channel and game names may look like
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NamesConfig {
+    pub users: NamePolicy,
+    pub channels: NamePolicy,
+    pub games: NamePolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NamePolicy {
+    /// allowed besides ASCII letters and digits; each kind of name has its own default set
+    pub extra_chars: Option<String>,
+    /// also allow letters and digits of other alphabets, as sent by WebSocket clients
+    pub unicode_letters: bool,
+    pub min_length: usize,
+    pub max_length: Option<usize>,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self {
+            extra_chars: None,
+            unicode_letters: false,
+            min_length: 1,
+            max_length: None,
+        }
+    }
+}
+
+/// Options for the sockets of accepted game client connections; OS defaults if unset
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SocketsConfig {
+    /// idle time before the OS starts probing whether the peer is still there, 0 disables
+    pub keepalive_secs: u64,
+    /// send small frames right away instead of batching them (TCP_NODELAY)
+    pub nodelay: bool,
+    pub send_buffer_bytes: Option<usize>,
+    pub recv_buffer_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// directory for persistent data; nothing is persisted if unset
+    pub data_dir: Option<PathBuf>,
+}
+
+/// A game version clients may connect with
+#[derive(Debug, Clone, Deserialize)]
+pub struct GameVersion {
+    /// GUID the game client identifies its version with
+    pub id: Uuid,
+    /// shown in the game's list of versions
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VersionsConfig {
+    /// clients identifying with another version are turned away. Clients that cannot identify
+    /// theirs, like WebSocket clients and bots, are assumed to run the first.
+    pub accepted: Vec<GameVersion>,
+}
+
+impl Default for VersionsConfig {
+    fn default() -> Self {
+        Self {
+            accepted: vec![GameVersion {
+                id: default_game_version(),
+                name: "tmp2.2".to_string(),
+            }],
+        }
+    }
+}
+
+impl VersionsConfig {
+    /// Position of the version in the list the clients get to see
+    pub fn index_of(&self, id: Uuid) -> Option<u32> {
+        self.accepted
+            .iter()
+            .position(|v| v.id == id)
+            .map(|idx| idx as u32)
+    }
+
+    pub fn name_of(&self, id: Uuid) -> Option<&str> {
+        self.accepted
+            .iter()
+            .find(|v| v.id == id)
+            .map(|v| v.name.as_str())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.accepted.iter().map(|v| v.name.clone()).collect()
+    }
+
+    /// The version assumed for clients that do not identify theirs
+    pub fn fallback(&self) -> Uuid {
+        self.accepted
+            .first()
+            .map(|v| v.id)
+            .unwrap_or_else(default_game_version)
+    }
+}
+
+/// Game clients that connect over IPv6. The game only knows IPv4 addresses, which it needs to
+/// connect to game hosts.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Ipv6Config {
+    /// IPv4 addresses to hand out for IPv6 clients that can also be reached over IPv4
+    pub mappings: BTreeMap<Ipv6Addr, Ipv4Addr>,
+    /// let in clients without a mapping for chat, under a made-up address they cannot host with
+    pub accept_unmapped: bool,
+}
+
+/// Tagging users and their games with the region they connect from
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// GeoIP2 or GeoLite2 country database in MaxMind's format; nobody is tagged if unset
+    pub database: Option<PathBuf>,
+    /// regions of addresses the database does not know, like those on a LAN
+    pub regions: BTreeMap<Ipv4Addr, String>,
+}
+
+/// Mirroring of the persistent data to a standby server that takes over if this one fails
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    pub enabled: bool,
+    /// shared secret the standby has to present
+    pub secret: String,
+    /// host:port to accept standby connections on
+    pub bind: Option<String>,
+    /// host:port of the primary's replication listener; if set, this server starts as standby
+    pub primary: Option<String>,
+    pub heartbeat_secs: u64,
+    /// how long the primary may stay silent before the standby takes over
+    pub takeover_secs: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secret: String::new(),
+            bind: None,
+            primary: None,
+            heartbeat_secs: 1,
+            takeover_secs: 5,
+        }
+    }
+}
+
+/// Logging of public chat and private message metadata to rotating files
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChatLogConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// channels to log, all of them if empty
+    pub channels: Vec<String>,
+    /// channels that are never logged
+    pub skip_channels: Vec<String>,
+    /// whether to log who sent private messages to whom; their text is never logged
+    pub private_messages: bool,
+    /// size in kilobytes at which the log file is rotated
+    pub max_file_kb: u64,
+    /// number of rotated files kept besides the current one
+    pub max_files: usize,
+}
+
+impl Default for ChatLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("chat_logs"),
+            channels: Vec::new(),
+            skip_channels: Vec::new(),
+            private_messages: true,
+            max_file_kb: 10 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+/// Logging of logins, joins, games and moderation to rotating files, for reconstructing incidents
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditLogConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// size in kilobytes at which the log file is rotated
+    pub max_file_kb: u64,
+    /// number of rotated files kept besides the current one
+    pub max_files: usize,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("audit_logs"),
+            max_file_kb: 10 * 1024,
+            max_files: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccountsConfig {
+    /// days after /deleteaccount during which logging in cancels the deletion
+    pub deletion_grace_days: u64,
+    /// names nobody may log in with unless they are listed as staff under `roles`
+    pub reserved_names: Vec<String>,
+    pub password_hashing: PasswordHashConfig,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            deletion_grace_days: 7,
+            reserved_names: ["admin", "administrator", "moderator", "server", "system"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            password_hashing: PasswordHashConfig::default(),
+        }
+    }
+}
+
+/// Argon2id cost parameters. Stored hashes with other parameters are replaced on the next login.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChannelsConfig {
+    /// channels announced before all others whenever they exist, if pinning is enabled
+    pub pinned: Vec<String>,
+    /// number of recent chat messages replayed to users joining a channel, none by default as
+    /// unpatched clients cannot tell them from new ones
+    pub history_size: usize,
+    /// most users allowed in a channel at once, by channel name
+    pub max_users: BTreeMap<String, usize>,
+}
+
+/// Featuring channels and games by announcing them before all others
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PinningConfig {
+    pub enabled: bool,
+}
+
+/// Limits for the chat macros users can define with `/macro`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MacrosConfig {
+    pub max_count: usize,
+    /// maximum length of a macro's text in bytes
+    pub max_length: usize,
+}
+
+impl Default for MacrosConfig {
+    fn default() -> Self {
+        Self {
+            max_count: 20,
+            max_length: 200,
+        }
+    }
+}
+
+/// Private messages kept for registered users while they are offline
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    /// messages a user's mailbox holds until they clear it with `/mail clear`
+    pub max_per_user: usize,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self { max_per_user: 20 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TournamentsConfig {
+    /// channel in which tournaments, pairings and results are announced
+    pub channel: String,
+}
+
+impl Default for TournamentsConfig {
+    fn default() -> Self {
+        Self {
+            channel: "Tournament".to_string(),
+        }
+    }
+}
+
+/// Idle presence bots that keep quiet channels from looking empty
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BotsConfig {
+    pub enabled: bool,
+    pub channels: Vec<String>,
+    /// bots in each channel when no real users are in it; every real user replaces one
+    pub per_channel: usize,
+    /// marks bots as such in the user list
+    pub prefix: String,
+    pub names: Vec<String>,
+    /// UTC hours during which bots are present; the range may wrap around midnight
+    pub from_hour: u32,
+    pub until_hour: u32,
+}
+
+impl Default for BotsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channels: vec!["General".to_string()],
+            per_channel: 3,
+            prefix: "[bot]".to_string(),
+            names: ["Scout", "Ranger", "Warden", "Pilot", "Drifter", "Sentry"]
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            from_hour: 0,
+            until_hour: 24,
+        }
+    }
+}
+
+/// A service bot that answers `!topic` questions in its channel and announces new games
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HelpBotConfig {
+    pub enabled: bool,
+    pub name: String,
+    pub channel: String,
+    pub announce_games: bool,
+    /// answers by topic, e.g. `rules = "Be nice"` answers `!rules`
+    pub topics: BTreeMap<String, String>,
+}
+
+impl Default for HelpBotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "HelpBot".to_string(),
+            channel: "General".to_string(),
+            announce_games: true,
+            topics: BTreeMap::new(),
+        }
+    }
+}
+
+/// Protection against rapid join/part cycling in a channel
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JoinFloodConfig {
+    pub enabled: bool,
+    /// joins of a single channel within the window that count as a flood
+    pub max_joins: usize,
+    pub window_secs: u64,
+    /// how long a flood lasts and how long an IP has to wait before rejoining during one
+    pub cooldown_secs: u64,
+}
+
+impl Default for JoinFloodConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_joins: 20,
+            window_secs: 10,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+/// Backoff and lockout after failed logins and idents, counted per IP and per username from each IP
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoginThrottleConfig {
+    pub enabled: bool,
+    /// failed attempts that are let through without any wait
+    pub free_attempts: u32,
+    /// wait after the first attempt past the free ones, doubling with each further failure
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    /// failed attempts after which the IP, or the username for that IP, is locked out
+    pub lockout_attempts: u32,
+    pub lockout_secs: u64,
+    /// how long after the last failure the count starts over
+    pub reset_secs: u64,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            free_attempts: 3,
+            backoff_secs: 2,
+            max_backoff_secs: 60,
+            lockout_attempts: 10,
+            lockout_secs: 15 * 60,
+            reset_secs: 60 * 60,
+        }
+    }
+}
+
+/// Clients that do not keep up with the messages sent to them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SlowClientsConfig {
+    /// how long a client's send queue may stay full before it is disconnected
+    pub evict_after_secs: u64,
+    /// how long a client's send queue may stay three quarters full before a warning is logged
+    pub warn_after_secs: u64,
+}
+
+impl Default for SlowClientsConfig {
+    fn default() -> Self {
+        Self {
+            evict_after_secs: 30,
+            warn_after_secs: 5,
+        }
+    }
+}
+
+/// Cleanup applied to every chat message before it is sent on
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SanitizeConfig {
+    /// longer messages are refused, measured after the cleanup
+    pub max_message_bytes: usize,
+    /// drop bytes below 0x20 and DEL, which the game shows as garbage
+    pub strip_control_chars: bool,
+    /// turn tabs and line breaks into spaces, collapse runs of spaces and trim both ends
+    pub normalize_whitespace: bool,
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 400,
+            strip_control_chars: true,
+            normalize_whitespace: true,
+        }
+    }
+}
+
+/// Messages posted to the default channel again and again, e.g. a community link
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AnnouncementsConfig {
+    pub scheduled: Vec<ScheduledAnnouncement>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduledAnnouncement {
+    pub text: String,
+    /// time between two postings, the first one following after one interval
+    pub interval_mins: u64,
+}
+
+/// Usernames with elevated privileges
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RolesConfig {
+    pub admins: Vec<String>,
+    pub moderators: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// file with one filtered word per line
+    pub word_list: Option<PathBuf>,
+    /// channels in which messages are not filtered
+    pub exempt_channels: Vec<String>,
+}
+
+/// Protects hosts from having their address handed to anyone who knows a game password
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    /// untrusted users have to be approved by the host before learning the host's IP
+    pub hide_host_ips: bool,
+    /// accounts at least this old are trusted; staff always is
+    pub trusted_account_days: u64,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            hide_host_ips: false,
+            trusted_account_days: 7,
+        }
+    }
+}
+
+/// Names for the client builds recognized by their handshake fingerprint
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BuildsConfig {
+    /// checked in order, the first matching rule names the build
+    pub rules: Vec<BuildRule>,
+}
+
+/// Every field that is set has to match the fingerprint
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BuildRule {
+    pub name: String,
+    pub language: Option<String>,
+    pub ident_size: Option<usize>,
+    pub ident_extra: Option<usize>,
+    pub login_extra: Option<usize>,
+    /// matches only clients whose ident arrived within this many milliseconds of connecting
+    pub max_ident_delay_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GamesConfig {
+    /// maximum number of players in a single match, including the host
+    pub max_players: u32,
+    /// port game hosts listen on, unless they declare another one with /hostport
+    pub default_port: u16,
+    /// keep started games listed, so they can be joined with `/playc` as spectator
+    pub spectators: bool,
+    /// how long a host has to open a requested game before it is removed
+    pub requested_timeout_secs: u64,
+    /// started games still occupied after this long are dropped, 0 keeps them indefinitely
+    pub max_started_secs: u64,
+    /// started games all players leave within this long are recorded as aborted if one of them
+    /// comes back to the lobby before it is over, 0 never does
+    pub aborted_within_secs: u64,
+    /// announce the games in the user's region and those with the closest hosts first on login
+    pub nearby_first: bool,
+}
+
+impl Default for GamesConfig {
+    fn default() -> Self {
+        Self {
+            max_players: 8,
+            default_port: 17173,
+            spectators: false,
+            requested_timeout_secs: 30,
+            max_started_secs: 6 * 60 * 60,
+            aborted_within_secs: 0,
+            nearby_first: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionsConfig {
+    /// how long users can return to the channel they left with the resume token they were
+    /// given on login, 0 disables resuming
+    pub resume_secs: u64,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self { resume_secs: 600 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    pub enabled: bool,
+    /// address handed to joiners instead of the game host's
+    pub public_ip: Ipv4Addr,
+    pub bind_ip: Ipv4Addr,
+    /// unpatched clients always connect to the default game port, so by default only one game
+    /// can be relayed
+    pub port_range_start: u16,
+    pub port_range_end: u16,
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            public_ip: Ipv4Addr::UNSPECIFIED,
+            bind_ip: Ipv4Addr::UNSPECIFIED,
+            port_range_start: 17173,
+            port_range_end: 17173,
+            idle_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PingConfig {
+    pub enabled: bool,
+    /// UDP port probes are sent to, the echo service by default
+    pub port: u16,
+    pub interval_secs: u64,
+    /// how long to wait for an answer before a host counts as unreachable
+    pub timeout_ms: u64,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 7,
+            interval_secs: 30,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MasterProtocol {
+    Http,
+    Udp,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MasterConfig {
+    pub enabled: bool,
+    pub protocol: MasterProtocol,
+    /// host:port of the master server
+    pub address: String,
+    /// request path for HTTP announcements
+    pub path: String,
+    pub interval_secs: u64,
+    pub server_name: String,
+    /// address under which players can reach this server
+    pub public_address: String,
+}
+
+impl Default for MasterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol: MasterProtocol::Http,
+            address: String::new(),
+            path: "/servers".to_string(),
+            interval_secs: 60,
+            server_name: "IE::Net".to_string(),
+            public_address: String::new(),
+        }
+    }
+}
+
+/// HTTP API for server operators
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdminApiConfig {
+    pub enabled: bool,
+    pub bind: String,
+    /// expected as `Authorization: Bearer <token>`; the API does not start without one
+    pub token: String,
+}
+
+impl Default for AdminApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "127.0.0.1:17180".to_string(),
+            token: String::new(),
+        }
+    }
+}
+
+/// Line-based operator console, reachable with e.g. telnet or netcat
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConsoleConfig {
+    pub enabled: bool,
+    pub bind: String,
+    /// asked for when connecting; without one, the console only listens on loopback addresses
+    pub password: String,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "127.0.0.1:17183".to_string(),
+            password: String::new(),
+        }
+    }
+}
+
+/// Public, read-only `/status.json` endpoint for community websites
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusApiConfig {
+    pub enabled: bool,
+    pub bind: String,
+    /// how long a status document is reused before the broker is asked again
+    pub cache_secs: u64,
+}
+
+impl Default for StatusApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "0.0.0.0:17181".to_string(),
+            cache_secs: 5,
+        }
+    }
+}
+
+/// Second listener speaking the chat command protocol over WebSocket for browser clients
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    pub bind: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "0.0.0.0:17182".to_string(),
+        }
+    }
+}
+
+/// Links to other IE::Net servers whose lobbies are shared with this one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    pub enabled: bool,
+    /// name under which this server's users, channels and games appear on peers
+    pub server_name: String,
+    /// shared secret every peer has to prove it knows
+    pub secret: String,
+    /// host:port to accept peering connections on; only outgoing links are made if unset
+    pub bind: Option<String>,
+    /// host:port of servers to connect to
+    pub peers: Vec<String>,
+    pub retry_secs: u64,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            server_name: String::new(),
+            secret: String::new(),
+            bind: None,
+            peers: Vec::new(),
+            retry_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsBackend {
+    None,
+    Statsd,
+    Otlp,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub backend: MetricsBackend,
+    /// host:port of the StatsD daemon or the OTLP/HTTP collector
+    pub address: String,
+    pub prefix: String,
+    pub interval_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            backend: MetricsBackend::None,
+            address: "127.0.0.1:8125".to_string(),
+            prefix: "ie_net".to_string(),
+            interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeartbeatProtocol {
+    Http,
+    Statsd,
+}
+
+/// Liveness reports to an external monitor, which can raise the alarm once they stop
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub enabled: bool,
+    pub protocol: HeartbeatProtocol,
+    /// host:port of the monitoring endpoint or StatsD daemon
+    pub address: String,
+    /// request path for HTTP heartbeats
+    pub path: String,
+    /// names the server in HTTP heartbeats and prefixes StatsD metrics
+    pub name: String,
+    pub interval_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol: HeartbeatProtocol::Http,
+            address: String::new(),
+            path: "/heartbeat".to_string(),
+            name: "ie_net".to_string(),
+            interval_secs: 30,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings that parse fine but that the server cannot run with
+    fn validate(&self) -> Result<()> {
+        if self.heartbeat.enabled && self.heartbeat.interval_secs == 0 {
+            bail!("heartbeat.interval_secs must be at least 1");
+        }
+        if self.replication.enabled && self.replication.heartbeat_secs == 0 {
+            bail!("replication.heartbeat_secs must be at least 1");
+        }
+        if self.ping.enabled && self.ping.interval_secs == 0 {
+            bail!("ping.interval_secs must be at least 1");
+        }
+        if self.metrics.backend != MetricsBackend::None && self.metrics.interval_secs == 0 {
+            bail!("metrics.interval_secs must be at least 1");
+        }
+        if self.tracing.enabled && self.tracing.interval_secs == 0 {
+            bail!("tracing.interval_secs must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.tracing.sample_ratio) {
+            bail!("tracing.sample_ratio must be between 0 and 1");
+        }
+        if self.master.enabled && self.master.interval_secs == 0 {
+            bail!("master.interval_secs must be at least 1");
+        }
+        if self.relay.enabled && self.relay.idle_timeout_secs == 0 {
+            bail!("relay.idle_timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TracingConfig {
+    pub enabled: bool,
+    /// host:port of the OTLP/HTTP collector
+    pub address: String,
+    pub service_name: String,
+    /// fraction of client commands that get traced, between 0 and 1
+    pub sample_ratio: f64,
+    pub interval_secs: u64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:4318".to_string(),
+            service_name: "ie_net".to_string(),
+            sample_ratio: 0.01,
+            interval_secs: 5,
+        }
+    }
+}
+
+/// Recording the raw traffic of game clients, to figure out the unknown parts of the protocol
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// each connection gets a file of its own in there; nothing is captured if unset
+    pub dir: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_intervals_are_rejected() {
+        assert!(Config::parse("").is_ok());
+        assert!(Config::parse("[heartbeat]\nenabled = true\ninterval_secs = 0").is_err());
+        assert!(Config::parse("[replication]\nenabled = true\nheartbeat_secs = 0").is_err());
+        assert!(Config::parse("[ping]\nenabled = true\ninterval_secs = 0").is_err());
+        assert!(Config::parse("[metrics]\nbackend = \"statsd\"\ninterval_secs = 0").is_err());
+        assert!(Config::parse("[metrics]\nbackend = \"none\"\ninterval_secs = 0").is_ok());
+        assert!(Config::parse("[tracing]\nenabled = true\ninterval_secs = 0").is_err());
+        assert!(Config::parse("[master]\nenabled = true\ninterval_secs = 0").is_err());
+        assert!(Config::parse("[relay]\nenabled = true\nidle_timeout_secs = 0").is_err());
+    }
+
+    #[test]
+    fn test_sample_ratio_must_be_a_fraction() {
+        assert!(Config::parse("[tracing]\nsample_ratio = 1.0").is_ok());
+        assert!(Config::parse("[tracing]\nsample_ratio = 1.5").is_err());
+        assert!(Config::parse("[tracing]\nsample_ratio = -0.1").is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use crate::admin_api::query;
+use crate::broker::control::ControlCommand;
+use crate::broker::EventSender;
+use crate::config::ConsoleConfig;
+use crate::server::{bind_listener, spawn_and_log_error, wait_for_shutdown};
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::stream::StreamExt;
+use tokio::sync::{mpsc, watch};
+
+/// Longer lines are no typing mistake, so the connection is dropped
+const MAX_LINE: u64 = 1024;
+
+const HELP: &str = "Commands:
+  list users|channels|games
+  kick <user>
+  ban <user>
+  unban <user>
+  broadcast <message>
+  reload words
+  shutdown [notice seconds]
+  quit";
+
+#[derive(Debug, PartialEq)]
+enum ConsoleCommand {
+    Help,
+    ListUsers,
+    ListChannels,
+    ListGames,
+    Kick(String),
+    Ban(String),
+    Unban(String),
+    Broadcast(String),
+    ReloadWords,
+    Shutdown(Option<u64>),
+    Quit,
+}
+
+impl ConsoleCommand {
+    fn parse(line: &str) -> Result<Self> {
+        let line = line.trim();
+        let (command, argument) = match line.find(char::is_whitespace) {
+            Some(position) => (&line[..position], line[position..].trim()),
+            None => (line, ""),
+        };
+        let username = || match argument {
+            "" => Err(anyhow!("{} needs a username", command)),
+            username => Ok(username.to_string()),
+        };
+        Ok(match command.to_ascii_lowercase().as_str() {
+            "help" => Self::Help,
+            "list" => match argument {
+                "users" => Self::ListUsers,
+                "channels" => Self::ListChannels,
+                "games" => Self::ListGames,
+                _ => return Err(anyhow!("Can list users, channels or games")),
+            },
+            "kick" => Self::Kick(username()?),
+            "ban" => Self::Ban(username()?),
+            "unban" => Self::Unban(username()?),
+            "broadcast" if !argument.is_empty() => Self::Broadcast(argument.to_string()),
+            "broadcast" => return Err(anyhow!("broadcast needs a message")),
+            "reload" if argument == "words" => Self::ReloadWords,
+            "reload" => return Err(anyhow!("Can reload words")),
+            "shutdown" if argument.is_empty() => Self::Shutdown(None),
+            "shutdown" => Self::Shutdown(Some(
+                argument
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid notice period {}", argument))?,
+            )),
+            "quit" | "exit" => Self::Quit,
+            _ => return Err(anyhow!("Unknown command {}, try help", command)),
+        })
+    }
+}
+
+/// Runs a console command, answering with the text to show the operator
+async fn execute(
+    command: ConsoleCommand,
+    broker: &mut EventSender,
+    shutdown_requests: &mut mpsc::Sender<Option<u64>>,
+) -> Result<String> {
+    Ok(match command {
+        ConsoleCommand::Help => HELP.to_string(),
+        ConsoleCommand::ListUsers => {
+            let users = query(broker, |respond_to| ControlCommand::ListUsers {
+                respond_to,
+            })
+            .await?;
+            let lines: Vec<String> = users
+                .iter()
+                .map(|u| {
+                    format!(
+                        "{} in {} from {}, build {}, online for {}s",
+                        u.username, u.location, u.ip_addr, u.build, u.connected_secs
+                    )
+                })
+                .collect();
+            format!("{} users online\n{}", users.len(), lines.join("\n"))
+        }
+        ConsoleCommand::ListChannels => {
+            let channels = query(broker, |respond_to| ControlCommand::ListChannels {
+                respond_to,
+            })
+            .await?;
+            let lines: Vec<String> = channels
+                .iter()
+                .map(|c| format!("{}: {} users", c.name, c.users))
+                .collect();
+            format!("{} channels\n{}", channels.len(), lines.join("\n"))
+        }
+        ConsoleCommand::ListGames => {
+            let games = query(broker, |respond_to| ControlCommand::ListGames {
+                respond_to,
+            })
+            .await?;
+            let lines: Vec<String> = games
+                .iter()
+                .map(|g| {
+                    format!(
+                        "{} hosted by {}, {}, {}/{} players",
+                        g.name, g.host, g.status, g.players, g.max_players
+                    )
+                })
+                .collect();
+            format!("{} games\n{}", games.len(), lines.join("\n"))
+        }
+        ConsoleCommand::Kick(username) => {
+            let name = username.clone();
+            let kicked = query(broker, |respond_to| ControlCommand::Kick {
+                username,
+                respond_to,
+            })
+            .await?;
+            if kicked {
+                format!("Kicked {}", name)
+            } else {
+                format!("{} is not online", name)
+            }
+        }
+        ConsoleCommand::Ban(username) => {
+            let name = username.clone();
+            query(broker, |respond_to| ControlCommand::Ban {
+                username,
+                respond_to,
+            })
+            .await?;
+            format!("Banned {}", name)
+        }
+        ConsoleCommand::Unban(username) => {
+            let name = username.clone();
+            let unbanned = query(broker, |respond_to| ControlCommand::Unban {
+                username,
+                respond_to,
+            })
+            .await?;
+            if unbanned {
+                format!("Unbanned {}", name)
+            } else {
+                format!("{} was not banned", name)
+            }
+        }
+        ConsoleCommand::Broadcast(message) => {
+            let recipients = query(broker, |respond_to| ControlCommand::Broadcast {
+                message,
+                filter: None,
+                respond_to,
+            })
+            .await?;
+            format!("Sent to {} users", recipients)
+        }
+        ConsoleCommand::ReloadWords => {
+            let words = query(broker, |respond_to| ControlCommand::ReloadWordList {
+                respond_to,
+            })
+            .await??;
+            format!("Filtering {} words", words)
+        }
+        ConsoleCommand::Shutdown(notice_secs) => {
+            shutdown_requests
+                .send(notice_secs)
+                .await
+                .map_err(|_| anyhow!("Server is already shutting down"))?;
+            "Shutting down".to_string()
+        }
+        ConsoleCommand::Quit => String::new(),
+    })
+}
+
+/// Reads a line, or returns None once the operator has disconnected
+async fn read_line(reader: &mut BufReader<impl AsyncRead + Unpin>) -> Result<Option<String>> {
+    let mut line = String::new();
+    let read = (&mut *reader).take(MAX_LINE).read_line(&mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && read as u64 == MAX_LINE {
+        return Err(anyhow!("Line too long"));
+    }
+    Ok(Some(line))
+}
+
+async fn session(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    password: String,
+    mut broker: EventSender,
+    mut shutdown_requests: mpsc::Sender<Option<u64>>,
+) -> Result<()> {
+    let (read, mut write) = stream.split();
+    let mut reader = BufReader::new(read);
+    if !password.is_empty() {
+        write.write_all(b"Password: ").await?;
+        match read_line(&mut reader).await? {
+            Some(line) if line.trim_end() == password => (),
+            _ => {
+                log::warn!("Wrong console password from {}", peer);
+                write.write_all(b"Wrong password\n").await?;
+                return Ok(());
+            }
+        }
+    }
+    log::info!("Console session opened from {}", peer);
+    write
+        .write_all(b"IE::Net console, type help for commands\n> ")
+        .await?;
+    while let Some(line) = read_line(&mut reader).await? {
+        if line.trim().is_empty() {
+            write.write_all(b"> ").await?;
+            continue;
+        }
+        let command = ConsoleCommand::parse(&line);
+        let answer = match command {
+            Ok(ConsoleCommand::Quit) => break,
+            Ok(command) => {
+                log::info!("Console command from {}: {:?}", peer, command);
+                execute(command, &mut broker, &mut shutdown_requests).await
+            }
+            Err(e) => Err(e),
+        };
+        let answer = answer.unwrap_or_else(|e| format!("Error: {}", e));
+        write.write_all(answer.trim_end().as_bytes()).await?;
+        write.write_all(b"\n> ").await?;
+    }
+    log::info!("Console session from {} closed", peer);
+    Ok(())
+}
+
+/// Accepts operator console connections until shutdown. Shutdown requests, with an optional
+/// notice period overriding the configured one, are handed to the server through
+/// `shutdown_requests`.
+pub async fn serve_loop(
+    config: ConsoleConfig,
+    broker: EventSender,
+    shutdown_requests: mpsc::Sender<Option<u64>>,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut listener = bind_listener(&config.bind)?;
+    if config.password.is_empty() && !listener.local_addr()?.ip().is_loopback() {
+        return Err(anyhow!(
+            "Console listens on {}, which needs a password",
+            config.bind
+        ));
+    }
+    log::info!("Console listening at {}", &config.bind);
+    let mut incoming = listener.incoming();
+    loop {
+        tokio::select! {
+            Some(connection) = incoming.next() => {
+                // a connection that failed on its way in must not take the console down
+                let stream = match connection {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("Could not accept console connection: {}", e);
+                        continue;
+                    }
+                };
+                let peer = match stream.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        log::warn!("Console connection went away right away: {}", e);
+                        continue;
+                    }
+                };
+                let session = session(
+                    stream,
+                    peer,
+                    config.password.clone(),
+                    broker.clone(),
+                    shutdown_requests.clone(),
+                );
+                let shutdown = wait_for_shutdown(shutdown_recv.clone());
+                spawn_and_log_error(
+                    async move {
+                        tokio::select! {
+                            result = session => result,
+                            _ = shutdown => Ok(()),
+                        }
+                    },
+                    "console_session",
+                );
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            else => break,
+        }
+    }
+    log::info!("Console shutting down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ConsoleCommand::parse("list users\r\n").unwrap(),
+            ConsoleCommand::ListUsers
+        );
+        assert_eq!(
+            ConsoleCommand::parse("KICK foo").unwrap(),
+            ConsoleCommand::Kick("foo".to_string())
+        );
+        assert_eq!(
+            ConsoleCommand::parse("broadcast  Server restarts soon ").unwrap(),
+            ConsoleCommand::Broadcast("Server restarts soon".to_string())
+        );
+        assert_eq!(
+            ConsoleCommand::parse("shutdown 60").unwrap(),
+            ConsoleCommand::Shutdown(Some(60))
+        );
+        assert_eq!(
+            ConsoleCommand::parse("shutdown").unwrap(),
+            ConsoleCommand::Shutdown(None)
+        );
+        assert_eq!(
+            ConsoleCommand::parse("reload words").unwrap(),
+            ConsoleCommand::ReloadWords
+        );
+        assert!(ConsoleCommand::parse("kick").is_err());
+        assert!(ConsoleCommand::parse("shutdown soon").is_err());
+        assert!(ConsoleCommand::parse("list bans").is_err());
+        assert!(ConsoleCommand::parse("reboot").is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use crate::broker::{Event, EventSender};
+use crate::config::FederationConfig;
+use crate::server::{bind_listener, connect_stream, spawn_and_log_error, wait_for_shutdown};
+use anyhow::{anyhow, Result};
+use blake2::digest::{Digest, Mac};
+use blake2::{Blake2b512, Blake2bMac512};
+use password_hash::rand_core::{OsRng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::stream::StreamExt;
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+pub type PeerSender = mpsc::Sender<PeerMessage>;
+
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+/// How long a peer gets to prove that it knows the secret
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CHALLENGE_LENGTH: usize = 32;
+
+/// A location as seen by one side of a peering link. Locations on the server sending the
+/// message have `on_receiver` unset, locations on the server receiving it have it set.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PeerLocation {
+    /// `#channel` or `$game`
+    pub name: String,
+    pub on_receiver: bool,
+}
+
+/// Messages exchanged between federated servers, one JSON document per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeerMessage {
+    Hello {
+        server_name: String,
+        /// random bytes the other side has to answer with a proof
+        challenge: Vec<u8>,
+    },
+    Proof {
+        proof: Vec<u8>,
+    },
+    UserPresent {
+        username: String,
+        location: PeerLocation,
+    },
+    UserGone {
+        username: String,
+    },
+    ChannelCreated {
+        name: String,
+    },
+    ChannelDropped {
+        name: String,
+    },
+    GameOpened {
+        name: String,
+        id: Uuid,
+        host_ip: Ipv4Addr,
+        /// only set if joiners have to use a port other than the default one
+        #[serde(default)]
+        port: Option<u16>,
+        password: Vec<u8>,
+        players: u32,
+        max_players: u32,
+    },
+    GameDropped {
+        name: String,
+    },
+    Public {
+        username: String,
+        location: PeerLocation,
+        message: Vec<u8>,
+    },
+    Private {
+        from: String,
+        to: String,
+        message: Vec<u8>,
+    },
+}
+
+/// Server names are used to tag remote names as `name@server`
+fn valid_server_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('@') && !name.contains(char::is_whitespace)
+}
+
+/// A keyed hash over the challenge of the verifying side, the challenge of the proving side and
+/// the name the prover goes by, which only a server that knows the secret can compute
+fn proof_mac(
+    secret: &str,
+    verifier_challenge: &[u8],
+    prover_challenge: &[u8],
+    prover: &str,
+) -> Blake2bMac512 {
+    let key = Blake2b512::digest(secret.as_bytes());
+    let mut mac = Blake2bMac512::new_from_slice(&key).expect("64 byte keys are accepted");
+    for part in &[verifier_challenge, prover_challenge, prover.as_bytes()] {
+        mac.update(&(part.len() as u64).to_le_bytes());
+        mac.update(part);
+    }
+    mac
+}
+
+/// Both sides send a random challenge and then answer the other's with a proof that they know
+/// the secret, so that the secret never crosses the wire. Returns the name of the peer.
+async fn handshake(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    config: &FederationConfig,
+) -> Result<String> {
+    if config.secret.is_empty() {
+        return Err(anyhow!("No federation secret is configured"));
+    }
+    let mut challenge = vec![0; CHALLENGE_LENGTH];
+    OsRng.fill_bytes(&mut challenge);
+    write_message(
+        writer,
+        &PeerMessage::Hello {
+            server_name: config.server_name.clone(),
+            challenge: challenge.clone(),
+        },
+    )
+    .await?;
+    // a peer going by this server's name could have it answer its own challenge
+    let (server, peer_challenge) = match read_message(reader, MAX_LINE_LENGTH).await? {
+        Some(PeerMessage::Hello {
+            server_name,
+            challenge: peer_challenge,
+        }) if valid_server_name(&server_name)
+            && server_name != config.server_name
+            && peer_challenge.len() == CHALLENGE_LENGTH
+            && peer_challenge != challenge =>
+        {
+            (server_name, peer_challenge)
+        }
+        _ => return Err(anyhow!("Peer did not introduce itself")),
+    };
+    let proof = proof_mac(
+        &config.secret,
+        &peer_challenge,
+        &challenge,
+        &config.server_name,
+    );
+    write_message(
+        writer,
+        &PeerMessage::Proof {
+            proof: proof.finalize().into_bytes().to_vec(),
+        },
+    )
+    .await?;
+    match read_message(reader, MAX_LINE_LENGTH).await? {
+        Some(PeerMessage::Proof { proof })
+            if proof_mac(&config.secret, &challenge, &peer_challenge, &server)
+                .verify_slice(&proof)
+                .is_ok() =>
+        {
+            Ok(server)
+        }
+        _ => Err(anyhow!("Peer {} failed to authenticate", server)),
+    }
+}
+
+/// Reads one JSON document per line, as also used for replication links
+pub(crate) async fn read_message<T: DeserializeOwned>(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    max_length: u64,
+) -> Result<Option<T>> {
+    let mut line = Vec::new();
+    let mut limited = reader.take(max_length);
+    if limited.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(anyhow!("Message too long"));
+    }
+    Ok(Some(serde_json::from_slice(&line)?))
+}
+
+pub(crate) async fn write_message<T: Serialize>(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    message: &T,
+) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+/// Runs a peering link after the connection has been established, in either direction
+async fn run_peer(
+    stream: TcpStream,
+    config: FederationConfig,
+    mut broker: EventSender,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let server = tokio::select! {
+        result = timeout(HANDSHAKE_TIMEOUT, handshake(&mut reader, &mut writer, &config)) => {
+            result.map_err(|_| anyhow!("Peer did not complete the handshake in time"))??
+        },
+        _ = wait_for_shutdown(shutdown_recv.clone()) => return Ok(()),
+    };
+
+    log::info!("Established federation link with {}", server);
+    let link = Uuid::new_v4();
+    let (send, mut outgoing) = mpsc::channel(256);
+    broker
+        .send(Event::PeerConnected {
+            server: server.clone(),
+            link,
+            send,
+        })
+        .await?;
+
+    let result: Result<()> = async {
+        loop {
+            tokio::select! {
+                message = read_message(&mut reader, MAX_LINE_LENGTH) => match message? {
+                    Some(message) => broker.send(Event::PeerMessage { server: server.clone(), message }).await?,
+                    None => break,
+                },
+                message = outgoing.next() => match message {
+                    Some(message) => write_message(&mut writer, &message).await?,
+                    // the broker dropped the link
+                    None => break,
+                },
+                Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    log::info!("Federation link with {} closed", server);
+    // the broker is already gone if the link closed because of a shutdown
+    let _ = broker
+        .send(Event::PeerDisconnected {
+            server: server.clone(),
+            link,
+        })
+        .await;
+    result
+}
+
+/// Accepts peering connections from other servers
+pub async fn listen_loop(
+    config: FederationConfig,
+    address: String,
+    broker: EventSender,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut listener = bind_listener(&address)?;
+    log::info!("Listening for federation peers at {}", &address);
+    let mut incoming = listener.incoming();
+    loop {
+        tokio::select! {
+            Some(connection) = incoming.next() => {
+                spawn_and_log_error(
+                    run_peer(connection?, config.clone(), broker.clone(), shutdown_recv.clone()),
+                    "federation_peer",
+                );
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            else => break,
+        }
+    }
+    Ok(())
+}
+
+/// Keeps a peering link to the given server, reconnecting whenever it drops
+pub async fn connect_loop(
+    config: FederationConfig,
+    address: String,
+    broker: EventSender,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let retry_delay = Duration::from_secs(config.retry_secs);
+    loop {
+        match connect_stream(&address).await {
+            Ok(stream) => {
+                if let Err(e) = run_peer(
+                    stream,
+                    config.clone(),
+                    broker.clone(),
+                    shutdown_recv.clone(),
+                )
+                .await
+                {
+                    log::warn!("Federation link to {} failed: {}", address, e);
+                }
+            }
+            Err(e) => log::warn!("Could not connect to federation peer {}: {}", address, e),
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(retry_delay) => (),
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_config(server_name: &str, secret: &str) -> FederationConfig {
+        FederationConfig {
+            server_name: server_name.to_string(),
+            secret: secret.to_string(),
+            ..FederationConfig::default()
+        }
+    }
+
+    /// Runs the handshake on both ends of a local connection, returning what each side made of
+    /// the other
+    async fn handshake_between(
+        first: FederationConfig,
+        second: FederationConfig,
+    ) -> (Result<String>, Result<String>) {
+        let mut listener = bind_listener("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (accepted, connected) = tokio::join!(listener.accept(), connect_stream(&addr));
+        let (first_read, mut first_write) = accepted.unwrap().0.into_split();
+        let (second_read, mut second_write) = connected.unwrap().into_split();
+        let mut first_read = BufReader::new(first_read);
+        let mut second_read = BufReader::new(second_read);
+        tokio::join!(
+            handshake(&mut first_read, &mut first_write, &first),
+            handshake(&mut second_read, &mut second_write, &second)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_the_same_secret() {
+        let (east, west) =
+            handshake_between(peer_config("east", "secret"), peer_config("west", "secret")).await;
+        assert_eq!(east.unwrap(), "west");
+        assert_eq!(west.unwrap(), "east");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_another_secret() {
+        let (east, west) =
+            handshake_between(peer_config("east", "secret"), peer_config("west", "guess")).await;
+        assert!(east.is_err());
+        assert!(west.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_a_peer_going_by_the_same_name() {
+        let (east, _) =
+            handshake_between(peer_config("east", "secret"), peer_config("east", "secret")).await;
+        assert!(east.is_err());
+    }
+
+    #[test]
+    fn test_proof_depends_on_every_part() {
+        let proof = |secret, verifier: &[u8], prover: &[u8], name| {
+            proof_mac(secret, verifier, prover, name)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        };
+        let expected = proof("secret", b"a", b"b", "east");
+        assert_eq!(proof("secret", b"a", b"b", "east"), expected);
+        assert_ne!(proof("guess", b"a", b"b", "east"), expected);
+        assert_ne!(proof("secret", b"b", b"a", "east"), expected);
+        assert_ne!(proof("secret", b"a", b"b", "west"), expected);
+        assert_ne!(proof("secret", b"ab", b"", "east"), expected);
+    }
+
+    #[tokio::test]
+    async fn test_message_roundtrip() {
+        let message = PeerMessage::Public {
+            username: "foo".to_string(),
+            location: PeerLocation {
+                name: "#General".to_string(),
+                on_receiver: true,
+            },
+            message: b"hi \"there\"".to_vec(),
+        };
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &message).await.unwrap();
+        let mut reader = BufReader::new(&buffer[..]);
+        assert_eq!(
+            read_message(&mut reader, MAX_LINE_LENGTH).await.unwrap(),
+            Some(message)
+        );
+        assert_eq!(
+            read_message::<PeerMessage>(&mut reader, MAX_LINE_LENGTH)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
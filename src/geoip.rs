@@ -0,0 +1,70 @@
+use crate::config::GeoIpConfig;
+use anyhow::{Context, Result};
+use maxminddb::{geoip2, Reader};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Tells which region an address is in, so that players can find others nearby. Regions are
+/// continent codes like EU or NA, which is as close as players need to be for a good ping.
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+    regions: BTreeMap<Ipv4Addr, String>,
+}
+
+impl GeoIp {
+    pub fn new(config: &GeoIpConfig) -> Result<Self> {
+        let reader = match &config.database {
+            Some(path) => {
+                let reader = Reader::open_readfile(path)
+                    .with_context(|| format!("Could not read GeoIP database {}", path.display()))?;
+                log::info!(
+                    "Loaded GeoIP database {} from {}",
+                    reader.metadata.database_type,
+                    path.display()
+                );
+                Some(reader)
+            }
+            None => None,
+        };
+        Ok(Self {
+            reader,
+            regions: config
+                .regions
+                .iter()
+                .map(|(ip, region)| (*ip, region.to_ascii_uppercase()))
+                .collect(),
+        })
+    }
+
+    /// The region of the address, if it is configured or the database knows it
+    pub fn region(&self, ip: Ipv4Addr) -> Option<String> {
+        if let Some(region) = self.regions.get(&ip) {
+            return Some(region.clone());
+        }
+        let country: geoip2::Country = self.reader.as_ref()?.lookup(IpAddr::V4(ip)).ok()?;
+        country
+            .continent?
+            .code
+            .map(|code| code.to_ascii_uppercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_regions() {
+        let mut config = GeoIpConfig::default();
+        config
+            .regions
+            .insert(Ipv4Addr::new(192, 168, 0, 1), "eu".to_string());
+        let geoip = GeoIp::new(&config).unwrap();
+
+        assert_eq!(
+            geoip.region(Ipv4Addr::new(192, 168, 0, 1)),
+            Some("EU".to_string())
+        );
+        assert_eq!(geoip.region(Ipv4Addr::new(192, 168, 0, 2)), None);
+    }
+}
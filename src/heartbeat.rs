@@ -0,0 +1,94 @@
+use crate::broker::status::ServerStatus;
+use crate::broker::{Event, EventSender};
+use crate::config::{HeartbeatConfig, HeartbeatProtocol};
+use crate::http::post_json;
+use crate::metrics::{Metric, MetricValue, MetricsSink, StatsdSink};
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+
+/// A broker that takes longer to answer counts as hung, so no heartbeat goes out
+const BROKER_TIMEOUT: Duration = Duration::from_secs(5);
+/// A heartbeat still not through after this long is given up on, so that a monitor that hangs
+/// neither holds up the next one nor the shutdown
+const BEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+async fn query_status(broker: &mut EventSender) -> Result<ServerStatus> {
+    let (respond_to, response) = oneshot::channel();
+    broker.send(Event::QueryState { respond_to }).await?;
+    Ok(tokio::time::timeout(BROKER_TIMEOUT, response)
+        .await
+        .map_err(|_| anyhow!("Broker did not answer within {:?}", BROKER_TIMEOUT))??)
+}
+
+fn gauges(status: &ServerStatus) -> Vec<Metric> {
+    let gauge = |name: &str, value: usize| Metric {
+        name: name.to_string(),
+        value: MetricValue::Gauge(value as i64),
+    };
+    vec![
+        Metric {
+            name: "heartbeat".to_string(),
+            value: MetricValue::Counter(1),
+        },
+        gauge("heartbeat.uptime_secs", status.uptime_secs as usize),
+        gauge("heartbeat.users_online", status.users_online as usize),
+        gauge("heartbeat.channels", status.channels.len()),
+        gauge("heartbeat.games_open", status.open_games.len()),
+    ]
+}
+
+async fn beat(
+    config: &HeartbeatConfig,
+    statsd: &mut Option<StatsdSink>,
+    broker: &mut EventSender,
+) -> Result<()> {
+    let status = query_status(broker).await?;
+    match config.protocol {
+        HeartbeatProtocol::Http => {
+            let body = json!({
+                "name": config.name,
+                "status": "alive",
+                "version": status.version,
+                "uptime_secs": status.uptime_secs,
+                "users_online": status.users_online,
+                "channels": status.channels.len(),
+                "games_open": status.open_games.len(),
+            });
+            match post_json(&config.address, &config.path, &body.to_string()).await? {
+                200..=299 => Ok(()),
+                status => Err(anyhow!("Monitor responded with status {}", status)),
+            }
+        }
+        HeartbeatProtocol::Statsd => {
+            let sink = statsd.get_or_insert_with(|| StatsdSink::new(&config.address, &config.name));
+            sink.flush(&gauges(&status)).await
+        }
+    }
+}
+
+/// Periodically tells an external monitor that the server is alive, along with a few gauges.
+/// Heartbeats stop when the broker hangs, not just when the process is gone.
+pub async fn heartbeat_loop(
+    config: HeartbeatConfig,
+    mut broker: EventSender,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    let mut statsd = None;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                match tokio::time::timeout(BEAT_TIMEOUT, beat(&config, &mut statsd, &mut broker)).await {
+                    Ok(Ok(())) => log::debug!("Sent heartbeat to {}", config.address),
+                    Ok(Err(e)) => log::warn!("Failed to send heartbeat: {}", e),
+                    Err(_) => log::warn!("Heartbeat to {} timed out", config.address),
+                }
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+        }
+    }
+    log::info!("Heartbeat shutting down");
+    Ok(())
+}
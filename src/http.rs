@@ -0,0 +1,272 @@
+use crate::server::{bind_listener, spawn_and_log_error};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::stream::StreamExt;
+use tokio::sync::watch;
+
+const MAX_HEADER_LENGTH: usize = 16 * 1024;
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+/// An outbound request that has not completed after this long is given up on, so that a stuck
+/// server cannot block whoever is waiting for the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimal HTTP/1.1 POST of a JSON document, returning the response status code.
+/// This is all the outbound HTTP the server needs, so it does not warrant a full client.
+pub async fn post_json(address: &str, path: &str, body: &str) -> Result<u16> {
+    let (status, _) = request(address, "POST", path, &[], body).await?;
+    Ok(status)
+}
+
+/// Sends a single request with a JSON body, returning the response's status code and body
+pub async fn request(
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, Vec<u8>)> {
+    tokio::time::timeout(
+        REQUEST_TIMEOUT,
+        send_request(address, method, path, headers, body),
+    )
+    .await
+    .map_err(|_| anyhow!("{} did not answer within {:?}", address, REQUEST_TIMEOUT))?
+}
+
+async fn send_request(
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, Vec<u8>)> {
+    // connecting through the standard library for the same reason as binding, see bind_listener
+    let target = address.to_string();
+    let stream =
+        tokio::task::spawn_blocking(move || std::net::TcpStream::connect(target)).await??;
+    let mut stream = TcpStream::from_std(stream)?;
+    let extra_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        method,
+        path,
+        address,
+        body.len(),
+        extra_headers,
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status = parse_status(&response)?;
+    let body = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(head_end) => response.split_off(head_end + 4),
+        None => Vec::new(),
+    };
+    Ok((status, body))
+}
+
+fn parse_status(response: &[u8]) -> Result<u16> {
+    let status_line = response
+        .split(|b| *b == b'\n')
+        .next()
+        .ok_or_else(|| anyhow!("Empty HTTP response"))?;
+    let status_line = String::from_utf8_lossy(status_line);
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("Malformed HTTP status line: {}", status_line.trim()))
+}
+
+/// An inbound HTTP request, as far as the server's small HTTP endpoints care
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn parse_head(head: &[u8]) -> Result<Request> {
+    let head = std::str::from_utf8(head)?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines
+        .next()
+        .ok_or_else(|| anyhow!("Empty HTTP request"))?
+        .split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(anyhow!("Malformed HTTP request line")),
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Reads a single HTTP/1.1 request; keep-alive is not supported
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Result<Request> {
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        if let Some(pos) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if received.len() > MAX_HEADER_LENGTH {
+            return Err(anyhow!("HTTP request header too long"));
+        }
+        let num_read = stream.read(&mut buf).await?;
+        if num_read == 0 {
+            return Err(anyhow!("Connection closed before end of HTTP request"));
+        }
+        received.extend_from_slice(&buf[..num_read]);
+    };
+
+    let mut request = parse_head(&received[..head_end])?;
+    let content_length: usize = match request.header("Content-Length") {
+        Some(length) => length.parse()?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_LENGTH {
+        return Err(anyhow!("HTTP request body too long"));
+    }
+    request.body = received.split_off(head_end + 4);
+    while request.body.len() < content_length {
+        let num_read = stream.read(&mut buf).await?;
+        if num_read == 0 {
+            return Err(anyhow!("Connection closed before end of HTTP request"));
+        }
+        request.body.extend_from_slice(&buf[..num_read]);
+    }
+    request.body.truncate(content_length);
+    Ok(request)
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// A JSON response to an inbound request
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+    /// lets scripts on any website read the response
+    pub allow_any_origin: bool,
+}
+
+impl Response {
+    pub fn json(status: u16, body: &Value) -> Self {
+        Self {
+            status,
+            body: body.to_string(),
+            allow_any_origin: false,
+        }
+    }
+}
+
+async fn write_response(stream: &mut (impl AsyncWrite + Unpin), response: &Response) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        response.status,
+        reason_phrase(response.status),
+        response.body.len(),
+        if response.allow_any_origin {
+            "Access-Control-Allow-Origin: *\r\n"
+        } else {
+            ""
+        },
+        response.body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Answers one request per connection with the handler until shutdown
+pub async fn serve<H, F>(
+    address: &str,
+    handler: H,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()>
+where
+    H: Fn(Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Response> + Send,
+{
+    let mut listener = bind_listener(address)?;
+    let mut incoming = listener.incoming();
+    loop {
+        tokio::select! {
+            Some(connection) = incoming.next() => {
+                let mut stream = connection?;
+                let handler = handler.clone();
+                spawn_and_log_error(
+                    async move {
+                        let request = read_request(&mut stream).await?;
+                        let response = handler(request).await;
+                        write_response(&mut stream, &response).await
+                    },
+                    "http_connection",
+                );
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            else => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = b"POST /api/kick HTTP/1.1\r\nHost: x\r\ncontent-length: 5\r\n\r\nhello";
+        let request = read_request(&mut &raw[..]).await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/kick");
+        assert_eq!(request.header("Host"), Some("x"));
+        assert_eq!(request.body, b"hello");
+
+        let truncated = b"GET / HTTP/1.1\r\nHost: x\r\n";
+        assert!(read_request(&mut &truncated[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(b"HTTP/1.1 200 OK\r\n\r\n").unwrap(), 200);
+        assert_eq!(parse_status(b"HTTP/1.0 404 Not Found\r\n").unwrap(), 404);
+        assert!(parse_status(b"garbage").is_err());
+    }
+}
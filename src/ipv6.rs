@@ -0,0 +1,73 @@
+use crate::config::Ipv6Config;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The IPv4 address the server knows a game client by. Dual-stack listeners see IPv4 clients
+/// as IPv4-mapped IPv6 addresses, which are translated back. Other IPv6 clients get their
+/// configured mapping, or a synthesized address if they are let in without one. Returns None
+/// for clients that are to be turned away.
+pub fn client_ipv4(peer: IpAddr, config: &Ipv6Config) -> Option<Ipv4Addr> {
+    match peer {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .or_else(|| config.mappings.get(&ip).copied())
+            .or_else(|| Some(synthesize(&ip)).filter(|_| config.accept_unmapped)),
+    }
+}
+
+/// Like `client_ipv4`, for clients that only chat and thus never need a real IPv4 address
+pub fn chat_ipv4(peer: IpAddr) -> Ipv4Addr {
+    match peer {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or_else(|| synthesize(&ip)),
+    }
+}
+
+/// Whether the address stands in for an IPv6 client, which nobody can connect to over IPv4
+pub fn is_synthesized(ip: Ipv4Addr) -> bool {
+    ip.octets()[0] >= 240
+}
+
+/// Derives an address in the reserved 240.0.0.0/4 block, which no real client comes from.
+/// Clients are told apart by their /64 network, so per-IP limits still work between networks.
+fn synthesize(ip: &Ipv6Addr) -> Ipv4Addr {
+    // FNV-1a, which stays the same across runs and builds
+    let hash = ip.octets()[..8].iter().fold(0x811c_9dc5u32, |hash, octet| {
+        (hash ^ *octet as u32).wrapping_mul(0x0100_0193)
+    });
+    Ipv4Addr::from(0xf000_0000 | (hash & 0x0fff_ffff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ipv4() {
+        let mapped_v4: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let native: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut config = Ipv6Config::default();
+
+        assert_eq!(
+            client_ipv4(mapped_v4, &config),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(client_ipv4(IpAddr::V6(native), &config), None);
+
+        config.accept_unmapped = true;
+        let synthesized = client_ipv4(IpAddr::V6(native), &config).unwrap();
+        assert!(is_synthesized(synthesized));
+        assert_eq!(chat_ipv4(IpAddr::V6(native)), synthesized);
+        let same_network: IpAddr = "2001:db8::2".parse().unwrap();
+        assert_eq!(client_ipv4(same_network, &config), Some(synthesized));
+
+        config
+            .mappings
+            .insert(native, Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(
+            client_ipv4(IpAddr::V6(native), &config),
+            Some(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert!(!is_synthesized(Ipv4Addr::new(203, 0, 113, 7)));
+    }
+}
@@ -1,11 +1,37 @@
 #[macro_use]
 extern crate nom;
 
-#[macro_use]
-extern crate downcast_rs;
-
+pub mod admin_api;
+mod announcements;
+mod audit_log;
 pub mod broker;
+mod capture;
+mod chat_log;
 mod client;
+pub mod config;
+mod console;
+pub mod federation;
+mod geoip;
+mod heartbeat;
+mod http;
+mod ipv6;
+mod master;
 pub mod messages;
+pub mod metrics;
+mod ping;
+pub mod protocol;
+pub mod protocol_trace;
+mod relay;
+pub mod replay;
+pub mod replication;
+mod rotating_log;
 pub mod server;
+mod status_api;
+pub mod storage;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod trace;
 mod util;
+mod validation;
+pub mod version;
+mod websocket;
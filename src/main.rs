@@ -1,20 +1,210 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use ie_net::admin_api::AdminClient;
+use ie_net::config::Config;
+use ie_net::protocol_trace;
 use ie_net::server;
+use ie_net::version::build_info;
+use serde_json::{json, Value};
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
 struct Options {
-    #[structopt(short, long, default_value = "0.0.0.0:17171")]
-    /// Listening address/port to receive connections from game clients
-    bind: String,
+    /// Runs the server if no subcommand is given
+    #[structopt(flatten)]
+    serve: ServeOptions,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Runs the server
+    Serve(ServeOptions),
+    /// Manages a running server through its admin API
+    Admin(AdminOptions),
+}
+
+#[derive(StructOpt, Debug)]
+struct ServeOptions {
+    #[structopt(short, long)]
+    /// Listening address/port to receive connections from game clients. May be given several
+    /// times, and replaces the addresses from the configuration file
+    bind: Vec<String>,
+
+    #[structopt(short, long, parse(from_os_str))]
+    /// Path to a TOML configuration file
+    config: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Directory to record the raw traffic of every game client connection to, one file per
+    /// connection, for reverse engineering the protocol
+    capture_dir: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct AdminOptions {
+    #[structopt(short, long, parse(from_os_str))]
+    /// Configuration file of the server, to take the admin API's address and token from
+    config: Option<PathBuf>,
+
+    #[structopt(short, long)]
+    /// Address of the admin API, overriding the configuration file
+    address: Option<String>,
+
+    #[structopt(short, long)]
+    /// Token of the admin API, overriding the configuration file
+    token: Option<String>,
+
+    #[structopt(subcommand)]
+    action: AdminAction,
+}
+
+#[derive(StructOpt, Debug)]
+enum AdminAction {
+    /// Lists the connected users
+    Users,
+    /// Lists the channels with their occupancy
+    Channels,
+    /// Lists the games
+    Games,
+    /// Counts logins and online users per client build
+    Builds,
+    /// Dumps logins, messages and completed games per day
+    Daily {
+        #[structopt(long)]
+        /// Prints CSV instead of JSON, e.g. for spreadsheets
+        csv: bool,
+    },
+    /// Disconnects a user
+    Kick { username: String },
+    /// Bans and disconnects a user
+    Ban { username: String },
+    /// Lifts a ban
+    Unban { username: String },
+    /// Announces a message to all users
+    Broadcast {
+        message: String,
+        #[structopt(short, long)]
+        /// Only addresses users matching this, e.g. "build ~ tmp2.1"
+        filter: Option<String>,
+    },
+    /// Dumps a user's frames to the protocol log
+    Trace {
+        username: String,
+        #[structopt(long)]
+        /// Switches tracing off again
+        off: bool,
+    },
+    /// Re-reads the content filter's word list
+    ReloadWords,
+}
+
+fn load_config(path: &Option<PathBuf>) -> Result<Config> {
+    match path {
+        Some(path) => Config::load(path),
+        None => Ok(Config::default()),
+    }
+}
+
+async fn serve(options: ServeOptions) -> Result<()> {
+    let mut config = load_config(&options.config)?;
+    if options.capture_dir.is_some() {
+        config.capture.dir = options.capture_dir;
+    }
+
+    flexi_logger::Logger::with_env_or_str("debug")
+        .add_writer(protocol_trace::WRITER_NAME, protocol_trace::log_writer()?)
+        .start()?;
+    log::info!("IE::Net server {} starting up...", build_info());
+
+    let addrs = if options.bind.is_empty() {
+        config.listen.bind.clone()
+    } else {
+        options.bind
+    };
+    server::run(addrs, config).await
+}
+
+/// Turns the admin API's per-day statistics into CSV with a header line
+fn daily_csv(days: &Value) -> String {
+    let columns = ["date", "logins", "messages", "games"];
+    let mut csv = columns.join(",") + "\n";
+    for day in days.as_array().into_iter().flatten() {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|c| match &day[c] {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            })
+            .collect();
+        csv += &(fields.join(",") + "\n");
+    }
+    csv
+}
+
+async fn admin(options: AdminOptions) -> Result<()> {
+    let config = load_config(&options.config)?.admin_api;
+    let address = options.address.unwrap_or(config.bind);
+    let token = options.token.unwrap_or(config.token);
+    if token.is_empty() {
+        return Err(anyhow!("No admin API token given or configured"));
+    }
+    let client = AdminClient::new(&address, &token);
+    let response = match options.action {
+        AdminAction::Users => client.get("/api/users").await?,
+        AdminAction::Channels => client.get("/api/channels").await?,
+        AdminAction::Games => client.get("/api/games").await?,
+        AdminAction::Builds => client.get("/api/builds").await?,
+        AdminAction::Daily { csv: true } => {
+            print!("{}", daily_csv(&client.get("/api/daily").await?));
+            return Ok(());
+        }
+        AdminAction::Daily { csv: false } => client.get("/api/daily").await?,
+        AdminAction::Kick { username } => {
+            client
+                .post("/api/kick", json!({ "username": username }))
+                .await?
+        }
+        AdminAction::Ban { username } => {
+            client
+                .post("/api/ban", json!({ "username": username }))
+                .await?
+        }
+        AdminAction::Unban { username } => {
+            client
+                .post("/api/unban", json!({ "username": username }))
+                .await?
+        }
+        AdminAction::Broadcast { message, filter } => {
+            client
+                .post(
+                    "/api/broadcast",
+                    json!({ "message": message, "filter": filter }),
+                )
+                .await?
+        }
+        AdminAction::Trace { username, off } => {
+            client
+                .post(
+                    "/api/trace",
+                    json!({ "username": username, "enabled": !off }),
+                )
+                .await?
+        }
+        AdminAction::ReloadWords => client.post("/api/reload-words", Value::Null).await?,
+    };
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let options = Options::from_args();
-
-    flexi_logger::Logger::with_env_or_str("debug").start()?;
-    log::info!("IE::Net server starting up...");
-
-    server::run(options.bind).await
+    match options.command {
+        Some(Command::Serve(serve_options)) => serve(serve_options).await,
+        Some(Command::Admin(admin_options)) => admin(admin_options).await,
+        None => serve(options.serve).await,
+    }
 }
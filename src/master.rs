@@ -0,0 +1,78 @@
+use crate::broker::control::ControlCommand;
+use crate::broker::{Event, EventSender};
+use crate::config::{MasterConfig, MasterProtocol};
+use crate::http::post_json;
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, watch};
+
+async fn build_announcement(
+    config: &MasterConfig,
+    game_versions: &[String],
+    broker: &mut EventSender,
+) -> Result<String> {
+    let (respond_to, response) = oneshot::channel();
+    broker
+        .send(Event::Control {
+            command: ControlCommand::DumpState { respond_to },
+        })
+        .await?;
+    let snapshot = response.await?;
+    Ok(json!({
+        "name": config.server_name,
+        "address": config.public_address,
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "game_versions": game_versions,
+        "users_online": snapshot.users.len(),
+        "channels": snapshot.channels.len(),
+        "games_open": snapshot.open_games.len(),
+    })
+    .to_string())
+}
+
+async fn announce(
+    config: &MasterConfig,
+    game_versions: &[String],
+    broker: &mut EventSender,
+) -> Result<()> {
+    let announcement = build_announcement(config, game_versions, broker).await?;
+    match config.protocol {
+        MasterProtocol::Http => {
+            match post_json(&config.address, &config.path, &announcement).await? {
+                200..=299 => Ok(()),
+                status => Err(anyhow!("Master server responded with status {}", status)),
+            }
+        }
+        MasterProtocol::Udp => {
+            let mut socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(&config.address).await?;
+            socket.send(announcement.as_bytes()).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Periodically announces this server to a master server so players can discover it
+pub async fn registration_loop(
+    config: MasterConfig,
+    game_versions: Vec<String>,
+    mut broker: EventSender,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                match announce(&config, &game_versions, &mut broker).await {
+                    Ok(()) => log::debug!("Announced server to master server {}", config.address),
+                    Err(e) => log::warn!("Failed to announce server to master server: {}", e),
+                }
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+        }
+    }
+    log::info!("Master server registration shutting down");
+    Ok(())
+}
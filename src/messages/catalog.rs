@@ -0,0 +1,264 @@
+/// Languages server messages are translated to. The game client tells its language in the
+/// ident; anything unknown gets English.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Language {
+    #[default]
+    English,
+    German,
+    French,
+    Polish,
+}
+
+impl Language {
+    pub fn from_client(name: &str) -> Self {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "german" | "deutsch" | "de" => Language::German,
+            "french" | "français" | "francais" | "fr" => Language::French,
+            "polish" | "polski" | "pl" => Language::Polish,
+            _ => Language::English,
+        }
+    }
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Language::English => &[],
+            Language::German => GERMAN,
+            Language::French => FRENCH,
+            Language::Polish => POLISH,
+        }
+    }
+
+    /// Translates a server text. Entries containing `{}` match texts that start and end the
+    /// same way, with the part in between carried over. Texts without a translation stay in
+    /// English.
+    pub fn translate(self, text: &str) -> String {
+        for (english, translated) in self.catalog() {
+            if let Some((prefix, suffix)) = english.split_once("{}") {
+                let inner = text
+                    .strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_suffix(suffix));
+                if let Some(inner) = inner {
+                    return translated.replacen("{}", inner, 1);
+                }
+            } else if *english == text {
+                return translated.to_string();
+            }
+        }
+        text.to_string()
+    }
+
+    /// Translates a server text and encodes it in the Windows code page the game uses for
+    /// the language. English texts are passed through as they are.
+    pub fn localize(self, text: &str) -> Vec<u8> {
+        match self {
+            Language::English => text.as_bytes().to_vec(),
+            Language::German | Language::French => self
+                .translate(text)
+                .chars()
+                // Windows-1252 agrees with Latin-1 on the letters
+                .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+                .collect(),
+            Language::Polish => self.translate(text).chars().map(windows_1250).collect(),
+        }
+    }
+}
+
+fn windows_1250(c: char) -> u8 {
+    match c {
+        'ą' => 0xb9,
+        'ć' => 0xe6,
+        'ę' => 0xea,
+        'ł' => 0xb3,
+        'ń' => 0xf1,
+        'ó' => 0xf3,
+        'ś' => 0x9c,
+        'ź' => 0x9f,
+        'ż' => 0xbf,
+        'Ą' => 0xa5,
+        'Ć' => 0xc6,
+        'Ę' => 0xca,
+        'Ł' => 0xa3,
+        'Ń' => 0xd1,
+        'Ó' => 0xd3,
+        'Ś' => 0x8c,
+        'Ź' => 0x8f,
+        'Ż' => 0xaf,
+        c if c.is_ascii() => c as u8,
+        _ => b'?',
+    }
+}
+
+const GERMAN: &[(&str, &str)] = &[
+    (
+        "Welcome to IE::Net, a community-operated EarthNet server",
+        "Willkommen bei IE::Net, einem von der Community betriebenen EarthNet-Server",
+    ),
+    (
+        "Wrong game version. Please install one of: {}",
+        "Falsche Spielversion. Bitte installiere eine von: {}",
+    ),
+    ("Wrong password", "Falsches Passwort"),
+    (
+        "You are banned from this server",
+        "Du bist von diesem Server verbannt",
+    ),
+    ("User does not exist", "Benutzer existiert nicht"),
+    ("User is not online", "Benutzer ist nicht online"),
+    ("Channel does not exist", "Kanal existiert nicht"),
+    ("Invalid channel name", "Ungültiger Kanalname"),
+    ("Game does not exist", "Spiel existiert nicht"),
+    ("Game already exists.", "Spiel existiert bereits."),
+    ("Game is full", "Spiel ist voll"),
+    ("Game has already started", "Spiel hat bereits begonnen"),
+    ("Invalid game name", "Ungültiger Spielname"),
+    ("Invalid password", "Ungültiges Passwort"),
+    ("Spectators are not allowed", "Zuschauer sind nicht erlaubt"),
+    (
+        "You are not hosting an open game",
+        "Du hostest kein offenes Spiel",
+    ),
+    (
+        "You are not hosting a running game",
+        "Du hostest kein laufendes Spiel",
+    ),
+    (
+        "IPv6 connections are not supported, please connect over IPv4",
+        "IPv6-Verbindungen werden nicht unterstützt, bitte verbinde dich über IPv4",
+    ),
+    (
+        "Hosting games needs an IPv4 connection",
+        "Zum Hosten von Spielen wird eine IPv4-Verbindung benötigt",
+    ),
+    ("You are not in a game", "Du bist in keinem Spiel"),
+    ("The name {} is taken", "Der Name {} ist vergeben"),
+];
+
+const FRENCH: &[(&str, &str)] = &[
+    (
+        "Welcome to IE::Net, a community-operated EarthNet server",
+        "Bienvenue sur IE::Net, un serveur EarthNet géré par la communauté",
+    ),
+    (
+        "Wrong game version. Please install one of: {}",
+        "Mauvaise version du jeu. Veuillez installer l'une de : {}",
+    ),
+    ("Wrong password", "Mot de passe incorrect"),
+    (
+        "You are banned from this server",
+        "Vous êtes banni de ce serveur",
+    ),
+    ("User does not exist", "L'utilisateur n'existe pas"),
+    ("User is not online", "L'utilisateur n'est pas en ligne"),
+    ("Channel does not exist", "Le canal n'existe pas"),
+    ("Invalid channel name", "Nom de canal invalide"),
+    ("Game does not exist", "La partie n'existe pas"),
+    ("Game already exists.", "La partie existe déjà."),
+    ("Game is full", "La partie est pleine"),
+    ("Game has already started", "La partie a déjà commencé"),
+    ("Invalid game name", "Nom de partie invalide"),
+    ("Invalid password", "Mot de passe invalide"),
+    (
+        "Spectators are not allowed",
+        "Les spectateurs ne sont pas autorisés",
+    ),
+    (
+        "You are not hosting an open game",
+        "Vous n'hébergez pas de partie ouverte",
+    ),
+    (
+        "You are not hosting a running game",
+        "Vous n'hébergez pas de partie en cours",
+    ),
+    (
+        "IPv6 connections are not supported, please connect over IPv4",
+        "Les connexions IPv6 ne sont pas prises en charge, veuillez vous connecter en IPv4",
+    ),
+    (
+        "Hosting games needs an IPv4 connection",
+        "Héberger une partie nécessite une connexion IPv4",
+    ),
+    ("You are not in a game", "Vous n'êtes pas dans une partie"),
+    ("The name {} is taken", "Le nom {} est déjà pris"),
+];
+
+const POLISH: &[(&str, &str)] = &[
+    (
+        "Welcome to IE::Net, a community-operated EarthNet server",
+        "Witaj w IE::Net, serwerze EarthNet prowadzonym przez społeczność",
+    ),
+    (
+        "Wrong game version. Please install one of: {}",
+        "Nieprawidłowa wersja gry. Zainstaluj jedną z: {}",
+    ),
+    ("Wrong password", "Nieprawidłowe hasło"),
+    (
+        "You are banned from this server",
+        "Masz zakaz wstępu na ten serwer",
+    ),
+    ("User does not exist", "Użytkownik nie istnieje"),
+    ("User is not online", "Użytkownik nie jest online"),
+    ("Channel does not exist", "Kanał nie istnieje"),
+    ("Invalid channel name", "Nieprawidłowa nazwa kanału"),
+    ("Game does not exist", "Gra nie istnieje"),
+    ("Game already exists.", "Gra już istnieje."),
+    ("Game is full", "Gra jest pełna"),
+    ("Game has already started", "Gra już się rozpoczęła"),
+    ("Invalid game name", "Nieprawidłowa nazwa gry"),
+    ("Invalid password", "Nieprawidłowe hasło"),
+    (
+        "Spectators are not allowed",
+        "Obserwatorzy nie są dozwoleni",
+    ),
+    (
+        "You are not hosting an open game",
+        "Nie hostujesz otwartej gry",
+    ),
+    (
+        "You are not hosting a running game",
+        "Nie hostujesz trwającej gry",
+    ),
+    (
+        "IPv6 connections are not supported, please connect over IPv4",
+        "Połączenia IPv6 nie są obsługiwane, połącz się przez IPv4",
+    ),
+    (
+        "Hosting games needs an IPv4 connection",
+        "Hostowanie gier wymaga połączenia IPv4",
+    ),
+    ("You are not in a game", "Nie jesteś w grze"),
+    ("The name {} is taken", "Nazwa {} jest zajęta"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(Language::from_client("Deutsch"), Language::German);
+        assert_eq!(Language::from_client("Klingon"), Language::English);
+        assert_eq!(Language::German.translate("Game is full"), "Spiel ist voll");
+        assert_eq!(
+            Language::French.translate("Wrong game version. Please install one of: tmp2.2"),
+            "Mauvaise version du jeu. Veuillez installer l'une de : tmp2.2"
+        );
+        assert_eq!(Language::Polish.translate("Spam"), "Spam");
+        assert_eq!(Language::English.translate("Game is full"), "Game is full");
+        assert_eq!(
+            Language::German.localize("Invalid channel name"),
+            b"Ung\xfcltiger Kanalname"
+        );
+        assert_eq!(
+            Language::Polish.localize("Game is full"),
+            b"Gra jest pe\xb3na"
+        );
+        assert_eq!(
+            Language::French.translate("The name Oscar is taken"),
+            "Le nom Oscar est déjà pris"
+        );
+        assert_eq!(
+            Language::French.translate("The name Oscar is reserved"),
+            "The name Oscar is reserved"
+        );
+    }
+}
@@ -1,6 +1,83 @@
 use crate::messages::raw_command::{try_parse_raw_command, RawCommand};
 use crate::util::bytevec_to_str;
 use anyhow::Result;
+use bytes::BytesMut;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum MacroAction {
+    Set { name: String, text: Vec<u8> },
+    Remove { name: String },
+    List,
+}
+
+#[derive(Debug)]
+pub enum MailAction {
+    Read,
+    Clear,
+}
+
+/// Conditions of `/games`, all of which a listed game meets
+#[derive(Debug, Default, PartialEq)]
+pub struct GameFilter {
+    /// part of the game name
+    pub name: Option<String>,
+    /// only games of the same game version as the user's
+    pub same_version: bool,
+    pub without_password: bool,
+    pub with_free_slots: bool,
+    /// only games hosted from the user's region
+    pub same_region: bool,
+    /// only games their host tagged like this
+    pub tag: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ClanAction {
+    Create { tag: String, name: String },
+    Invite { username: String },
+    Join { tag: String },
+    Kick { username: String },
+    Leave,
+    Info { tag: Option<String> },
+}
+
+#[derive(Debug)]
+pub enum TournamentAction {
+    Create {
+        name: String,
+    },
+    Start {
+        name: String,
+    },
+    /// decides a match without it being played
+    Advance {
+        game_name: String,
+        winner: String,
+    },
+    Cancel {
+        name: String,
+    },
+    Status {
+        name: String,
+    },
+}
+
+/// The entries of a user's profile that /setinfo changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileField {
+    Country,
+    Faction,
+}
+
+impl fmt::Display for ProfileField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Country => write!(f, "country"),
+            Self::Faction => write!(f, "favorite faction"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum ClientCommand {
@@ -17,14 +94,127 @@ pub enum ClientCommand {
     HostGame {
         game_name: String,
         password_or_guid: Vec<u8>,
+        /// region or locale the host labels the game with, like EU or de
+        tag: Option<String>,
     },
     JoinGame {
         game_name: String,
         password: Vec<u8>,
+        /// watch a game instead of playing in it, which started games only allow
+        spectator: bool,
+    },
+    GameResult {
+        winner: String,
+    },
+    History {
+        username: Option<String>,
+    },
+    Rank {
+        username: Option<String>,
+    },
+    Top10,
+    /// `/stats`, shows how busy the server is and how long it has been running
+    Stats,
+    /// `/version`, shows the server build, the accepted game versions and protocol extensions
+    Version,
+    DeleteAccount {
+        token: Option<String>,
+    },
+    /// `/resume <token>`, returns to where the user was before losing the connection
+    Resume {
+        token: String,
+    },
+    Pin {
+        target: String,
+    },
+    Unpin {
+        target: String,
+    },
+    Macro {
+        action: MacroAction,
+    },
+    /// `/hostport [port]`, declares the port the user's game listens on, or resets it
+    HostPort {
+        port: Option<u16>,
+    },
+    /// `/g <name>`, sends the text of one of the user's macros
+    ExpandMacro {
+        name: String,
+    },
+    /// `/approve <user>`, lets a user who knocked on the host's game join it
+    Approve {
+        username: String,
+    },
+    /// `/mail [clear]`, lists or deletes the messages received while offline
+    Mail {
+        action: MailAction,
+    },
+    /// `/setinfo <country|faction> [value]`, changes or clears a profile entry
+    SetInfo {
+        field: ProfileField,
+        value: Option<String>,
+    },
+    /// `/finger <user>`, shows where a user is and their profile
+    Finger {
+        username: String,
+    },
+    /// `/clan <create|invite|join|kick|leave|info> ...`, manages clan membership
+    Clan {
+        action: ClanAction,
+    },
+    /// `/tournament <create|start|cancel|status> <name>` or `/tournament advance <winner> <game>`
+    Tournament {
+        action: TournamentAction,
+    },
+    /// `/signup <tournament>`
+    SignUp {
+        tournament: String,
+    },
+    /// `/games [free] [nopassword] [version] [region] [tag:<tag>] [name]`, lists the open games that match
+    Games {
+        filter: GameFilter,
+    },
+    /// `/list`, shows the channels with their user counts and the open games
+    List,
+    /// `/ping [game]`, shows how far the user or the game's host is from the server
+    Ping {
+        game_name: Option<String>,
+    },
+    /// `/find <term>`, searches channel and game names
+    Find {
+        term: String,
+    },
+    /// `/slowmode <seconds>`, limits how often each user may speak in the current channel
+    Slowmode {
+        secs: u64,
+    },
+    /// `/maxusers <count|off>`, limits how many users may join the current channel
+    MaxUsers {
+        max_users: Option<usize>,
+    },
+    /// `/announce <text>`, sends a server notice to everyone online
+    Announce {
+        text: Vec<u8>,
+    },
+    /// `/notice <user> <text>`, sends a server notice to one user
+    Notice {
+        username: String,
+        text: Vec<u8>,
+    },
+    /// `/invisible <on|off>`, hides an admin from other users, also on later logins
+    Invisible {
+        enabled: bool,
+    },
+    /// `/leave` or `/part`, takes the user from their game back to the lobby
+    LeaveGame,
+    /// `/nick <name>`, changes the user's name for the rest of the session
+    Nick {
+        new_name: String,
     },
     NoOp,
     Unknown {
         command: String,
+        params: Vec<Vec<u8>>,
     },
     Malformed {
         reason: String,
@@ -37,7 +227,7 @@ fn concat_params(params: &[Vec<u8>]) -> Vec<u8> {
         if i != 0 {
             result.push(0x20); // space separator
         }
-        result.extend_from_slice(&param);
+        result.extend_from_slice(param);
     }
     result
 }
@@ -85,6 +275,7 @@ fn hostgame_from_raw(raw: &RawCommand) -> ClientCommand {
     ClientCommand::HostGame {
         game_name: String::from_utf8_lossy(&raw.params[1]).to_string(),
         password_or_guid: raw.params[2].to_vec(),
+        tag: raw.params.get(3).map(|tag| bytevec_to_str(tag)),
     }
 }
 
@@ -97,6 +288,329 @@ fn joingame_from_raw(raw: &RawCommand) -> ClientCommand {
     ClientCommand::JoinGame {
         game_name: String::from_utf8_lossy(&raw.params[1]).to_string(),
         password: raw.params[2].to_vec(),
+        spectator: raw
+            .params
+            .get(3)
+            .is_some_and(|p| p.eq_ignore_ascii_case(b"spectator")),
+    }
+}
+
+fn gameresult_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Missing parameters for /gameresult".to_string(),
+        };
+    }
+    ClientCommand::GameResult {
+        winner: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn history_from_raw(raw: &RawCommand) -> ClientCommand {
+    ClientCommand::History {
+        username: raw.params.first().map(|p| bytevec_to_str(p)),
+    }
+}
+
+fn rank_from_raw(raw: &RawCommand) -> ClientCommand {
+    ClientCommand::Rank {
+        username: raw.params.first().map(|p| bytevec_to_str(p)),
+    }
+}
+
+fn pin_from_raw(raw: &RawCommand, pin: bool) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: format!("Missing parameters for /{}", raw.command),
+        };
+    }
+    let target = bytevec_to_str(&raw.params[0]);
+    if pin {
+        ClientCommand::Pin { target }
+    } else {
+        ClientCommand::Unpin { target }
+    }
+}
+
+fn deleteaccount_from_raw(raw: &RawCommand) -> ClientCommand {
+    ClientCommand::DeleteAccount {
+        token: raw.params.first().map(|p| bytevec_to_str(p)),
+    }
+}
+
+fn resume_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Usage: /resume <token>".to_string(),
+        };
+    }
+    ClientCommand::Resume {
+        token: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn macro_from_raw(raw: &RawCommand) -> ClientCommand {
+    let action = match (raw.params.first().map(|p| p.as_slice()), raw.params.len()) {
+        (Some(b"set"), n) if n >= 3 => MacroAction::Set {
+            name: bytevec_to_str(&raw.params[1]),
+            text: concat_params(&raw.params[2..]),
+        },
+        (Some(b"del"), 2) => MacroAction::Remove {
+            name: bytevec_to_str(&raw.params[1]),
+        },
+        (Some(b"list"), 1) => MacroAction::List,
+        _ => {
+            return ClientCommand::Malformed {
+                reason: "Usage: /macro set <name> <text>, /macro del <name> or /macro list"
+                    .to_string(),
+            }
+        }
+    };
+    ClientCommand::Macro { action }
+}
+
+fn expandmacro_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Missing parameters for /g".to_string(),
+        };
+    }
+    ClientCommand::ExpandMacro {
+        name: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn approve_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Missing parameters for /approve".to_string(),
+        };
+    }
+    ClientCommand::Approve {
+        username: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn mail_from_raw(raw: &RawCommand) -> ClientCommand {
+    let action = match raw.params.first().map(|p| p.as_slice()) {
+        None => MailAction::Read,
+        Some(b"clear") if raw.params.len() == 1 => MailAction::Clear,
+        _ => {
+            return ClientCommand::Malformed {
+                reason: "Usage: /mail or /mail clear".to_string(),
+            }
+        }
+    };
+    ClientCommand::Mail { action }
+}
+
+fn setinfo_from_raw(raw: &RawCommand) -> ClientCommand {
+    let field = match raw.params.first().map(|p| p.as_slice()) {
+        Some(b"country") => ProfileField::Country,
+        Some(b"faction") => ProfileField::Faction,
+        _ => {
+            return ClientCommand::Malformed {
+                reason: "Usage: /setinfo <country|faction> [value]".to_string(),
+            }
+        }
+    };
+    let value = match raw.params.len() {
+        1 => None,
+        _ => Some(bytevec_to_str(&concat_params(&raw.params[1..]))),
+    };
+    ClientCommand::SetInfo { field, value }
+}
+
+fn finger_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Usage: /finger <user>".to_string(),
+        };
+    }
+    ClientCommand::Finger {
+        username: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn nick_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.len() != 1 {
+        return ClientCommand::Malformed {
+            reason: "Usage: /nick <name>".to_string(),
+        };
+    }
+    ClientCommand::Nick {
+        new_name: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn invisible_from_raw(raw: &RawCommand) -> ClientCommand {
+    match raw.params.first().map(|p| p.to_ascii_lowercase()) {
+        Some(param) if param == b"on" => ClientCommand::Invisible { enabled: true },
+        Some(param) if param == b"off" => ClientCommand::Invisible { enabled: false },
+        _ => ClientCommand::Malformed {
+            reason: "Usage: /invisible <on|off>".to_string(),
+        },
+    }
+}
+
+fn clan_from_raw(raw: &RawCommand) -> ClientCommand {
+    let param = |i: usize| bytevec_to_str(&raw.params[i]);
+    let action = match (raw.params.first().map(|p| p.as_slice()), raw.params.len()) {
+        (None, _) => ClanAction::Info { tag: None },
+        (Some(b"info"), 1) => ClanAction::Info { tag: None },
+        (Some(b"info"), 2) => ClanAction::Info {
+            tag: Some(param(1)),
+        },
+        (Some(b"create"), n) if n >= 3 => ClanAction::Create {
+            tag: param(1),
+            name: bytevec_to_str(&concat_params(&raw.params[2..])),
+        },
+        (Some(b"invite"), 2) => ClanAction::Invite { username: param(1) },
+        (Some(b"join"), 2) => ClanAction::Join { tag: param(1) },
+        (Some(b"kick"), 2) => ClanAction::Kick { username: param(1) },
+        (Some(b"leave"), 1) => ClanAction::Leave,
+        _ => {
+            return ClientCommand::Malformed {
+                reason: "Usage: /clan create <tag> <name>, /clan invite <user>, /clan join <tag>, \
+                    /clan kick <user>, /clan leave or /clan info [tag]"
+                    .to_string(),
+            }
+        }
+    };
+    ClientCommand::Clan { action }
+}
+
+fn tournament_from_raw(raw: &RawCommand) -> ClientCommand {
+    let param = |i: usize| bytevec_to_str(&raw.params[i]);
+    let action = match (raw.params.first().map(|p| p.as_slice()), raw.params.len()) {
+        (Some(b"create"), 2) => TournamentAction::Create { name: param(1) },
+        (Some(b"start"), 2) => TournamentAction::Start { name: param(1) },
+        (Some(b"cancel"), 2) => TournamentAction::Cancel { name: param(1) },
+        (Some(b"status"), 2) => TournamentAction::Status { name: param(1) },
+        (Some(b"advance"), n) if n >= 3 => TournamentAction::Advance {
+            winner: param(1),
+            game_name: bytevec_to_str(&concat_params(&raw.params[2..])),
+        },
+        _ => {
+            return ClientCommand::Malformed {
+                reason: "Usage: /tournament <create|start|cancel|status> <name> \
+                    or /tournament advance <winner> <game>"
+                    .to_string(),
+            }
+        }
+    };
+    ClientCommand::Tournament { action }
+}
+
+fn signup_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Usage: /signup <tournament>".to_string(),
+        };
+    }
+    ClientCommand::SignUp {
+        tournament: bytevec_to_str(&raw.params[0]),
+    }
+}
+
+fn find_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Usage: /find <term>".to_string(),
+        };
+    }
+    ClientCommand::Find {
+        term: bytevec_to_str(&concat_params(&raw.params[..])),
+    }
+}
+
+fn games_from_raw(raw: &RawCommand) -> ClientCommand {
+    let mut filter = GameFilter::default();
+    let mut name = Vec::new();
+    for param in &raw.params {
+        match param.to_ascii_lowercase().as_slice() {
+            b"free" => filter.with_free_slots = true,
+            b"nopassword" => filter.without_password = true,
+            b"version" => filter.same_version = true,
+            b"region" => filter.same_region = true,
+            [b't', b'a', b'g', b':', tag @ ..] => filter.tag = Some(bytevec_to_str(tag)),
+            _ => name.push(param.clone()),
+        }
+    }
+    if !name.is_empty() {
+        filter.name = Some(bytevec_to_str(&concat_params(&name)));
+    }
+    ClientCommand::Games { filter }
+}
+
+fn ping_from_raw(raw: &RawCommand) -> ClientCommand {
+    ClientCommand::Ping {
+        game_name: Some(&raw.params)
+            .filter(|params| !params.is_empty())
+            .map(|params| bytevec_to_str(&concat_params(params))),
+    }
+}
+
+fn slowmode_from_raw(raw: &RawCommand) -> ClientCommand {
+    let secs = match raw.params.first().map(|p| bytevec_to_str(p)) {
+        Some(param) if param.eq_ignore_ascii_case("off") => Some(0),
+        Some(param) => param.parse().ok(),
+        None => None,
+    };
+    match secs {
+        Some(secs) => ClientCommand::Slowmode { secs },
+        None => ClientCommand::Malformed {
+            reason: "Usage: /slowmode <seconds|off>".to_string(),
+        },
+    }
+}
+
+fn maxusers_from_raw(raw: &RawCommand) -> ClientCommand {
+    let max_users = match raw.params.first().map(|p| bytevec_to_str(p)) {
+        Some(param) if param.eq_ignore_ascii_case("off") => Some(None),
+        Some(param) => param.parse().ok().map(Some),
+        None => None,
+    };
+    match max_users {
+        Some(max_users) => ClientCommand::MaxUsers { max_users },
+        None => ClientCommand::Malformed {
+            reason: "Usage: /maxusers <count|off>".to_string(),
+        },
+    }
+}
+
+fn announce_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.is_empty() {
+        return ClientCommand::Malformed {
+            reason: "Usage: /announce <text>".to_string(),
+        };
+    }
+    ClientCommand::Announce {
+        text: concat_params(&raw.params[..]),
+    }
+}
+
+fn notice_from_raw(raw: &RawCommand) -> ClientCommand {
+    if raw.params.len() < 2 {
+        return ClientCommand::Malformed {
+            reason: "Usage: /notice <user> <text>".to_string(),
+        };
+    }
+    ClientCommand::Notice {
+        username: bytevec_to_str(&raw.params[0]),
+        text: concat_params(&raw.params[1..]),
+    }
+}
+
+fn hostport_from_raw(raw: &RawCommand) -> ClientCommand {
+    match raw.params.first() {
+        None => ClientCommand::HostPort { port: None },
+        Some(param) => match bytevec_to_str(param).parse() {
+            Ok(port) => ClientCommand::HostPort { port: Some(port) },
+            Err(_) => ClientCommand::Malformed {
+                reason: "Usage: /hostport [port]".to_string(),
+            },
+        },
     }
 }
 
@@ -110,22 +624,97 @@ fn match_raw_command(raw: RawCommand) -> ClientCommand {
         "playv" => ClientCommand::NoOp,
         "playd" => ClientCommand::NoOp,
         "playi" => ClientCommand::NoOp,
+        "gameresult" => gameresult_from_raw(&raw),
+        "history" => history_from_raw(&raw),
+        "rank" => rank_from_raw(&raw),
+        "top10" => ClientCommand::Top10,
+        "stats" => ClientCommand::Stats,
+        "version" => ClientCommand::Version,
+        "deleteaccount" => deleteaccount_from_raw(&raw),
+        "resume" => resume_from_raw(&raw),
+        "pin" => pin_from_raw(&raw, true),
+        "unpin" => pin_from_raw(&raw, false),
+        "macro" => macro_from_raw(&raw),
+        "g" => expandmacro_from_raw(&raw),
+        "hostport" => hostport_from_raw(&raw),
+        "approve" => approve_from_raw(&raw),
+        "games" => games_from_raw(&raw),
+        "list" => ClientCommand::List,
+        "ping" => ping_from_raw(&raw),
+        "find" => find_from_raw(&raw),
+        "leave" | "part" => ClientCommand::LeaveGame,
+        "nick" => nick_from_raw(&raw),
+        "invisible" => invisible_from_raw(&raw),
+        "slowmode" => slowmode_from_raw(&raw),
+        "maxusers" => maxusers_from_raw(&raw),
+        "announce" => announce_from_raw(&raw),
+        "notice" => notice_from_raw(&raw),
+        "mail" => mail_from_raw(&raw),
+        "setinfo" => setinfo_from_raw(&raw),
+        "finger" => finger_from_raw(&raw),
+        "clan" => clan_from_raw(&raw),
+        "tournament" => tournament_from_raw(&raw),
+        "signup" => signup_from_raw(&raw),
         "nop" => ClientCommand::NoOp,
         _ => ClientCommand::Unknown {
             command: raw.command,
+            params: raw.params,
         },
     }
 }
 
 impl ClientCommand {
-    pub fn try_parse(data: &mut Vec<u8>) -> Result<Option<ClientCommand>> {
+    /// Name of the metric that records how long the broker takes to handle the command
+    pub fn metric_name(&self) -> &'static str {
+        match self {
+            ClientCommand::Send { .. } => "commands.send",
+            ClientCommand::PrivateMessage { .. } => "commands.private_message",
+            ClientCommand::Join { .. } => "commands.join",
+            ClientCommand::HostGame { .. } => "commands.host_game",
+            ClientCommand::JoinGame { .. } => "commands.join_game",
+            ClientCommand::GameResult { .. } => "commands.game_result",
+            ClientCommand::History { .. } => "commands.history",
+            ClientCommand::Rank { .. } => "commands.rank",
+            ClientCommand::Top10 => "commands.top10",
+            ClientCommand::Stats => "commands.stats",
+            ClientCommand::Version => "commands.version",
+            ClientCommand::DeleteAccount { .. } => "commands.delete_account",
+            ClientCommand::Resume { .. } => "commands.resume",
+            ClientCommand::Pin { .. } => "commands.pin",
+            ClientCommand::Unpin { .. } => "commands.unpin",
+            ClientCommand::Macro { .. } => "commands.macro",
+            ClientCommand::HostPort { .. } => "commands.host_port",
+            ClientCommand::ExpandMacro { .. } => "commands.expand_macro",
+            ClientCommand::Approve { .. } => "commands.approve",
+            ClientCommand::Mail { .. } => "commands.mail",
+            ClientCommand::SetInfo { .. } => "commands.set_info",
+            ClientCommand::Finger { .. } => "commands.finger",
+            ClientCommand::Clan { .. } => "commands.clan",
+            ClientCommand::Tournament { .. } => "commands.tournament",
+            ClientCommand::SignUp { .. } => "commands.sign_up",
+            ClientCommand::Games { .. } => "commands.games",
+            ClientCommand::List => "commands.list",
+            ClientCommand::Ping { .. } => "commands.ping",
+            ClientCommand::Find { .. } => "commands.find",
+            ClientCommand::Slowmode { .. } => "commands.slowmode",
+            ClientCommand::MaxUsers { .. } => "commands.max_users",
+            ClientCommand::Announce { .. } => "commands.announce",
+            ClientCommand::Notice { .. } => "commands.notice",
+            ClientCommand::Invisible { .. } => "commands.invisible",
+            ClientCommand::LeaveGame => "commands.leave_game",
+            ClientCommand::Nick { .. } => "commands.nick",
+            ClientCommand::NoOp => "commands.no_op",
+            ClientCommand::Unknown { .. } => "commands.unknown",
+            ClientCommand::Malformed { .. } => "commands.malformed",
+        }
+    }
+
+    /// Splits the next NUL-terminated command off the front of the buffer without copying
+    pub fn try_parse(data: &mut BytesMut) -> Result<Option<ClientCommand>> {
         if let Some(position) = data.iter().position(|c| *c == 0) {
-            let message_bytes = data.drain(..position + 1);
-            log::debug!(
-                "Received message: {}",
-                bytevec_to_str(message_bytes.as_slice())
-            );
-            return match try_parse_raw_command(&message_bytes.as_slice()[..position]) {
+            let message_bytes = data.split_to(position + 1);
+            log::debug!("Received message: {}", bytevec_to_str(&message_bytes));
+            return match try_parse_raw_command(&message_bytes[..position]) {
                 Ok(raw) => Ok(Some(match_raw_command(raw))),
                 Err(_) => Ok(Some(ClientCommand::Malformed {
                     reason: "Received message is invalid".to_string(),
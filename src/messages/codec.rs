@@ -0,0 +1,178 @@
+use crate::messages::client_command::ClientCommand;
+use crate::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use crate::protocol_trace::{Direction, ProtocolTrace};
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use std::sync::Arc;
+use tokio_util::codec::{Decoder, Encoder};
+use uuid::Uuid;
+
+/// No valid message leaves this much unparsed data behind, so a client exceeding it is dropped
+const MAX_RECEIVE_BUFFER: usize = 4096;
+
+/// What the game client is expected to send next. Which stage follows depends on how the
+/// server answers, so the connection handler advances the codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Ident,
+    Login,
+    Commands,
+}
+
+/// A message from the game client, as framed on the wire
+#[derive(Debug)]
+pub enum ClientFrame {
+    Ident(IdentFrame),
+    Login(LoginClientMessage),
+    Command(ClientCommand),
+}
+
+/// The size of the ident tells apart game builds, so it is kept along with the message
+#[derive(Debug)]
+pub struct IdentFrame {
+    pub message: IdentClientMessage,
+    /// bytes on the wire, compressed
+    pub size: usize,
+}
+
+/// The EarthNet framing: zlib-compressed, length-prefixed ident and login messages, followed
+/// by NUL-terminated commands once logged in. Server messages are prepared in full before
+/// they are sent, so encoding just passes their bytes on.
+#[derive(Debug)]
+pub struct EarthNetCodec {
+    stage: Stage,
+    client_id: Uuid,
+    protocol_trace: ProtocolTrace,
+}
+
+impl EarthNetCodec {
+    /// A codec for a fresh connection, which logs the frames it decodes to the session's trace
+    pub fn new(client_id: Uuid, protocol_trace: ProtocolTrace) -> Self {
+        Self {
+            stage: Stage::Ident,
+            client_id,
+            protocol_trace,
+        }
+    }
+
+    pub fn set_stage(&mut self, stage: Stage) {
+        self.stage = stage;
+    }
+}
+
+impl Decoder for EarthNetCodec {
+    type Item = ClientFrame;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ClientFrame>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let initially_available = src.len();
+        // parsing consumes the frame, so keep a copy around for the trace
+        let unparsed = self.protocol_trace.is_enabled().then(|| src.clone());
+        let frame = match self.stage {
+            Stage::Ident => IdentClientMessage::try_parse(src)?.map(|message| {
+                ClientFrame::Ident(IdentFrame {
+                    message,
+                    size: initially_available - src.len(),
+                })
+            }),
+            Stage::Login => LoginClientMessage::try_parse(src)?.map(ClientFrame::Login),
+            Stage::Commands => ClientCommand::try_parse(src)?.map(ClientFrame::Command),
+        };
+        match frame {
+            Some(frame) => {
+                if let Some(unparsed) = unparsed {
+                    let raw = &unparsed[..initially_available - src.len()];
+                    self.protocol_trace
+                        .log_frame(self.client_id, Direction::Received, raw);
+                }
+                Ok(Some(frame))
+            }
+            None if src.len() > MAX_RECEIVE_BUFFER => Err(anyhow!(
+                "{} bytes pending without a complete message",
+                src.len()
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// A client may well hang up in the middle of a message, which is no error
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<ClientFrame>> {
+        self.decode(src)
+    }
+}
+
+impl Encoder<Arc<[u8]>> for EarthNetCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, prepared: Arc<[u8]>, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&prepared);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> EarthNetCodec {
+        EarthNetCodec::new(Uuid::new_v4(), ProtocolTrace::default())
+    }
+
+    #[test]
+    fn test_decode_stages() {
+        let ident = IdentClientMessage {
+            game_version: Uuid::new_v4(),
+            language: b"English".to_vec(),
+            extra_bytes: 0,
+        }
+        .prepare_message()
+        .unwrap();
+        let login = LoginClientMessage {
+            username: b"foo".to_vec(),
+            password: b"".to_vec(),
+            extra_bytes: 0,
+        }
+        .prepare_message()
+        .unwrap();
+        let mut codec = codec();
+        let mut src = BytesMut::from(&ident[..ident.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&ident[ident.len() - 1..]);
+        src.extend_from_slice(&login);
+        src.extend_from_slice(b"/join \"Lounge\"\0/pon");
+        match codec.decode(&mut src).unwrap() {
+            Some(ClientFrame::Ident(frame)) => assert_eq!(frame.size, ident.len()),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        codec.set_stage(Stage::Login);
+        match codec.decode(&mut src).unwrap() {
+            Some(ClientFrame::Login(login)) => assert_eq!(login.username, b"foo"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        codec.set_stage(Stage::Commands);
+        match codec.decode(&mut src).unwrap() {
+            Some(ClientFrame::Command(ClientCommand::Join { channel })) => {
+                assert_eq!(channel, "Lounge")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_garbage() {
+        let mut codec = codec();
+        let mut src = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        assert!(codec.decode(&mut src).is_err());
+
+        codec.set_stage(Stage::Commands);
+        let mut src = BytesMut::from(&[b'x'; MAX_RECEIVE_BUFFER + 1][..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
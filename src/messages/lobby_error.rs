@@ -0,0 +1,122 @@
+use crate::messages::catalog::Language;
+use std::fmt;
+
+/// Errors that clients commonly run into. Each has a stable code, so that tooling does not have
+/// to match on texts, and an English text that the catalog translates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LobbyError {
+    UserNotFound,
+    UserNotOnline,
+    ChannelNotFound,
+    InvalidChannelName,
+    GameNotFound,
+    GameExists,
+    GameFull,
+    GameStarted,
+    InvalidGameName,
+    InvalidPassword,
+    SpectatorsNotAllowed,
+    NotInGame,
+    NotHostingOpenGame,
+    NotHostingRunningGame,
+    HostingNeedsIpv4,
+    NameTaken(String),
+}
+
+impl LobbyError {
+    /// Identifies the error independently of its text, which may be reworded or translated
+    pub fn code(&self) -> &'static str {
+        match self {
+            LobbyError::UserNotFound => "user_not_found",
+            LobbyError::UserNotOnline => "user_not_online",
+            LobbyError::ChannelNotFound => "channel_not_found",
+            LobbyError::InvalidChannelName => "invalid_channel_name",
+            LobbyError::GameNotFound => "game_not_found",
+            LobbyError::GameExists => "game_exists",
+            LobbyError::GameFull => "game_full",
+            LobbyError::GameStarted => "game_started",
+            LobbyError::InvalidGameName => "invalid_game_name",
+            LobbyError::InvalidPassword => "invalid_password",
+            LobbyError::SpectatorsNotAllowed => "spectators_not_allowed",
+            LobbyError::NotInGame => "not_in_game",
+            LobbyError::NotHostingOpenGame => "not_hosting_open_game",
+            LobbyError::NotHostingRunningGame => "not_hosting_running_game",
+            LobbyError::HostingNeedsIpv4 => "hosting_needs_ipv4",
+            LobbyError::NameTaken(_) => "name_taken",
+        }
+    }
+
+    /// The English text, which is also what the catalog looks up translations by
+    pub fn text(&self) -> String {
+        match self {
+            LobbyError::UserNotFound => "User does not exist".to_string(),
+            LobbyError::UserNotOnline => "User is not online".to_string(),
+            LobbyError::ChannelNotFound => "Channel does not exist".to_string(),
+            LobbyError::InvalidChannelName => "Invalid channel name".to_string(),
+            LobbyError::GameNotFound => "Game does not exist".to_string(),
+            LobbyError::GameExists => "Game already exists.".to_string(),
+            LobbyError::GameFull => "Game is full".to_string(),
+            LobbyError::GameStarted => "Game has already started".to_string(),
+            LobbyError::InvalidGameName => "Invalid game name".to_string(),
+            LobbyError::InvalidPassword => "Invalid password".to_string(),
+            LobbyError::SpectatorsNotAllowed => "Spectators are not allowed".to_string(),
+            LobbyError::NotInGame => "You are not in a game".to_string(),
+            LobbyError::NotHostingOpenGame => "You are not hosting an open game".to_string(),
+            LobbyError::NotHostingRunningGame => "You are not hosting a running game".to_string(),
+            LobbyError::HostingNeedsIpv4 => "Hosting games needs an IPv4 connection".to_string(),
+            LobbyError::NameTaken(name) => format!("The name {} is taken", name),
+        }
+    }
+
+    pub fn translate(&self, language: Language) -> String {
+        language.translate(&self.text())
+    }
+}
+
+impl fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translated_into_every_language() {
+        let errors = [
+            LobbyError::UserNotFound,
+            LobbyError::UserNotOnline,
+            LobbyError::ChannelNotFound,
+            LobbyError::InvalidChannelName,
+            LobbyError::GameNotFound,
+            LobbyError::GameExists,
+            LobbyError::GameFull,
+            LobbyError::GameStarted,
+            LobbyError::InvalidGameName,
+            LobbyError::InvalidPassword,
+            LobbyError::SpectatorsNotAllowed,
+            LobbyError::NotInGame,
+            LobbyError::NotHostingOpenGame,
+            LobbyError::NotHostingRunningGame,
+            LobbyError::HostingNeedsIpv4,
+            LobbyError::NameTaken("Oscar".to_string()),
+        ];
+        for language in [Language::German, Language::French, Language::Polish] {
+            for error in &errors {
+                assert_ne!(
+                    error.translate(language),
+                    error.text(),
+                    "{} has no {:?} translation",
+                    error.code(),
+                    language
+                );
+            }
+        }
+        assert_eq!(
+            LobbyError::NameTaken("Oscar".to_string()).translate(Language::German),
+            "Der Name Oscar ist vergeben"
+        );
+    }
+}
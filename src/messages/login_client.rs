@@ -1,51 +1,92 @@
+use crate::messages::login_server::{compress_bytes, write_slice};
 use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, BytesMut};
 use nom::Err::Incomplete;
 use nom::IResult;
-use nom::Needed::Size;
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct IdentClientMessage {
     pub game_version: Uuid,
     pub language: Vec<u8>,
+    /// bytes following the known fields, which some builds send
+    pub extra_bytes: usize,
 }
 
 #[derive(Debug)]
 pub struct LoginClientMessage {
     pub username: Vec<u8>,
     pub password: Vec<u8>,
+    /// bytes following the known fields, which some builds send
+    pub extra_bytes: usize,
 }
 
-fn try_parse<T>(data: &mut Vec<u8>, parser: fn(&[u8]) -> IResult<&[u8], T>) -> Result<Option<T>> {
-    let (remaining, msg) = match parser(&data) {
-        Ok((remaining, ident)) => (remaining.len(), ident),
-        Err(Incomplete(Size(n))) if n > 1024 => {
-            return Err(anyhow!("Message size {} is too large, assuming error", n))
+/// Login messages are tiny, so anything longer on the wire is a broken or hostile client
+pub(crate) const MAX_COMPRESSED_SIZE: usize = 1024;
+/// Caps what a compressed message may inflate to, so a zlib bomb cannot eat the memory
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 4096;
+
+/// Parses a message off the front of the buffer, which only advances past it and copies nothing
+pub(crate) fn try_parse<T>(
+    data: &mut BytesMut,
+    parser: fn(&[u8]) -> IResult<&[u8], T>,
+) -> Result<Option<T>> {
+    // check the announced length before waiting for the rest of the message to arrive
+    if data.len() >= 4 {
+        let length = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if !(4..=MAX_COMPRESSED_SIZE).contains(&length) {
+            return Err(anyhow!("Message size {} is out of bounds", length));
         }
+    }
+    let (remaining, msg) = match parser(data) {
+        Ok((remaining, ident)) => (remaining.len(), ident),
         Err(Incomplete(_)) => return Ok(None),
-        _ => return Err(anyhow!("Error parsing ident message")),
+        _ => return Err(anyhow!("Error parsing login message")),
     };
-    data.drain(..data.len() - remaining);
+    data.advance(data.len() - remaining);
     Ok(Some(msg))
 }
 
 impl IdentClientMessage {
-    pub fn try_parse(data: &mut Vec<u8>) -> Result<Option<Self>> {
+    pub fn try_parse(data: &mut BytesMut) -> Result<Option<Self>> {
         try_parse(data, parsers::compressed_ident_message)
     }
+
+    /// Encodes the message as a client sends it; `extra_bytes` are not written
+    pub fn prepare_message(&self) -> Result<Vec<u8>> {
+        let (a, b, c, d) = self.game_version.as_fields();
+        let mut message = Vec::new();
+        message.put_u32_le(a);
+        message.put_u16_le(b);
+        message.put_u16_le(c);
+        message.extend_from_slice(d);
+        write_slice(&mut message, &self.language);
+        compress_bytes(&message)
+    }
 }
 
 impl LoginClientMessage {
-    pub fn try_parse(data: &mut Vec<u8>) -> Result<Option<Self>> {
+    pub fn try_parse(data: &mut BytesMut) -> Result<Option<Self>> {
         try_parse(data, parsers::compressed_login_message)
     }
+
+    /// Encodes the message as a client sends it; `extra_bytes` are not written
+    pub fn prepare_message(&self) -> Result<Vec<u8>> {
+        let mut message = Vec::new();
+        write_slice(&mut message, &self.username);
+        write_slice(&mut message, &self.password);
+        compress_bytes(&message)
+    }
 }
 
-mod parsers {
-    use crate::messages::login_client::{IdentClientMessage, LoginClientMessage};
+pub(crate) mod parsers {
+    use crate::messages::login_client::{
+        IdentClientMessage, LoginClientMessage, MAX_DECOMPRESSED_SIZE,
+    };
     use libflate::zlib;
     use nom::bytes::complete::take;
     use nom::combinator::map_res;
+    use nom::error::ErrorKind;
     use nom::multi::count;
     use nom::number::complete::{le_u16, le_u32, le_u8};
     use nom::number::streaming;
@@ -66,7 +107,7 @@ mod parsers {
     /// This is a length-delimited block of data where the first 4 bytes
     /// denote the length of the following data block
     /// Expects that all required bytes are present in the input
-    fn length_delimited_data(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    pub fn length_delimited_data(input: &[u8]) -> IResult<&[u8], &[u8]> {
         let (input, length) = le_u32(input)?;
         take(length)(input)
     }
@@ -75,18 +116,31 @@ mod parsers {
     /// the 4 bytes of the length info itself
     /// May return Err::Incomplete
     fn length_delimited_message(input: &[u8]) -> IResult<&[u8], &[u8]> {
-        let (input, length) = streaming::le_u32(input)?;
-        nom::bytes::streaming::take(length - 4)(input)
+        let (rest, length) = streaming::le_u32(input)?;
+        match length.checked_sub(4) {
+            Some(length) => nom::bytes::streaming::take(length)(rest),
+            None => Err(nom::Err::Error((input, ErrorKind::LengthValue))),
+        }
     }
 
     /// Parses a zlib-compressed message and returns the uncompressed data
+    /// Fails if the data would inflate past MAX_DECOMPRESSED_SIZE
     pub fn compressed_message(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
         map_res(
             length_delimited_message,
             |compressed| -> io::Result<Vec<u8>> {
-                let mut decoder = zlib::Decoder::new(compressed)?;
+                let decoder = zlib::Decoder::new(compressed)?;
                 let mut decompressed = Vec::new();
-                decoder.read_to_end(&mut decompressed)?;
+                decoder
+                    .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+                    log::warn!(
+                        "Compressed message inflates to more than {} bytes",
+                        MAX_DECOMPRESSED_SIZE
+                    );
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
                 Ok(decompressed)
             },
         )(input)
@@ -100,6 +154,7 @@ mod parsers {
             IdentClientMessage {
                 game_version: guid,
                 language: lang.to_vec(),
+                extra_bytes: input.len(),
             },
         ))
     }
@@ -124,6 +179,7 @@ mod parsers {
             LoginClientMessage {
                 username: username.to_vec(),
                 password: password.to_vec(),
+                extra_bytes: input.len(),
             },
         ))
     }
@@ -161,3 +217,99 @@ mod parsers {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prepare_message_roundtrip() {
+        let version = Uuid::parse_str("534ba248-a87c-4ce9-8bee-bc376aae6134").unwrap();
+        let ident = IdentClientMessage {
+            game_version: version,
+            language: b"English".to_vec(),
+            extra_bytes: 0,
+        };
+        let mut data = BytesMut::from(&ident.prepare_message().unwrap()[..]);
+        let ident = IdentClientMessage::try_parse(&mut data).unwrap().unwrap();
+        assert_eq!(ident.game_version, version);
+        assert_eq!(ident.language, b"English");
+        assert_eq!(ident.extra_bytes, 0);
+        assert!(data.is_empty());
+
+        let login = LoginClientMessage {
+            username: b"foo".to_vec(),
+            password: b"secret".to_vec(),
+            extra_bytes: 0,
+        };
+        let mut data = BytesMut::from(&login.prepare_message().unwrap()[..]);
+        let login = LoginClientMessage::try_parse(&mut data).unwrap().unwrap();
+        assert_eq!(login.username, b"foo");
+        assert_eq!(login.password, b"secret");
+    }
+
+    #[test]
+    fn test_out_of_bounds_length_is_rejected() {
+        let mut data = BytesMut::from(&100_000u32.to_le_bytes()[..]);
+        assert!(IdentClientMessage::try_parse(&mut data).is_err());
+        let mut data = BytesMut::from(&2u32.to_le_bytes()[..]);
+        data.extend_from_slice(&[0; 8]);
+        assert!(LoginClientMessage::try_parse(&mut data).is_err());
+    }
+
+    /// Builds a zlib stream of zero bytes using one bit per byte. Back-references would squeeze
+    /// more out, but this already inflates past the limit within a single login message
+    fn zeros_zlib_stream(count: usize) -> Vec<u8> {
+        let mut bits = Vec::new();
+        let mut put = |value: u32, width: u32| bits.extend((0..width).map(|i| value >> i & 1));
+        // final dynamic block with 257 literal/length and 2 distance codes
+        put(1, 1);
+        put(2, 2);
+        put(0, 5);
+        put(1, 5);
+        put(14, 4);
+        // code length codes in their peculiar order, only 18 and 1 are used
+        for length in &[0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] {
+            put(*length, 3);
+        }
+        // literal 0 and end of block get one bit, all else is unused; then the distances
+        put(0, 1);
+        put(1, 1);
+        put(138 - 11, 7);
+        put(1, 1);
+        put(117 - 11, 7);
+        put(0, 1);
+        put(0, 1);
+        put(0, 1);
+        for _ in 0..count {
+            put(0, 1);
+        }
+        put(1, 1);
+        let mut stream = vec![0x78, 0x01];
+        stream.extend(bits.chunks(8).map(|byte| {
+            byte.iter()
+                .rev()
+                .fold(0u8, |acc, bit| acc << 1 | *bit as u8)
+        }));
+        stream.extend_from_slice(&(((count % 65521) as u32) << 16 | 1).to_be_bytes());
+        stream
+    }
+
+    fn framed(compressed: &[u8]) -> BytesMut {
+        let mut data = BytesMut::from(&(compressed.len() as u32 + 4).to_le_bytes()[..]);
+        data.extend_from_slice(compressed);
+        data
+    }
+
+    #[test]
+    fn test_zlib_bomb_is_rejected() {
+        let mut data = framed(&zeros_zlib_stream(4000));
+        let inflated = parsers::compressed_message(&data).unwrap().1;
+        assert_eq!(inflated, vec![0; 4000]);
+
+        let bomb = zeros_zlib_stream(7000);
+        assert!(bomb.len() + 4 <= MAX_COMPRESSED_SIZE);
+        data = framed(&bomb);
+        assert!(LoginClientMessage::try_parse(&mut data).is_err());
+    }
+}
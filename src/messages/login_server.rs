@@ -1,6 +1,8 @@
-use crate::messages::ServerMessage;
+use crate::messages::catalog::Language;
+use crate::messages::login_client::try_parse;
+use crate::messages::WireMessage;
 use anyhow::Result;
-use bytes::BufMut;
+use bytes::{BufMut, BytesMut};
 use libflate::zlib;
 use std::io;
 
@@ -26,8 +28,19 @@ pub struct RejectServerMessage {
     pub reason: String,
 }
 
-fn compress_bytes(uncompressed_bytes: &[u8]) -> Result<Vec<u8>> {
-    let mut encoder = zlib::Encoder::new(Vec::new())?;
+/// The server's answer to an ident or login message, as received by a client
+#[derive(Debug)]
+pub enum LoginResponse<T> {
+    Accepted(T),
+    Rejected(RejectServerMessage),
+}
+
+/// Login messages are only a few hundred bytes, so they are sent as stored blocks.
+/// Besides, libflate's decoder fails debug-build safety checks on back-references,
+/// which would keep our own client from reading them in tests.
+pub(crate) fn compress_bytes(uncompressed_bytes: &[u8]) -> Result<Vec<u8>> {
+    let options = zlib::EncodeOptions::new().no_compression();
+    let mut encoder = zlib::Encoder::with_options(Vec::new(), options)?;
     io::copy(&mut &uncompressed_bytes[..], &mut encoder)?;
     let mut compressed = encoder.finish().into_result()?;
     let mut final_bytes = Vec::new();
@@ -36,12 +49,12 @@ fn compress_bytes(uncompressed_bytes: &[u8]) -> Result<Vec<u8>> {
     Ok(final_bytes)
 }
 
-fn write_slice(data: &mut Vec<u8>, slice: &[u8]) {
+pub(crate) fn write_slice(data: &mut Vec<u8>, slice: &[u8]) {
     data.put_u32_le(slice.len() as u32);
     data.extend_from_slice(slice);
 }
 
-impl ServerMessage for IdentServerMessage {
+impl WireMessage for IdentServerMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         let mut message = Vec::new();
         // message OK status
@@ -53,15 +66,25 @@ impl ServerMessage for IdentServerMessage {
         message.put_u32_le(0x1aff3b3cu32);
         message.put_u32_le(0x1aff3b3cu32);
 
-        Ok(compress_bytes(&message)?)
+        compress_bytes(&message)
     }
 }
 
-impl ServerMessage for WelcomeServerMessage {
+impl WireMessage for WelcomeServerMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
+        self.prepare_with(self.welcome_message.as_bytes())
+    }
+
+    fn prepare_localized(&self, language: Language) -> Result<Vec<u8>> {
+        self.prepare_with(&language.localize(&self.welcome_message))
+    }
+}
+
+impl WelcomeServerMessage {
+    fn prepare_with(&self, welcome_message: &[u8]) -> Result<Vec<u8>> {
         let mut content = Vec::new();
-        write_slice(&mut content, &self.server_ident.as_bytes());
-        write_slice(&mut content, &self.welcome_message.as_bytes());
+        write_slice(&mut content, self.server_ident.as_bytes());
+        write_slice(&mut content, welcome_message);
         // some of these numbers are currently unknown
         content.put_u64_le(25);
         content.put_u32_le(24);
@@ -118,17 +141,191 @@ impl ServerMessage for WelcomeServerMessage {
         message.put_u32_le(0);
         write_slice(&mut message, &content);
 
-        Ok(compress_bytes(&message)?)
+        compress_bytes(&message)
     }
 }
 
-impl ServerMessage for RejectServerMessage {
+impl WireMessage for RejectServerMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
+        Self::prepare_with(self.reason.as_bytes())
+    }
+
+    fn prepare_localized(&self, language: Language) -> Result<Vec<u8>> {
+        Self::prepare_with(&language.localize(&self.reason))
+    }
+}
+
+impl RejectServerMessage {
+    fn prepare_with(reason: &[u8]) -> Result<Vec<u8>> {
         let mut content = Vec::new();
         // reject code
         content.put_u32_le(2);
-        write_slice(&mut content, self.reason.as_bytes());
+        write_slice(&mut content, reason);
+
+        compress_bytes(&content)
+    }
+}
+
+impl IdentServerMessage {
+    pub fn try_parse(data: &mut BytesMut) -> Result<Option<LoginResponse<Self>>> {
+        try_parse(data, parsers::compressed_ident_response)
+    }
+}
+
+impl WelcomeServerMessage {
+    pub fn try_parse(data: &mut BytesMut) -> Result<Option<LoginResponse<Self>>> {
+        try_parse(data, parsers::compressed_welcome_response)
+    }
+}
+
+mod parsers {
+    use crate::messages::login_client::parsers::{compressed_message, length_delimited_data};
+    use crate::messages::login_server::{
+        IdentServerMessage, LoginResponse, RejectServerMessage, WelcomeServerMessage,
+    };
+    use crate::util::bytevec_to_str;
+    use nom::bytes::complete::{tag, take};
+    use nom::combinator::{map, map_res};
+    use nom::multi::many_till;
+    use nom::number::complete::{le_u32, le_u64, le_u8};
+    use nom::sequence::{pair, preceded};
+    use nom::IResult;
 
-        Ok(compress_bytes(&content)?)
+    fn text(input: &[u8]) -> IResult<&[u8], String> {
+        map(length_delimited_data, bytevec_to_str)(input)
+    }
+
+    fn reject(input: &[u8]) -> IResult<&[u8], RejectServerMessage> {
+        map(preceded(tag(&2u32.to_le_bytes()[..]), text), |reason| {
+            RejectServerMessage { reason }
+        })(input)
+    }
+
+    fn ident(input: &[u8]) -> IResult<&[u8], IdentServerMessage> {
+        map(
+            preceded(tag(&0u32.to_le_bytes()[..]), take(20usize)),
+            |_| IdentServerMessage {},
+        )(input)
+    }
+
+    /// entries of an index byte and a string, terminated by 0xff
+    fn string_list(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+        map(
+            many_till(preceded(le_u8, text), tag(&[0xffu8][..])),
+            |(list, _)| list,
+        )(input)
+    }
+
+    fn welcome_content(input: &[u8]) -> IResult<&[u8], WelcomeServerMessage> {
+        let (input, server_ident) = text(input)?;
+        let (input, welcome_message) = text(input)?;
+        let (input, _) = pair(le_u64, le_u32)(input)?;
+        let (input, players_total) = le_u32(input)?;
+        let (input, players_online) = le_u32(input)?;
+        let (input, channels_total) = le_u32(input)?;
+        let (input, games_total) = le_u32(input)?;
+        let (input, _) = pair(le_u32, le_u32)(input)?;
+        let (input, games_available) = le_u32(input)?;
+        let (input, _) = le_u32(input)?;
+        let (input, game_versions) = string_list(input)?;
+        let (input, _) = pair(string_list, string_list)(input)?;
+        let (input, _) = le_u8(input)?;
+        let (input, initial_channel) = text(input)?;
+        Ok((
+            input,
+            WelcomeServerMessage {
+                server_ident,
+                welcome_message,
+                players_total,
+                players_online,
+                channels_total,
+                games_total,
+                // not transmitted
+                games_running: 0,
+                games_available,
+                game_versions,
+                initial_channel,
+            },
+        ))
+    }
+
+    fn welcome(input: &[u8]) -> IResult<&[u8], WelcomeServerMessage> {
+        map_res(
+            preceded(tag(&0u32.to_le_bytes()[..]), length_delimited_data),
+            |content| welcome_content(content).map(|(_, welcome)| welcome),
+        )(input)
+    }
+
+    fn response<T>(
+        input: &[u8],
+        accepted: fn(&[u8]) -> IResult<&[u8], T>,
+    ) -> IResult<&[u8], LoginResponse<T>> {
+        map_res(compressed_message, |decompressed| {
+            if let Ok((_, reject)) = reject(&decompressed) {
+                return Ok(LoginResponse::Rejected(reject));
+            }
+            match accepted(&decompressed) {
+                Ok((_, message)) => Ok(LoginResponse::Accepted(message)),
+                Err(_) => Err(()),
+            }
+        })(input)
+    }
+
+    pub fn compressed_ident_response(
+        input: &[u8],
+    ) -> IResult<&[u8], LoginResponse<IdentServerMessage>> {
+        response(input, ident)
+    }
+
+    pub fn compressed_welcome_response(
+        input: &[u8],
+    ) -> IResult<&[u8], LoginResponse<WelcomeServerMessage>> {
+        response(input, welcome)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_welcome_roundtrip() {
+        let welcome = WelcomeServerMessage {
+            server_ident: "IE::Net".to_string(),
+            welcome_message: "Hello".to_string(),
+            players_total: 5,
+            players_online: 3,
+            channels_total: 2,
+            games_total: 1,
+            games_running: 0,
+            games_available: 1,
+            game_versions: vec!["2.2".to_string()],
+            initial_channel: "General".to_string(),
+        };
+        let mut data = BytesMut::from(&welcome.prepare_message().unwrap()[..]);
+        data.extend_from_slice(b"/&channel");
+        match WelcomeServerMessage::try_parse(&mut data).unwrap() {
+            Some(LoginResponse::Accepted(parsed)) => {
+                assert_eq!(parsed.server_ident, "IE::Net");
+                assert_eq!(parsed.welcome_message, "Hello");
+                assert_eq!(parsed.players_online, 3);
+                assert_eq!(parsed.game_versions, vec!["2.2".to_string()]);
+                assert_eq!(parsed.initial_channel, "General");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(&data[..], b"/&channel");
+
+        let reject = RejectServerMessage {
+            reason: "Banned".to_string(),
+        };
+        let mut data = BytesMut::from(&reject.prepare_message().unwrap()[..]);
+        match IdentServerMessage::try_parse(&mut data).unwrap() {
+            Some(LoginResponse::Rejected(reject)) => assert_eq!(reject.reason, "Banned"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let ident = IdentServerMessage {}.prepare_message().unwrap();
+        let mut data = BytesMut::from(&ident[..ident.len() - 1]);
+        assert!(IdentServerMessage::try_parse(&mut data).unwrap().is_none());
     }
 }
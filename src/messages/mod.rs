@@ -1,15 +1,91 @@
+pub mod catalog;
 pub mod client_command;
+pub mod codec;
+pub mod lobby_error;
 pub mod login_client;
 pub mod login_server;
 pub mod raw_command;
 pub mod server_messages;
 
 use anyhow::Result;
-use downcast_rs::DowncastSync;
-use std::fmt::Debug;
+use catalog::Language;
+use login_server::{IdentServerMessage, RejectServerMessage, WelcomeServerMessage};
+use server_messages::*;
 
-pub trait ServerMessage: DowncastSync + Debug + Send + Sync {
+/// Serialization of a message the server sends, as the game expects it on the wire
+pub trait WireMessage {
     fn prepare_message(&self) -> Result<Vec<u8>>;
+
+    /// Prepares the message for a client running the game in the given language
+    fn prepare_localized(&self, _language: Language) -> Result<Vec<u8>> {
+        self.prepare_message()
+    }
+}
+
+macro_rules! server_messages {
+    ($($variant:ident($message:ty) => $name:literal,)*) => {
+        /// Everything the server sends to game clients, so that handlers can match on the kind
+        /// of message and have to deal with all of them
+        #[derive(Debug)]
+        pub enum ServerMessage {
+            $($variant($message),)*
+        }
+
+        impl ServerMessage {
+            /// Name of the metric that counts how often the message is sent
+            pub fn metric_name(&self) -> &'static str {
+                match self {
+                    $(ServerMessage::$variant(_) => concat!("messages_sent.", $name),)*
+                }
+            }
+        }
+
+        impl WireMessage for ServerMessage {
+            fn prepare_message(&self) -> Result<Vec<u8>> {
+                match self {
+                    $(ServerMessage::$variant(message) => message.prepare_message(),)*
+                }
+            }
+
+            fn prepare_localized(&self, language: Language) -> Result<Vec<u8>> {
+                match self {
+                    $(ServerMessage::$variant(message) => message.prepare_localized(language),)*
+                }
+            }
+        }
+
+        $(
+            impl From<$message> for ServerMessage {
+                fn from(message: $message) -> Self {
+                    ServerMessage::$variant(message)
+                }
+            }
+        )*
+    };
 }
 
-impl_downcast!(ServerMessage);
+server_messages! {
+    Ident(IdentServerMessage) => "ident",
+    Welcome(WelcomeServerMessage) => "welcome",
+    Reject(RejectServerMessage) => "reject",
+    Send(SendMessage) => "send",
+    Private(PrivateMessage) => "private",
+    SentPrivate(SentPrivateMessage) => "sent_private",
+    Error(ErrorMessage) => "error",
+    NewChannel(NewChannelMessage) => "new_channel",
+    DropChannel(DropChannelMessage) => "drop_channel",
+    NewUser(NewUserMessage) => "new_user",
+    UserJoined(UserJoinedMessage) => "user_joined",
+    UserLeft(UserLeftMessage) => "user_left",
+    JoinChannel(JoinChannelMessage) => "join_channel",
+    CreateGame(CreateGameMessage) => "create_game",
+    JoinGame(JoinGameMessage) => "join_game",
+    NewGame(NewGameMessage) => "new_game",
+    DropGame(DropGameMessage) => "drop_game",
+    SyncStats(SyncStatsMessage) => "sync_stats",
+    Info(InfoMessage) => "info",
+    Announcement(AnnouncementMessage) => "announcement",
+    Notice(NoticeMessage) => "notice",
+    MatchSummary(MatchSummaryMessage) => "match_summary",
+    Raw(RawMessage) => "raw",
+}
@@ -1,11 +1,16 @@
 use crate::broker::ArcServerMessage;
-use crate::messages::ServerMessage;
+use crate::messages::catalog::Language;
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::WireMessage;
 use anyhow::Result;
 use nom::AsBytes;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
 use uuid::Uuid;
 
+/// Name under which the server's own messages appear in the chat
+pub const SERVER_NAME: &str = "IE::Net";
+
 #[derive(Debug)]
 pub struct SendMessage {
     pub username: String,
@@ -29,6 +34,8 @@ pub struct SentPrivateMessage {
 #[derive(Debug)]
 pub struct ErrorMessage {
     pub error: String,
+    /// identifies errors raised from a `LobbyError`, the game client never sees it
+    pub code: Option<&'static str>,
 }
 
 #[derive(Debug)]
@@ -44,6 +51,7 @@ pub struct DropChannelMessage {
 #[derive(Debug)]
 pub struct NewUserMessage {
     pub username: String,
+    pub version_idx: u32,
 }
 
 #[derive(Debug)]
@@ -79,12 +87,21 @@ pub struct JoinGameMessage {
     pub password: Vec<u8>,
     pub ip_addr: Ipv4Addr,
     pub id: Uuid,
+    /// only set if the host does not listen on the default port
+    pub port: Option<u16>,
 }
 
 #[derive(Debug)]
 pub struct NewGameMessage {
     pub game_name: String,
     pub id: Uuid,
+    /// for the server's own announcements, game clients are not told
+    pub players: u32,
+    pub max_players: u32,
+    /// the game has started and can only be joined as spectator
+    pub spectators_only: bool,
+    /// what the host tagged the game with, which game clients are not told about
+    pub tag: Option<String>,
 }
 
 #[derive(Debug)]
@@ -101,6 +118,50 @@ pub struct SyncStatsMessage {
     pub channels_total: u32,
 }
 
+/// A line of text from the server itself, displayed like a chat message
+#[derive(Debug)]
+pub struct InfoMessage {
+    pub text: String,
+}
+
+/// A notice from the admins to everyone online, marked so it stands out from the chat
+#[derive(Debug)]
+pub struct AnnouncementMessage {
+    pub text: Vec<u8>,
+}
+
+/// A warning from an admin to one user, marked so it cannot be taken for a private message
+#[derive(Debug)]
+pub struct NoticeMessage {
+    pub text: Vec<u8>,
+}
+
+/// How long a finished match lasted and who played it, posted for everyone in the lobby to see
+#[derive(Debug)]
+pub struct MatchSummaryMessage {
+    pub game_name: String,
+    pub duration_secs: u64,
+    pub players: Vec<String>,
+    pub winner: Option<String>,
+}
+
+impl MatchSummaryMessage {
+    pub fn text(&self) -> String {
+        let minutes = self.duration_secs / 60;
+        let mut text = format!(
+            "Match {} lasted {} minute{}, players: {}",
+            self.game_name,
+            minutes,
+            if minutes == 1 { "" } else { "s" },
+            self.players.join(", ")
+        );
+        if let Some(winner) = &self.winner {
+            text.push_str(&format!(", winner: {}", winner));
+        }
+        text
+    }
+}
+
 #[derive(Debug)]
 pub struct RawMessage {
     pub message: String,
@@ -118,7 +179,7 @@ fn escape_quotes(input: &[u8]) -> Vec<u8> {
     result
 }
 
-fn prepare_command(command: &str, params: &[&[u8]]) -> Vec<u8> {
+pub(crate) fn prepare_command(command: &str, params: &[&[u8]]) -> Vec<u8> {
     let mut result = Vec::new();
     result.extend_from_slice(command.as_ref());
     for param in params {
@@ -131,15 +192,40 @@ fn prepare_command(command: &str, params: &[&[u8]]) -> Vec<u8> {
     result
 }
 
+impl InfoMessage {
+    pub fn new_info(text: &str) -> ArcServerMessage {
+        Arc::new(
+            InfoMessage {
+                text: text.to_string(),
+            }
+            .into(),
+        )
+    }
+}
+
 impl ErrorMessage {
     pub fn new_err(error: &str) -> ArcServerMessage {
-        Arc::new(ErrorMessage {
-            error: error.to_string(),
-        })
+        Arc::new(
+            ErrorMessage {
+                error: error.to_string(),
+                code: None,
+            }
+            .into(),
+        )
+    }
+
+    pub fn new_coded(error: LobbyError) -> ArcServerMessage {
+        Arc::new(
+            ErrorMessage {
+                error: error.text(),
+                code: Some(error.code()),
+            }
+            .into(),
+        )
     }
 }
 
-impl ServerMessage for SendMessage {
+impl WireMessage for SendMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/send",
@@ -148,7 +234,7 @@ impl ServerMessage for SendMessage {
     }
 }
 
-impl ServerMessage for PrivateMessage {
+impl WireMessage for PrivateMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/msg",
@@ -162,7 +248,7 @@ impl ServerMessage for PrivateMessage {
     }
 }
 
-impl ServerMessage for SentPrivateMessage {
+impl WireMessage for SentPrivateMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/msgc",
@@ -171,13 +257,20 @@ impl ServerMessage for SentPrivateMessage {
     }
 }
 
-impl ServerMessage for ErrorMessage {
+impl WireMessage for ErrorMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command("/error", &[self.error.as_bytes()]))
     }
+
+    fn prepare_localized(&self, language: Language) -> Result<Vec<u8>> {
+        Ok(prepare_command(
+            "/error",
+            &[&language.localize(&self.error)],
+        ))
+    }
 }
 
-impl ServerMessage for NewChannelMessage {
+impl WireMessage for NewChannelMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/$channel",
@@ -187,7 +280,7 @@ impl ServerMessage for NewChannelMessage {
     }
 }
 
-impl ServerMessage for DropChannelMessage {
+impl WireMessage for DropChannelMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/&channel",
@@ -196,13 +289,17 @@ impl ServerMessage for DropChannelMessage {
     }
 }
 
-impl ServerMessage for NewUserMessage {
+impl WireMessage for NewUserMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
-        Ok(prepare_command("$user", &[self.username.as_bytes(), b"0"]))
+        let version = format!("{}", self.version_idx);
+        Ok(prepare_command(
+            "$user",
+            &[self.username.as_bytes(), version.as_bytes()],
+        ))
     }
 }
 
-impl ServerMessage for UserJoinedMessage {
+impl WireMessage for UserJoinedMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         let version = format!("{}", self.version_idx);
         let mut params = vec![self.username.as_bytes(), version.as_bytes()];
@@ -213,7 +310,7 @@ impl ServerMessage for UserJoinedMessage {
     }
 }
 
-impl ServerMessage for UserLeftMessage {
+impl WireMessage for UserLeftMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         let mut params = vec![self.username.as_bytes()];
         if let Some(destination) = self.destination.as_ref() {
@@ -223,13 +320,13 @@ impl ServerMessage for UserLeftMessage {
     }
 }
 
-impl ServerMessage for JoinChannelMessage {
+impl WireMessage for JoinChannelMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command("/join", &[self.channel_name.as_bytes()]))
     }
 }
 
-impl ServerMessage for CreateGameMessage {
+impl WireMessage for CreateGameMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/plays",
@@ -244,7 +341,7 @@ impl ServerMessage for CreateGameMessage {
     }
 }
 
-impl ServerMessage for JoinGameMessage {
+impl WireMessage for JoinGameMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         let ip_as_u32 = self
             .ip_addr
@@ -252,23 +349,31 @@ impl ServerMessage for JoinGameMessage {
             .iter()
             .rev()
             .fold(0u32, |x, y| (x << 8) + (*y as u32));
-        Ok(prepare_command(
-            "/playc",
-            &[
-                self.version.to_hyphenated().to_string().as_bytes(),
-                self.game_name.as_bytes(),
-                self.password.as_bytes(),
-                format!("0x{:08x}", ip_as_u32).as_bytes(),
-                self.id.to_hyphenated().to_string().as_bytes(),
-                self.ip_addr.to_string().as_bytes(),
-            ],
-        ))
+        let version = self.version.to_hyphenated().to_string();
+        let ip_hex = format!("0x{:08x}", ip_as_u32);
+        let id = self.id.to_hyphenated().to_string();
+        let ip = self.ip_addr.to_string();
+        let port = self.port.map(|p| p.to_string());
+        let mut params = vec![
+            version.as_bytes(),
+            self.game_name.as_bytes(),
+            self.password.as_bytes(),
+            ip_hex.as_bytes(),
+            id.as_bytes(),
+            ip.as_bytes(),
+        ];
+        // unpatched clients ignore the trailing parameter and always use the default port
+        if let Some(port) = &port {
+            params.push(port.as_bytes());
+        }
+        Ok(prepare_command("/playc", &params))
     }
 }
 
-impl ServerMessage for NewGameMessage {
+impl WireMessage for NewGameMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
-        // TODO: what do all these extra params actually mean?
+        // TODO: what do all these extra params actually mean? Nothing shows which of them
+        // would carry the player counts, so those are not sent
         Ok(prepare_command(
             "/$play",
             &[
@@ -277,19 +382,19 @@ impl ServerMessage for NewGameMessage {
                 b"0",
                 b"0",
                 self.id.to_hyphenated().to_string().as_bytes(),
-                b"0",
+                if self.spectators_only { b"1" } else { b"0" },
             ],
         ))
     }
 }
 
-impl ServerMessage for DropGameMessage {
+impl WireMessage for DropGameMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command("/&play", &[self.game_name.as_bytes()]))
     }
 }
 
-impl ServerMessage for SyncStatsMessage {
+impl WireMessage for SyncStatsMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         Ok(prepare_command(
             "/syncstats",
@@ -306,7 +411,39 @@ impl ServerMessage for SyncStatsMessage {
     }
 }
 
-impl ServerMessage for RawMessage {
+impl WireMessage for InfoMessage {
+    fn prepare_message(&self) -> Result<Vec<u8>> {
+        Ok(prepare_command(
+            "/send",
+            &[SERVER_NAME.as_bytes(), self.text.as_bytes()],
+        ))
+    }
+}
+
+impl WireMessage for AnnouncementMessage {
+    fn prepare_message(&self) -> Result<Vec<u8>> {
+        let text = [&b"*** Announcement: "[..], &self.text, b" ***"].concat();
+        Ok(prepare_command("/send", &[SERVER_NAME.as_bytes(), &text]))
+    }
+}
+
+impl WireMessage for NoticeMessage {
+    fn prepare_message(&self) -> Result<Vec<u8>> {
+        let text = [&b"*** Notice from the admins: "[..], &self.text, b" ***"].concat();
+        Ok(prepare_command("/send", &[SERVER_NAME.as_bytes(), &text]))
+    }
+}
+
+impl WireMessage for MatchSummaryMessage {
+    fn prepare_message(&self) -> Result<Vec<u8>> {
+        Ok(prepare_command(
+            "/send",
+            &[SERVER_NAME.as_bytes(), self.text().as_bytes()],
+        ))
+    }
+}
+
+impl WireMessage for RawMessage {
     fn prepare_message(&self) -> Result<Vec<u8>> {
         let mut msg_bytes = self.message.as_bytes().to_vec();
         msg_bytes.push(0);
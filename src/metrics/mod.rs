@@ -0,0 +1,162 @@
+mod otlp;
+mod statsd;
+
+use crate::config::{MetricsBackend, MetricsConfig};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+pub use otlp::OtlpSink;
+pub use statsd::StatsdSink;
+
+/// Upper bounds of the latency buckets in microseconds, anything slower goes into one more
+pub const LATENCY_BOUNDS: [u64; 8] = [100, 250, 500, 1_000, 2_500, 10_000, 50_000, 250_000];
+
+/// Distribution of the latencies recorded since the previous flush
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Histogram {
+    pub count: u64,
+    /// in microseconds
+    pub sum: u64,
+    pub buckets: [u64; LATENCY_BOUNDS.len() + 1],
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+        self.count += 1;
+        self.sum += micros;
+        self.buckets[bucket] += 1;
+    }
+
+    /// in microseconds
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricValue {
+    /// increase since the previous flush
+    Counter(u64),
+    Gauge(i64),
+    Latency(Histogram),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub value: MetricValue,
+}
+
+/// A backend that metrics are periodically pushed to.
+#[async_trait]
+pub trait MetricsSink: Send {
+    async fn flush(&mut self, metrics: &[Metric]) -> Result<()>;
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<&'static str, u64>,
+    gauges: BTreeMap<&'static str, i64>,
+    histograms: BTreeMap<&'static str, Histogram>,
+}
+
+/// Cheaply clonable handle used to record metrics from anywhere in the server.
+/// Recording never blocks on a backend; values are collected until the exporter
+/// task takes them.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn increment(&self, name: &'static str) {
+        self.add(name, 1);
+    }
+
+    pub fn add(&self, name: &'static str, value: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry.counters.entry(name).or_insert(0) += value;
+    }
+
+    pub fn gauge(&self, name: &'static str, value: i64) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.gauges.insert(name, value);
+    }
+
+    pub fn record(&self, name: &'static str, latency: Duration) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.histograms.entry(name).or_default().record(latency);
+    }
+
+    /// Records how long it took since `start`
+    pub fn record_since(&self, name: &'static str, start: Instant) {
+        self.record(name, start.elapsed());
+    }
+
+    /// Returns all gauges plus the counter increments and latencies since the last call
+    pub fn take(&self) -> Vec<Metric> {
+        let mut registry = self.registry.lock().unwrap();
+        let counters = std::mem::take(&mut registry.counters);
+        let histograms = std::mem::take(&mut registry.histograms);
+        counters
+            .into_iter()
+            .map(|(name, value)| Metric {
+                name: name.to_string(),
+                value: MetricValue::Counter(value),
+            })
+            .chain(registry.gauges.iter().map(|(name, value)| Metric {
+                name: name.to_string(),
+                value: MetricValue::Gauge(*value),
+            }))
+            .chain(histograms.into_iter().map(|(name, histogram)| Metric {
+                name: name.to_string(),
+                value: MetricValue::Latency(histogram),
+            }))
+            .collect()
+    }
+}
+
+pub fn create_sink(config: &MetricsConfig) -> Option<Box<dyn MetricsSink>> {
+    match config.backend {
+        MetricsBackend::None => None,
+        MetricsBackend::Statsd => Some(Box::new(StatsdSink::new(&config.address, &config.prefix))),
+        MetricsBackend::Otlp => Some(Box::new(OtlpSink::new(&config.address, &config.prefix))),
+    }
+}
+
+pub async fn export_loop(
+    metrics: Metrics,
+    mut sink: Box<dyn MetricsSink>,
+    interval: Duration,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                if let Err(e) = sink.flush(&metrics.take()).await {
+                    log::warn!("Failed to export metrics: {}", e);
+                }
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+        }
+    }
+
+    // push whatever was recorded since the last tick
+    sink.flush(&metrics.take()).await?;
+    log::info!("Metrics exporter shutting down");
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use crate::http::post_json;
+use crate::metrics::{Metric, MetricValue, MetricsSink, LATENCY_BOUNDS};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Exports metrics to an OpenTelemetry collector using OTLP/HTTP with JSON encoding
+pub struct OtlpSink {
+    address: String,
+    service_name: String,
+}
+
+impl OtlpSink {
+    pub fn new(address: &str, service_name: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            service_name: service_name.to_string(),
+        }
+    }
+}
+
+fn unix_nanos() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn metric_to_json(metric: &Metric, timestamp: &str) -> Value {
+    match metric.value {
+        MetricValue::Counter(value) => json!({
+            "name": metric.name,
+            "sum": {
+                "dataPoints": [{ "asInt": value.to_string(), "timeUnixNano": timestamp }],
+                // delta temporality, counters are reset on every flush
+                "aggregationTemporality": 1,
+                "isMonotonic": true,
+            },
+        }),
+        MetricValue::Gauge(value) => json!({
+            "name": metric.name,
+            "gauge": {
+                "dataPoints": [{ "asInt": value.to_string(), "timeUnixNano": timestamp }],
+            },
+        }),
+        MetricValue::Latency(histogram) => json!({
+            "name": metric.name,
+            "unit": "us",
+            "histogram": {
+                "dataPoints": [{
+                    "count": histogram.count.to_string(),
+                    "sum": histogram.sum as f64,
+                    "bucketCounts": histogram
+                        .buckets
+                        .iter()
+                        .map(|count| count.to_string())
+                        .collect::<Vec<_>>(),
+                    "explicitBounds": LATENCY_BOUNDS
+                        .iter()
+                        .map(|bound| *bound as f64)
+                        .collect::<Vec<_>>(),
+                    "timeUnixNano": timestamp,
+                }],
+                "aggregationTemporality": 1,
+            },
+        }),
+    }
+}
+
+#[async_trait]
+impl MetricsSink for OtlpSink {
+    async fn flush(&mut self, metrics: &[Metric]) -> Result<()> {
+        if metrics.is_empty() {
+            return Ok(());
+        }
+        let timestamp = unix_nanos();
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": self.service_name },
+                    }],
+                },
+                "scopeMetrics": [{
+                    "scope": { "name": "ie_net" },
+                    "metrics": metrics
+                        .iter()
+                        .map(|m| metric_to_json(m, &timestamp))
+                        .collect::<Vec<_>>(),
+                }],
+            }],
+        });
+        match post_json(&self.address, "/v1/metrics", &body.to_string()).await? {
+            200..=299 => Ok(()),
+            status => Err(anyhow!("OTLP collector responded with status {}", status)),
+        }
+    }
+}
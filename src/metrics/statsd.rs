@@ -0,0 +1,87 @@
+use crate::metrics::{Metric, MetricValue, MetricsSink};
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+/// Pushes metrics to a StatsD daemon using the plain text UDP protocol
+pub struct StatsdSink {
+    address: String,
+    prefix: String,
+    socket: Option<UdpSocket>,
+}
+
+impl StatsdSink {
+    pub fn new(address: &str, prefix: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            prefix: prefix.to_string(),
+            socket: None,
+        }
+    }
+}
+
+pub(super) fn format_metric(prefix: &str, metric: &Metric) -> String {
+    match metric.value {
+        MetricValue::Counter(value) => format!("{}.{}:{}|c", prefix, metric.name, value),
+        MetricValue::Gauge(value) => format!("{}.{}:{}|g", prefix, metric.name, value),
+        // StatsD aggregates timings itself, so it gets the count and the mean of the interval
+        MetricValue::Latency(histogram) => format!(
+            "{p}.{n}.count:{}|c\n{p}.{n}.mean:{}|ms",
+            histogram.count,
+            histogram.mean() as f64 / 1000.0,
+            p = prefix,
+            n = metric.name,
+        ),
+    }
+}
+
+#[async_trait]
+impl MetricsSink for StatsdSink {
+    async fn flush(&mut self, metrics: &[Metric]) -> Result<()> {
+        if self.socket.is_none() {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(&self.address).await?;
+            self.socket = Some(socket);
+        }
+        let socket = self.socket.as_mut().unwrap();
+        for metric in metrics {
+            socket
+                .send(format_metric(&self.prefix, metric).as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Histogram;
+
+    #[test]
+    fn test_format_metric() {
+        let counter = Metric {
+            name: "logins".to_string(),
+            value: MetricValue::Counter(3),
+        };
+        let gauge = Metric {
+            name: "users_online".to_string(),
+            value: MetricValue::Gauge(12),
+        };
+        assert_eq!(format_metric("ie_net", &counter), "ie_net.logins:3|c");
+        assert_eq!(format_metric("ie_net", &gauge), "ie_net.users_online:12|g");
+
+        let latency = Metric {
+            name: "commands.send".to_string(),
+            value: MetricValue::Latency(Histogram {
+                count: 4,
+                sum: 5000,
+                ..Default::default()
+            }),
+        };
+        assert_eq!(
+            format_metric("ie_net", &latency),
+            "ie_net.commands.send.count:4|c\nie_net.commands.send.mean:1.25|ms"
+        );
+    }
+}
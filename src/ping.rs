@@ -0,0 +1,142 @@
+use crate::config::PingConfig;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time::{interval, timeout};
+
+type Latencies = Arc<Mutex<HashMap<Ipv4Addr, Duration>>>;
+
+/// Periodically measures the round trip time to game hosts and users, as a hint for picking a
+/// game. Hosts are probed with a UDP datagram to their echo port. Both an echo and the ICMP
+/// "port unreachable" a host without echo service answers with complete a round trip, so no
+/// raw sockets are needed. Hosts that answer neither, e.g. behind a firewall, have no latency.
+pub struct Pinger {
+    hosts: watch::Sender<HashSet<Ipv4Addr>>,
+    latencies: Latencies,
+}
+
+impl Pinger {
+    /// Starts probing in the background, which stops once the pinger is dropped
+    pub fn new(config: &PingConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let (hosts, hosts_recv) = watch::channel(HashSet::new());
+        let latencies = Latencies::default();
+        tokio::spawn(ping_loop(config.clone(), hosts_recv, latencies.clone()));
+        Some(Self { hosts, latencies })
+    }
+
+    /// Replaces the hosts to probe, forgetting the latencies of all others
+    pub fn set_hosts(&self, hosts: HashSet<Ipv4Addr>) {
+        self.latencies
+            .lock()
+            .unwrap()
+            .retain(|ip, _| hosts.contains(ip));
+        // the loop only goes away together with the pinger
+        let _ = self.hosts.broadcast(hosts);
+    }
+
+    pub fn latency(&self, ip: Ipv4Addr) -> Option<Duration> {
+        self.latencies.lock().unwrap().get(&ip).copied()
+    }
+}
+
+async fn ping_loop(
+    config: PingConfig,
+    mut hosts_recv: watch::Receiver<HashSet<Ipv4Addr>>,
+    latencies: Latencies,
+) {
+    let mut hosts = HashSet::new();
+    let mut ticks = interval(Duration::from_secs(config.interval_secs));
+    loop {
+        let to_probe: Vec<Ipv4Addr> = tokio::select! {
+            _ = ticks.tick() => hosts.iter().copied().collect(),
+            update = hosts_recv.recv() => match update {
+                Some(update) => {
+                    // new hosts get their latency right away instead of on the next tick
+                    let new_hosts = update.difference(&hosts).copied().collect();
+                    hosts = update;
+                    new_hosts
+                }
+                None => break,
+            },
+        };
+        for ip in to_probe {
+            tokio::spawn(probe_and_record(config.clone(), ip, latencies.clone()));
+        }
+    }
+}
+
+async fn probe_and_record(config: PingConfig, ip: Ipv4Addr, latencies: Latencies) {
+    let wait = Duration::from_millis(config.timeout_ms);
+    match probe(ip, config.port, wait).await {
+        Ok(Some(latency)) => {
+            latencies.lock().unwrap().insert(ip, latency);
+        }
+        Ok(None) => {
+            latencies.lock().unwrap().remove(&ip);
+        }
+        Err(e) => log::warn!("Could not probe game host {}: {}", ip, e),
+    }
+}
+
+/// Measures the round trip to the host, or returns None if it did not answer in time
+async fn probe(ip: Ipv4Addr, port: u16, wait: Duration) -> Result<Option<Duration>> {
+    let mut socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // only a connected socket learns about the ICMP answer
+    socket.connect((ip, port)).await?;
+    let sent_at = Instant::now();
+    socket.send(b"ie_net ping").await?;
+    let mut buf = [0u8; 64];
+    match timeout(wait, socket.recv(&mut buf)).await {
+        Ok(Ok(_)) => Ok(Some(sent_at.elapsed())),
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Ok(Some(sent_at.elapsed())),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    #[tokio::test]
+    async fn test_probe_gets_echo() {
+        let mut echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], &from).await.unwrap();
+        });
+        let latency = probe(Ipv4Addr::LOCALHOST, port, WAIT).await.unwrap();
+        assert!(latency.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_probe_counts_unreachable_port() {
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let latency = probe(Ipv4Addr::LOCALHOST, port, WAIT).await.unwrap();
+        assert!(latency.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_probe_times_out_on_silence() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let latency = probe(Ipv4Addr::LOCALHOST, port, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(latency.is_none());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::client::default_game_version;
+use crate::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use crate::messages::login_server::{IdentServerMessage, LoginResponse, WelcomeServerMessage};
+use crate::messages::raw_command::RawCommand;
+use crate::messages::server_messages::prepare_command;
+use crate::server::connect_stream;
+use crate::util::bytevec_to_str;
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A connection to a lobby server that behaves like the game client, for bots and tests.
+/// Works with any EarthNet compatible server, not just this one.
+pub struct Client {
+    stream: TcpStream,
+    received: BytesMut,
+    welcome: WelcomeServerMessage,
+}
+
+impl Client {
+    /// Connects and logs in, failing with the server's reason if it turns the client away
+    pub async fn connect(addr: &str, username: &str, password: &str) -> Result<Self> {
+        Self::login(connect_stream(addr).await?, username, password).await
+    }
+
+    /// Performs the handshake on an established connection
+    pub async fn login(mut stream: TcpStream, username: &str, password: &str) -> Result<Self> {
+        let mut received = BytesMut::new();
+        let ident = IdentClientMessage {
+            game_version: default_game_version(),
+            language: b"English".to_vec(),
+            extra_bytes: 0,
+        };
+        stream.write_all(&ident.prepare_message()?).await?;
+        loop {
+            match IdentServerMessage::try_parse(&mut received)? {
+                Some(LoginResponse::Accepted(_)) => break,
+                Some(LoginResponse::Rejected(reject)) => return Err(anyhow!(reject.reason)),
+                None => read_more(&mut stream, &mut received).await?,
+            }
+        }
+
+        let login = LoginClientMessage {
+            username: username.as_bytes().to_vec(),
+            password: password.as_bytes().to_vec(),
+            extra_bytes: 0,
+        };
+        stream.write_all(&login.prepare_message()?).await?;
+        loop {
+            match WelcomeServerMessage::try_parse(&mut received)? {
+                Some(LoginResponse::Accepted(welcome)) => {
+                    return Ok(Self {
+                        stream,
+                        received,
+                        welcome,
+                    })
+                }
+                Some(LoginResponse::Rejected(reject)) => return Err(anyhow!(reject.reason)),
+                None => read_more(&mut stream, &mut received).await?,
+            }
+        }
+    }
+
+    pub fn welcome(&self) -> &WelcomeServerMessage {
+        &self.welcome
+    }
+
+    /// Sends a command, e.g. `send("join", &[b"Clan Wars"])`
+    pub async fn send(&mut self, command: &str, params: &[&[u8]]) -> Result<()> {
+        let bytes = prepare_command(&format!("/{}", command), params);
+        self.stream.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    /// Says something in the current channel or game
+    pub async fn say(&mut self, message: &str) -> Result<()> {
+        self.send("send", &[message.as_bytes()]).await
+    }
+
+    pub async fn join(&mut self, channel: &str) -> Result<()> {
+        self.send("join", &[channel.as_bytes()]).await
+    }
+
+    /// Waits for the next command from the server. The command is given without its leading
+    /// slash, e.g. `send` with the sender and message as parameters. None once the server
+    /// has closed the connection.
+    pub async fn next_command(&mut self) -> Result<Option<RawCommand>> {
+        loop {
+            if let Some(position) = self.received.iter().position(|b| *b == 0) {
+                let bytes = self.received.split_to(position + 1);
+                return Ok(Some(parse_server_command(&bytes[..position])));
+            }
+            if self.stream.read_buf(&mut self.received).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+async fn read_more(stream: &mut TcpStream, received: &mut BytesMut) -> Result<()> {
+    if stream.read_buf(received).await? == 0 {
+        return Err(anyhow!("Server closed the connection during login"));
+    }
+    Ok(())
+}
+
+/// Server commands are a name followed by quoted parameters, which never contain quotes
+/// themselves. Unlike client commands, names may contain symbols, e.g. `/$channel`.
+fn parse_server_command(bytes: &[u8]) -> RawCommand {
+    let (name, rest) = match bytes.iter().position(|b| *b == b' ') {
+        Some(space) => bytes.split_at(space),
+        None => (bytes, &[][..]),
+    };
+    RawCommand {
+        command: bytevec_to_str(name.strip_prefix(b"/").unwrap_or(name)),
+        params: rest
+            .split(|b| *b == b'"')
+            .skip(1)
+            .step_by(2)
+            .map(unescape_quotes)
+            .collect(),
+    }
+}
+
+fn unescape_quotes(param: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(param.len());
+    let mut rest = param;
+    while let Some((b, tail)) = rest.split_first() {
+        match rest.strip_prefix(b"%22") {
+            Some(after_quote) => {
+                result.push(b'"');
+                rest = after_quote;
+            }
+            None => {
+                result.push(*b);
+                rest = tail;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_command() {
+        let command = parse_server_command(b"/send \"foo\" \"say %22hi%22\"");
+        assert_eq!(command.command, "send");
+        assert_eq!(
+            command.params,
+            vec![b"foo".to_vec(), b"say \"hi\"".to_vec()]
+        );
+        let command = parse_server_command(b"/$channel \"General\" \"0\"");
+        assert_eq!(command.command, "$channel");
+        assert_eq!(command.params.len(), 2);
+        assert!(parse_server_command(b"/ping").params.is_empty());
+    }
+}
@@ -0,0 +1,2 @@
+//! The game's lobby protocol as seen from the other end of the connection
+pub mod client;
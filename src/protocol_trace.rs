@@ -0,0 +1,120 @@
+use crate::capture::Capture;
+use anyhow::Result;
+use flexi_logger::writers::FileLogWriter;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Name of the log writer that receives the frame dumps
+pub const WRITER_NAME: &str = "protocol";
+const TARGET: &str = "{protocol}";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// Whether the frames of a single session are dumped to the protocol log. The connection
+/// handler owns the session, while the broker switches tracing on and off. Independent of
+/// that, the session's raw traffic may be captured to a file from start to end.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolTrace {
+    enabled: Arc<AtomicBool>,
+    capture: Option<Capture>,
+}
+
+impl ProtocolTrace {
+    pub fn with_capture(capture: Capture) -> Self {
+        Self {
+            enabled: Arc::default(),
+            capture: Some(capture),
+        }
+    }
+
+    /// Records bytes exactly as read from or written to the socket, if capturing
+    pub fn capture(&self, direction: Direction, bytes: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.record(direction, bytes);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn log_frame(&self, client_id: Uuid, direction: Direction, frame: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let arrow = match direction {
+            Direction::Received => "<<",
+            Direction::Sent => ">>",
+        };
+        log::info!(
+            target: TARGET,
+            "{} {} {} bytes\n{}",
+            client_id,
+            arrow,
+            frame.len(),
+            hex_dump(frame)
+        );
+    }
+}
+
+/// Formats bytes as lines of 16 in hex, followed by their printable characters
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x} ", i * 16);
+        for byte in line {
+            let _ = write!(dump, " {:02x}", byte);
+        }
+        let padding = (16 - line.len()) * 3;
+        let text: String = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            })
+            .collect();
+        let _ = writeln!(dump, "{:padding$}  |{}|", "", text, padding = padding);
+    }
+    dump
+}
+
+/// The writer to register with the logger under `WRITER_NAME`
+pub fn log_writer() -> Result<Box<FileLogWriter>> {
+    Ok(Box::new(
+        FileLogWriter::builder()
+            .discriminant(WRITER_NAME)
+            .suppress_timestamp()
+            .append()
+            .format(flexi_logger::opt_format)
+            .try_build()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(hex_dump(&[]), "");
+        assert_eq!(
+            hex_dump(b"/send \"hi\"\x00"),
+            "00000000  2f 73 65 6e 64 20 22 68 69 22 00                 |/send \"hi\".|\n"
+        );
+        let dump = hex_dump(&[0x41; 17]);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("00000010  41   "));
+        assert!(lines[1].ends_with("|A|"));
+    }
+}
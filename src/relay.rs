@@ -0,0 +1,197 @@
+use crate::config::RelayConfig;
+use crate::server::spawn_and_log_error;
+use anyhow::Result;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+/// Allocates UDP relay ports for hosted games, so that joiners connect to the server rather
+/// than to the host. Joiners are handed the relay's public address instead of the host's,
+/// which keeps the host's address private and lets in joiners who cannot reach the host
+/// themselves. The host still has to be reachable from the relay on its game port; the relay
+/// does not get through a NAT the host has not forwarded that port on. Each joiner gets its
+/// own upstream socket towards the host, so the host sees every joiner as a separate peer.
+#[derive(Clone)]
+pub struct Relay {
+    config: Arc<RelayConfig>,
+    ports_in_use: Arc<Mutex<HashSet<u16>>>,
+}
+
+/// A relay port in use by a game. The relay stops when this is dropped.
+#[derive(Debug)]
+pub struct RelayAllocation {
+    pub public_ip: Ipv4Addr,
+    pub port: u16,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Relay {
+    pub fn new(config: &RelayConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        Some(Self {
+            config: Arc::new(config.clone()),
+            ports_in_use: Default::default(),
+        })
+    }
+
+    /// Starts relaying to the given host on a free port, if there is one
+    pub async fn allocate(&self, host: SocketAddrV4) -> Option<RelayAllocation> {
+        for port in self.config.port_range_start..=self.config.port_range_end {
+            if !self.ports_in_use.lock().unwrap().insert(port) {
+                continue;
+            }
+            match UdpSocket::bind((self.config.bind_ip, port)).await {
+                Ok(socket) => {
+                    let (stop_send, stop_recv) = oneshot::channel();
+                    log::info!("Relaying port {} to game host {}", port, host);
+                    let ports_in_use = self.ports_in_use.clone();
+                    let idle_timeout = Duration::from_secs(self.config.idle_timeout_secs);
+                    spawn_and_log_error(
+                        async move {
+                            let result = relay_loop(socket, host, idle_timeout, stop_recv).await;
+                            ports_in_use.lock().unwrap().remove(&port);
+                            log::info!("Relay on port {} stopped", port);
+                            result
+                        },
+                        "relay_loop",
+                    );
+                    return Some(RelayAllocation {
+                        public_ip: self.config.public_ip,
+                        port,
+                        _stop: stop_send,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Could not bind relay port {}: {}", port, e);
+                    self.ports_in_use.lock().unwrap().remove(&port);
+                }
+            }
+        }
+        log::warn!("No free relay port for game host {}", host);
+        None
+    }
+}
+
+struct Joiner {
+    upstream: SendHalf,
+    last_active: Instant,
+    _stop: oneshot::Sender<()>,
+}
+
+async fn relay_loop(
+    socket: UdpSocket,
+    host: SocketAddrV4,
+    idle_timeout: Duration,
+    mut stop: oneshot::Receiver<()>,
+) -> Result<()> {
+    let (mut recv, mut send) = socket.split();
+    let (reply_send, mut reply_recv) = mpsc::channel::<(SocketAddr, Vec<u8>)>(64);
+    let mut joiners: HashMap<SocketAddr, Joiner> = HashMap::new();
+    let host_addr = SocketAddr::V4(host);
+    let mut cleanup = tokio::time::interval(idle_timeout);
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            received = recv.recv_from(&mut buf) => {
+                // joiners may share the host's address, e.g. when playing from the same LAN
+                let (len, from) = received?;
+                let joiner = match joiners.entry(from) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        e.insert(connect_upstream(from, host_addr, reply_send.clone()).await?)
+                    }
+                };
+                joiner.last_active = Instant::now();
+                joiner.upstream.send(&buf[..len]).await?;
+            },
+            Some((joiner, data)) = reply_recv.recv() => {
+                send.send_to(&data, &joiner).await?;
+            },
+            _ = cleanup.tick() => {
+                joiners.retain(|_, j| j.last_active.elapsed() < idle_timeout);
+            },
+            _ = &mut stop => break,
+        }
+    }
+    Ok(())
+}
+
+async fn connect_upstream(
+    joiner: SocketAddr,
+    host: SocketAddr,
+    mut replies: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+) -> Result<Joiner> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(host).await?;
+    let (mut recv, send) = socket.split();
+    let (stop_send, mut stop_recv) = oneshot::channel::<()>();
+    spawn_and_log_error(
+        async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                tokio::select! {
+                    received = recv.recv(&mut buf) => {
+                        let len = received?;
+                        if replies.send((joiner, buf[..len].to_vec())).await.is_err() {
+                            break;
+                        }
+                    },
+                    _ = &mut stop_recv => break,
+                }
+            }
+            Ok(())
+        },
+        "relay_upstream",
+    );
+    Ok(Joiner {
+        upstream: send,
+        last_active: Instant::now(),
+        _stop: stop_send,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_relays_both_directions() {
+        let mut host = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let host_port = host.local_addr().unwrap().port();
+        let relay = Relay::new(&RelayConfig {
+            enabled: true,
+            public_ip: Ipv4Addr::LOCALHOST,
+            bind_ip: Ipv4Addr::LOCALHOST,
+            port_range_start: 27173,
+            port_range_end: 27180,
+            idle_timeout_secs: 60,
+        })
+        .unwrap();
+        let allocation = relay
+            .allocate(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), host_port))
+            .await
+            .unwrap();
+
+        let mut joiner = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        joiner
+            .send_to(b"hello", (Ipv4Addr::LOCALHOST, allocation.port))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, relayed_from) = host.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        host.send_to(b"welcome", &relayed_from).await.unwrap();
+        let (len, from) = joiner.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"welcome");
+        assert_eq!(from.port(), allocation.port);
+    }
+}
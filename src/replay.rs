@@ -0,0 +1,216 @@
+//! Reads back the sessions recorded with `--capture-dir`, so that real game client sessions
+//! can serve as regression tests. `TestWorld::replay` feeds a recording to a client handler.
+
+use crate::capture::Chunk;
+use crate::messages::login_client::parsers::compressed_message;
+use crate::protocol_trace::{hex_dump, Direction};
+use crate::version::build_info;
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// A captured session, chunk by chunk as the server read and wrote them
+pub struct Recording {
+    /// the build of the server that recorded the session, if noted in the header
+    server_build: Option<String>,
+    chunks: Vec<Chunk>,
+}
+
+/// A message between server and game client: zlib-compressed and length-prefixed during
+/// login, NUL-terminated text commands afterwards
+#[derive(Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// holds the decompressed message
+    Compressed(Vec<u8>),
+    /// holds the command including its terminating NUL
+    Command(Vec<u8>),
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Compressed(bytes) => write!(f, "compressed\n{}", hex_dump(bytes)),
+            Frame::Command(bytes) => write!(f, "{:?}", String::from_utf8_lossy(bytes)),
+        }
+    }
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read recording {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid recording {}", path.display()))
+    }
+
+    /// Parses a capture file. Lines starting with `#` are comments, so recordings can be
+    /// annotated before they are checked in.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut server_build = None;
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut expected_len = 0;
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || anyhow!("Invalid line {}: {}", number + 1, line);
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some((_, build)) = comment.split_once(", server ") {
+                    server_build = Some(build.trim().to_string());
+                }
+            } else if let Some((hex, _)) = line.split_once("  |") {
+                // the offset comes first, and the printable characters may contain anything
+                let chunk = chunks.last_mut().ok_or_else(invalid)?;
+                for byte in hex.split_whitespace().skip(1) {
+                    chunk
+                        .bytes
+                        .push(u8::from_str_radix(byte, 16).map_err(|_| invalid())?);
+                }
+            } else if !line.trim().is_empty() {
+                if chunks.last().is_some_and(|c| c.bytes.len() != expected_len) {
+                    return Err(anyhow!("Chunk before line {} is incomplete", number + 1));
+                }
+                let (chunk, len) = parse_chunk_header(line).ok_or_else(invalid)?;
+                chunks.push(chunk);
+                expected_len = len;
+            }
+        }
+        if chunks.last().is_some_and(|c| c.bytes.len() != expected_len) {
+            return Err(anyhow!("Last chunk is incomplete"));
+        }
+        Ok(Self {
+            server_build,
+            chunks,
+        })
+    }
+
+    /// The chunks the client sent, in order
+    pub fn received(&self) -> impl Iterator<Item = &[u8]> {
+        self.chunks
+            .iter()
+            .filter(|c| matches!(c.direction, Direction::Received))
+            .map(|c| c.bytes.as_slice())
+    }
+
+    /// Everything the server sent, in order
+    pub fn sent(&self) -> Vec<u8> {
+        self.chunks
+            .iter()
+            .filter(|c| matches!(c.direction, Direction::Sent))
+            .flat_map(|c| c.bytes.iter().copied())
+            .collect()
+    }
+
+    /// The frames the server sent, as this build would send them. Only the server build
+    /// announced during login is expected to differ, so it is replaced by the current one.
+    pub fn expected_frames(&self) -> Result<Vec<Frame>> {
+        let mut frames = split_frames(&self.sent())?;
+        if let Some(build) = &self.server_build {
+            for frame in &mut frames {
+                if let Frame::Compressed(bytes) = frame {
+                    *bytes = replace_bytes(bytes, build.as_bytes(), build_info().as_bytes());
+                }
+            }
+        }
+        Ok(frames)
+    }
+}
+
+/// Parses e.g. `1.500 << 5 bytes` into an empty chunk and its length
+fn parse_chunk_header(line: &str) -> Option<(Chunk, usize)> {
+    let mut parts = line.split_whitespace();
+    let elapsed = Duration::from_secs_f64(parts.next()?.parse().ok()?);
+    let direction = match parts.next()? {
+        "<<" => Direction::Received,
+        ">>" => Direction::Sent,
+        _ => return None,
+    };
+    let len = parts.next()?.parse().ok()?;
+    let chunk = Chunk {
+        direction,
+        elapsed,
+        bytes: Vec::with_capacity(len),
+    };
+    Some((chunk, len))
+}
+
+/// Splits the bytes sent in one direction into frames, which have to be complete
+pub fn split_frames(mut bytes: &[u8]) -> Result<Vec<Frame>> {
+    let mut frames = Vec::new();
+    while !bytes.is_empty() {
+        // a compressed message may well start with a slash, depending on its length
+        if let Ok((rest, message)) = compressed_message(bytes) {
+            frames.push(Frame::Compressed(message));
+            bytes = rest;
+        } else if bytes[0] == b'/' {
+            let end = bytes
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow!("Unterminated command at the end"))?;
+            frames.push(Frame::Command(bytes[..=end].to_vec()));
+            bytes = &bytes[end + 1..];
+        } else {
+            return Err(anyhow!("Unknown frame after {} frames", frames.len()));
+        }
+    }
+    Ok(frames)
+}
+
+fn replace_bytes(bytes: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut replaced = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if !from.is_empty() && rest.starts_with(from) {
+            replaced.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            replaced.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::login_server::compress_bytes;
+
+    #[test]
+    fn test_parse() {
+        let text = "# client 1 from 127.0.0.1:1234, connected at 1, server 0.1.0 (abcdef0)\n\
+                    0.000 << 5 bytes\n\
+                    00000000  2f 6e 6f 70 00                                   |/nop.|\n\
+                    0.010 >> 18 bytes\n\
+                    00000000  2f 6e 6f 70 00 2f 6e 6f 70 20 22 7c 20 20 7c 22  |/nop./nop \"|  |\"|\n\
+                    00000010  00 00                                            |..|\n";
+        let recording = Recording::parse(text).unwrap();
+        assert_eq!(recording.server_build.as_deref(), Some("0.1.0 (abcdef0)"));
+        assert_eq!(recording.received().collect::<Vec<_>>(), vec![b"/nop\0"]);
+        assert_eq!(recording.sent().len(), 18);
+
+        let truncated = text.replace("18 bytes", "19 bytes");
+        assert!(Recording::parse(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_split_frames() {
+        let mut bytes = compress_bytes(b"welcome").unwrap();
+        bytes.extend_from_slice(b"/join \"General\"\0/nop\0");
+        assert_eq!(
+            split_frames(&bytes).unwrap(),
+            vec![
+                Frame::Compressed(b"welcome".to_vec()),
+                Frame::Command(b"/join \"General\"\0".to_vec()),
+                Frame::Command(b"/nop\0".to_vec()),
+            ]
+        );
+        assert!(split_frames(b"/nop").is_err());
+    }
+
+    #[test]
+    fn test_replace_bytes() {
+        assert_eq!(
+            replace_bytes(b"IE::Net 1 (a)", b"1 (a)", b"2 (b)"),
+            b"IE::Net 2 (b)"
+        );
+        assert_eq!(replace_bytes(b"abc", b"", b"x"), b"abc");
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config::ReplicationConfig;
+use crate::federation::{read_message, write_message};
+use crate::server::{bind_listener, connect_stream, spawn_and_log_error};
+use crate::storage::{read_documents, write_document};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::stream::StreamExt;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::sync::watch;
+use tokio::time::{delay_for, interval, timeout};
+
+/// Passes every document the storage writes on to the connected standby servers
+pub type ReplicationFeed = broadcast::Sender<ReplicationMessage>;
+
+/// writes a standby may fall behind by before it has to reconnect and start over
+const FEED_CAPACITY: usize = 1024;
+/// unlike federation messages, a line carries a whole document
+const MAX_LINE_LENGTH: u64 = 64 * 1024 * 1024;
+/// for the unauthenticated greeting
+const MAX_HELLO_LENGTH: u64 = 4 * 1024;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Messages from the primary to a standby, one JSON document per line.
+/// Both sides start by presenting the secret.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplicationMessage {
+    Hello {
+        secret: String,
+    },
+    /// the complete contents of a storage document
+    Document {
+        name: String,
+        contents: String,
+    },
+    Heartbeat,
+}
+
+pub fn feed() -> ReplicationFeed {
+    broadcast::channel(FEED_CAPACITY).0
+}
+
+/// Documents are named by the server itself, but a standby must not write wherever it is told
+fn valid_document_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Sends a standby every document there is, then every write as it happens
+async fn run_standby_link(
+    stream: TcpStream,
+    config: ReplicationConfig,
+    data_dir: PathBuf,
+    mut writes: broadcast::Receiver<ReplicationMessage>,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let standby = stream.peer_addr()?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    match read_message(&mut reader, MAX_HELLO_LENGTH).await? {
+        Some(ReplicationMessage::Hello { secret })
+            if secret == config.secret && !config.secret.is_empty() => {}
+        _ => return Err(anyhow!("Standby {} failed to authenticate", standby)),
+    }
+    let hello = ReplicationMessage::Hello {
+        secret: config.secret.clone(),
+    };
+    write_message(&mut writer, &hello).await?;
+
+    // the link subscribed to the writes before, so none can fall between the copy and them
+    let documents = read_documents(&data_dir)?;
+    log::info!(
+        "Standby {} connected, sending {} documents",
+        standby,
+        documents.len()
+    );
+    for (name, contents) in documents {
+        write_message(
+            &mut writer,
+            &ReplicationMessage::Document { name, contents },
+        )
+        .await?;
+    }
+
+    let mut heartbeat = interval(Duration::from_secs(config.heartbeat_secs));
+    loop {
+        tokio::select! {
+            write = writes.recv() => match write {
+                Ok(message) => write_message(&mut writer, &message).await?,
+                Err(RecvError::Lagged(_)) => {
+                    return Err(anyhow!("Standby {} fell behind and has to start over", standby))
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = heartbeat.tick() => write_message(&mut writer, &ReplicationMessage::Heartbeat).await?,
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+        }
+    }
+    log::info!("Replication link with standby {} closed", standby);
+    Ok(())
+}
+
+/// Accepts connections from standby servers
+pub async fn serve_loop(
+    config: ReplicationConfig,
+    address: String,
+    data_dir: PathBuf,
+    feed: ReplicationFeed,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut listener = bind_listener(&address)?;
+    log::info!("Listening for standby servers at {}", &address);
+    let mut incoming = listener.incoming();
+    loop {
+        tokio::select! {
+            Some(connection) = incoming.next() => {
+                spawn_and_log_error(
+                    run_standby_link(
+                        connection?,
+                        config.clone(),
+                        data_dir.clone(),
+                        feed.subscribe(),
+                        shutdown_recv.clone(),
+                    ),
+                    "replication_standby_link",
+                );
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            else => break,
+        }
+    }
+    Ok(())
+}
+
+/// Copies the primary's documents into the data directory for as long as it is alive
+async fn mirror_primary(
+    config: &ReplicationConfig,
+    primary: &str,
+    data_dir: &Path,
+    last_contact: &mut Instant,
+) -> Result<()> {
+    let silence = Duration::from_secs(config.takeover_secs);
+    let stream = timeout(silence, connect_stream(primary)).await??;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let hello = ReplicationMessage::Hello {
+        secret: config.secret.clone(),
+    };
+    write_message(&mut writer, &hello).await?;
+    match timeout(silence, read_message(&mut reader, MAX_HELLO_LENGTH)).await?? {
+        Some(ReplicationMessage::Hello { secret }) if secret == config.secret => {}
+        _ => return Err(anyhow!("Primary failed to authenticate")),
+    }
+    log::info!("Replicating from primary {}", primary);
+
+    loop {
+        let message = match timeout(silence, read_message(&mut reader, MAX_LINE_LENGTH)).await {
+            Ok(message) => message?,
+            Err(_) => return Err(anyhow!("Primary stopped sending heartbeats")),
+        };
+        *last_contact = Instant::now();
+        match message {
+            Some(ReplicationMessage::Document { name, contents }) => {
+                if !valid_document_name(&name) {
+                    return Err(anyhow!("Invalid document name {}", name));
+                }
+                write_document(data_dir, &name, &contents).await?;
+            }
+            Some(ReplicationMessage::Heartbeat) => (),
+            Some(ReplicationMessage::Hello { .. }) => {
+                return Err(anyhow!("Primary greeted twice"));
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Runs a standby: mirrors the primary's data and returns once the primary has not been heard
+/// from for `takeover_secs`, at which point this server has to take over
+pub async fn follow_primary(
+    config: &ReplicationConfig,
+    primary: &str,
+    data_dir: &Path,
+) -> Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    let silence = Duration::from_secs(config.takeover_secs);
+    let mut last_contact = Instant::now();
+    loop {
+        match mirror_primary(config, primary, data_dir, &mut last_contact).await {
+            Ok(()) => log::warn!("Primary {} closed the replication link", primary),
+            Err(e) => log::warn!("Replication link to primary {} failed: {}", primary, e),
+        }
+        if last_contact.elapsed() >= silence {
+            log::warn!(
+                "No word from primary {} for {} seconds, taking over",
+                primary,
+                config.takeover_secs
+            );
+            return Ok(());
+        }
+        delay_for(RETRY_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_document_name() {
+        assert!(valid_document_name("accounts"));
+        assert!(valid_document_name("match_history"));
+        assert!(!valid_document_name(""));
+        assert!(!valid_document_name("../accounts"));
+        assert!(!valid_document_name("/etc/passwd"));
+    }
+}
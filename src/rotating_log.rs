@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+fn rotated_path(dir: &Path, file_name: &str, index: usize) -> PathBuf {
+    match index {
+        0 => dir.join(file_name),
+        n => dir.join(format!("{}.{}", file_name, n)),
+    }
+}
+
+/// Shifts every log file one index up, dropping the oldest
+async fn rotate(dir: &Path, file_name: &str, max_files: usize) -> Result<()> {
+    let oldest = rotated_path(dir, file_name, max_files);
+    if oldest.exists() {
+        tokio::fs::remove_file(&oldest).await?;
+    }
+    for index in (0..max_files).rev() {
+        let path = rotated_path(dir, file_name, index);
+        if path.exists() {
+            tokio::fs::rename(&path, rotated_path(dir, file_name, index + 1)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn open_log(dir: &Path, file_name: &str) -> Result<(File, u64)> {
+    let path = rotated_path(dir, file_name, 0);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    let size = file.metadata().await?.len();
+    Ok((file, size))
+}
+
+/// Appends entries as JSON lines to `file_name` in `dir`. Once the file would grow past
+/// `max_bytes`, it is rotated, keeping `max_files` older files besides the current one.
+/// Finishes when the sender is dropped and all entries are written.
+pub(crate) async fn json_lines_writer<T: Serialize>(
+    dir: PathBuf,
+    file_name: &'static str,
+    max_bytes: u64,
+    max_files: usize,
+    mut entries: mpsc::UnboundedReceiver<T>,
+) -> Result<()> {
+    let (mut file, mut size) = open_log(&dir, file_name).await?;
+    while let Some(entry) = entries.recv().await {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        if size > 0 && size + line.len() as u64 > max_bytes {
+            file.flush().await?;
+            drop(file);
+            rotate(&dir, file_name, max_files).await?;
+            let (reopened, reopened_size) = open_log(&dir, file_name).await?;
+            file = reopened;
+            size = reopened_size;
+        }
+        file.write_all(&line).await?;
+        size += line.len() as u64;
+    }
+    file.flush().await?;
+    log::info!("Writer for {} finished", file_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("ie_net_rotating_log_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (send, recv) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(json_lines_writer(dir.clone(), "test.log", 100, 1, recv));
+        for i in 0..3 {
+            send.send(format!("{:0>60}", format!("user{}", i))).unwrap();
+        }
+        drop(send);
+        writer.await.unwrap().unwrap();
+
+        // every entry is too long to share a file with another, only the last two are kept
+        let current = std::fs::read_to_string(rotated_path(&dir, "test.log", 0)).unwrap();
+        let rotated = std::fs::read_to_string(rotated_path(&dir, "test.log", 1)).unwrap();
+        assert!(current.contains("user2"));
+        assert!(rotated.contains("user1"));
+        assert!(!rotated_path(&dir, "test.log", 2).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,50 +1,407 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 
-use crate::broker::{broker_loop, Event};
+use crate::admin_api;
+use crate::announcements;
+use crate::broker::control::ControlCommand;
+use crate::broker::plugin::BrokerPlugin;
+use crate::broker::{broker_loop, Event, EventSender};
 use crate::client::client_handler;
+use crate::config::{Config, Ipv6Config, ShutdownConfig, SocketsConfig, VersionsConfig};
+use crate::console;
+use crate::federation;
+use crate::heartbeat::heartbeat_loop;
+use crate::master::registration_loop;
+use crate::metrics::{create_sink, export_loop, Metrics};
+use crate::replication;
+use crate::status_api;
+use crate::trace::{self, Tracer};
+use crate::validation::NameValidator;
+use crate::websocket;
+use futures::future;
 use std::future::Future;
-use tokio::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::signal;
 use tokio::stream::StreamExt;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
 use tokio::task;
 use tokio::task::JoinHandle;
+use tokio::time::delay_for;
+
+pub async fn run(addrs: Vec<String>, config: Config) -> Result<()> {
+    run_with_plugins(addrs, config, Vec::new()).await
+}
+
+/// Runs the server with custom behavior added to the broker
+pub async fn run_with_plugins(
+    addrs: Vec<String>,
+    config: Config,
+    plugins: Vec<Box<dyn BrokerPlugin>>,
+) -> Result<()> {
+    run_until(addrs, config, plugins, signal_watch()).await
+}
+
+/// Runs the server until `shutdown` completes instead of waiting for a signal.
+/// Returns once every task the server started has finished.
+///
+/// Shutdown happens in two steps: first the listeners stop accepting connections and users get
+/// the configured notice, then the broker is sent `Event::Shutdown`, which it stops on after
+/// the events queued before it, and the shutdown watch fires, which every other task selects
+/// on. Client handlers drop their connection after flushing what was queued, and each accept
+/// loop waits for its handlers before it finishes.
+pub async fn run_until(
+    addrs: Vec<String>,
+    config: Config,
+    plugins: Vec<Box<dyn BrokerPlugin>>,
+    shutdown: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    tokio::pin!(shutdown);
+    if addrs.is_empty() {
+        return Err(anyhow!("At least one listen address is needed"));
+    }
+    if config.versions.accepted.is_empty() {
+        return Err(anyhow!("At least one game version has to be accepted"));
+    }
+    let replication = &config.replication;
+    let data_dir = match (&config.storage.data_dir, replication.enabled) {
+        (None, true) => return Err(anyhow!("Replication needs a storage data_dir")),
+        (data_dir, _) => data_dir.clone(),
+    };
+    if let (true, Some(primary), Some(data_dir)) =
+        (replication.enabled, &replication.primary, &data_dir)
+    {
+        log::info!("Starting as standby of {}", primary);
+        tokio::select! {
+            result = replication::follow_primary(replication, primary, data_dir) => result?,
+            result = &mut shutdown => {
+                log::info!("Received shutdown signal");
+                return result;
+            }
+        }
+    }
+
+    // a server that cannot listen at every one of its addresses must not run
+    let listeners = addrs
+        .iter()
+        .map(|addr| bind_listener(addr).with_context(|| format!("Could not listen at {}", addr)))
+        .collect::<Result<Vec<_>>>()?;
 
-pub async fn run(addr: String) -> Result<()> {
     let (shutdown_send, shutdown_recv) = watch::channel(false);
+    // listeners stop accepting connections before the rest of the server goes down
+    let (draining_send, draining_recv) = watch::channel(false);
+
+    let metrics = Metrics::new();
+    let metrics_handle = create_sink(&config.metrics).map(|sink| {
+        spawn_and_log_error(
+            export_loop(
+                metrics.clone(),
+                sink,
+                Duration::from_secs(config.metrics.interval_secs),
+                shutdown_recv.clone(),
+            ),
+            "metrics_export_loop",
+        )
+    });
+
+    let tracer = if config.tracing.enabled {
+        Tracer::new(config.tracing.sample_ratio)
+    } else {
+        Tracer::disabled()
+    };
+    let trace_handle = if config.tracing.enabled {
+        Some(spawn_and_log_error(
+            trace::export_loop(
+                tracer.clone(),
+                config.tracing.address.clone(),
+                config.tracing.service_name.clone(),
+                Duration::from_secs(config.tracing.interval_secs),
+                shutdown_recv.clone(),
+            ),
+            "trace_export_loop",
+        ))
+    } else {
+        None
+    };
 
-    let (broker_sender, broker_receiver) = mpsc::channel(256);
-    let mut broker_handle = spawn_and_log_error(
-        broker_loop(broker_receiver, shutdown_recv.clone()),
+    let replication_feed = match (replication.enabled, &replication.bind) {
+        (true, Some(_)) => Some(replication::feed()),
+        _ => None,
+    };
+    let replication_handle = match (&replication_feed, &replication.bind, data_dir) {
+        (Some(feed), Some(bind), Some(data_dir)) => Some(spawn_and_log_error(
+            replication::serve_loop(
+                replication.clone(),
+                bind.clone(),
+                data_dir,
+                feed.clone(),
+                shutdown_recv.clone(),
+            ),
+            "replication_serve_loop",
+        )),
+        _ => None,
+    };
+
+    let (mut broker_sender, broker_receiver) = mpsc::channel(256);
+    let mut broker_handle = Some(spawn_and_log_error(
+        broker_loop(
+            broker_receiver,
+            shutdown_recv.clone(),
+            metrics.clone(),
+            config.clone(),
+            tracer.clone(),
+            plugins,
+            replication_feed,
+        ),
         "broker_loop",
-    );
-    let mut accept_handle = spawn_and_log_error(
-        accept_loop(addr, shutdown_recv.clone(), broker_sender),
-        "accept_loop",
-    );
+    ));
+    let master_handle = if config.master.enabled {
+        Some(spawn_and_log_error(
+            registration_loop(
+                config.master.clone(),
+                config.versions.names(),
+                broker_sender.clone(),
+                shutdown_recv.clone(),
+            ),
+            "master_registration_loop",
+        ))
+    } else {
+        None
+    };
+    let heartbeat_handle = if config.heartbeat.enabled {
+        Some(spawn_and_log_error(
+            heartbeat_loop(
+                config.heartbeat.clone(),
+                broker_sender.clone(),
+                shutdown_recv.clone(),
+            ),
+            "heartbeat_loop",
+        ))
+    } else {
+        None
+    };
+    let announcements_handle = if !config.announcements.scheduled.is_empty() {
+        Some(spawn_and_log_error(
+            announcements::schedule_loop(
+                config.announcements.clone(),
+                broker_sender.clone(),
+                shutdown_recv.clone(),
+            ),
+            "announcement_loop",
+        ))
+    } else {
+        None
+    };
+    let admin_api_handle = if config.admin_api.enabled {
+        Some(spawn_and_log_error(
+            admin_api::serve_loop(
+                config.admin_api.clone(),
+                broker_sender.clone(),
+                shutdown_recv.clone(),
+            ),
+            "admin_api_loop",
+        ))
+    } else {
+        None
+    };
+    // the console can request a shutdown, optionally with its own notice period
+    let (console_shutdown_send, mut console_shutdown_recv) = mpsc::channel(1);
+    let console_handle = if config.console.enabled {
+        Some(spawn_and_log_error(
+            console::serve_loop(
+                config.console.clone(),
+                broker_sender.clone(),
+                console_shutdown_send,
+                shutdown_recv.clone(),
+            ),
+            "console_loop",
+        ))
+    } else {
+        None
+    };
+    let status_handle = if config.status.enabled {
+        Some(spawn_and_log_error(
+            status_api::serve_loop(
+                config.status.clone(),
+                broker_sender.clone(),
+                shutdown_recv.clone(),
+            ),
+            "status_api_loop",
+        ))
+    } else {
+        None
+    };
+    let names = Arc::new(NameValidator::new(&config.names));
+    let websocket_handle = if config.websocket.enabled {
+        Some(spawn_and_log_error(
+            websocket::listen_loop(
+                config.websocket.clone(),
+                websocket::LoginSettings {
+                    game_version: config.versions.fallback(),
+                    names: names.clone(),
+                },
+                broker_sender.clone(),
+                metrics.clone(),
+                tracer.clone(),
+                shutdown_recv.clone(),
+                draining_recv.clone(),
+            ),
+            "websocket_listen_loop",
+        ))
+    } else {
+        None
+    };
+    let mut federation_handles = Vec::new();
+    if config.federation.enabled {
+        if let Some(bind) = &config.federation.bind {
+            federation_handles.push(spawn_and_log_error(
+                federation::listen_loop(
+                    config.federation.clone(),
+                    bind.clone(),
+                    broker_sender.clone(),
+                    shutdown_recv.clone(),
+                ),
+                "federation_listen_loop",
+            ));
+        }
+        for peer in &config.federation.peers {
+            federation_handles.push(spawn_and_log_error(
+                federation::connect_loop(
+                    config.federation.clone(),
+                    peer.clone(),
+                    broker_sender.clone(),
+                    shutdown_recv.clone(),
+                ),
+                "federation_connect_loop",
+            ));
+        }
+    }
+    if let Some(dir) = &config.capture.dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create capture directory {}", dir.display()))?;
+        log::warn!(
+            "Capturing the traffic of all game clients to {}",
+            dir.display()
+        );
+    }
+    let clients = Arc::new(ClientSettings::new(&config, names));
+    let mut accept_handles: Vec<JoinHandle<()>> = listeners
+        .into_iter()
+        .map(|listener| {
+            spawn_and_log_error(
+                accept_loop(
+                    listener,
+                    clients.clone(),
+                    shutdown_recv.clone(),
+                    draining_recv.clone(),
+                    broker_sender.clone(),
+                    metrics.clone(),
+                    tracer.clone(),
+                ),
+                "accept_loop",
+            )
+        })
+        .collect();
 
-    let result = shutdown_watch(&mut accept_handle, &mut broker_handle).await;
+    let requested = async {
+        tokio::select! {
+            result = &mut shutdown => result.map(|_| None),
+            Some(notice_secs) = console_shutdown_recv.recv() => {
+                log::info!("Shutdown requested on the console");
+                Ok(notice_secs)
+            }
+        }
+    };
+    let result = shutdown_watch(&mut accept_handles, &mut broker_handle, requested).await;
+    draining_send.broadcast(true)?;
+    let result = match result {
+        Ok(Some(notice_secs)) => {
+            let shutdown_config = ShutdownConfig {
+                notice_secs: notice_secs.unwrap_or(config.shutdown.notice_secs),
+                ..config.shutdown.clone()
+            };
+            wind_down(&shutdown_config, broker_sender.clone()).await;
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    };
     log::info!("Shutting down server");
+    // the broker is gone already if it failed
+    let _ = broker_sender.send(Event::Shutdown).await;
     shutdown_send.broadcast(true)?;
-    accept_handle.await?;
-    broker_handle.await?;
+    for accept_handle in accept_handles {
+        accept_handle.await?;
+    }
+    if let Some(broker_handle) = broker_handle {
+        broker_handle.await?;
+    }
+    if let Some(master_handle) = master_handle {
+        master_handle.await?;
+    }
+    if let Some(heartbeat_handle) = heartbeat_handle {
+        heartbeat_handle.await?;
+    }
+    if let Some(announcements_handle) = announcements_handle {
+        announcements_handle.await?;
+    }
+    if let Some(admin_api_handle) = admin_api_handle {
+        admin_api_handle.await?;
+    }
+    if let Some(console_handle) = console_handle {
+        console_handle.await?;
+    }
+    if let Some(status_handle) = status_handle {
+        status_handle.await?;
+    }
+    if let Some(websocket_handle) = websocket_handle {
+        websocket_handle.await?;
+    }
+    for handle in federation_handles {
+        handle.await?;
+    }
+    if let Some(replication_handle) = replication_handle {
+        replication_handle.await?;
+    }
+    if let Some(metrics_handle) = metrics_handle {
+        metrics_handle.await?;
+    }
+    if let Some(trace_handle) = trace_handle {
+        trace_handle.await?;
+    }
 
     result
 }
 
-async fn shutdown_watch(
-    accept_handle: &mut JoinHandle<()>,
-    broker_handle: &mut JoinHandle<()>,
-) -> Result<()> {
-    tokio::select! {
-        result = accept_handle => result?,
-        result = broker_handle => result?,
-        result = signal_watch() => {
+/// Waits for the shutdown request or for an accept loop or the broker loop to end on its own,
+/// so that the server does not keep running with a listener missing. A loop that has ended is
+/// taken out of its handles, as it must not be awaited again. Returns what the shutdown request
+/// yielded if there was one, which leaves time to wind down.
+async fn shutdown_watch<T>(
+    accept_handles: &mut Vec<JoinHandle<()>>,
+    broker_handle: &mut Option<JoinHandle<()>>,
+    shutdown: impl Future<Output = Result<T>>,
+) -> Result<Option<T>> {
+    let broker = match broker_handle.as_mut() {
+        Some(broker) if !accept_handles.is_empty() => broker,
+        _ => return Ok(None),
+    };
+    let accept = future::select_all(accept_handles.iter_mut());
+    let (accept_finished, broker_finished, result) = tokio::select! {
+        (result, index, _) = accept => (Some(index), false, result.map(|_| None).map_err(Into::into)),
+        result = broker => (None, true, result.map(|_| None).map_err(Into::into)),
+        result = shutdown => {
             log::info!("Received shutdown signal");
-            result?
+            (None, false, result.map(Some))
         }
     };
-    Ok(())
+    if let Some(index) = accept_finished {
+        accept_handles.remove(index);
+    }
+    if broker_finished {
+        *broker_handle = None;
+    }
+    result
 }
 
 #[cfg(target_family = "windows")]
@@ -64,31 +421,205 @@ async fn signal_watch() -> Result<()> {
     Ok(())
 }
 
+/// Warns the users that the server is going down and gives them time to wrap up
+async fn wind_down(config: &ShutdownConfig, mut broker: EventSender) {
+    if config.notice_secs > 0 {
+        log::info!("Shutting down in {} seconds", config.notice_secs);
+        let (respond_to, _) = oneshot::channel();
+        let notice = Event::Control {
+            command: ControlCommand::Broadcast {
+                message: format!("Server shutting down in {}s", config.notice_secs),
+                filter: None,
+                respond_to,
+            },
+        };
+        if broker.send(notice).await.is_err() {
+            return;
+        }
+        delay_for(Duration::from_secs(config.notice_secs)).await;
+    }
+    if !config.wait_for_games {
+        return;
+    }
+    let deadline = Instant::now() + Duration::from_secs(config.max_wait_secs);
+    loop {
+        let (respond_to, status) = oneshot::channel();
+        if broker.send(Event::QueryState { respond_to }).await.is_err() {
+            return;
+        }
+        let open_games = match status.await {
+            Ok(status) => status.open_games.len(),
+            Err(_) => return,
+        };
+        if open_games == 0 {
+            return;
+        }
+        if Instant::now() >= deadline {
+            log::info!("Giving up on waiting for {} open games", open_games);
+            return;
+        }
+        log::info!("Waiting for {} open games to start", open_games);
+        delay_for(Duration::from_secs(1)).await;
+    }
+}
+
+/// What the accept loops and client handlers share about the game clients they accept
+pub(crate) struct ClientSettings {
+    pub(crate) versions: Arc<VersionsConfig>,
+    pub(crate) names: Arc<NameValidator>,
+    pub(crate) ipv6: Ipv6Config,
+    /// one permit per client connection allowed at once, if limited
+    slots: Option<Arc<Semaphore>>,
+    pause_accepting: bool,
+    sockets: SocketsConfig,
+    /// where the raw traffic of each connection is recorded, if anywhere
+    pub(crate) capture_dir: Option<PathBuf>,
+}
+
+impl ClientSettings {
+    pub(crate) fn new(config: &Config, names: Arc<NameValidator>) -> Self {
+        Self {
+            versions: Arc::new(config.versions.clone()),
+            names,
+            ipv6: config.ipv6.clone(),
+            slots: config
+                .limits
+                .max_clients
+                .map(|max_clients| Arc::new(Semaphore::new(max_clients))),
+            pause_accepting: config.limits.pause_accepting,
+            sockets: config.sockets.clone(),
+            capture_dir: config.capture.dir.clone(),
+        }
+    }
+}
+
+/// Tunes an accepted socket, so that e.g. connections behind vanished NAT mappings are
+/// eventually closed by the OS
+fn apply_socket_options(stream: &TcpStream, config: &SocketsConfig) -> std::io::Result<()> {
+    if config.keepalive_secs > 0 {
+        stream.set_keepalive(Some(Duration::from_secs(config.keepalive_secs)))?;
+    }
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(size) = config.send_buffer_bytes {
+        stream.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_bytes {
+        stream.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
+
 async fn accept_loop(
-    addr: String,
+    mut listener: TcpListener,
+    clients: Arc<ClientSettings>,
     mut shutdown_recv: watch::Receiver<bool>,
+    mut draining_recv: watch::Receiver<bool>,
     broker_sender: mpsc::Sender<Event>,
+    metrics: Metrics,
+    tracer: Tracer,
 ) -> Result<()> {
-    let mut listener = TcpListener::bind(&addr).await?;
+    let addr = listener.local_addr()?;
     log::info!("Listening for connections at {}", &addr);
 
+    // every client handler holds a clone, so the channel closes once all of them are finished
+    let (handlers_alive, mut handlers_finished) = mpsc::channel::<()>(1);
     let mut incoming_connections = listener.incoming();
     loop {
+        // while paused, new connections wait in the listen backlog until a client leaves
+        let reserved = match &clients.slots {
+            Some(slots) if clients.pause_accepting && slots.available_permits() == 0 => {
+                log::warn!("Client limit reached, pausing accepting at {}", &addr);
+                tokio::select! {
+                    permit = slots.clone().acquire_owned() => {
+                        log::info!("Resuming accepting at {}", &addr);
+                        Some(permit)
+                    },
+                    Some(shutdown) = shutdown_recv.recv() => if shutdown { break } else { continue },
+                    Some(draining) = draining_recv.recv() => if draining { break } else { continue },
+                    else => break,
+                }
+            }
+            _ => None,
+        };
         tokio::select! {
             Some(connection) = incoming_connections.next() => {
                 let connection = connection?;
+                let permit = match (reserved, &clients.slots) {
+                    (Some(permit), _) => Some(permit),
+                    (None, Some(slots)) => match slots.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            log::warn!("Client limit reached, closing new connection");
+                            metrics.increment("connections.refused");
+                            continue;
+                        }
+                    },
+                    (None, None) => None,
+                };
                 log::info!("New connection established");
-                spawn_and_log_error(client_handler(connection, broker_sender.clone()), "client_handler");
+                metrics.increment("connections.accepted");
+                if let Err(e) = apply_socket_options(&connection, &clients.sockets) {
+                    log::warn!("Could not set socket options: {}", e);
+                }
+                let handler = client_handler(
+                    connection,
+                    broker_sender.clone(),
+                    metrics.clone(),
+                    tracer.clone(),
+                    clients.clone(),
+                    shutdown_recv.clone(),
+                );
+                let alive = handlers_alive.clone();
+                spawn_and_log_error(
+                    async move {
+                        let _alive = alive;
+                        let _permit = permit;
+                        handler.await
+                    },
+                    "client_handler",
+                );
             },
             Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            Some(draining) = draining_recv.recv() => if draining { break },
             else => break,
         }
     }
 
-    log::info!("Accept loop shutting down");
+    log::info!("Accept loop at {} shutting down", &addr);
+    // refuse further connections while the handlers finish
+    drop(incoming_connections);
+    drop(listener);
+    drop(handlers_alive);
+    handlers_finished.recv().await;
+    log::info!("All client handlers finished");
     Ok(())
 }
 
+/// Completes once shutdown was requested, for work that has to be interrupted midway
+pub(crate) async fn wait_for_shutdown(mut shutdown_recv: watch::Receiver<bool>) {
+    while let Some(shutdown) = shutdown_recv.recv().await {
+        if shutdown {
+            return;
+        }
+    }
+}
+
+/// Binds through the standard library, as the async runtime's own socket setup passes
+/// malformed addresses to the OS with current compilers
+pub(crate) fn bind_listener(addr: &str) -> Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// Like `bind_listener`, connects through the standard library
+pub(crate) async fn connect_stream(addr: &str) -> Result<TcpStream> {
+    let addr = addr.to_string();
+    let stream = task::spawn_blocking(move || std::net::TcpStream::connect(addr)).await??;
+    Ok(TcpStream::from_std(stream)?)
+}
+
 pub fn spawn_and_log_error<F>(future: F, description: &'static str) -> task::JoinHandle<()>
 where
     F: Future<Output = Result<()>> + Send + 'static,
@@ -99,3 +630,24 @@ where
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_socket_options() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_std(client).unwrap();
+        let config = SocketsConfig {
+            keepalive_secs: 30,
+            nodelay: true,
+            send_buffer_bytes: Some(16 * 1024),
+            recv_buffer_bytes: None,
+        };
+        apply_socket_options(&stream, &config).unwrap();
+        assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(30)));
+        assert!(stream.nodelay().unwrap());
+    }
+}
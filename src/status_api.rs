@@ -0,0 +1,73 @@
+use crate::broker::status::ServerStatus;
+use crate::broker::{Event, EventSender};
+use crate::config::StatusApiConfig;
+use crate::http::{serve, Request, Response};
+use anyhow::Result;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, watch};
+
+/// The last status document handed out, so that busy websites do not hit the broker on every view
+type Cache = Arc<Mutex<Option<(Instant, String)>>>;
+
+async fn query_state(broker: &mut EventSender) -> Result<ServerStatus> {
+    let (respond_to, response) = oneshot::channel();
+    broker.send(Event::QueryState { respond_to }).await?;
+    Ok(response.await?)
+}
+
+async fn status_json(mut broker: EventSender, cache: Cache, max_age: Duration) -> Result<String> {
+    if let Some((created, body)) = &*cache.lock().unwrap() {
+        if created.elapsed() < max_age {
+            return Ok(body.clone());
+        }
+    }
+    let body = serde_json::to_string(&query_state(&mut broker).await?)?;
+    *cache.lock().unwrap() = Some((Instant::now(), body.clone()));
+    Ok(body)
+}
+
+async fn handle_request(
+    request: Request,
+    broker: EventSender,
+    cache: Cache,
+    max_age: Duration,
+) -> Response {
+    let mut response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status.json") => match status_json(broker, cache, max_age).await {
+            Ok(body) => Response {
+                status: 200,
+                body,
+                allow_any_origin: false,
+            },
+            Err(e) => {
+                log::warn!("Failed to produce server status: {}", e);
+                Response::json(500, &json!({ "error": "status unavailable" }))
+            }
+        },
+        (_, "/status.json") => Response::json(405, &json!({ "error": "method not allowed" })),
+        _ => Response::json(404, &json!({ "error": "not found" })),
+    };
+    response.allow_any_origin = true;
+    response
+}
+
+/// Serves the public, read-only status endpoint until shutdown
+pub async fn serve_loop(
+    config: StatusApiConfig,
+    broker: EventSender,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    log::info!("Status endpoint listening at {}", &config.bind);
+    let cache = Cache::default();
+    let max_age = Duration::from_secs(config.cache_secs);
+    serve(
+        &config.bind,
+        move |request| handle_request(request, broker.clone(), cache.clone(), max_age),
+        shutdown_recv,
+    )
+    .await?;
+    log::info!("Status endpoint shutting down");
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use crate::replication::{ReplicationFeed, ReplicationMessage};
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[derive(Debug)]
+struct WriteDocument {
+    name: &'static str,
+    contents: String,
+}
+
+/// Persists named JSON documents in a data directory.
+/// Documents are loaded synchronously at startup, while writes are handed to a
+/// dedicated writer task so that the broker never blocks on disk I/O.
+/// Without a data directory, nothing is persisted and every document starts out empty.
+pub struct Storage {
+    dir: Option<PathBuf>,
+    writes: Option<mpsc::UnboundedSender<WriteDocument>>,
+}
+
+impl Storage {
+    pub fn in_memory() -> Self {
+        Self {
+            dir: None,
+            writes: None,
+        }
+    }
+
+    /// Opens the storage and spawns its writer task. Written documents are also passed on to
+    /// the replication feed, if there is one.
+    /// The writer finishes once the storage is dropped and all pending writes are flushed.
+    pub fn open(
+        dir: &Path,
+        replication: Option<ReplicationFeed>,
+    ) -> Result<(Self, JoinHandle<()>)> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create data directory {}", dir.display()))?;
+        let (send, recv) = mpsc::unbounded_channel();
+        let handle = crate::server::spawn_and_log_error(
+            storage_writer(dir.to_path_buf(), recv, replication),
+            "storage_writer",
+        );
+        Ok((
+            Self {
+                dir: Some(dir.to_path_buf()),
+                writes: Some(send),
+            },
+            handle,
+        ))
+    }
+
+    pub fn load<T: DeserializeOwned + Default>(&self, name: &'static str) -> Result<T> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(T::default()),
+        };
+        let path = document_path(dir, name);
+        if !path.exists() {
+            return Ok(T::default());
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        serde_json::from_str(&contents).with_context(|| format!("Corrupt {}", path.display()))
+    }
+
+    pub fn save<T: Serialize>(&self, name: &'static str, value: &T) {
+        let writes = match &self.writes {
+            Some(writes) => writes,
+            None => return,
+        };
+        match serde_json::to_string_pretty(value) {
+            Ok(contents) => {
+                if writes.send(WriteDocument { name, contents }).is_err() {
+                    log::error!("Storage writer is gone, could not save {}", name);
+                }
+            }
+            Err(e) => log::error!("Could not serialize {}: {}", name, e),
+        }
+    }
+}
+
+fn document_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.json", name))
+}
+
+/// Reads every document in the data directory, by name
+pub fn read_documents(dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut documents = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some("json".as_ref()) {
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                documents.push((name.to_string(), std::fs::read_to_string(&path)?));
+            }
+        }
+    }
+    Ok(documents)
+}
+
+pub async fn write_document(dir: &Path, name: &str, contents: &str) -> Result<()> {
+    // write to a temporary file first so a crash never leaves a truncated document
+    let path = document_path(dir, name);
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, contents).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+async fn storage_writer(
+    dir: PathBuf,
+    mut writes: mpsc::UnboundedReceiver<WriteDocument>,
+    replication: Option<ReplicationFeed>,
+) -> Result<()> {
+    while let Some(write) = writes.recv().await {
+        write_document(&dir, write.name, &write.contents).await?;
+        if let Some(replication) = &replication {
+            // fails only if no standby is connected
+            let _ = replication.send(ReplicationMessage::Document {
+                name: write.name.to_string(),
+                contents: write.contents,
+            });
+        }
+    }
+    log::info!("Storage writer finished");
+    Ok(())
+}
@@ -0,0 +1,764 @@
+//! Drives a broker in-process for tests, without any sockets. Enabled by the `test-util` feature.
+//! `TestWorld::builder()` sets up users, channels and games, whose clients can then be taken
+//! out of the world to send commands as them and to check what they received.
+//! Captured sessions of real game clients can be replayed against the world as well.
+
+use crate::broker::control::ControlCommand;
+use crate::broker::fingerprint::Fingerprint;
+use crate::broker::plugin::BrokerPlugin;
+use crate::broker::snapshot::{diff, ClientView, LobbySnapshot, Mismatch};
+use crate::broker::status::ServerStatus;
+use crate::broker::user::Location;
+use crate::broker::{
+    broker_loop, message_queue, Event, EventSender, MessageReceiver, OutgoingMessage,
+};
+use crate::client::{client_handler, default_game_version};
+use crate::config::Config;
+use crate::federation::PeerMessage;
+use crate::messages::client_command::ClientCommand;
+use crate::messages::lobby_error::LobbyError;
+use crate::messages::ServerMessage;
+use crate::metrics::Metrics;
+use crate::protocol_trace::ProtocolTrace;
+use crate::replay::{split_frames, Frame, Recording};
+use crate::server::ClientSettings;
+use crate::trace::Tracer;
+use crate::validation::NameValidator;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Shutdown};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+/// A running broker together with the clients created by its builder
+pub struct TestWorld {
+    events: EventSender,
+    /// kept so the broker does not take the closed watch for a shutdown
+    #[allow(dead_code)]
+    shutdown_send: watch::Sender<bool>,
+    join_handle: JoinHandle<Result<()>>,
+    clients: HashMap<String, TestClient>,
+    /// for the client handlers that replay recordings
+    client_settings: Arc<ClientSettings>,
+}
+
+/// How long the server gets to answer a replayed chunk before the next one is sent
+const REPLAY_SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// What the server sent when a recorded session was replayed against a `TestWorld`
+pub struct Replay {
+    expected: Vec<Frame>,
+    sent: Vec<u8>,
+}
+
+/// Describes the lobby a `TestWorld` starts out with
+#[derive(Default)]
+pub struct TestWorldBuilder {
+    config: Config,
+    plugins: Vec<Box<dyn BrokerPlugin>>,
+    users: Vec<String>,
+    channels: Vec<(String, Vec<String>)>,
+    games: Vec<(String, String)>,
+}
+
+impl TestWorldBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn plugin(mut self, plugin: Box<dyn BrokerPlugin>) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    /// A user logged in to the default channel
+    pub fn user(mut self, username: &str) -> Self {
+        if !self.users.iter().any(|u| u == username) {
+            self.users.push(username.to_string());
+        }
+        self
+    }
+
+    /// A channel with the given users in it, who are logged in if they were not mentioned before
+    pub fn channel(mut self, name: &str, members: &[&str]) -> Self {
+        for member in members {
+            self = self.user(member);
+        }
+        let members = members.iter().map(|m| m.to_string()).collect();
+        self.channels.push((name.to_string(), members));
+        self
+    }
+
+    /// An open game without password, hosted by the given user
+    pub fn game(mut self, name: &str, host: &str) -> Self {
+        self = self.user(host);
+        self.games.push((name.to_string(), host.to_string()));
+        self
+    }
+
+    /// Starts the broker and sets up users, then channels, then games
+    pub async fn build(self) -> TestWorld {
+        let mut world = TestWorld::with_plugins(self.config, self.plugins);
+        for username in self.users {
+            let client = world.new_client(&username).await;
+            world.clients.insert(username, client);
+        }
+        for (channel, members) in self.channels {
+            for member in members {
+                let id = world.clients[&member].id;
+                world
+                    .send_command_as(
+                        id,
+                        ClientCommand::Join {
+                            channel: channel.clone(),
+                        },
+                    )
+                    .await;
+            }
+        }
+        for (game, host) in self.games {
+            let id = world.clients[&host].id;
+            world.host_game_as(id, &game, Uuid::new_v4()).await;
+        }
+        world
+    }
+}
+
+/// Stands in for a federated server linked to the broker
+pub struct TestPeer {
+    server: String,
+    link: Uuid,
+    messages: mpsc::Receiver<PeerMessage>,
+    received: Vec<PeerMessage>,
+}
+
+pub struct TestClient {
+    id: Uuid,
+    username: String,
+    messages: MessageReceiver,
+    view: ClientView,
+    errors: Vec<String>,
+    error_codes: Vec<&'static str>,
+    rejections: Vec<String>,
+    infos: Vec<String>,
+    announcements: Vec<Vec<u8>>,
+    notices: Vec<Vec<u8>>,
+    match_summaries: Vec<String>,
+    chat: Vec<(String, Vec<u8>)>,
+    private_messages: Vec<(String, Vec<u8>)>,
+    /// game names and ports from the join information the client was given
+    game_joins: Vec<(String, Option<u16>)>,
+    announced_channels: Vec<String>,
+    protocol_trace: ProtocolTrace,
+}
+
+impl Default for TestWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestWorld {
+    pub fn builder() -> TestWorldBuilder {
+        TestWorldBuilder::default()
+    }
+
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self::with_plugins(config, Vec::new())
+    }
+
+    pub fn with_plugins(config: Config, plugins: Vec<Box<dyn BrokerPlugin>>) -> Self {
+        let (sender, receiver) = mpsc::channel(64);
+        let (shutdown_send, shutdown_recv) = watch::channel(false);
+        let names = Arc::new(NameValidator::new(&config.names));
+        let client_settings = Arc::new(ClientSettings::new(&config, names));
+        let join_handle = task::spawn(broker_loop(
+            receiver,
+            shutdown_recv,
+            Metrics::new(),
+            config,
+            Tracer::disabled(),
+            plugins,
+            None,
+        ));
+        Self {
+            events: sender,
+            shutdown_send,
+            join_handle,
+            clients: HashMap::new(),
+            client_settings,
+        }
+    }
+
+    /// Hands out a client created by the builder
+    pub fn take_client(&mut self, username: &str) -> TestClient {
+        self.clients
+            .remove(username)
+            .unwrap_or_else(|| panic!("no client named {} in the test world", username))
+    }
+
+    pub async fn new_client(&mut self, username: &str) -> TestClient {
+        self.new_client_with_version(username, default_game_version())
+            .await
+    }
+
+    pub async fn new_client_with_version(
+        &mut self,
+        username: &str,
+        game_version: Uuid,
+    ) -> TestClient {
+        self.login(username, "", game_version).await
+    }
+
+    pub async fn new_client_with_password(&mut self, username: &str, password: &str) -> TestClient {
+        self.login(username, password, default_game_version()).await
+    }
+
+    /// Logs in and waits for the broker's answer, as the password is checked in the background
+    /// while the broker goes on with events sent later
+    async fn login(&mut self, username: &str, password: &str, game_version: Uuid) -> TestClient {
+        let id = Uuid::new_v4();
+        let (message_send, mut message_recv) = message_queue();
+        let protocol_trace = ProtocolTrace::default();
+        self.send(Event::NewUser {
+            send: message_send,
+            id,
+            ip_addr: Ipv4Addr::new(127, 0, 0, 1),
+            fingerprint: Fingerprint::default(),
+            protocol_trace: protocol_trace.clone(),
+            username: username.to_string(),
+            password: password.to_string(),
+            game_version,
+        })
+        .await;
+        let answer = timeout(Duration::from_secs(5), message_recv.recv())
+            .await
+            .expect("login was not answered in time");
+
+        let mut client = TestClient {
+            id,
+            username: username.to_string(),
+            messages: message_recv,
+            view: ClientView::new(),
+            errors: Vec::new(),
+            error_codes: Vec::new(),
+            rejections: Vec::new(),
+            infos: Vec::new(),
+            announcements: Vec::new(),
+            notices: Vec::new(),
+            match_summaries: Vec::new(),
+            chat: Vec::new(),
+            private_messages: Vec::new(),
+            game_joins: Vec::new(),
+            announced_channels: Vec::new(),
+            protocol_trace,
+        };
+        if let Some(answer) = answer {
+            client.process_message(answer);
+        }
+        client
+    }
+
+    /// Disconnects the client like a closed connection would
+    pub async fn disconnect(&mut self, client: TestClient) {
+        self.send(Event::DropClient { id: client.id }).await;
+    }
+
+    /// Stops the broker once it has handled every event sent so far. Afterwards, the clients'
+    /// message queues are closed, so they can be processed to the end.
+    pub async fn shutdown(mut self) {
+        self.send(Event::Shutdown).await;
+        self.join_handle.await.unwrap().unwrap();
+    }
+
+    /// Another sender of events, like the ones client handlers hold
+    pub fn event_sender(&self) -> EventSender {
+        self.events.clone()
+    }
+
+    pub async fn dump_state(&mut self) -> LobbySnapshot {
+        self.control(|respond_to| ControlCommand::DumpState { respond_to })
+            .await
+    }
+
+    pub async fn query_state(&mut self) -> ServerStatus {
+        let (respond_to, response) = oneshot::channel();
+        self.send(Event::QueryState { respond_to }).await;
+        response.await.unwrap()
+    }
+
+    pub async fn control<T>(
+        &mut self,
+        command: impl FnOnce(oneshot::Sender<T>) -> ControlCommand,
+    ) -> T {
+        let (respond_to, response) = oneshot::channel();
+        self.send(Event::Control {
+            command: command(respond_to),
+        })
+        .await;
+        response.await.unwrap()
+    }
+
+    pub async fn trace_protocol(&mut self, username: &str, enabled: bool) -> bool {
+        self.control(|respond_to| ControlCommand::TraceProtocol {
+            username: username.to_string(),
+            enabled,
+            respond_to,
+        })
+        .await
+    }
+
+    /// Goes through the two-step hosting handshake to open a game without password
+    pub async fn host_game(&mut self, host: &TestClient, game_name: &str, id: Uuid) {
+        self.host_game_as(host.id, game_name, id).await;
+    }
+
+    async fn host_game_as(&mut self, host: Uuid, game_name: &str, id: Uuid) {
+        self.send_command_as(
+            host,
+            ClientCommand::HostGame {
+                game_name: game_name.to_string(),
+                password_or_guid: b"".to_vec(),
+                tag: None,
+            },
+        )
+        .await;
+        self.send_command_as(
+            host,
+            ClientCommand::HostGame {
+                game_name: game_name.to_string(),
+                password_or_guid: id.to_hyphenated().to_string().into_bytes(),
+                tag: None,
+            },
+        )
+        .await;
+    }
+
+    /// The host starting the game is the third step of the hosting handshake
+    pub async fn start_game(&mut self, host: &TestClient, game_name: &str) {
+        self.send_command(
+            host,
+            ClientCommand::HostGame {
+                game_name: game_name.to_string(),
+                password_or_guid: Uuid::new_v4().to_hyphenated().to_string().into_bytes(),
+                tag: None,
+            },
+        )
+        .await;
+    }
+
+    /// Moves the client into an open game, as the game client does after connecting to the host
+    pub async fn join_game(&mut self, client: &TestClient, game_name: &str, id: Uuid) {
+        self.send_command(
+            client,
+            ClientCommand::JoinGame {
+                game_name: game_name.to_string(),
+                password: id.to_hyphenated().to_string().into_bytes(),
+                spectator: false,
+            },
+        )
+        .await;
+    }
+
+    pub async fn link_peer(&mut self, server: &str) -> TestPeer {
+        let link = Uuid::new_v4();
+        let (send, messages) = mpsc::channel(256);
+        self.send(Event::PeerConnected {
+            server: server.to_string(),
+            link,
+            send,
+        })
+        .await;
+        TestPeer {
+            server: server.to_string(),
+            link,
+            messages,
+            received: Vec::new(),
+        }
+    }
+
+    pub async fn send_peer_message(&mut self, peer: &TestPeer, message: PeerMessage) {
+        self.send(Event::PeerMessage {
+            server: peer.server.clone(),
+            message,
+        })
+        .await;
+    }
+
+    pub async fn unlink_peer(&mut self, peer: &TestPeer) {
+        self.send(Event::PeerDisconnected {
+            server: peer.server.clone(),
+            link: peer.link,
+        })
+        .await;
+    }
+
+    /// Plays a recorded session to a client handler connected to the world, chunk by chunk,
+    /// and collects what the server sends back until the connection is closed
+    pub async fn replay(&mut self, recording: &Recording) -> Replay {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (_shutdown_send, shutdown_recv) = watch::channel(false);
+        let handler = task::spawn(client_handler(
+            TcpStream::from_std(server).unwrap(),
+            self.events.clone(),
+            Metrics::new(),
+            Tracer::disabled(),
+            self.client_settings.clone(),
+            shutdown_recv,
+        ));
+
+        let mut client = TcpStream::from_std(client).unwrap();
+        let mut sent = Vec::new();
+        for chunk in recording.received() {
+            client.write_all(chunk).await.unwrap();
+            let mut read_buf = [0u8; 1024];
+            while let Ok(Ok(n)) = timeout(REPLAY_SETTLE_TIME, client.read(&mut read_buf)).await {
+                if n == 0 {
+                    break;
+                }
+                sent.extend_from_slice(&read_buf[..n]);
+            }
+        }
+        client.shutdown(Shutdown::Write).unwrap();
+        // the server may reset the connection rather than close it, which ends the session all
+        // the same
+        let _ = timeout(Duration::from_secs(5), client.read_to_end(&mut sent))
+            .await
+            .expect("server did not close the connection");
+        timeout(Duration::from_secs(5), handler)
+            .await
+            .expect("client handler did not finish")
+            .unwrap()
+            .unwrap();
+
+        Replay {
+            expected: recording.expected_frames().unwrap(),
+            sent,
+        }
+    }
+
+    pub async fn send(&mut self, event: Event) {
+        self.events.send(event).await.unwrap();
+    }
+
+    pub async fn send_command(&mut self, client: &TestClient, command: ClientCommand) {
+        self.send_command_as(client.id, command).await;
+    }
+
+    async fn send_command_as(&mut self, id: Uuid, command: ClientCommand) {
+        self.send(Event::Command {
+            id,
+            command,
+            trace: None,
+        })
+        .await;
+    }
+}
+
+impl Replay {
+    pub fn sent(&self) -> &[u8] {
+        &self.sent
+    }
+
+    /// Checks that the server answered frame by frame as it did when the session was recorded
+    pub fn should_match_recording(&self) {
+        let frames = split_frames(&self.sent).expect("server sent an incomplete frame");
+        for (i, (frame, expected)) in frames.iter().zip(&self.expected).enumerate() {
+            assert_eq!(frame, expected, "frame {} differs from the recording", i);
+        }
+        assert_eq!(
+            frames.len(),
+            self.expected.len(),
+            "server sent a different number of frames, got {:?}",
+            frames
+        );
+    }
+}
+
+impl TestPeer {
+    pub async fn process_messages(&mut self) {
+        while let Some(message) = self.messages.recv().await {
+            self.received.push(message);
+        }
+    }
+
+    pub fn should_have_received(&self, message: &PeerMessage) {
+        assert!(
+            self.received.contains(message),
+            "missing expected peer message, got {:?}",
+            self.received
+        );
+    }
+}
+
+impl TestClient {
+    pub async fn process_messages(&mut self) {
+        while let Some(outgoing) = self.messages.recv().await {
+            self.process_message(outgoing);
+        }
+    }
+
+    /// Processes messages until one arrives in chat from the user, for answers that the
+    /// broker gets from somewhere else than the test
+    pub async fn wait_for_chat_from(&mut self, username: &str) -> Vec<u8> {
+        let seen = self.chat.len();
+        let wait = async {
+            while let Some(outgoing) = self.messages.recv().await {
+                self.process_message(outgoing);
+                if let Some((_, message)) = self.chat[seen..].iter().find(|(u, _)| u == username) {
+                    return message.clone();
+                }
+            }
+            panic!("broker went away before {} said anything", username);
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), wait)
+            .await
+            .expect("no chat message arrived in time")
+    }
+
+    /// Processes messages until the resume token the client was given on login arrives
+    pub async fn wait_for_resume_token(&mut self) -> String {
+        let token = |infos: &[String]| {
+            infos.iter().find_map(|i| {
+                let rest = i.split("/resume ").nth(1)?;
+                rest.split(' ').next().map(str::to_string)
+            })
+        };
+        let wait = async {
+            loop {
+                if let Some(token) = token(&self.infos) {
+                    return token;
+                }
+                match self.messages.recv().await {
+                    Some(outgoing) => self.process_message(outgoing),
+                    None => panic!("broker went away before handing out a resume token"),
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), wait)
+            .await
+            .expect("no resume token arrived in time")
+    }
+
+    fn process_message(&mut self, outgoing: OutgoingMessage) {
+        let message = outgoing.message;
+        self.view.apply(&message);
+        match &*message {
+            ServerMessage::Error(error) => {
+                self.errors.push(error.error.clone());
+                self.error_codes.extend(error.code);
+            }
+            ServerMessage::Reject(reject) => self.rejections.push(reject.reason.clone()),
+            ServerMessage::NewChannel(channel) => {
+                self.announced_channels.push(channel.channel_name.clone())
+            }
+            ServerMessage::Info(info) => self.infos.push(info.text.clone()),
+            ServerMessage::Announcement(announcement) => {
+                self.announcements.push(announcement.text.clone())
+            }
+            ServerMessage::Notice(notice) => self.notices.push(notice.text.clone()),
+            ServerMessage::MatchSummary(summary) => self.match_summaries.push(summary.text()),
+            ServerMessage::Private(private) => self
+                .private_messages
+                .push((private.from.clone(), private.message.clone())),
+            ServerMessage::JoinGame(join) => {
+                self.game_joins.push((join.game_name.clone(), join.port))
+            }
+            ServerMessage::Send(chat) => self
+                .chat
+                .push((chat.username.clone(), chat.message.clone())),
+            _ => {}
+        }
+    }
+
+    pub fn diff(&self, snapshot: &LobbySnapshot) -> Vec<Mismatch> {
+        diff(snapshot, &self.username, &self.view)
+    }
+
+    pub fn should_have_channel(&self, channel: &str) {
+        assert!(
+            self.view.channels.contains(channel),
+            "missing expected channel"
+        );
+    }
+
+    pub fn should_not_have_channel(&self, channel: &str) {
+        assert!(!self.view.channels.contains(channel), "unexpected channel");
+    }
+
+    pub fn should_have_game(&self, game: &str) {
+        assert!(self.view.games.contains(game), "missing expected game");
+    }
+
+    pub fn should_not_have_game(&self, game: &str) {
+        assert!(!self.view.games.contains(game), "unexpected game");
+    }
+
+    pub fn should_be_in(&self, location: &Location) {
+        assert_eq!(self.view.location, *location, "not in expected location");
+    }
+
+    pub fn should_be_traced(&self, traced: bool) {
+        assert_eq!(self.protocol_trace.is_enabled(), traced);
+    }
+
+    pub fn should_have_error(&self, error: &str) {
+        assert!(
+            self.errors.iter().any(|e| e == error),
+            "missing expected error, got {:?}",
+            self.errors
+        );
+    }
+
+    pub fn should_have_lobby_error(&self, error: &LobbyError) {
+        assert!(
+            self.error_codes.contains(&error.code()),
+            "missing expected error {}, got {:?}",
+            error.code(),
+            self.error_codes
+        );
+    }
+
+    pub fn should_not_have_lobby_error(&self, error: &LobbyError) {
+        assert!(
+            !self.error_codes.contains(&error.code()),
+            "unexpected error {}",
+            error.code()
+        );
+    }
+
+    pub fn should_be_rejected_with(&self, reason: &str) {
+        assert!(
+            self.rejections.iter().any(|r| r == reason),
+            "missing expected rejection, got {:?}",
+            self.rejections
+        );
+    }
+
+    pub fn should_have_info_containing(&self, text: &str) {
+        assert!(
+            self.infos.iter().any(|i| i.contains(text)),
+            "missing expected info, got {:?}",
+            self.infos
+        );
+    }
+
+    pub fn should_not_have_info_containing(&self, text: &str) {
+        assert!(
+            self.infos.iter().all(|i| !i.contains(text)),
+            "unexpected info, got {:?}",
+            self.infos
+        );
+    }
+
+    pub fn should_have_chat(&self, username: &str, message: &[u8]) {
+        assert!(
+            self.chat
+                .iter()
+                .any(|(u, m)| u == username && m.as_slice() == message),
+            "missing expected chat message, got {:?}",
+            self.chat
+        );
+    }
+
+    pub fn should_not_have_chat(&self, username: &str, message: &[u8]) {
+        assert!(
+            !self
+                .chat
+                .iter()
+                .any(|(u, m)| u == username && m.as_slice() == message),
+            "unexpected chat message, got {:?}",
+            self.chat
+        );
+    }
+
+    pub fn should_have_announcement(&self, text: &[u8]) {
+        assert!(
+            self.announcements.iter().any(|a| a.as_slice() == text),
+            "missing expected announcement, got {:?}",
+            self.announcements
+        );
+    }
+
+    pub fn should_have_notice(&self, text: &[u8]) {
+        assert!(
+            self.notices.iter().any(|n| n.as_slice() == text),
+            "missing expected notice, got {:?}",
+            self.notices
+        );
+    }
+
+    pub fn should_not_have_notices(&self) {
+        assert!(
+            self.notices.is_empty(),
+            "unexpected notices {:?}",
+            self.notices
+        );
+    }
+
+    pub fn should_have_match_summary(&self, text: &str) {
+        assert!(
+            self.match_summaries.iter().any(|s| s == text),
+            "missing expected match summary, got {:?}",
+            self.match_summaries
+        );
+    }
+
+    pub fn should_have_private_message(&self, from: &str, message: &[u8]) {
+        assert!(
+            self.private_messages
+                .iter()
+                .any(|(f, m)| f == from && m.as_slice() == message),
+            "missing expected private message, got {:?}",
+            self.private_messages
+        );
+    }
+
+    pub fn should_have_join_info(&self, game_name: &str, port: Option<u16>) {
+        assert!(
+            self.game_joins
+                .iter()
+                .any(|(g, p)| g == game_name && *p == port),
+            "missing expected game join info, got {:?}",
+            self.game_joins
+        );
+    }
+
+    pub fn should_not_have_join_info(&self, game_name: &str) {
+        assert!(
+            self.game_joins.iter().all(|(g, _)| g != game_name),
+            "unexpected game join info"
+        );
+    }
+
+    pub fn should_have_first_announced_channel(&self, channel: &str) {
+        assert_eq!(
+            self.announced_channels.first().map(String::as_str),
+            Some(channel),
+            "unexpected channel announcement order"
+        );
+    }
+
+    pub fn should_be_in_sync_with(&self, snapshot: &LobbySnapshot) {
+        let mismatches = self.diff(snapshot);
+        assert!(
+            mismatches.is_empty(),
+            "client out of sync: {:?}",
+            mismatches
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use crate::http::post_json;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// Identifies a span within a trace, so that child spans can be attached to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpanRecord {
+    pub name: &'static str,
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+/// An in-progress span, which is recorded with its tracer when dropped
+pub struct Span {
+    record: SpanRecord,
+    tracer: Arc<TracerInner>,
+}
+
+impl Span {
+    pub fn context(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.record.trace_id,
+            span_id: self.record.span_id,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.record.end = SystemTime::now();
+        self.tracer
+            .finished
+            .lock()
+            .unwrap()
+            .push(self.record.clone());
+    }
+}
+
+struct TracerInner {
+    sample_ratio: f64,
+    finished: Mutex<Vec<SpanRecord>>,
+}
+
+/// Cheaply clonable handle to create spans. A disabled tracer never creates any.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    inner: Option<Arc<TracerInner>>,
+}
+
+fn random_span_id() -> u64 {
+    Uuid::new_v4().as_u128() as u64
+}
+
+impl Tracer {
+    pub fn disabled() -> Self {
+        Default::default()
+    }
+
+    pub fn new(sample_ratio: f64) -> Self {
+        Self {
+            inner: Some(Arc::new(TracerInner {
+                sample_ratio,
+                finished: Mutex::new(Vec::new()),
+            })),
+        }
+    }
+
+    /// Starts a new trace, subject to sampling
+    pub fn start_trace(&self, name: &'static str) -> Option<Span> {
+        let inner = self.inner.as_ref()?;
+        let trace_id = Uuid::new_v4().as_u128();
+        if (trace_id % 10_000) as f64 >= inner.sample_ratio * 10_000.0 {
+            return None;
+        }
+        Some(self.create_span(inner, name, trace_id, None))
+    }
+
+    /// Starts a child span of the given context. Untraced work stays untraced.
+    pub fn start_span(&self, name: &'static str, parent: Option<TraceContext>) -> Option<Span> {
+        let inner = self.inner.as_ref()?;
+        let parent = parent?;
+        Some(self.create_span(inner, name, parent.trace_id, Some(parent.span_id)))
+    }
+
+    fn create_span(
+        &self,
+        inner: &Arc<TracerInner>,
+        name: &'static str,
+        trace_id: u128,
+        parent_span_id: Option<u64>,
+    ) -> Span {
+        let now = SystemTime::now();
+        Span {
+            record: SpanRecord {
+                name,
+                trace_id,
+                span_id: random_span_id(),
+                parent_span_id,
+                start: now,
+                end: now,
+            },
+            tracer: inner.clone(),
+        }
+    }
+
+    /// Returns all spans finished since the last call
+    pub fn take(&self) -> Vec<SpanRecord> {
+        match &self.inner {
+            Some(inner) => std::mem::take(&mut *inner.finished.lock().unwrap()),
+            None => Vec::new(),
+        }
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: Option<TraceContext>;
+}
+
+/// Runs the future with the given trace context as the current one
+pub async fn scope<F: Future>(context: Option<TraceContext>, f: F) -> F::Output {
+    CURRENT.scope(context, f).await
+}
+
+/// The trace context of the currently executing task, if any
+pub fn current() -> Option<TraceContext> {
+    CURRENT.try_with(|c| *c).unwrap_or(None)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn span_to_json(span: &SpanRecord) -> Value {
+    let mut json = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "name": span.name,
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+    });
+    if let Some(parent) = span.parent_span_id {
+        json["parentSpanId"] = json!(format!("{:016x}", parent));
+    }
+    json
+}
+
+async fn export(address: &str, service_name: &str, spans: &[SpanRecord]) -> Result<()> {
+    if spans.is_empty() {
+        return Ok(());
+    }
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": service_name },
+                }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "ie_net" },
+                "spans": spans.iter().map(span_to_json).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+    match post_json(address, "/v1/traces", &body.to_string()).await? {
+        200..=299 => Ok(()),
+        status => Err(anyhow!("OTLP collector responded with status {}", status)),
+    }
+}
+
+/// Periodically exports finished spans to an OTLP/HTTP collector
+pub async fn export_loop(
+    tracer: Tracer,
+    address: String,
+    service_name: String,
+    interval: Duration,
+    mut shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                if let Err(e) = export(&address, &service_name, &tracer.take()).await {
+                    log::warn!("Failed to export traces: {}", e);
+                }
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+        }
+    }
+
+    export(&address, &service_name, &tracer.take()).await?;
+    log::info!("Trace exporter shutting down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_spans_share_trace() {
+        let tracer = Tracer::new(1.0);
+        let root = tracer.start_trace("root").unwrap();
+        let child = tracer.start_span("child", Some(root.context())).unwrap();
+        let (root_ctx, child_ctx) = (root.context(), child.context());
+        drop(child);
+        drop(root);
+
+        let spans = tracer.take();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(child_ctx.trace_id, root_ctx.trace_id);
+        assert_eq!(spans[0].parent_span_id, Some(root_ctx.span_id));
+        assert_eq!(spans[1].parent_span_id, None);
+    }
+
+    #[test]
+    fn test_disabled_tracer_creates_no_spans() {
+        let tracer = Tracer::disabled();
+        assert!(tracer.start_trace("root").is_none());
+        assert!(tracer.take().is_empty());
+    }
+}
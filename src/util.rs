@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 pub fn bytevec_to_str(input: &[u8]) -> String {
     String::from_utf8_lossy(input).to_string()
 }
@@ -5,3 +7,29 @@ pub fn bytevec_to_str(input: &[u8]) -> String {
 pub fn only_allowed_chars_not_empty(input: &str, allowed: &str) -> bool {
     !input.is_empty() && input.chars().all(|c| allowed.contains(c))
 }
+
+/// Formats a duration for players, down to minutes, e.g. `2d 3h 5m`
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(59)), "0m");
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 60)), "3h 1m");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 86400 + 300)),
+            "2d 0h 5m"
+        );
+    }
+}
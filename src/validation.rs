@@ -0,0 +1,92 @@
+use crate::config::{NamePolicy, NamesConfig};
+
+/// Characters allowed in names besides ASCII letters and digits, unless configured otherwise
+const USERNAME_CHARS: &str = "-_.|()[]{}";
+const CHANNEL_NAME_CHARS: &str = "-_";
+const GAME_NAME_CHARS: &str = "-_+.| ";
+
+/// Checks user, channel and game names against the configured policies
+#[derive(Debug, Clone)]
+pub struct NameValidator {
+    users: NamePolicy,
+    channels: NamePolicy,
+    games: NamePolicy,
+}
+
+impl NameValidator {
+    pub fn new(config: &NamesConfig) -> Self {
+        Self {
+            users: config.users.clone(),
+            channels: config.channels.clone(),
+            games: config.games.clone(),
+        }
+    }
+
+    pub fn is_valid_username(&self, name: &str) -> bool {
+        is_valid(&self.users, USERNAME_CHARS, name)
+    }
+
+    /// Channel names also go for tournaments, which are played in a channel of the same name
+    pub fn is_valid_channel_name(&self, name: &str) -> bool {
+        is_valid(&self.channels, CHANNEL_NAME_CHARS, name)
+    }
+
+    pub fn is_valid_game_name(&self, name: &str) -> bool {
+        is_valid(&self.games, GAME_NAME_CHARS, name)
+    }
+}
+
+impl Default for NameValidator {
+    fn default() -> Self {
+        Self::new(&NamesConfig::default())
+    }
+}
+
+fn is_valid(policy: &NamePolicy, default_chars: &str, name: &str) -> bool {
+    let extra_chars = policy.extra_chars.as_deref().unwrap_or(default_chars);
+    let length = name.chars().count();
+    length >= policy.min_length.max(1)
+        && policy.max_length.is_none_or(|max| length <= max)
+        && name.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || (policy.unicode_letters && c.is_alphanumeric())
+                || extra_chars.contains(c)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let names = NameValidator::default();
+        assert!(names.is_valid_username("[Clan]foo_1"));
+        assert!(!names.is_valid_username("foo bar"));
+        assert!(!names.is_valid_username(""));
+        assert!(names.is_valid_channel_name("Clan-War"));
+        assert!(!names.is_valid_channel_name("Clan.War"));
+        assert!(names.is_valid_game_name("2v2 no rush"));
+        assert!(!names.is_valid_username("Jürgen"));
+    }
+
+    #[test]
+    fn test_configured_policy() {
+        let config = NamesConfig {
+            users: NamePolicy {
+                extra_chars: Some("_".to_string()),
+                unicode_letters: true,
+                min_length: 3,
+                max_length: Some(8),
+            },
+            ..NamesConfig::default()
+        };
+        let names = NameValidator::new(&config);
+        assert!(names.is_valid_username("Jürgen_2"));
+        assert!(names.is_valid_username("Ζωή"));
+        assert!(!names.is_valid_username("Jo"));
+        assert!(!names.is_valid_username("Jürgen_22"));
+        assert!(!names.is_valid_username("foo.bar"));
+        assert!(!names.is_valid_username("foo\u{fffd}"));
+    }
+}
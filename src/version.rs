@@ -0,0 +1,8 @@
+/// Version of the crate and the commit it was built from, e.g. `0.1.0 (1a2b3c4)`
+pub fn build_info() -> String {
+    format!(
+        "{} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("IE_NET_GIT_HASH")
+    )
+}
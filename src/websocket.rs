@@ -0,0 +1,391 @@
+use crate::broker::fingerprint::Fingerprint;
+use crate::broker::{
+    message_queue, Event, EventSender, MessageReceiver, MessageSender, OutgoingMessage,
+};
+use crate::client::{stop_writing, FLUSH_TIMEOUT};
+use crate::config::WebSocketConfig;
+use crate::ipv6::chat_ipv4;
+use crate::messages::client_command::ClientCommand;
+use crate::messages::login_server::RejectServerMessage;
+use crate::messages::raw_command::try_parse_raw_command;
+use crate::messages::server_messages::{prepare_command, ErrorMessage};
+use crate::messages::{ServerMessage, WireMessage};
+use crate::metrics::Metrics;
+use crate::protocol_trace::{Direction, ProtocolTrace};
+use crate::server::{bind_listener, spawn_and_log_error, wait_for_shutdown};
+use crate::trace::Tracer;
+use crate::util::bytevec_to_str;
+use crate::validation::NameValidator;
+use anyhow::Result;
+use bytes::BytesMut;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+/// A frame carries a single command, so anything bigger than this is not worth reading
+const MAX_FRAME_SIZE: usize = 4096;
+
+/// What WebSocket clients are checked against and logged in with
+#[derive(Clone)]
+pub struct LoginSettings {
+    /// browsers have no game version of their own, so they count as this one
+    pub game_version: Uuid,
+    pub names: Arc<NameValidator>,
+}
+
+/// Accepts browser clients. Every text frame carries one command in the same format the game
+/// client uses, just without the terminating null byte. The first frame has to be
+/// `/login <username> [password]`.
+pub async fn listen_loop(
+    config: WebSocketConfig,
+    login: LoginSettings,
+    broker: EventSender,
+    metrics: Metrics,
+    tracer: Tracer,
+    mut shutdown_recv: watch::Receiver<bool>,
+    mut draining_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut listener = bind_listener(&config.bind)?;
+    log::info!("Listening for WebSocket connections at {}", &config.bind);
+    // every handler holds a clone, so the channel closes once all of them are finished
+    let (handlers_alive, mut handlers_finished) = mpsc::channel::<()>(1);
+    let mut incoming = listener.incoming();
+    loop {
+        tokio::select! {
+            Some(connection) = incoming.next() => {
+                let connection = connection?;
+                log::info!("New WebSocket connection established");
+                metrics.increment("connections.websocket");
+                let handler = websocket_handler(
+                    connection,
+                    login.clone(),
+                    broker.clone(),
+                    metrics.clone(),
+                    tracer.clone(),
+                    shutdown_recv.clone(),
+                );
+                let alive = handlers_alive.clone();
+                spawn_and_log_error(
+                    async move {
+                        let _alive = alive;
+                        handler.await
+                    },
+                    "websocket_handler",
+                );
+            },
+            Some(shutdown) = shutdown_recv.recv() => if shutdown { break },
+            Some(draining) = draining_recv.recv() => if draining { break },
+            else => break,
+        }
+    }
+
+    log::info!("WebSocket listener shutting down");
+    drop(incoming);
+    drop(listener);
+    drop(handlers_alive);
+    handlers_finished.recv().await;
+    Ok(())
+}
+
+async fn websocket_handler(
+    stream: TcpStream,
+    login: LoginSettings,
+    mut broker: EventSender,
+    metrics: Metrics,
+    tracer: Tracer,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let ip_addr = chat_ipv4(stream.peer_addr()?.ip());
+    let shutdown = wait_for_shutdown(shutdown_recv.clone());
+    tokio::pin!(shutdown);
+    let websocket = tokio::select! {
+        websocket = tokio_tungstenite::accept_async_with_config(stream, Some(frame_limits())) => websocket?,
+        _ = &mut shutdown => return Ok(()),
+    };
+    let (sink, mut frames) = websocket.split();
+    let (client_sender, client_receiver) = message_queue();
+    let (write_shutdown_send, mut write_shutdown_recv) = mpsc::channel(1);
+    let client_id = Uuid::new_v4();
+    let protocol_trace = ProtocolTrace::default();
+    spawn_and_log_error(
+        websocket_write_loop(
+            client_id,
+            sink,
+            client_receiver,
+            write_shutdown_send,
+            metrics,
+            protocol_trace.clone(),
+            shutdown_recv,
+        ),
+        "websocket_write_loop",
+    );
+    // handed over to the broker once the client has logged in
+    let mut login_send = Some(client_sender);
+
+    log::info!(
+        "Starting WebSocket handler for client with id {}",
+        client_id
+    );
+
+    loop {
+        let text = tokio::select! {
+            text = next_text_frame(client_id, &mut frames) => match text {
+                Some(text) => text,
+                None => break,
+            },
+            _ = write_shutdown_recv.recv() => {
+                log::info!("Writer for client {} shut down, stopping read handler", client_id);
+                break
+            },
+            _ = &mut shutdown => {
+                log::info!("WebSocket handler for client {} shutting down", client_id);
+                return Ok(());
+            },
+        };
+        protocol_trace.log_frame(client_id, Direction::Received, text.as_bytes());
+        login_send = match login_send {
+            Some(send) => {
+                let trace = protocol_trace.clone();
+                process_login(client_id, ip_addr, &login, &text, &mut broker, send, trace).await?
+            }
+            None => {
+                process_commands(client_id, text, &mut broker, &tracer).await?;
+                None
+            }
+        };
+    }
+    log::info!("WebSocket handler finished for client {}", client_id);
+    broker.send(Event::DropClient { id: client_id }).await?;
+    Ok(())
+}
+
+/// Waits for the next text frame; None once the connection is closed
+async fn next_text_frame(
+    client_id: Uuid,
+    frames: &mut SplitStream<WebSocketStream<TcpStream>>,
+) -> Option<String> {
+    loop {
+        match frames.next().await? {
+            Ok(Message::Text(text)) => return Some(text),
+            Ok(Message::Close(_)) => return None,
+            // pings are answered by the WebSocket implementation, binary frames have no meaning
+            Ok(_) => continue,
+            Err(e) => {
+                log::warn!(
+                    "Error when reading from WebSocket client {}: {}",
+                    client_id,
+                    e
+                );
+                return None;
+            }
+        }
+    }
+}
+
+/// Returns the sender again if the client still has to log in
+async fn process_login(
+    client_id: Uuid,
+    ip_addr: Ipv4Addr,
+    login: &LoginSettings,
+    text: &str,
+    broker: &mut EventSender,
+    mut send: MessageSender,
+    protocol_trace: ProtocolTrace,
+) -> Result<Option<MessageSender>> {
+    match parse_login(text, &login.names) {
+        Some((username, password)) => {
+            broker
+                .send(Event::NewUser {
+                    id: client_id,
+                    game_version: login.game_version,
+                    send,
+                    ip_addr,
+                    fingerprint: Fingerprint::websocket(),
+                    protocol_trace,
+                    username,
+                    password,
+                })
+                .await?;
+            Ok(None)
+        }
+        None => {
+            send.push(OutgoingMessage::new(Arc::new(
+                RejectServerMessage {
+                    reason: "Expected /login <username> [password]".to_string(),
+                }
+                .into(),
+            )));
+            Ok(Some(send))
+        }
+    }
+}
+
+/// Extracts username and password from a `/login` frame if the username is acceptable
+fn parse_login(text: &str, names: &NameValidator) -> Option<(String, String)> {
+    let raw = try_parse_raw_command(text.as_bytes()).ok()?;
+    if raw.command != "login" || raw.params.is_empty() || raw.params.len() > 2 {
+        return None;
+    }
+    let username = bytevec_to_str(&raw.params[0]);
+    if !names.is_valid_username(&username) {
+        return None;
+    }
+    let password = raw
+        .params
+        .get(1)
+        .map(|p| bytevec_to_str(p))
+        .unwrap_or_default();
+    Some((username, password))
+}
+
+fn frame_limits() -> tungstenite::protocol::WebSocketConfig {
+    tungstenite::protocol::WebSocketConfig {
+        max_send_queue: None,
+        max_message_size: Some(MAX_FRAME_SIZE),
+        max_frame_size: Some(MAX_FRAME_SIZE),
+    }
+}
+
+async fn process_commands(
+    client_id: Uuid,
+    text: String,
+    broker: &mut EventSender,
+    tracer: &Tracer,
+) -> Result<()> {
+    let mut received = BytesMut::with_capacity(text.len() + 1);
+    received.extend_from_slice(text.as_bytes());
+    received.extend_from_slice(&[0]);
+    while let Some(command) = ClientCommand::try_parse(&mut received)? {
+        let span = tracer.start_trace("websocket.parse_command");
+        broker
+            .send(Event::Command {
+                id: client_id,
+                command,
+                trace: span.as_ref().map(|s| s.context()),
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+async fn websocket_write_loop(
+    client_id: Uuid,
+    mut sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+    mut messages: MessageReceiver,
+    _shutdown_send: mpsc::Sender<()>,
+    metrics: Metrics,
+    protocol_trace: ProtocolTrace,
+    shutdown_recv: watch::Receiver<bool>,
+) -> Result<()> {
+    let closed = messages.closed();
+    let write_all = async {
+        while let Some(msg) = messages.recv().await {
+            log::debug!(
+                "Sending message to WebSocket client {}: {:?}",
+                client_id,
+                msg.message
+            );
+            metrics.increment(msg.message.metric_name());
+            for frame in to_text_frames(&msg.message)? {
+                protocol_trace.log_frame(client_id, Direction::Sent, frame.as_bytes());
+                sink.send(Message::Text(frame)).await?;
+            }
+        }
+        sink.close().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::pin!(write_all);
+    tokio::select! {
+        result = &mut write_all => result?,
+        _ = stop_writing(closed, shutdown_recv) => {
+            if let Ok(result) = timeout(FLUSH_TIMEOUT, write_all).await {
+                result?;
+            }
+        }
+    }
+    log::info!("Writer for WebSocket client {} is finished", client_id);
+    Ok(())
+}
+
+/// Translates a message for the game client into text frames, one per command. The binary
+/// login responses get command equivalents so that browser clients never see binary data.
+fn to_text_frames(message: &ServerMessage) -> Result<Vec<String>> {
+    let bytes = match message {
+        ServerMessage::Welcome(welcome) => prepare_command(
+            "/welcome",
+            &[
+                welcome.server_ident.as_bytes(),
+                welcome.welcome_message.as_bytes(),
+                welcome.initial_channel.as_bytes(),
+            ],
+        ),
+        ServerMessage::Reject(reject) => prepare_command("/reject", &[reject.reason.as_bytes()]),
+        // browser clients get the code, so they need not match on the text
+        ServerMessage::Error(ErrorMessage {
+            error,
+            code: Some(code),
+        }) => prepare_command("/error", &[error.as_bytes(), code.as_bytes()]),
+        _ => message.prepare_message()?,
+    };
+    Ok(bytes
+        .split(|b| *b == 0)
+        .filter(|command| !command.is_empty())
+        .map(bytevec_to_str)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::lobby_error::LobbyError;
+    use crate::messages::server_messages::SendMessage;
+
+    #[test]
+    fn test_parse_login() {
+        let names = NameValidator::default();
+        assert_eq!(
+            parse_login("/login foo \"secret pw\"", &names),
+            Some(("foo".to_string(), "secret pw".to_string()))
+        );
+        assert_eq!(
+            parse_login("/login foo", &names),
+            Some(("foo".to_string(), String::new()))
+        );
+        assert_eq!(parse_login("/login", &names), None);
+        assert_eq!(parse_login("/login f*o", &names), None);
+        assert_eq!(parse_login("/send hello", &names), None);
+    }
+
+    #[test]
+    fn test_to_text_frames() {
+        let chat = SendMessage {
+            username: "foo".to_string(),
+            message: b"hi \"there\"".to_vec(),
+        }
+        .into();
+        assert_eq!(
+            to_text_frames(&chat).unwrap(),
+            vec!["/send \"foo\" \"hi %22there%22\"".to_string()]
+        );
+        let reject = RejectServerMessage {
+            reason: "Wrong password".to_string(),
+        }
+        .into();
+        assert_eq!(
+            to_text_frames(&reject).unwrap(),
+            vec!["/reject \"Wrong password\"".to_string()]
+        );
+        assert_eq!(
+            to_text_frames(&ErrorMessage::new_coded(LobbyError::GameFull)).unwrap(),
+            vec!["/error \"Game is full\" \"game_full\"".to_string()]
+        );
+    }
+}
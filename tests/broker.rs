@@ -1,12 +1,41 @@
-mod common;
-
-use crate::common::TestBroker;
+use ie_net::broker::control::ControlCommand;
+use ie_net::broker::fingerprint::Fingerprint;
+use ie_net::broker::plugin::{BrokerPlugin, Verdict};
+use ie_net::broker::predicate::UserPredicate;
+use ie_net::broker::snapshot::Mismatch;
 use ie_net::broker::user::Location;
-use ie_net::messages::client_command::ClientCommand;
+use ie_net::broker::user::User;
+use ie_net::broker::{message_queue, Event, BACKLOG_CAPACITY, SEND_QUEUE_CAPACITY};
+use ie_net::config::{BuildRule, Config, GameVersion};
+use ie_net::federation::{PeerLocation, PeerMessage};
+use ie_net::messages::client_command::{
+    ClanAction, ClientCommand, GameFilter, MacroAction, MailAction, ProfileField, TournamentAction,
+};
+use ie_net::messages::lobby_error::LobbyError;
+use ie_net::protocol_trace::ProtocolTrace;
+use ie_net::replay::Recording;
+use ie_net::testing::TestWorld;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn shutdown_should_not_wait_for_other_event_senders() {
+    let mut broker = TestWorld::new();
+    let mut client = broker.new_client("foo").await;
+    let _handler = broker.event_sender();
+    tokio::time::timeout(std::time::Duration::from_secs(5), broker.shutdown())
+        .await
+        .expect("broker did not stop");
+    client.process_messages().await;
+
+    client.should_have_channel("General");
+}
 
 #[tokio::test]
 async fn new_user_should_join_general_channel() {
-    let mut broker = TestBroker::new();
+    let mut broker = TestWorld::new();
     let mut client = broker.new_client("foo").await;
     broker.shutdown().await;
     client.process_messages().await;
@@ -19,7 +48,7 @@ async fn new_user_should_join_general_channel() {
 
 #[tokio::test]
 async fn join_channel() {
-    let mut broker = TestBroker::new();
+    let mut broker = TestWorld::new();
     let mut client = broker.new_client("foo").await;
     broker
         .send_command(
@@ -38,3 +67,2517 @@ async fn join_channel() {
         name: "MyChannel".to_string(),
     });
 }
+
+#[tokio::test]
+async fn names_should_follow_the_configured_policy() {
+    let mut config = Config::default();
+    config.names.channels.max_length = Some(8);
+    config.names.games.extra_chars = Some("-".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("foo")
+        .build()
+        .await;
+    let mut foo = world.take_client("foo");
+    for channel in &["Sprawling", "Short"] {
+        world
+            .send_command(
+                &foo,
+                ClientCommand::Join {
+                    channel: channel.to_string(),
+                },
+            )
+            .await;
+    }
+    world
+        .send_command(
+            &foo,
+            ClientCommand::HostGame {
+                game_name: "no rush".to_string(),
+                password_or_guid: b"".to_vec(),
+                tag: None,
+            },
+        )
+        .await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_not_have_channel("Sprawling");
+    foo.should_have_channel("Short");
+    foo.should_have_error("Invalid channel name");
+    foo.should_have_error("Invalid game name");
+}
+
+#[tokio::test]
+async fn look_alike_names_should_count_as_taken() {
+    let mut world = TestWorld::new();
+    let _oscar = world.new_client("Oscar").await;
+    // with a Greek capital omicron, Cyrillic letters and fullwidth letters
+    let _greek = world.new_client("\u{039f}scar").await;
+    let _cyrillic = world.new_client("\u{041e}s\u{0441}\u{0430}r").await;
+    let _fullwidth = world.new_client("\u{ff4f}\u{ff53}car").await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+
+    assert_eq!(snapshot.users.len(), 1);
+    assert!(snapshot.users.contains_key("Oscar"));
+}
+
+#[tokio::test]
+async fn clients_should_be_in_sync_with_broker_state() {
+    let mut world = TestWorld::builder()
+        .user("foo")
+        .channel("MyChannel", &["bar"])
+        .build()
+        .await;
+    let mut foo = world.take_client("foo");
+    let mut bar = world.take_client("bar");
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    foo.should_be_in_sync_with(&snapshot);
+    bar.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn diff_should_report_phantom_channel() {
+    let mut broker = TestWorld::new();
+    let mut client = broker.new_client("foo").await;
+    let mut snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    client.process_messages().await;
+
+    snapshot.channels.remove("General");
+    assert_eq!(
+        client.diff(&snapshot),
+        vec![Mismatch::PhantomChannel("General".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn joining_full_game_should_fail() {
+    let mut config = Config::default();
+    config.games.max_players = 2;
+    let mut broker = TestWorld::with_config(config);
+    let host = broker.new_client("host").await;
+    let joiner = broker.new_client("joiner").await;
+    let mut late = broker.new_client("late").await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&host, "MyGame", game_id).await;
+    broker.join_game(&joiner, "MyGame", game_id).await;
+    broker
+        .send_command(
+            &late,
+            ClientCommand::JoinGame {
+                game_name: "MyGame".to_string(),
+                password: b"".to_vec(),
+                spectator: false,
+            },
+        )
+        .await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    late.process_messages().await;
+
+    assert_eq!(
+        snapshot.users.get("joiner"),
+        Some(&Location::Game {
+            name: "MyGame".to_string()
+        })
+    );
+    late.should_have_error("Game is full");
+    late.should_have_lobby_error(&LobbyError::GameFull);
+}
+
+#[tokio::test]
+async fn games_running_for_too_long_should_be_dropped() {
+    let mut config = Config::default();
+    config.games.max_started_secs = 1;
+    let mut broker = TestWorld::with_config(config);
+    let mut host = broker.new_client("host").await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&host, "Forever", game_id).await;
+    broker.start_game(&host, "Forever").await;
+    tokio::time::delay_for(std::time::Duration::from_millis(1100)).await;
+    // stale games are looked for after every event
+    broker.query_state().await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    host.process_messages().await;
+
+    host.should_have_info_containing("$Forever has been running for too long and was dropped");
+    host.should_be_in_sync_with(&snapshot);
+    host.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn leaving_a_game_should_return_to_the_lobby() {
+    let mut world = TestWorld::new();
+    let mut host = world.new_client("host").await;
+    let mut joiner = world.new_client("joiner").await;
+    let game_id = Uuid::new_v4();
+    world.host_game(&host, "MyGame", game_id).await;
+    world.join_game(&joiner, "MyGame", game_id).await;
+    world.send_command(&joiner, ClientCommand::LeaveGame).await;
+    let snapshot = world.dump_state().await;
+    world.send_command(&host, ClientCommand::LeaveGame).await;
+    world.send_command(&host, ClientCommand::LeaveGame).await;
+    let after_host_left = world.dump_state().await;
+    world.shutdown().await;
+    host.process_messages().await;
+    joiner.process_messages().await;
+
+    assert_eq!(
+        snapshot.users.get("joiner"),
+        Some(&Location::Channel {
+            name: "General".to_string()
+        })
+    );
+    assert!(snapshot.open_games.contains("MyGame"));
+    assert!(after_host_left.open_games.is_empty());
+    host.should_have_error("You are not in a game");
+    host.should_not_have_game("MyGame");
+    host.should_be_in_sync_with(&after_host_left);
+    joiner.should_be_in_sync_with(&after_host_left);
+}
+
+#[tokio::test]
+async fn games_should_be_dropped_when_their_host_leaves() {
+    let mut world = TestWorld::new();
+    let host = world.new_client("host").await;
+    let mut joiner = world.new_client("joiner").await;
+    let requester = world.new_client("requester").await;
+    let mut successor = world.new_client("successor").await;
+    let game_id = Uuid::new_v4();
+    world.host_game(&host, "MyGame", game_id).await;
+    world.join_game(&joiner, "MyGame", game_id).await;
+    // hosting is only requested until the host's game client confirms it
+    world
+        .send_command(
+            &requester,
+            ClientCommand::HostGame {
+                game_name: "Pending".to_string(),
+                password_or_guid: b"".to_vec(),
+                tag: None,
+            },
+        )
+        .await;
+    world.disconnect(host).await;
+    world.disconnect(requester).await;
+    world
+        .send_command(
+            &successor,
+            ClientCommand::HostGame {
+                game_name: "Pending".to_string(),
+                password_or_guid: b"".to_vec(),
+                tag: None,
+            },
+        )
+        .await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    joiner.process_messages().await;
+    successor.process_messages().await;
+
+    assert!(snapshot.open_games.is_empty());
+    joiner.should_have_info_containing("The host has left, $MyGame was dropped");
+    joiner.should_not_have_game("MyGame");
+    joiner.should_be_in_sync_with(&snapshot);
+    successor.should_not_have_lobby_error(&LobbyError::GameExists);
+}
+
+#[tokio::test]
+async fn games_players_come_back_from_right_away_should_be_aborted() {
+    let mut config = Config::default();
+    config.games.aborted_within_secs = 300;
+    let mut world = TestWorld::with_config(config);
+    let host = world.new_client("host").await;
+    let joiner = world.new_client("joiner").await;
+    let mut watcher = world.new_client("watcher").await;
+    let game_id = Uuid::new_v4();
+    world.host_game(&host, "MyGame", game_id).await;
+    world.join_game(&joiner, "MyGame", game_id).await;
+    world.start_game(&host, "MyGame").await;
+    // the game clients drop the lobby connection when launching the match
+    world.disconnect(host).await;
+    world.disconnect(joiner).await;
+    let _joiner = world.new_client("joiner").await;
+    world
+        .send_command(
+            &watcher,
+            ClientCommand::History {
+                username: Some("joiner".to_string()),
+            },
+        )
+        .await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    watcher.process_messages().await;
+
+    watcher.should_have_info_containing("The match MyGame fell apart");
+    watcher.should_have_info_containing("MyGame (0 min): host, joiner - aborted");
+    watcher.should_not_have_game("MyGame");
+    watcher.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn launched_games_should_be_recorded_when_nobody_comes_back_early() {
+    let mut config = Config::default();
+    config.games.aborted_within_secs = 1;
+    let mut world = TestWorld::with_config(config);
+    let host = world.new_client("host").await;
+    let joiner = world.new_client("joiner").await;
+    let mut watcher = world.new_client("watcher").await;
+    let game_id = Uuid::new_v4();
+    world.host_game(&host, "MyGame", game_id).await;
+    world.join_game(&joiner, "MyGame", game_id).await;
+    world.start_game(&host, "MyGame").await;
+    world.disconnect(host).await;
+    world.disconnect(joiner).await;
+    tokio::time::delay_for(std::time::Duration::from_millis(1100)).await;
+    // launched games are settled after every event
+    let _joiner = world.new_client("joiner").await;
+    world
+        .send_command(
+            &watcher,
+            ClientCommand::History {
+                username: Some("joiner".to_string()),
+            },
+        )
+        .await;
+    world.shutdown().await;
+    watcher.process_messages().await;
+
+    watcher.should_not_have_info_containing("fell apart");
+    watcher.should_have_info_containing("MyGame (0 min): host, joiner - winner: unknown");
+    watcher.should_have_match_summary("Match MyGame lasted 0 minutes, players: host, joiner");
+}
+
+#[tokio::test]
+async fn finished_games_should_be_summed_up_in_the_default_channel() {
+    let mut world = TestWorld::new();
+    let host = world.new_client("host").await;
+    let joiner = world.new_client("joiner").await;
+    let mut watcher = world.new_client("watcher").await;
+    for (game_name, player) in [("Reported", &host), ("Returned", &joiner)].iter() {
+        let game_id = Uuid::new_v4();
+        world.host_game(player, game_name, game_id).await;
+        world.start_game(player, game_name).await;
+    }
+    world
+        .send_command(
+            &host,
+            ClientCommand::GameResult {
+                winner: "host".to_string(),
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &joiner,
+            ClientCommand::Join {
+                channel: "General".to_string(),
+            },
+        )
+        .await;
+    world.shutdown().await;
+    watcher.process_messages().await;
+
+    watcher
+        .should_have_match_summary("Match Reported lasted 0 minutes, players: host, winner: host");
+    watcher.should_have_match_summary("Match Returned lasted 0 minutes, players: joiner");
+}
+
+#[tokio::test]
+async fn reported_game_result_should_show_in_history() {
+    let mut broker = TestWorld::new();
+    let host = broker.new_client("host").await;
+    let mut joiner = broker.new_client("joiner").await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&host, "MyGame", game_id).await;
+    broker.join_game(&joiner, "MyGame", game_id).await;
+    broker.start_game(&host, "MyGame").await;
+    broker
+        .send_command(
+            &host,
+            ClientCommand::GameResult {
+                winner: "joiner".to_string(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &joiner,
+            ClientCommand::History {
+                username: Some("host".to_string()),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    joiner.process_messages().await;
+
+    joiner.should_have_info_containing("MyGame (0 min): host, joiner - winner: joiner");
+    joiner.should_have_info_containing("$MyGame is over, joiner won");
+    joiner.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn game_result_should_only_name_a_player() {
+    let mut broker = TestWorld::new();
+    let mut host = broker.new_client("host").await;
+    let _bystander = broker.new_client("bystander").await;
+    let mut watcher = broker.new_client("watcher").await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&host, "MyGame", game_id).await;
+    broker.start_game(&host, "MyGame").await;
+    broker
+        .send_command(
+            &host,
+            ClientCommand::GameResult {
+                winner: "bystander".to_string(),
+            },
+        )
+        .await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    host.process_messages().await;
+    watcher.process_messages().await;
+
+    host.should_have_error("bystander did not play in MyGame");
+    host.should_not_have_info_containing("has been recorded");
+    watcher.should_not_have_info_containing("won");
+    // the game goes on until the host names a player
+    assert_eq!(
+        snapshot.users["host"],
+        Location::Game {
+            name: "MyGame".to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn totals_should_count_logins_and_games() {
+    let mut broker = TestWorld::new();
+    let host = broker.new_client("host").await;
+    let joiner = broker.new_client("joiner").await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&host, "MyGame", game_id).await;
+    broker.join_game(&joiner, "MyGame", game_id).await;
+    broker.start_game(&host, "MyGame").await;
+    broker
+        .send_command(
+            &host,
+            ClientCommand::GameResult {
+                winner: "joiner".to_string(),
+            },
+        )
+        .await;
+    broker.disconnect(joiner).await;
+    let _joiner = broker.new_client("joiner").await;
+    let status = broker.query_state().await;
+    broker.shutdown().await;
+
+    assert_eq!(status.totals.logins, 3);
+    assert_eq!(status.totals.accounts, 2);
+    assert_eq!(status.totals.games_hosted, 1);
+    assert_eq!(status.totals.games_completed, 1);
+    assert_eq!(status.totals.peak_users, 2);
+}
+
+#[tokio::test]
+async fn pinned_channel_should_be_announced_first() {
+    let mut config = Config::default();
+    config.roles.admins = vec!["boss".to_string()];
+    config.pinning.enabled = true;
+    let mut broker = TestWorld::with_config(config);
+    let boss = broker.new_client("boss").await;
+    broker
+        .send_command(
+            &boss,
+            ClientCommand::Join {
+                channel: "Event".to_string(),
+            },
+        )
+        .await;
+    let _other = broker.new_client("other").await;
+    broker
+        .send_command(
+            &boss,
+            ClientCommand::Pin {
+                target: "#Event".to_string(),
+            },
+        )
+        .await;
+    let mut late = broker.new_client("late").await;
+    broker.shutdown().await;
+    late.process_messages().await;
+
+    late.should_have_first_announced_channel("Event");
+}
+
+#[tokio::test]
+async fn pinning_should_be_refused_unless_enabled() {
+    let mut config = Config::default();
+    config.roles.admins = vec!["boss".to_string()];
+    let mut broker = TestWorld::with_config(config);
+    let mut boss = broker.new_client("boss").await;
+    broker
+        .send_command(
+            &boss,
+            ClientCommand::Pin {
+                target: "#General".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    boss.process_messages().await;
+
+    boss.should_have_error("Pinning is disabled");
+}
+
+#[tokio::test]
+async fn remote_channel_should_be_joinable() {
+    let mut broker = TestWorld::new();
+    let mut peer = broker.link_peer("remote").await;
+    broker
+        .send_peer_message(
+            &peer,
+            PeerMessage::ChannelCreated {
+                name: "Lobby".to_string(),
+            },
+        )
+        .await;
+    broker
+        .send_peer_message(
+            &peer,
+            PeerMessage::UserPresent {
+                username: "bob".to_string(),
+                location: PeerLocation {
+                    name: "#Lobby".to_string(),
+                    on_receiver: false,
+                },
+            },
+        )
+        .await;
+    let mut foo = broker.new_client("foo").await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Join {
+                channel: "Lobby@remote".to_string(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Send {
+                message: b"hi".to_vec(),
+            },
+        )
+        .await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+    peer.process_messages().await;
+
+    foo.should_be_in(&Location::RemoteChannel {
+        server: "remote".to_string(),
+        name: "Lobby".to_string(),
+    });
+    foo.should_be_in_sync_with(&snapshot);
+    assert!(snapshot.users.contains_key("bob@remote"));
+    let location = PeerLocation {
+        name: "#Lobby".to_string(),
+        on_receiver: true,
+    };
+    peer.should_have_received(&PeerMessage::UserPresent {
+        username: "foo".to_string(),
+        location: location.clone(),
+    });
+    peer.should_have_received(&PeerMessage::Public {
+        username: "foo".to_string(),
+        location,
+        message: b"hi".to_vec(),
+    });
+}
+
+#[tokio::test]
+async fn users_should_return_to_general_when_peer_disconnects() {
+    let mut broker = TestWorld::new();
+    let peer = broker.link_peer("remote").await;
+    broker
+        .send_peer_message(
+            &peer,
+            PeerMessage::ChannelCreated {
+                name: "Lobby".to_string(),
+            },
+        )
+        .await;
+    let mut foo = broker.new_client("foo").await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Join {
+                channel: "Lobby@remote".to_string(),
+            },
+        )
+        .await;
+    broker.unlink_peer(&peer).await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+    foo.should_not_have_channel("Lobby@remote");
+    foo.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn kicked_user_should_be_disconnected() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let kicked = broker
+        .control(|respond_to| ControlCommand::Kick {
+            username: "foo".to_string(),
+            respond_to,
+        })
+        .await;
+    // the message stream ends once the broker dropped the user's sender
+    foo.process_messages().await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+
+    assert!(kicked);
+    assert!(snapshot.users.is_empty());
+    foo.should_have_info_containing("disconnected by an administrator");
+}
+
+#[tokio::test]
+async fn banned_user_should_not_log_in_again() {
+    let mut broker = TestWorld::new();
+    let _foo = broker.new_client("foo").await;
+    broker
+        .control(|respond_to| ControlCommand::Ban {
+            username: "Foo".to_string(),
+            respond_to,
+        })
+        .await;
+    let _again = broker.new_client("foo").await;
+    let snapshot = broker.dump_state().await;
+    let unbanned = broker
+        .control(|respond_to| ControlCommand::Unban {
+            username: "foo".to_string(),
+            respond_to,
+        })
+        .await;
+    let _after_unban = broker.new_client("foo").await;
+    let after_unban = broker.dump_state().await;
+    broker.shutdown().await;
+
+    assert!(snapshot.users.is_empty());
+    assert!(unbanned);
+    assert!(after_unban.users.contains_key("foo"));
+}
+
+#[tokio::test]
+async fn ban_should_keep_look_alikes_out() {
+    let mut broker = TestWorld::new();
+    broker
+        .control(|respond_to| ControlCommand::Ban {
+            username: "Oscar".to_string(),
+            respond_to,
+        })
+        .await;
+    // with a Cyrillic capital o and fullwidth letters
+    let _cyrillic = broker.new_client("\u{041e}scar").await;
+    let _fullwidth = broker.new_client("\u{ff4f}\u{ff53}car").await;
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+
+    assert!(snapshot.users.is_empty());
+}
+
+#[tokio::test]
+async fn join_flood_should_throttle_rejoins() {
+    let mut config = Config::default();
+    config.join_flood.enabled = true;
+    config.join_flood.max_joins = 3;
+    config.roles.moderators = vec!["mod".to_string()];
+    let mut broker = TestWorld::with_config(config);
+    let mut moderator = broker.new_client("mod").await;
+    let mut flooder = broker.new_client("flooder").await;
+    for channel in [
+        "Target", "Other", "Target", "Other", "Target", "Other", "Target", "Other", "Target",
+    ] {
+        broker
+            .send_command(
+                &flooder,
+                ClientCommand::Join {
+                    channel: channel.to_string(),
+                },
+            )
+            .await;
+    }
+    broker.shutdown().await;
+    flooder.process_messages().await;
+    moderator.process_messages().await;
+
+    flooder.should_have_error("Channel is being flooded, please wait 30 seconds before rejoining");
+    flooder.should_be_in(&Location::Channel {
+        name: "Other".to_string(),
+    });
+    moderator.should_have_info_containing("Join flood detected in #Target");
+}
+
+#[tokio::test]
+async fn status_should_list_channels_and_open_games() {
+    let mut broker = TestWorld::new();
+    let host = broker.new_client("host").await;
+    let _other = broker.new_client("other").await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    let status = broker.query_state().await;
+    broker.shutdown().await;
+
+    assert_eq!(status.users_online, 2);
+    assert!(status.version.starts_with(env!("CARGO_PKG_VERSION")));
+    assert_eq!(status.channels.len(), 1);
+    assert_eq!(status.channels[0].name, "General");
+    assert_eq!(status.channels[0].users, 1);
+    assert_eq!(status.open_games.len(), 1);
+    assert_eq!(status.open_games[0].host, "host");
+}
+
+#[tokio::test]
+async fn chat_should_be_sanitized_before_it_is_sent() {
+    let mut config = Config::default();
+    config.sanitize.max_message_bytes = 10;
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("foo")
+        .user("bar")
+        .build()
+        .await;
+    let mut foo = world.take_client("foo");
+    let mut bar = world.take_client("bar");
+    for message in &[&b" gl\t\x07hf "[..], b"much too long for this server"] {
+        world
+            .send_command(
+                &foo,
+                ClientCommand::Send {
+                    message: message.to_vec(),
+                },
+            )
+            .await;
+    }
+    world.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    bar.should_have_chat("foo", b"gl hf");
+    foo.should_have_error("Messages may be at most 10 characters long");
+}
+
+#[tokio::test]
+async fn slowmode_should_limit_messages_per_user() {
+    let mut config = Config::default();
+    config.roles.moderators.push("mod".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("mod")
+        .user("foo")
+        .build()
+        .await;
+    let mut moderator = world.take_client("mod");
+    let mut foo = world.take_client("foo");
+    world
+        .send_command(&foo, ClientCommand::Slowmode { secs: 60 })
+        .await;
+    world
+        .send_command(&moderator, ClientCommand::Slowmode { secs: 60 })
+        .await;
+    for (client, message) in &[(&foo, "first"), (&foo, "second"), (&moderator, "exempt")] {
+        world
+            .send_command(
+                client,
+                ClientCommand::Send {
+                    message: message.as_bytes().to_vec(),
+                },
+            )
+            .await;
+    }
+    world.shutdown().await;
+    foo.process_messages().await;
+    moderator.process_messages().await;
+
+    foo.should_have_error("Only admins and moderators can set slowmode");
+    foo.should_have_info_containing("Slowmode is on in #General, one message every 60 seconds");
+    foo.should_have_error("Slowmode is on in #General, wait another 60 seconds");
+    moderator.should_have_chat("foo", b"first");
+    moderator.should_not_have_chat("foo", b"second");
+    foo.should_have_chat("mod", b"exempt");
+}
+
+#[tokio::test]
+async fn full_channels_should_refuse_joins() {
+    let mut config = Config::default();
+    config.channels.max_users.insert("lounge".to_string(), 1);
+    config.roles.moderators.push("mod".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .channel("Lounge", &["foo"])
+        .channel("Cellar", &["mod"])
+        .user("bar")
+        .build()
+        .await;
+    let mut moderator = world.take_client("mod");
+    let mut bar = world.take_client("bar");
+    world
+        .send_command(&moderator, ClientCommand::MaxUsers { max_users: Some(1) })
+        .await;
+    for channel in &["Lounge", "Cellar"] {
+        world
+            .send_command(
+                &bar,
+                ClientCommand::Join {
+                    channel: channel.to_string(),
+                },
+            )
+            .await;
+    }
+    // staff may join anyway
+    world
+        .send_command(
+            &moderator,
+            ClientCommand::Join {
+                channel: "Lounge".to_string(),
+            },
+        )
+        .await;
+    world.shutdown().await;
+    moderator.process_messages().await;
+    bar.process_messages().await;
+
+    moderator.should_have_info_containing("#Cellar now takes at most 1 users");
+    bar.should_have_error("#Lounge is full, try #General instead");
+    bar.should_have_error("#Cellar is full, try #General instead");
+    bar.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+    moderator.should_be_in(&Location::Channel {
+        name: "Lounge".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn returning_users_should_be_back_in_their_channel() {
+    let mut world = TestWorld::builder()
+        .channel("Lounge", &["foo"])
+        .user("bar")
+        .build()
+        .await;
+    let mut foo = world.take_client("foo");
+    let token = foo.wait_for_resume_token().await;
+    world.disconnect(foo).await;
+    let mut foo = world.new_client("foo").await;
+    let mut newcomer = world.new_client("baz").await;
+    // a token only works for the account it was issued to
+    world
+        .send_command(
+            &newcomer,
+            ClientCommand::Resume {
+                token: token.clone(),
+            },
+        )
+        .await;
+    world
+        .send_command(&foo, ClientCommand::Resume { token })
+        .await;
+    world.shutdown().await;
+    foo.process_messages().await;
+    newcomer.process_messages().await;
+
+    foo.should_have_info_containing("Welcome back, you are in #Lounge again");
+    foo.should_be_in(&Location::Channel {
+        name: "Lounge".to_string(),
+    });
+    newcomer.should_not_have_info_containing("Welcome back");
+    newcomer.should_have_error("Invalid or expired resume token");
+    newcomer.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn returning_users_should_need_their_resume_token() {
+    let mut world = TestWorld::builder()
+        .channel("Lounge", &["foo"])
+        .build()
+        .await;
+    let foo = world.take_client("foo");
+    world.disconnect(foo).await;
+    let mut foo = world.new_client("foo").await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Resume {
+                token: "guessed".to_string(),
+            },
+        )
+        .await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_not_have_info_containing("Welcome back");
+    foo.should_have_error("Invalid or expired resume token");
+    foo.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn players_returning_from_a_game_should_be_back_in_their_channel() {
+    let mut world = TestWorld::builder()
+        .channel("Lounge", &["host", "joiner"])
+        .build()
+        .await;
+    let host = world.take_client("host");
+    let mut joiner = world.take_client("joiner");
+    let token = joiner.wait_for_resume_token().await;
+    let game_id = Uuid::new_v4();
+    world.host_game(&host, "MyGame", game_id).await;
+    world.join_game(&joiner, "MyGame", game_id).await;
+    world.start_game(&host, "MyGame").await;
+    world.disconnect(joiner).await;
+    let mut joiner = world.new_client("joiner").await;
+    world
+        .send_command(&joiner, ClientCommand::Resume { token })
+        .await;
+    world.shutdown().await;
+    joiner.process_messages().await;
+
+    joiner.should_have_info_containing("Welcome back, you are in #Lounge again");
+    joiner.should_be_in(&Location::Channel {
+        name: "Lounge".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn announcements_should_reach_everyone() {
+    let mut config = Config::default();
+    config.roles.admins.push("boss".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("boss")
+        .game("MyGame", "foo")
+        .build()
+        .await;
+    let mut boss = world.take_client("boss");
+    let mut foo = world.take_client("foo");
+    for client in &[&foo, &boss] {
+        world
+            .send_command(
+                client,
+                ClientCommand::Announce {
+                    text: b"Restart at 20:00".to_vec(),
+                },
+            )
+            .await;
+    }
+    world.shutdown().await;
+    boss.process_messages().await;
+    foo.process_messages().await;
+
+    foo.should_have_error("Only admins can make announcements");
+    foo.should_have_announcement(b"Restart at 20:00");
+    boss.should_have_announcement(b"Restart at 20:00");
+}
+
+#[tokio::test]
+async fn notices_should_reach_only_their_target() {
+    let mut config = Config::default();
+    config.roles.admins.push("boss".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("boss")
+        .user("foo")
+        .user("bar")
+        .build()
+        .await;
+    let mut boss = world.take_client("boss");
+    let mut foo = world.take_client("foo");
+    let mut bar = world.take_client("bar");
+    let notice = |username: &str| ClientCommand::Notice {
+        username: username.to_string(),
+        text: b"Stop spamming or you will be kicked".to_vec(),
+    };
+    world.send_command(&boss, notice("FOO")).await;
+    world.send_command(&boss, notice("nobody")).await;
+    world.send_command(&bar, notice("foo")).await;
+    world.shutdown().await;
+    boss.process_messages().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    foo.should_have_notice(b"Stop spamming or you will be kicked");
+    boss.should_have_info_containing("Notice sent to foo");
+    boss.should_have_error("User is not online");
+    bar.should_have_error("Only admins can send notices");
+    bar.should_not_have_notices();
+    boss.should_not_have_notices();
+}
+
+#[tokio::test]
+async fn invisible_admins_should_go_unnoticed() {
+    let mut config = Config::default();
+    config.roles.admins.push("boss".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("boss")
+        .user("foo")
+        .channel("Lounge", &["bar"])
+        .build()
+        .await;
+    let mut boss = world.take_client("boss");
+    let mut foo = world.take_client("foo");
+    let mut bar = world.take_client("bar");
+    world
+        .send_command(&foo, ClientCommand::Invisible { enabled: true })
+        .await;
+    world
+        .send_command(&boss, ClientCommand::Invisible { enabled: true })
+        .await;
+    world
+        .send_command(
+            &boss,
+            ClientCommand::Join {
+                channel: "Lounge".to_string(),
+            },
+        )
+        .await;
+    // staying invisible on the next login
+    let token = boss.wait_for_resume_token().await;
+    world.disconnect(boss).await;
+    let mut boss = world.new_client("boss").await;
+    world
+        .send_command(&boss, ClientCommand::Resume { token })
+        .await;
+    world
+        .send_command(
+            &bar,
+            ClientCommand::Send {
+                message: b"nobody here".to_vec(),
+            },
+        )
+        .await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    boss.process_messages().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    foo.should_have_error("Only admins can be invisible");
+    boss.should_have_info_containing("You are invisible, type /invisible off to be seen");
+    boss.should_be_in(&Location::Channel {
+        name: "Lounge".to_string(),
+    });
+    boss.should_have_chat("bar", b"nobody here");
+    foo.should_be_in_sync_with(&snapshot);
+    bar.should_be_in_sync_with(&snapshot);
+    assert!(!snapshot.users.contains_key("boss"));
+}
+
+#[tokio::test]
+async fn invisible_admins_should_seem_offline_to_players() {
+    let mut config = Config::default();
+    config.roles.admins.push("boss".to_string());
+    let mut world = TestWorld::with_config(config);
+    let boss = world.new_client_with_password("boss", "secret").await;
+    let mut foo = world.new_client("foo").await;
+    world
+        .send_command(&boss, ClientCommand::Invisible { enabled: true })
+        .await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Finger {
+                username: "boss".to_string(),
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::PrivateMessage {
+                target: "boss".to_string(),
+                message: b"are you there?".to_vec(),
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Nick {
+                new_name: "boss".to_string(),
+            },
+        )
+        .await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_info_containing("boss is offline");
+    foo.should_not_have_info_containing("boss is online");
+    foo.should_have_info_containing(
+        "boss is offline and will get your message on their next login",
+    );
+    foo.should_have_error("The name boss is taken");
+}
+
+#[tokio::test]
+async fn scheduled_announcements_should_go_to_the_default_channel() {
+    let mut world = TestWorld::builder()
+        .user("foo")
+        .channel("Elsewhere", &["bar"])
+        .build()
+        .await;
+    let mut foo = world.take_client("foo");
+    let mut bar = world.take_client("bar");
+    world
+        .send(Event::ScheduledAnnouncement {
+            text: "Game night on Friday".to_string(),
+        })
+        .await;
+    world.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    foo.should_have_info_containing("Game night on Friday");
+    bar.should_not_have_info_containing("Game night on Friday");
+}
+
+#[tokio::test]
+async fn broadcast_should_only_reach_users_matching_filter() {
+    let mut broker = TestWorld::new();
+    let host = broker.new_client("host").await;
+    let mut chatter = broker.new_client("chatter").await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    let recipients = broker
+        .control(|respond_to| ControlCommand::Broadcast {
+            message: "Welcome to General".to_string(),
+            filter: Some(UserPredicate::parse("channel = general and idle < 1h").unwrap()),
+            respond_to,
+        })
+        .await;
+    broker.shutdown().await;
+    chatter.process_messages().await;
+
+    assert_eq!(recipients, 1);
+    chatter.should_have_info_containing("Welcome to General");
+}
+
+#[tokio::test]
+async fn bots_should_make_room_for_real_users() {
+    let mut config = Config::default();
+    config.bots.enabled = true;
+    config.bots.per_channel = 2;
+    let mut broker = TestWorld::with_config(config);
+    let _foo = broker.new_client("foo").await;
+    let with_one_user = broker
+        .control(|respond_to| ControlCommand::ListUsers { respond_to })
+        .await;
+    let _bar = broker.new_client("bar").await;
+    let with_two_users = broker
+        .control(|respond_to| ControlCommand::ListUsers { respond_to })
+        .await;
+    let status = broker.query_state().await;
+    broker.shutdown().await;
+
+    let bots: Vec<_> = with_one_user.iter().filter(|u| u.bot).collect();
+    assert_eq!(bots.len(), 1);
+    assert!(bots[0].username.starts_with("[bot]"));
+    assert_eq!(bots[0].location, "#General");
+    assert!(with_two_users.iter().all(|u| !u.bot));
+    assert_eq!(status.users_online, 2);
+}
+
+#[tokio::test]
+async fn builds_should_be_counted_by_fingerprint() {
+    let mut config = Config::default();
+    config.builds.rules.push(BuildRule {
+        name: "test build".to_string(),
+        ident_size: Some(0),
+        ..BuildRule::default()
+    });
+    let mut broker = TestWorld::with_config(config);
+    let _foo = broker.new_client("foo").await;
+    let _bar = broker.new_client("bar").await;
+    let users = broker
+        .control(|respond_to| ControlCommand::ListUsers { respond_to })
+        .await;
+    let builds = broker
+        .control(|respond_to| ControlCommand::ListBuilds { respond_to })
+        .await;
+    broker.shutdown().await;
+
+    assert!(users.iter().all(|u| u.build == "test build"));
+    assert_eq!(builds.len(), 1);
+    assert_eq!(builds[0].build, "test build");
+    assert_eq!(builds[0].online, 2);
+    assert_eq!(builds[0].logins, 2);
+}
+
+#[tokio::test]
+async fn daily_stats_should_count_activity() {
+    let mut broker = TestWorld::new();
+    let foo = broker.new_client("foo").await;
+    let _bar = broker.new_client("bar").await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Send {
+                message: b"hi".to_vec(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::PrivateMessage {
+                target: "bar".to_string(),
+                message: b"psst".to_vec(),
+            },
+        )
+        .await;
+    let days = broker
+        .control(|respond_to| ControlCommand::DailyStats { respond_to })
+        .await;
+    broker.shutdown().await;
+
+    // the test may run across midnight
+    assert!(!days.is_empty() && days.len() <= 2);
+    assert_eq!(days.iter().map(|d| d.stats.logins).sum::<u64>(), 2);
+    assert_eq!(days.iter().map(|d| d.stats.messages).sum::<u64>(), 2);
+    assert_eq!(days.iter().map(|d| d.stats.games).sum::<u64>(), 0);
+}
+
+#[tokio::test]
+async fn macros_should_expand_to_chat_messages() {
+    let mut config = Config::default();
+    config.macros.max_count = 1;
+    let mut broker = TestWorld::with_config(config);
+    let mut foo = broker.new_client("foo").await;
+    let mut bar = broker.new_client("bar").await;
+    for action in [
+        MacroAction::Set {
+            name: "gg".to_string(),
+            text: b"Good game everyone!".to_vec(),
+        },
+        MacroAction::Set {
+            name: "hi".to_string(),
+            text: b"Hello".to_vec(),
+        },
+    ] {
+        broker
+            .send_command(&foo, ClientCommand::Macro { action })
+            .await;
+    }
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::ExpandMacro {
+                name: "GG".to_string(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::ExpandMacro {
+                name: "hi".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    bar.should_have_chat("foo", b"Good game everyone!");
+    foo.should_have_error("You cannot have more than 1 macros");
+    foo.should_have_error("No macro named hi");
+}
+
+#[tokio::test]
+async fn joiners_should_get_the_declared_host_port() {
+    let mut broker = TestWorld::new();
+    let custom_host = broker.new_client("custom").await;
+    let default_host = broker.new_client("default").await;
+    let mut joiner = broker.new_client("joiner").await;
+    broker
+        .send_command(&custom_host, ClientCommand::HostPort { port: Some(27000) })
+        .await;
+    broker
+        .host_game(&custom_host, "Custom", Uuid::new_v4())
+        .await;
+    broker
+        .host_game(&default_host, "Default", Uuid::new_v4())
+        .await;
+    for game_name in ["Custom", "Default"] {
+        broker
+            .send_command(
+                &joiner,
+                ClientCommand::JoinGame {
+                    game_name: game_name.to_string(),
+                    password: Vec::new(),
+                    spectator: false,
+                },
+            )
+            .await;
+    }
+    broker.shutdown().await;
+    joiner.process_messages().await;
+
+    joiner.should_have_join_info("Custom", Some(27000));
+    joiner.should_have_join_info("Default", None);
+}
+
+#[tokio::test]
+async fn hidden_host_ip_should_need_approval() {
+    let mut config = Config::default();
+    config.privacy.hide_host_ips = true;
+    let mut broker = TestWorld::with_config(config);
+    let mut host = broker.new_client("host").await;
+    let mut approved = broker.new_client("approved").await;
+    let mut waiting = broker.new_client("waiting").await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    for joiner in [&approved, &waiting] {
+        broker
+            .send_command(
+                joiner,
+                ClientCommand::JoinGame {
+                    game_name: "MyGame".to_string(),
+                    password: Vec::new(),
+                    spectator: false,
+                },
+            )
+            .await;
+    }
+    broker
+        .send_command(
+            &host,
+            ClientCommand::Approve {
+                username: "approved".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    host.process_messages().await;
+    approved.process_messages().await;
+    waiting.process_messages().await;
+
+    host.should_have_info_containing("waiting would like to join your game");
+    waiting.should_have_info_containing("The host has been asked to let you join");
+    waiting.should_not_have_join_info("MyGame");
+    approved.should_have_join_info("MyGame", None);
+}
+
+struct ShoutingPlugin;
+
+impl BrokerPlugin for ShoutingPlugin {
+    fn name(&self) -> &str {
+        "shouting"
+    }
+
+    fn on_message(&mut self, _user: &User, message: &mut Vec<u8>) -> Verdict {
+        message.make_ascii_uppercase();
+        Verdict::Allow
+    }
+
+    fn on_game_created(&mut self, _host: &User, game_name: &str) -> Verdict {
+        if game_name == "Quiet" {
+            Verdict::Veto("Quiet games are not allowed".to_string())
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn on_command(&mut self, _user: &User, command: &str, _params: &[Vec<u8>]) -> Option<String> {
+        Some(format!("pong from {}", command)).filter(|_| command == "ping")
+    }
+}
+
+#[tokio::test]
+async fn plugins_should_modify_and_veto_events() {
+    let mut broker = TestWorld::with_plugins(Config::default(), vec![Box::new(ShoutingPlugin)]);
+    let mut foo = broker.new_client("foo").await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Send {
+                message: b"hello".to_vec(),
+            },
+        )
+        .await;
+    broker.host_game(&foo, "Quiet", Uuid::new_v4()).await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Unknown {
+                command: "ping".to_string(),
+                params: Vec::new(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_chat("foo", b"HELLO");
+    foo.should_have_error("Quiet games are not allowed");
+    foo.should_have_info_containing("pong from ping");
+}
+
+#[tokio::test]
+async fn help_bot_should_answer_questions_and_announce_games() {
+    let mut config = Config::default();
+    config.help_bot.enabled = true;
+    config
+        .help_bot
+        .topics
+        .insert("rules".to_string(), "Be nice".to_string());
+    let mut broker = TestWorld::with_config(config);
+    let mut foo = broker.new_client("foo").await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Send {
+                message: b"!rules".to_vec(),
+            },
+        )
+        .await;
+    assert_eq!(foo.wait_for_chat_from("HelpBot").await, b"Be nice");
+
+    let bar = broker.new_client("bar").await;
+    broker.host_game(&bar, "FFA", Uuid::new_v4()).await;
+    assert_eq!(
+        foo.wait_for_chat_from("HelpBot").await,
+        b"New game: FFA (1/8)"
+    );
+    let status = broker.query_state().await;
+    assert_eq!(status.users_online, 2);
+    broker.shutdown().await;
+}
+
+#[tokio::test]
+async fn find_should_match_names_loosely() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let bar = broker.new_client("bar").await;
+    broker
+        .send_command(
+            &bar,
+            ClientCommand::Join {
+                channel: "Earth Veterans".to_string(),
+            },
+        )
+        .await;
+    broker
+        .host_game(&bar, "eRtH_2150-FFA", Uuid::new_v4())
+        .await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Find {
+                term: "Érth 2150".to_string(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::Find {
+                term: "moon".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_info_containing("$eRtH_2150-FFA (1/8)");
+    foo.should_have_info_containing("No channels or games match moon");
+}
+
+#[tokio::test]
+async fn list_should_show_channels_and_open_games() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let bar = broker.new_client("bar").await;
+    let host = broker.new_client("host").await;
+    broker
+        .send_command(
+            &bar,
+            ClientCommand::Join {
+                channel: "Veterans".to_string(),
+            },
+        )
+        .await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    broker.send_command(&foo, ClientCommand::List).await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_info_containing("#General (1 user)");
+    foo.should_have_info_containing("#Veterans (1 user)");
+    foo.should_have_info_containing("$MyGame (1/8)");
+}
+
+#[tokio::test]
+async fn stats_should_show_server_load() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let host = broker.new_client("host").await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    broker.send_command(&foo, ClientCommand::Stats).await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_info_containing(&format!(
+        "2 users online, 1 open and 0 running games, up for 0m, version {}",
+        ie_net::version::build_info()
+    ));
+}
+
+#[tokio::test]
+async fn version_should_show_build_and_game_versions() {
+    let mut config = Config::default();
+    config.versions.accepted.push(GameVersion {
+        id: Uuid::from_u128(1),
+        name: "community".to_string(),
+    });
+    let mut world = TestWorld::with_config(config);
+    let mut foo = world.new_client("foo").await;
+    world.send_command(&foo, ClientCommand::Version).await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_info_containing(&format!("IE::Net {}", ie_net::version::build_info()));
+    foo.should_have_info_containing(
+        "Game version tmp2.2: 534ba248-a87c-4ce9-8bee-bc376aae6134 (yours)",
+    );
+    foo.should_have_info_containing("Game version community: 00000000-0000-0000-0000-000000000001");
+    foo.should_have_info_containing("Extensions: /hostport");
+}
+
+#[tokio::test]
+async fn service_bot_names_should_be_reserved() {
+    let mut config = Config::default();
+    config.help_bot.enabled = true;
+    let mut broker = TestWorld::with_config(config);
+    let _impostor = broker.new_client("help_bot").await;
+    let _foo = broker.new_client("foo").await;
+    let status = broker.query_state().await;
+    broker.shutdown().await;
+
+    // only foo, bots are not counted
+    assert_eq!(status.users_online, 1);
+}
+
+#[tokio::test]
+async fn reserved_names_should_be_refused_unless_staff() {
+    let mut config = Config::default();
+    config.roles.admins.push("Admin".to_string());
+    let mut world = TestWorld::with_config(config);
+    let mut admin = world.new_client("admin").await;
+    let mut server = world.new_client("Server").await;
+    world.shutdown().await;
+    admin.process_messages().await;
+    server.process_messages().await;
+
+    server.should_be_rejected_with("The name Server is reserved");
+    admin.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn registered_names_should_need_their_password() {
+    let mut world = TestWorld::new();
+    let owner = world.new_client_with_password("foo", "secret").await;
+    world.disconnect(owner).await;
+    let mut impostor = world.new_client("foo").await;
+    world.shutdown().await;
+    impostor.process_messages().await;
+
+    impostor.should_be_rejected_with("The name foo is registered, log in with its password");
+}
+
+#[tokio::test]
+async fn nick_should_rename_the_user_everywhere() {
+    let mut world = TestWorld::new();
+    let mut foo = world.new_client("foo").await;
+    let mut bar = world.new_client("bar").await;
+    let owner = world.new_client_with_password("owner", "secret").await;
+    world.disconnect(owner).await;
+    for new_name in &["foo", "owner", "admin", "bad name"] {
+        world
+            .send_command(
+                &bar,
+                ClientCommand::Nick {
+                    new_name: new_name.to_string(),
+                },
+            )
+            .await;
+    }
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Nick {
+                new_name: "baz".to_string(),
+            },
+        )
+        .await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    assert!(snapshot.users.contains_key("baz"));
+    assert!(!snapshot.users.contains_key("foo"));
+    // bar's list shows baz instead of foo
+    bar.should_be_in_sync_with(&snapshot);
+    foo.should_have_info_containing("You are now known as baz");
+    bar.should_have_error("foo is already online");
+    bar.should_have_error("The name owner is taken");
+    bar.should_have_lobby_error(&LobbyError::NameTaken("owner".to_string()));
+    bar.should_have_error("The name admin is reserved");
+    bar.should_have_error("bad name contains characters not allowed in names");
+}
+
+#[tokio::test]
+async fn nick_should_keep_account_data_under_the_login_name() {
+    let mut world = TestWorld::new();
+    let mut foo = world.new_client("foo").await;
+    let mut bar = world.new_client("bar").await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Clan {
+                action: ClanAction::Create {
+                    tag: "UCS".to_string(),
+                    name: "United Civilized States".to_string(),
+                },
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Nick {
+                new_name: "baz".to_string(),
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &bar,
+            ClientCommand::PrivateMessage {
+                target: "foo".to_string(),
+                message: b"gg".to_vec(),
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Clan {
+                action: ClanAction::Info { tag: None },
+            },
+        )
+        .await;
+    // the login name stays taken while its owner goes by another one
+    let impostor = world.new_client("foo").await;
+    let snapshot = world.dump_state().await;
+    world.disconnect(impostor).await;
+    world.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    assert!(snapshot.users.contains_key("baz"));
+    assert!(!snapshot.users.contains_key("foo"));
+    let channel = Location::Channel {
+        name: "Clan_UCS".to_string(),
+    };
+    foo.should_be_in(&channel);
+    foo.should_have_info_containing("[UCS] United Civilized States, led by foo");
+    foo.should_have_private_message("bar", b"gg");
+}
+
+#[tokio::test]
+async fn protocol_trace_should_be_toggled_per_session() {
+    let mut broker = TestWorld::new();
+    let foo = broker.new_client("foo").await;
+    let bar = broker.new_client("bar").await;
+    assert!(broker.trace_protocol("foo", true).await);
+    foo.should_be_traced(true);
+    bar.should_be_traced(false);
+    assert!(!broker.trace_protocol("baz", true).await);
+    assert!(broker.trace_protocol("foo", false).await);
+    foo.should_be_traced(false);
+    broker.shutdown().await;
+}
+
+#[tokio::test]
+async fn users_moving_on_should_get_all_of_their_old_channel_first() {
+    let mut world = TestWorld::builder().user("foo").user("bar").build().await;
+    let mut foo = world.take_client("foo");
+    let bar = world.take_client("bar");
+    world
+        .send_command(
+            &bar,
+            ClientCommand::Send {
+                message: b"before".to_vec(),
+            },
+        )
+        .await;
+    let _baz = world.new_client("baz").await;
+    world
+        .send_command(
+            &foo,
+            ClientCommand::Join {
+                channel: "Lounge".to_string(),
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &bar,
+            ClientCommand::Send {
+                message: b"after".to_vec(),
+            },
+        )
+        .await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_chat("bar", b"before");
+    foo.should_not_have_chat("bar", b"after");
+    // baz joining General must not end up in the list of the Lounge
+    foo.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn late_joiners_should_see_recent_chat() {
+    let mut config = Config::default();
+    config.channels.history_size = 2;
+    let mut broker = TestWorld::with_config(config);
+    let foo = broker.new_client("foo").await;
+    for message in &["one", "two", "three"] {
+        broker
+            .send_command(
+                &foo,
+                ClientCommand::Send {
+                    message: message.as_bytes().to_vec(),
+                },
+            )
+            .await;
+    }
+    let mut bar = broker.new_client("bar").await;
+    broker.shutdown().await;
+    bar.process_messages().await;
+
+    bar.should_have_chat("foo", b"(earlier) two");
+    bar.should_have_chat("foo", b"(earlier) three");
+    bar.should_not_have_chat("foo", b"(earlier) one");
+}
+
+#[tokio::test]
+async fn test_world_should_set_up_the_lobby() {
+    let mut world = TestWorld::builder()
+        .channel("Tournament", &["foo"])
+        .game("FFA", "bar")
+        .build()
+        .await;
+    let snapshot = world.dump_state().await;
+    let mut foo = world.take_client("foo");
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    assert_eq!(
+        snapshot.users.get("foo"),
+        Some(&Location::Channel {
+            name: "Tournament".to_string()
+        })
+    );
+    assert!(snapshot.open_games.contains("FFA"));
+    foo.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn private_messages_should_be_kept_for_offline_users() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let bar = broker.new_client("bar").await;
+    broker.disconnect(bar).await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::PrivateMessage {
+                target: "bar".to_string(),
+                message: b"gg".to_vec(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::PrivateMessage {
+                target: "nobody".to_string(),
+                message: b"hi".to_vec(),
+            },
+        )
+        .await;
+    let mut bar = broker.new_client("bar").await;
+    broker
+        .send_command(
+            &bar,
+            ClientCommand::Mail {
+                action: MailAction::Read,
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    foo.should_have_info_containing("bar is offline");
+    foo.should_have_error("User does not exist");
+    bar.should_have_private_message("foo", b"gg");
+    bar.should_have_info_containing("from foo: gg");
+}
+
+#[tokio::test]
+async fn finger_should_show_profile() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let mut bar = broker.new_client("bar").await;
+    for (field, value) in [
+        (ProfileField::Faction, Some("UCS")),
+        (ProfileField::Country, Some("Poland")),
+        (
+            ProfileField::Faction,
+            Some("way too long for a faction name"),
+        ),
+    ] {
+        broker
+            .send_command(
+                &foo,
+                ClientCommand::SetInfo {
+                    field,
+                    value: value.map(str::to_string),
+                },
+            )
+            .await;
+    }
+    broker
+        .send_command(
+            &bar,
+            ClientCommand::Finger {
+                username: "FOO".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+
+    foo.should_have_error("Profile entries may be at most 24 characters long");
+    bar.should_have_info_containing("foo is online in #General");
+    bar.should_have_info_containing("Country: Poland");
+    bar.should_have_info_containing("Favorite faction: UCS");
+}
+
+#[tokio::test]
+async fn finger_should_show_the_send_queue_to_admins() {
+    let mut config = Config::default();
+    config.roles.admins.push("boss".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .user("boss")
+        .user("foo")
+        .build()
+        .await;
+    let mut boss = world.take_client("boss");
+    let mut foo = world.take_client("foo");
+    for (client, target) in [(&boss, "foo"), (&foo, "boss")] {
+        world
+            .send_command(
+                client,
+                ClientCommand::Finger {
+                    username: target.to_string(),
+                },
+            )
+            .await;
+    }
+    world.shutdown().await;
+    boss.process_messages().await;
+    foo.process_messages().await;
+
+    boss.should_have_info_containing("Send queue: ");
+    foo.should_not_have_info_containing("Send queue: ");
+}
+
+#[tokio::test]
+async fn clan_members_should_share_a_channel_and_tag() {
+    let mut broker = TestWorld::new();
+    let mut foo = broker.new_client("foo").await;
+    let mut bar = broker.new_client("bar").await;
+    let mut baz = broker.new_client("baz").await;
+    let clan = |action| ClientCommand::Clan { action };
+    broker
+        .send_command(
+            &foo,
+            clan(ClanAction::Create {
+                tag: "UCS".to_string(),
+                name: "United Civilized States".to_string(),
+            }),
+        )
+        .await;
+    broker
+        .send_command(
+            &foo,
+            clan(ClanAction::Invite {
+                username: "bar".to_string(),
+            }),
+        )
+        .await;
+    broker
+        .send_command(
+            &bar,
+            clan(ClanAction::Join {
+                tag: "ucs".to_string(),
+            }),
+        )
+        .await;
+    broker
+        .send_command(
+            &baz,
+            ClientCommand::Join {
+                channel: "Clan_UCS".to_string(),
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &bar,
+            ClientCommand::Send {
+                message: b"hi".to_vec(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    foo.process_messages().await;
+    bar.process_messages().await;
+    baz.process_messages().await;
+
+    let channel = Location::Channel {
+        name: "Clan_UCS".to_string(),
+    };
+    foo.should_be_in(&channel);
+    bar.should_be_in(&channel);
+    bar.should_have_info_containing("foo invites you to [UCS]");
+    foo.should_have_info_containing("bar joined [UCS]");
+    foo.should_have_chat("bar", b"[UCS] hi");
+    baz.should_have_error("Only members of [UCS] may join #Clan_UCS");
+}
+
+#[tokio::test]
+async fn tournament_should_advance_on_game_results() {
+    let mut config = Config::default();
+    config.roles.admins = vec!["boss".to_string()];
+    let mut broker = TestWorld::builder()
+        .config(config)
+        .channel("Tournament", &["boss", "foo", "bar"])
+        .user("baz")
+        .build()
+        .await;
+    let mut boss = broker.take_client("boss");
+    let foo = broker.take_client("foo");
+    let bar = broker.take_client("bar");
+    let mut baz = broker.take_client("baz");
+    let tournament = |action| ClientCommand::Tournament { action };
+    broker
+        .send_command(
+            &boss,
+            tournament(TournamentAction::Create {
+                name: "Cup".to_string(),
+            }),
+        )
+        .await;
+    for player in [&foo, &bar] {
+        broker
+            .send_command(
+                player,
+                ClientCommand::SignUp {
+                    tournament: "cup".to_string(),
+                },
+            )
+            .await;
+    }
+    broker
+        .send_command(
+            &boss,
+            tournament(TournamentAction::Start {
+                name: "Cup".to_string(),
+            }),
+        )
+        .await;
+    broker.host_game(&baz, "Cup R1 M1", Uuid::new_v4()).await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&foo, "Cup R1 M1", game_id).await;
+    broker.join_game(&bar, "Cup R1 M1", game_id).await;
+    broker.start_game(&foo, "Cup R1 M1").await;
+    broker
+        .send_command(
+            &foo,
+            ClientCommand::GameResult {
+                winner: "bar".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    boss.process_messages().await;
+    baz.process_messages().await;
+
+    boss.should_have_info_containing("The tournament Cup is open");
+    boss.should_have_info_containing("foo vs bar in $Cup R1 M1");
+    baz.should_have_error("Cup R1 M1 is reserved for the tournament match foo vs bar");
+    boss.should_have_info_containing("bar won the tournament Cup!");
+}
+
+#[tokio::test]
+async fn tournaments_should_be_announced_in_the_default_channel_without_their_own() {
+    let mut config = Config::default();
+    config.roles.admins = vec!["boss".to_string()];
+    let mut world = TestWorld::with_config(config);
+    let boss = world.new_client("boss").await;
+    let mut foo = world.new_client("foo").await;
+    world
+        .send_command(
+            &boss,
+            ClientCommand::Tournament {
+                action: TournamentAction::Create {
+                    name: "Cup".to_string(),
+                },
+            },
+        )
+        .await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    foo.should_have_info_containing("The tournament Cup is open");
+}
+
+#[tokio::test]
+async fn started_game_should_be_joinable_as_spectator() {
+    let mut config = Config::default();
+    config.games.max_players = 2;
+    config.games.spectators = true;
+    let mut broker = TestWorld::with_config(config);
+    let host = broker.new_client("host").await;
+    let joiner = broker.new_client("joiner").await;
+    let game_id = Uuid::new_v4();
+    broker.host_game(&host, "MyGame", game_id).await;
+    broker.join_game(&joiner, "MyGame", game_id).await;
+    broker.start_game(&host, "MyGame").await;
+    let mut late = broker.new_client("late").await;
+    let mut watcher = broker.new_client("watcher").await;
+    broker
+        .send_command(
+            &late,
+            ClientCommand::JoinGame {
+                game_name: "MyGame".to_string(),
+                password: Vec::new(),
+                spectator: false,
+            },
+        )
+        .await;
+    for password in [Vec::new(), game_id.to_hyphenated().to_string().into_bytes()] {
+        broker
+            .send_command(
+                &watcher,
+                ClientCommand::JoinGame {
+                    game_name: "MyGame".to_string(),
+                    password,
+                    spectator: true,
+                },
+            )
+            .await;
+    }
+    let snapshot = broker.dump_state().await;
+    broker.shutdown().await;
+    late.process_messages().await;
+    watcher.process_messages().await;
+
+    late.should_have_error("Game has already started");
+    late.should_not_have_join_info("MyGame");
+    watcher.should_have_join_info("MyGame", None);
+    assert!(snapshot.open_games.contains("MyGame"));
+    assert_eq!(
+        snapshot.users.get("watcher"),
+        Some(&Location::Game {
+            name: "MyGame".to_string()
+        })
+    );
+    late.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn games_command_should_filter_open_games() {
+    let mut broker = TestWorld::new();
+    let host = broker.new_client("host").await;
+    let other = broker.new_client("other").await;
+    let locked = broker.new_client("locked").await;
+    let mut lister = broker.new_client("lister").await;
+    broker.host_game(&host, "Erth_FFA", Uuid::new_v4()).await;
+    broker.host_game(&other, "Clan War", Uuid::new_v4()).await;
+    broker
+        .send_command(
+            &locked,
+            ClientCommand::HostGame {
+                game_name: "Secret FFA".to_string(),
+                password_or_guid: b"pw".to_vec(),
+                tag: None,
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &locked,
+            ClientCommand::HostGame {
+                game_name: "Secret FFA".to_string(),
+                password_or_guid: Uuid::new_v4().to_hyphenated().to_string().into_bytes(),
+                tag: None,
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &lister,
+            ClientCommand::Games {
+                filter: GameFilter {
+                    name: Some("ffa".to_string()),
+                    without_password: true,
+                    ..GameFilter::default()
+                },
+            },
+        )
+        .await;
+    broker
+        .send_command(
+            &lister,
+            ClientCommand::Games {
+                filter: GameFilter {
+                    name: Some("nothing".to_string()),
+                    ..GameFilter::default()
+                },
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    lister.process_messages().await;
+
+    lister.should_have_info_containing("$Erth_FFA (1/8)");
+    lister.should_not_have_info_containing("Clan War");
+    lister.should_not_have_info_containing("Secret FFA");
+    lister.should_have_info_containing("No open games match");
+}
+
+#[tokio::test]
+async fn hosts_should_be_able_to_tag_their_games() {
+    let mut broker = TestWorld::new();
+    let tagger = broker.new_client("tagger").await;
+    let other = broker.new_client("other").await;
+    let mut sloppy = broker.new_client("sloppy").await;
+    let mut lister = broker.new_client("lister").await;
+    broker.host_game(&other, "Untagged", Uuid::new_v4()).await;
+    let commands = [
+        (&sloppy, "Broken", b"".to_vec()),
+        (&tagger, "Tagged", b"".to_vec()),
+        (
+            &tagger,
+            "Tagged",
+            Uuid::new_v4().to_hyphenated().to_string().into_bytes(),
+        ),
+    ];
+    for (client, game_name, password_or_guid) in commands {
+        let tag = if game_name == "Broken" {
+            "not a tag"
+        } else {
+            "eu"
+        };
+        broker
+            .send_command(
+                client,
+                ClientCommand::HostGame {
+                    game_name: game_name.to_string(),
+                    password_or_guid,
+                    tag: Some(tag.to_string()),
+                },
+            )
+            .await;
+    }
+    broker
+        .send_command(
+            &lister,
+            ClientCommand::Games {
+                filter: GameFilter {
+                    tag: Some("EU".to_string()),
+                    ..GameFilter::default()
+                },
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    sloppy.process_messages().await;
+    lister.process_messages().await;
+
+    sloppy.should_have_error("Game tags may only have up to 8 letters, digits or dashes");
+    lister.should_have_info_containing("$Tagged (1/8, EU)");
+    lister.should_not_have_info_containing("Untagged");
+}
+
+#[tokio::test]
+async fn games_should_be_filtered_by_region() {
+    let mut config = Config::default();
+    config
+        .geoip
+        .regions
+        .insert(Ipv4Addr::new(127, 0, 0, 1), "eu".to_string());
+    let mut world = TestWorld::builder()
+        .config(config)
+        .game("Erth_FFA", "host")
+        .user("lister")
+        .build()
+        .await;
+    let mut lister = world.take_client("lister");
+    world
+        .send_command(
+            &lister,
+            ClientCommand::Games {
+                filter: GameFilter {
+                    same_region: true,
+                    ..GameFilter::default()
+                },
+            },
+        )
+        .await;
+    world
+        .send_command(
+            &lister,
+            ClientCommand::Finger {
+                username: "host".to_string(),
+            },
+        )
+        .await;
+    world.shutdown().await;
+    lister.process_messages().await;
+
+    lister.should_have_info_containing("$Erth_FFA (1/8)");
+    lister.should_have_info_containing("Region: EU");
+
+    // nobody is close to anyone without a region
+    let mut world = TestWorld::builder()
+        .game("Erth_FFA", "host")
+        .user("lister")
+        .build()
+        .await;
+    let mut lister = world.take_client("lister");
+    world
+        .send_command(
+            &lister,
+            ClientCommand::Games {
+                filter: GameFilter {
+                    same_region: true,
+                    ..GameFilter::default()
+                },
+            },
+        )
+        .await;
+    world.shutdown().await;
+    lister.process_messages().await;
+
+    lister.should_have_info_containing("No open games match");
+}
+
+#[tokio::test]
+async fn ping_should_report_host_and_user_latency() {
+    // nothing listens there, so the host answers with ICMP port unreachable
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::default();
+    config.ping.enabled = true;
+    config.ping.port = port;
+    let mut broker = TestWorld::with_config(config);
+    let host = broker.new_client("host").await;
+    let mut pinger = broker.new_client("pinger").await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    broker.dump_state().await;
+    tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+    broker
+        .send_command(
+            &pinger,
+            ClientCommand::Ping {
+                game_name: Some("$MyGame".to_string()),
+            },
+        )
+        .await;
+    broker
+        .send_command(&pinger, ClientCommand::Ping { game_name: None })
+        .await;
+    broker
+        .send_command(
+            &pinger,
+            ClientCommand::Finger {
+                username: "host".to_string(),
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    pinger.process_messages().await;
+
+    pinger.should_have_info_containing("The host of MyGame is");
+    pinger.should_have_info_containing("You are ");
+    pinger.should_have_info_containing("host is online in $MyGame, ");
+}
+
+#[tokio::test]
+async fn games_should_only_be_visible_to_the_same_version() {
+    let community = Uuid::new_v4();
+    let mut config = Config::default();
+    config.versions.accepted.push(GameVersion {
+        id: community,
+        name: "community".to_string(),
+    });
+    let mut broker = TestWorld::with_config(config);
+    let host = broker.new_client("host").await;
+    let mut same = broker.new_client("same").await;
+    let mut other = broker.new_client_with_version("other", community).await;
+    broker.host_game(&host, "MyGame", Uuid::new_v4()).await;
+    broker
+        .send_command(
+            &other,
+            ClientCommand::JoinGame {
+                game_name: "MyGame".to_string(),
+                password: Vec::new(),
+                spectator: false,
+            },
+        )
+        .await;
+    broker.shutdown().await;
+    same.process_messages().await;
+    other.process_messages().await;
+
+    same.should_have_game("MyGame");
+    other.should_not_have_game("MyGame");
+    other.should_have_error("MyGame needs game version tmp2.2");
+    other.should_not_have_join_info("MyGame");
+}
+
+#[tokio::test]
+async fn repeated_wrong_passwords_should_throttle_logins() {
+    let mut config = Config::default();
+    config.login_throttle.enabled = true;
+    config.login_throttle.free_attempts = 1;
+    config.login_throttle.backoff_secs = 60;
+    let mut world = TestWorld::with_config(config);
+    let owner = world.new_client_with_password("foo", "secret").await;
+    world.disconnect(owner).await;
+    let mut first = world.new_client_with_password("foo", "guess").await;
+    let mut second = world.new_client_with_password("foo", "guess").await;
+    let mut owner = world.new_client_with_password("foo", "secret").await;
+    world.shutdown().await;
+    first.process_messages().await;
+    second.process_messages().await;
+    owner.process_messages().await;
+
+    first.should_be_rejected_with("Wrong password");
+    second.should_be_rejected_with("Wrong password");
+    owner.should_be_rejected_with("Too many failed logins, try again in 60 seconds");
+}
+
+#[tokio::test]
+async fn second_logins_should_be_rejected_before_the_password_check() {
+    let mut config = Config::default();
+    config.login_throttle.enabled = true;
+    config.login_throttle.free_attempts = 1;
+    config.login_throttle.backoff_secs = 60;
+    let mut world = TestWorld::with_config(config);
+    let owner = world.new_client_with_password("foo", "secret").await;
+    let mut first = world.new_client_with_password("foo", "guess").await;
+    let mut second = world.new_client_with_password("foo", "guess").await;
+    world.disconnect(owner).await;
+    let mut owner = world.new_client_with_password("foo", "secret").await;
+    world.shutdown().await;
+    first.process_messages().await;
+    second.process_messages().await;
+    owner.process_messages().await;
+
+    first.should_be_rejected_with("Already logged in");
+    second.should_be_rejected_with("Already logged in");
+    owner.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn broker_should_go_on_while_a_password_is_checked() {
+    let mut config = Config::default();
+    // slow enough that the lobby answers long before the hash is done
+    config.accounts.password_hashing.iterations = 200;
+    let mut world = TestWorld::with_config(config);
+    let (send, mut messages) = message_queue();
+    world
+        .event_sender()
+        .send(Event::NewUser {
+            id: Uuid::new_v4(),
+            username: "foo".to_string(),
+            password: "secret".to_string(),
+            game_version: Uuid::new_v4(),
+            ip_addr: Ipv4Addr::new(127, 0, 0, 1),
+            fingerprint: Fingerprint::default(),
+            protocol_trace: ProtocolTrace::default(),
+            send,
+        })
+        .await
+        .unwrap();
+    let status = world.query_state().await;
+    let answered_early = timeout(Duration::from_millis(10), messages.recv())
+        .await
+        .is_ok();
+    let welcome = timeout(Duration::from_secs(60), messages.recv()).await;
+    world.shutdown().await;
+
+    assert_eq!(status.users_online, 0);
+    assert!(
+        !answered_early,
+        "login was answered before the status query"
+    );
+    assert!(welcome.expect("login was not answered").is_some());
+}
+
+#[tokio::test]
+async fn logins_beyond_the_user_limit_should_be_rejected() {
+    let mut config = Config::default();
+    config.limits.max_users = Some(1);
+    let mut world = TestWorld::with_config(config);
+    let foo = world.new_client("foo").await;
+    let mut bar = world.new_client("bar").await;
+    world.disconnect(foo).await;
+    let mut baz = world.new_client("baz").await;
+    world.shutdown().await;
+    bar.process_messages().await;
+    baz.process_messages().await;
+
+    bar.should_be_rejected_with("The server is full");
+    baz.should_be_in(&Location::Channel {
+        name: "General".to_string(),
+    });
+}
+
+#[tokio::test]
+async fn login_should_reach_the_client_in_full_however_busy_the_lobby_is() {
+    let mut config = Config::default();
+    // cheap hashing, as every login registers an account
+    config.accounts.password_hashing.memory_kib = 64;
+    config.accounts.password_hashing.iterations = 1;
+    let mut world = TestWorld::with_config(config);
+    // many more users than fit into the send queue of a client that is not reading yet
+    for i in 0..100 {
+        world.new_client(&format!("user{}", i)).await;
+    }
+    let mut foo = world.new_client("foo").await;
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+    foo.process_messages().await;
+
+    assert_eq!(snapshot.users.len(), 101);
+    foo.should_be_in_sync_with(&snapshot);
+}
+
+#[tokio::test]
+async fn client_that_stops_reading_should_be_evicted() {
+    let mut config = Config::default();
+    config.slow_clients.evict_after_secs = 0;
+    let mut world = TestWorld::with_config(config);
+    let mut slow = world.new_client("slow").await;
+    // skips ahead to the broker's next check for stalled clients
+    tokio::time::pause();
+    // more than fits into the client's queue
+    for i in 0..300 {
+        world
+            .control(|respond_to| ControlCommand::Broadcast {
+                message: format!("announcement {}", i),
+                filter: None,
+                respond_to,
+            })
+            .await;
+    }
+    // the queue ends while the broker is still running, which closes the connection
+    timeout(Duration::from_secs(10), slow.process_messages())
+        .await
+        .expect("slow client was not disconnected");
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+
+    slow.should_have_info_containing("Disconnected for not keeping up with the server");
+    assert!(!snapshot.users.contains_key("slow"));
+}
+
+#[tokio::test]
+async fn client_whose_backlog_runs_full_should_be_evicted() {
+    // far longer than the test takes, so only the full backlog gets the client evicted
+    let mut config = Config::default();
+    config.slow_clients.evict_after_secs = 3600;
+    let mut world = TestWorld::with_config(config);
+    let mut slow = world.new_client("slow").await;
+    tokio::time::pause();
+    for i in 0..SEND_QUEUE_CAPACITY + BACKLOG_CAPACITY + 10 {
+        world
+            .control(|respond_to| ControlCommand::Broadcast {
+                message: format!("announcement {}", i),
+                filter: None,
+                respond_to,
+            })
+            .await;
+    }
+    timeout(Duration::from_secs(10), slow.process_messages())
+        .await
+        .expect("slow client was not disconnected");
+    let snapshot = world.dump_state().await;
+    world.shutdown().await;
+
+    assert!(!snapshot.users.contains_key("slow"));
+}
+
+#[tokio::test]
+async fn recorded_sessions_should_replay_unchanged() {
+    let recording = Recording::parse(include_str!("captures/lounge_chat.txt")).unwrap();
+    // the resume token differs on every login
+    let mut config = Config::default();
+    config.sessions.resume_secs = 0;
+    let mut world = TestWorld::with_config(config);
+    let replay = world.replay(&recording).await;
+    world.shutdown().await;
+
+    replay.should_match_recording();
+}
@@ -0,0 +1,1023 @@
+use anyhow::Result;
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use ie_net::admin_api::AdminClient;
+use ie_net::broker::plugin::{BrokerPlugin, Verdict};
+use ie_net::broker::user::User;
+use ie_net::config::{Config, GameVersion};
+use ie_net::messages::login_client::{IdentClientMessage, LoginClientMessage};
+use ie_net::messages::login_server::{IdentServerMessage, LoginResponse};
+use ie_net::protocol::client::Client;
+use ie_net::server;
+use serde_json::{json, Value};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{delay_for, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+/// Counts the events that reached the broker, so tests know when to pull the plug
+#[derive(Clone, Default)]
+struct Probe {
+    logins: Arc<Mutex<Vec<String>>>,
+    messages: Arc<Mutex<usize>>,
+}
+
+impl Probe {
+    fn logins(&self) -> usize {
+        self.logins.lock().unwrap().len()
+    }
+
+    fn messages(&self) -> usize {
+        *self.messages.lock().unwrap()
+    }
+
+    async fn wait_until(&self, condition: impl Fn(&Probe) -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !condition(self) {
+                delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("broker did not see the expected events");
+    }
+}
+
+impl BrokerPlugin for Probe {
+    fn name(&self) -> &str {
+        "probe"
+    }
+
+    fn on_login(&mut self, user: &User) -> Verdict {
+        self.logins.lock().unwrap().push(user.username.clone());
+        Verdict::Allow
+    }
+
+    fn on_message(&mut self, _user: &User, _message: &mut Vec<u8>) -> Verdict {
+        *self.messages.lock().unwrap() += 1;
+        Verdict::Allow
+    }
+}
+
+/// The real server, started through the same entry point as the binary
+struct RunningServer {
+    addr: String,
+    probe: Probe,
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl RunningServer {
+    async fn start(config: Config) -> Self {
+        Self::start_at(vec![free_addr()], config).await
+    }
+
+    async fn start_at(addrs: Vec<String>, config: Config) -> Self {
+        let server = Self::spawn_at(addrs, config);
+        // wait for the listener to come up
+        drop(server.connect().await);
+        server
+    }
+
+    /// Starts the server without waiting for it to listen
+    fn spawn(config: Config) -> Self {
+        Self::spawn_at(vec![free_addr()], config)
+    }
+
+    /// Clients connect to the first of the addresses unless told otherwise
+    fn spawn_at(addrs: Vec<String>, config: Config) -> Self {
+        let addr = addrs[0].clone();
+        let probe = Probe::default();
+        let (shutdown, shutdown_recv) = oneshot::channel();
+        let handle = tokio::spawn(server::run_until(
+            addrs,
+            config,
+            vec![Box::new(probe.clone())],
+            async {
+                let _ = shutdown_recv.await;
+                Ok(())
+            },
+        ));
+        Self {
+            addr,
+            probe,
+            shutdown,
+            handle,
+        }
+    }
+
+    async fn connect(&self) -> GameConnection {
+        GameConnection {
+            stream: connect_to(&self.addr).await,
+        }
+    }
+
+    async fn login(&self, username: &str, password: &str) -> GameConnection {
+        let mut connection = self.connect().await;
+        connection.ident().await;
+        connection.login(username, password).await;
+        connection
+    }
+
+    /// Shuts the server down and checks that every task it started has finished
+    async fn stop(self) {
+        self.shutdown.send(()).unwrap();
+        let result = timeout(Duration::from_secs(10), self.handle)
+            .await
+            .expect("server tasks are still running after shutdown")
+            .unwrap();
+        assert!(result.is_ok(), "server failed: {:?}", result);
+    }
+}
+
+/// Speaks the game's protocol over a raw socket
+struct GameConnection {
+    stream: TcpStream,
+}
+
+impl GameConnection {
+    async fn ident(&mut self) {
+        self.ident_as(default_version(), "English").await;
+    }
+
+    async fn ident_as(&mut self, game_version: Uuid, language: &str) {
+        let ident = IdentClientMessage {
+            game_version,
+            language: language.as_bytes().to_vec(),
+            extra_bytes: 0,
+        };
+        let bytes = ident.prepare_message().unwrap();
+        self.stream.write_all(&bytes).await.unwrap();
+    }
+
+    async fn login(&mut self, username: &str, password: &str) {
+        let login = LoginClientMessage {
+            username: username.as_bytes().to_vec(),
+            password: password.as_bytes().to_vec(),
+            extra_bytes: 0,
+        };
+        let bytes = login.prepare_message().unwrap();
+        self.stream.write_all(&bytes).await.unwrap();
+    }
+
+    async fn ident_response(&mut self) -> LoginResponse<IdentServerMessage> {
+        let mut received = BytesMut::new();
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(response) = IdentServerMessage::try_parse(&mut received).unwrap() {
+                    return response;
+                }
+                let n = self.stream.read_buf(&mut received).await.unwrap();
+                assert!(n > 0, "server closed the connection");
+            }
+        })
+        .await
+        .expect("server did not answer the ident")
+    }
+
+    async fn command(&mut self, command: &str) -> io::Result<()> {
+        let mut bytes = command.as_bytes().to_vec();
+        bytes.push(0);
+        self.stream.write_all(&bytes).await
+    }
+
+    /// The server discards anything sent to a closed socket and answers with a reset, so
+    /// writing fails eventually. Reading would have to wait for everything the server had
+    /// still queued for the client.
+    async fn should_be_closed(mut self) {
+        timeout(Duration::from_secs(10), async {
+            while self.command("/pong").await.is_ok() {
+                delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("server did not close the connection");
+    }
+}
+
+/// Version 2.2, which the server accepts by default
+fn default_version() -> Uuid {
+    Uuid::parse_str("534ba248-a87c-4ce9-8bee-bc376aae6134").unwrap()
+}
+
+/// Connects to one of the server's listeners, waiting for it to come up
+async fn connect_to(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = std::net::TcpStream::connect(addr) {
+            return TcpStream::from_std(stream).unwrap();
+        }
+        delay_for(Duration::from_millis(10)).await;
+    }
+    panic!("server did not start listening at {}", addr);
+}
+
+/// Skips what the server sends until the given command arrives, returning its parameters
+async fn wait_for_command(client: &mut Client, command: &str) -> Vec<Vec<u8>> {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let received = client.next_command().await.unwrap().unwrap();
+            if received.command == command {
+                return received.params;
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("server never sent {}", command))
+}
+
+/// Waits for the next chat line, past the resume token every login is given
+async fn wait_for_chat(client: &mut Client) -> Vec<Vec<u8>> {
+    loop {
+        let params = wait_for_command(client, "send").await;
+        if !params[1].windows(8).any(|w| w == b"/resume ") {
+            return params;
+        }
+    }
+}
+
+/// Lets the OS pick a free port to hand to a server
+fn free_addr() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string()
+}
+
+fn temp_data_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("ie_net_test_{}", Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn shutdown_when_address_is_taken() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap().to_string();
+    let result = timeout(
+        Duration::from_secs(10),
+        server::run_until(
+            vec![addr],
+            Config::default(),
+            Vec::new(),
+            futures::future::pending(),
+        ),
+    )
+    .await
+    .expect("server did not shut down after failing to listen");
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn shutdown_during_handshake() {
+    let server = RunningServer::start(Config::default()).await;
+    let silent = server.connect().await;
+    let mut greeted = server.connect().await;
+    greeted.ident().await;
+    let mut ident_response = [0u8; 4];
+    greeted
+        .stream
+        .read_exact(&mut ident_response)
+        .await
+        .unwrap();
+
+    server.stop().await;
+
+    silent.should_be_closed().await;
+    greeted.should_be_closed().await;
+}
+
+#[tokio::test]
+async fn shutdown_during_broadcast() {
+    let server = RunningServer::start(Config::default()).await;
+    let mut clients = Vec::new();
+    for name in &["foo", "bar", "baz"] {
+        clients.push(server.login(name, "").await);
+    }
+    server.probe.wait_until(|p| p.logins() == 3).await;
+    for client in &mut clients {
+        for i in 0..20 {
+            client
+                .command(&format!("/send \"hello {}\"", i))
+                .await
+                .unwrap();
+        }
+    }
+    server.probe.wait_until(|p| p.messages() > 0).await;
+
+    server.stop().await;
+
+    for client in clients {
+        client.should_be_closed().await;
+    }
+}
+
+#[tokio::test]
+async fn shutdown_should_close_websocket_clients() {
+    let mut config = Config::default();
+    config.websocket.enabled = true;
+    config.websocket.bind = free_addr();
+    let addr = config.websocket.bind.clone();
+    let server = RunningServer::start(config).await;
+    let stream = connect_to(&addr).await;
+    let url = format!("ws://{}", addr);
+    let (mut websocket, _) = tokio_tungstenite::client_async(url.as_str(), stream)
+        .await
+        .unwrap();
+    websocket
+        .send(Message::Text("/login foo".to_string()))
+        .await
+        .unwrap();
+    server.probe.wait_until(|p| p.logins() == 1).await;
+
+    server.stop().await;
+
+    timeout(Duration::from_secs(5), async {
+        while let Some(Ok(_)) = websocket.next().await {}
+    })
+    .await
+    .expect("server did not close the websocket");
+}
+
+#[tokio::test]
+async fn shutdown_with_full_send_queues() {
+    let server = RunningServer::start(Config::default()).await;
+    let slow = server.login("slow", "").await;
+    slow.stream.set_recv_buffer_size(4096).unwrap();
+    let mut spammer = server.login("spammer", "").await;
+    server.probe.wait_until(|p| p.logins() == 2).await;
+
+    // the slow client never reads, so its queue fills up, which must not hold up anyone else
+    let message = format!("/send \"{}\"", "x".repeat(900));
+    for _ in 0..5000 {
+        timeout(Duration::from_millis(500), spammer.command(&message))
+            .await
+            .expect("a client that stopped reading held up the server")
+            .unwrap();
+    }
+
+    server.stop().await;
+
+    slow.should_be_closed().await;
+    spammer.should_be_closed().await;
+}
+
+#[tokio::test]
+async fn shutdown_with_pending_storage_writes() {
+    let data_dir = temp_data_dir();
+    let mut config = Config::default();
+    config.storage.data_dir = Some(data_dir.clone());
+    let server = RunningServer::start(config).await;
+    let mut clients = Vec::new();
+    for i in 0..20 {
+        clients.push(server.login(&format!("user{}", i), "secret").await);
+    }
+    // every login registers an account, which is saved right away
+    server.probe.wait_until(|p| p.logins() == 20).await;
+
+    server.stop().await;
+
+    let accounts = std::fs::read_to_string(data_dir.join("accounts.json")).unwrap();
+    for i in 0..20 {
+        assert!(accounts.contains(&format!("\"user{}\"", i)));
+    }
+    assert!(!data_dir.join("accounts.json.tmp").exists());
+    for client in clients {
+        client.should_be_closed().await;
+    }
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn totals_should_survive_restarts() {
+    let data_dir = temp_data_dir();
+    let mut config = Config::default();
+    config.storage.data_dir = Some(data_dir.clone());
+    for _ in 0..2 {
+        let server = RunningServer::start(config.clone()).await;
+        let client = server.login("foo", "secret").await;
+        server.probe.wait_until(|p| p.logins() == 1).await;
+        server.stop().await;
+        client.should_be_closed().await;
+    }
+
+    let totals: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(data_dir.join("totals.json")).unwrap())
+            .unwrap();
+    assert_eq!(totals["logins"], 2);
+    assert_eq!(totals["accounts"], 1);
+    assert_eq!(totals["peak_users"], 1);
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn totals_should_start_from_the_existing_accounts() {
+    let data_dir = temp_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(
+        data_dir.join("accounts.json"),
+        r#"[{"username": "old", "password": "secret", "created_at": 0}]"#,
+    )
+    .unwrap();
+    let mut config = Config::default();
+    config.storage.data_dir = Some(data_dir.clone());
+    let server = RunningServer::start(config).await;
+    let client = server.login("new", "secret").await;
+    server.probe.wait_until(|p| p.logins() == 1).await;
+    server.stop().await;
+    client.should_be_closed().await;
+
+    let totals: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(data_dir.join("totals.json")).unwrap())
+            .unwrap();
+    assert_eq!(totals["accounts"], 2);
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn heartbeats_should_reach_the_monitor() {
+    let monitor = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = Config::default();
+    config.heartbeat.enabled = true;
+    config.heartbeat.address = monitor.local_addr().unwrap().to_string();
+    config.heartbeat.name = "test".to_string();
+    let (heartbeat_send, heartbeat_recv) = oneshot::channel();
+    std::thread::spawn(move || {
+        use std::io::{Read, Write};
+        let (mut stream, _) = monitor.accept().unwrap();
+        let mut request = vec![0u8; 4096];
+        let mut received = 0;
+        while !request[..received].ends_with(b"}") {
+            received += stream.read(&mut request[received..]).unwrap();
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let _ = heartbeat_send.send(String::from_utf8_lossy(&request[..received]).to_string());
+    });
+    let server = RunningServer::start(config).await;
+    let heartbeat = timeout(Duration::from_secs(5), heartbeat_recv)
+        .await
+        .expect("no heartbeat arrived")
+        .unwrap();
+    server.stop().await;
+
+    assert!(heartbeat.starts_with("POST /heartbeat HTTP/1.1"));
+    let body = &heartbeat[heartbeat.find("\r\n\r\n").unwrap() + 4..];
+    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(body["name"], "test");
+    assert_eq!(body["status"], "alive");
+    assert_eq!(body["users_online"], 0);
+}
+
+#[tokio::test]
+async fn clients_should_talk_through_the_server() {
+    let server = RunningServer::start(Config::default()).await;
+    let mut foo = Client::connect(&server.addr, "foo", "").await.unwrap();
+    let mut bar = Client::connect(&server.addr, "bar", "").await.unwrap();
+    assert_eq!(foo.welcome().initial_channel, "General");
+    server.probe.wait_until(|p| p.logins() == 2).await;
+
+    foo.say("hello \"bar\"").await.unwrap();
+    let message = wait_for_chat(&mut bar).await;
+    assert_eq!(message, vec![b"foo".to_vec(), b"hello \"bar\"".to_vec()]);
+
+    let rejected = Client::connect(&server.addr, "no spaces", "").await;
+    assert_eq!(
+        rejected.err().unwrap().to_string(),
+        "translateInvalidCharactersInName"
+    );
+
+    server.stop().await;
+    timeout(Duration::from_secs(5), async {
+        while let Ok(Some(_)) = foo.next_command().await {}
+    })
+    .await
+    .expect("server did not close the connection");
+}
+
+#[tokio::test]
+async fn joiners_should_reach_relayed_games_through_the_server() {
+    let mut game_host = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let host_port = game_host.local_addr().unwrap().port();
+    let relay_port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::default();
+    config.relay.enabled = true;
+    config.relay.public_ip = Ipv4Addr::LOCALHOST;
+    config.relay.bind_ip = Ipv4Addr::LOCALHOST;
+    config.relay.port_range_start = relay_port;
+    config.relay.port_range_end = relay_port;
+    let server = RunningServer::start(config).await;
+    let mut host = Client::connect(&server.addr, "host", "").await.unwrap();
+    let mut joiner = Client::connect(&server.addr, "joiner", "").await.unwrap();
+    let version = default_version().to_hyphenated().to_string();
+    let game_id = Uuid::new_v4().to_hyphenated().to_string();
+    host.send("hostport", &[host_port.to_string().as_bytes()])
+        .await
+        .unwrap();
+    host.send("plays", &[version.as_bytes(), b"Relayed", b""])
+        .await
+        .unwrap();
+    host.send(
+        "plays",
+        &[version.as_bytes(), b"Relayed", game_id.as_bytes()],
+    )
+    .await
+    .unwrap();
+    let join_info = timeout(Duration::from_secs(5), async {
+        loop {
+            let command = joiner.next_command().await.unwrap().unwrap();
+            if command.command == "$play" {
+                joiner
+                    .send("playc", &[version.as_bytes(), b"Relayed", b""])
+                    .await
+                    .unwrap();
+            } else if command.command == "playc" {
+                return command.params;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(join_info[5], b"127.0.0.1".to_vec());
+    assert_eq!(join_info[6], relay_port.to_string().into_bytes());
+
+    let mut game_joiner = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    game_joiner
+        .send_to(b"hello", (Ipv4Addr::LOCALHOST, relay_port))
+        .await
+        .unwrap();
+    let mut buf = [0u8; 16];
+    let (len, relayed_from) = timeout(Duration::from_secs(5), game_host.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    game_host.send_to(b"welcome", &relayed_from).await.unwrap();
+    let (len, from) = timeout(Duration::from_secs(5), game_joiner.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"welcome");
+    assert_eq!(from.port(), relay_port);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn chat_should_be_logged_to_disk() {
+    let log_dir = temp_data_dir();
+    let mut config = Config::default();
+    config.chat_log.enabled = true;
+    config.chat_log.dir = log_dir.clone();
+    config.chat_log.skip_channels = vec!["Secret".to_string()];
+    let server = RunningServer::start(config).await;
+    let mut foo = Client::connect(&server.addr, "foo", "").await.unwrap();
+    let mut bar = Client::connect(&server.addr, "bar", "").await.unwrap();
+    server.probe.wait_until(|p| p.logins() == 2).await;
+
+    foo.send("msg", &[b"bar", b"psst"]).await.unwrap();
+    foo.join("Secret").await.unwrap();
+    foo.say("hidden").await.unwrap();
+    foo.join("General").await.unwrap();
+    foo.say("hello").await.unwrap();
+    // once bar sees the last message, the broker has handled all of them
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let command = bar.next_command().await.unwrap().unwrap();
+            if command.command == "send" && command.params[1] == b"hello" {
+                return;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    server.stop().await;
+
+    let log = std::fs::read_to_string(log_dir.join("chat.log")).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2, "unexpected chat log {}", log);
+    assert_eq!(lines[0]["kind"], "private");
+    assert_eq!(lines[0]["from"], "foo");
+    assert_eq!(lines[0]["to"], "bar");
+    assert!(lines[0].get("message").is_none());
+    assert_eq!(lines[1]["kind"], "public");
+    assert_eq!(lines[1]["channel"], "General");
+    assert_eq!(lines[1]["message"], "hello");
+    std::fs::remove_dir_all(&log_dir).unwrap();
+}
+
+#[tokio::test]
+async fn logins_should_be_audited() {
+    let log_dir = temp_data_dir();
+    let mut config = Config::default();
+    config.audit_log.enabled = true;
+    config.audit_log.dir = log_dir.clone();
+    let server = RunningServer::start(config).await;
+    let _foo = Client::connect(&server.addr, "foo", "secret")
+        .await
+        .unwrap();
+    assert!(Client::connect(&server.addr, "foo", "wrong").await.is_err());
+    server.stop().await;
+
+    let log = std::fs::read_to_string(log_dir.join("audit.log")).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3, "unexpected audit log {}", log);
+    assert_eq!(lines[0]["kind"], "login");
+    assert_eq!(lines[0]["username"], "foo");
+    assert_eq!(lines[0]["ip"], "127.0.0.1");
+    assert_eq!(lines[1]["kind"], "join_channel");
+    assert_eq!(lines[1]["channel"], "General");
+    assert_eq!(lines[2]["kind"], "login_rejected");
+    assert_eq!(lines[2]["reason"], "Already logged in");
+    assert!(lines[2]["time"].as_u64().unwrap() > 0);
+    std::fs::remove_dir_all(&log_dir).unwrap();
+}
+
+#[tokio::test]
+async fn rejected_logins_should_be_closed() {
+    let server = RunningServer::start(Config::default()).await;
+    let foo = server.login("foo", "secret").await;
+    server.probe.wait_until(|p| p.logins() == 1).await;
+    server.login("foo", "wrong").await.should_be_closed().await;
+    server.login("foo", "secret").await.should_be_closed().await;
+    drop(foo);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn federated_servers_should_share_their_users() {
+    let east_link = free_addr();
+    let mut config = Config::default();
+    config.federation.enabled = true;
+    config.federation.server_name = "east".to_string();
+    config.federation.secret = "secret".to_string();
+    config.federation.bind = Some(east_link.clone());
+    let east = RunningServer::start(config.clone()).await;
+    config.federation.server_name = "west".to_string();
+    config.federation.bind = None;
+    config.federation.peers = vec![east_link];
+    config.federation.retry_secs = 1;
+    let west = RunningServer::start(config).await;
+    let _foo = Client::connect(&east.addr, "foo", "").await.unwrap();
+    let mut bar = Client::connect(&west.addr, "bar", "").await.unwrap();
+
+    // east's channels arrive with the lobby bar is sent on login or once the link is up
+    while wait_for_command(&mut bar, "$channel").await[0] != b"General@east" {}
+    bar.join("General@east").await.unwrap();
+    let user = wait_for_command(&mut bar, "$user").await;
+    assert_eq!(user[0], b"foo@east");
+    west.stop().await;
+    east.stop().await;
+}
+
+#[tokio::test]
+async fn standby_should_take_over_with_replicated_accounts() {
+    let primary_dir = temp_data_dir();
+    let standby_dir = temp_data_dir();
+    let mut config = Config::default();
+    config.replication.enabled = true;
+    config.replication.secret = "s3cret".to_string();
+    config.replication.takeover_secs = 1;
+    let mut primary_config = config.clone();
+    primary_config.storage.data_dir = Some(primary_dir.clone());
+    primary_config.replication.bind = Some(free_addr());
+    let mut standby_config = config;
+    standby_config.storage.data_dir = Some(standby_dir.clone());
+    standby_config.replication.primary = primary_config.replication.bind.clone();
+
+    let primary = RunningServer::start(primary_config).await;
+    let standby = RunningServer::spawn(standby_config);
+    let _foo = primary.login("foo", "secret").await;
+    primary.probe.wait_until(|p| p.logins() == 1).await;
+    let accounts = standby_dir.join("accounts.json");
+    timeout(Duration::from_secs(5), async {
+        while !std::fs::read_to_string(&accounts)
+            .unwrap_or_default()
+            .contains("\"foo\"")
+        {
+            delay_for(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("account was not replicated to the standby");
+
+    primary.stop().await;
+    let rejected = timeout(Duration::from_secs(10), async {
+        loop {
+            match Client::connect(&standby.addr, "foo", "wrong").await {
+                Err(e) if e.to_string() == "Wrong password" => return,
+                _ => delay_for(Duration::from_millis(100)).await,
+            }
+        }
+    })
+    .await;
+    assert!(rejected.is_ok(), "standby did not take over");
+    Client::connect(&standby.addr, "foo", "secret")
+        .await
+        .unwrap();
+    standby.stop().await;
+
+    std::fs::remove_dir_all(&primary_dir).unwrap();
+    std::fs::remove_dir_all(&standby_dir).unwrap();
+}
+
+#[tokio::test]
+async fn clients_of_any_accepted_version_should_log_in() {
+    let community = Uuid::new_v4();
+    let mut config = Config::default();
+    config.versions.accepted.push(GameVersion {
+        id: community,
+        name: "community".to_string(),
+    });
+    let server = RunningServer::start(config).await;
+    let mut stranger = server.connect().await;
+    stranger.ident_as(Uuid::new_v4(), "English").await;
+    stranger.login("stranger", "").await;
+    let mut newer = server.connect().await;
+    newer.ident_as(community, "English").await;
+    newer.login("newer", "").await;
+    let older = server.login("older", "").await;
+    server.probe.wait_until(|p| p.logins() == 2).await;
+    // give the stranger's login time to arrive, if it was let through
+    delay_for(Duration::from_millis(100)).await;
+    let mut logins = server.probe.logins.lock().unwrap().clone();
+    logins.sort();
+    assert_eq!(logins, vec!["newer".to_string(), "older".to_string()]);
+    drop((stranger, newer, older));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn rejection_should_be_in_the_client_language() {
+    let server = RunningServer::start(Config::default()).await;
+    let mut connection = server.connect().await;
+    connection.ident_as(Uuid::new_v4(), "Deutsch").await;
+    match connection.ident_response().await {
+        LoginResponse::Rejected(reject) => assert_eq!(
+            reject.reason,
+            "Falsche Spielversion. Bitte installiere eine von: tmp2.2"
+        ),
+        LoginResponse::Accepted(_) => panic!("ident with unknown version was accepted"),
+    }
+    drop(connection);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn ipv6_clients_should_be_told_to_use_ipv4() {
+    let addr = std::net::TcpListener::bind("[::1]:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let server = RunningServer::start_at(vec![addr.clone()], Config::default()).await;
+    let mut connection = server.connect().await;
+    connection.ident_as(default_version(), "Deutsch").await;
+    match connection.ident_response().await {
+        // the umlauts arrive in the game's code page
+        LoginResponse::Rejected(reject) => {
+            assert!(reject
+                .reason
+                .starts_with("IPv6-Verbindungen werden nicht unterst"))
+        }
+        LoginResponse::Accepted(_) => panic!("IPv6 client without IPv4 address was accepted"),
+    }
+    drop(connection);
+    server.stop().await;
+
+    let mut config = Config::default();
+    config.ipv6.accept_unmapped = true;
+    let server = RunningServer::start_at(vec![addr], config).await;
+    let mut connection = server.connect().await;
+    connection.ident().await;
+    assert!(matches!(
+        connection.ident_response().await,
+        LoginResponse::Accepted(_)
+    ));
+    drop(connection);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn clients_should_reach_the_server_on_every_bind_address() {
+    let second = free_addr();
+    let server =
+        RunningServer::start_at(vec![free_addr(), second.clone()], Config::default()).await;
+    let _foo = Client::connect(&server.addr, "foo", "").await.unwrap();
+    let _bar = Client::connect(&second, "bar", "").await.unwrap();
+    server.probe.wait_until(|p| p.logins() == 2).await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn server_should_not_start_when_an_extra_address_is_taken() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addrs = vec![free_addr(), taken.local_addr().unwrap().to_string()];
+    let result = timeout(
+        Duration::from_secs(10),
+        server::run_until(
+            addrs,
+            Config::default(),
+            Vec::new(),
+            futures::future::pending(),
+        ),
+    )
+    .await
+    .expect("server did not give up after failing to listen");
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn connections_beyond_the_limit_should_be_closed() {
+    let mut config = Config::default();
+    config.limits.max_clients = Some(1);
+    let server = RunningServer::start(config).await;
+    // the connection that waited for the listener frees its slot once its handler notices
+    delay_for(Duration::from_millis(100)).await;
+    let foo = server.login("foo", "").await;
+    server.probe.wait_until(|p| p.logins() == 1).await;
+    server.connect().await.should_be_closed().await;
+
+    drop(foo);
+    delay_for(Duration::from_millis(100)).await;
+    let _bar = server.login("bar", "").await;
+    server.probe.wait_until(|p| p.logins() == 2).await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn console_should_control_the_server() {
+    let mut config = Config::default();
+    config.console.enabled = true;
+    config.console.bind = free_addr();
+    config.console.password = "letmein".to_string();
+    let console_addr = config.console.bind.clone();
+    let server = RunningServer::start(config).await;
+    let mut foo = Client::connect(&server.addr, "foo", "").await.unwrap();
+    server.probe.wait_until(|p| p.logins() == 1).await;
+
+    let mut console = BufReader::new(connect_to(&console_addr).await);
+    console.write_all(b"letmein\nlist users\n").await.unwrap();
+    let mut line = String::new();
+    while !line.contains("users online") {
+        line.clear();
+        console.read_line(&mut line).await.unwrap();
+    }
+    assert!(
+        line.contains("1 users online"),
+        "unexpected answer {}",
+        line
+    );
+    line.clear();
+    console.read_line(&mut line).await.unwrap();
+    assert!(
+        line.starts_with("foo in #General"),
+        "unexpected answer {}",
+        line
+    );
+
+    console.write_all(b"broadcast maintenance\n").await.unwrap();
+    let notice = wait_for_chat(&mut foo).await[1].clone();
+    assert_eq!(notice, b"maintenance".to_vec());
+
+    console.write_all(b"shutdown 0\n").await.unwrap();
+    let result = timeout(Duration::from_secs(10), server.handle)
+        .await
+        .expect("server did not shut down from the console")
+        .unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn admin_client_should_manage_the_server() {
+    let mut config = Config::default();
+    config.admin_api.enabled = true;
+    config.admin_api.bind = free_addr();
+    config.admin_api.token = "secret".to_string();
+    let admin_addr = config.admin_api.bind.clone();
+    let server = RunningServer::start(config).await;
+    let foo = server.login("foo", "").await;
+    server.probe.wait_until(|p| p.logins() == 1).await;
+    drop(connect_to(&admin_addr).await);
+
+    let client = AdminClient::new(&admin_addr, "secret");
+    let users = client.get("/api/users").await.unwrap();
+    assert_eq!(users[0]["username"], "foo");
+    let kicked = client
+        .post("/api/kick", json!({ "username": "foo" }))
+        .await
+        .unwrap();
+    assert_eq!(kicked["kicked"], true);
+    foo.should_be_closed().await;
+
+    let wrong_token = AdminClient::new(&admin_addr, "guess");
+    assert!(wrong_token.get("/api/users").await.is_err());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn word_list_should_be_reloaded_through_the_admin_api() {
+    let data_dir = temp_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let word_list = data_dir.join("badwords.txt");
+    std::fs::write(&word_list, "darn\n").unwrap();
+    let mut config = Config::default();
+    config.filter.word_list = Some(word_list.clone());
+    config.admin_api.enabled = true;
+    config.admin_api.bind = free_addr();
+    config.admin_api.token = "secret".to_string();
+    let admin_addr = config.admin_api.bind.clone();
+    let server = RunningServer::start(config).await;
+    let mut foo = Client::connect(&server.addr, "foo", "").await.unwrap();
+    let mut bar = Client::connect(&server.addr, "bar", "").await.unwrap();
+    server.probe.wait_until(|p| p.logins() == 2).await;
+
+    std::fs::write(&word_list, "darn\nheck\n").unwrap();
+    let client = AdminClient::new(&admin_addr, "secret");
+    let reloaded = client.post("/api/reload-words", Value::Null).await.unwrap();
+    assert_eq!(reloaded["words"], 2);
+    foo.send("send", &[b"heck"]).await.unwrap();
+    let message = wait_for_chat(&mut bar).await[1].clone();
+    assert_eq!(message, b"****".to_vec());
+
+    std::fs::remove_file(&word_list).unwrap();
+    assert!(client.post("/api/reload-words", Value::Null).await.is_err());
+    server.stop().await;
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_should_be_announced_ahead() {
+    let mut config = Config::default();
+    config.shutdown.notice_secs = 1;
+    let server = RunningServer::start(config).await;
+    let mut foo = Client::connect(&server.addr, "foo", "").await.unwrap();
+    server.probe.wait_until(|p| p.logins() == 1).await;
+
+    let addr = server.addr.clone();
+    let stopped = tokio::spawn(server.stop());
+    let notice = wait_for_chat(&mut foo).await[1].clone();
+    assert_eq!(notice, b"Server shutting down in 1s".to_vec());
+    // the listener closes alongside the notice
+    timeout(Duration::from_secs(5), async {
+        while std::net::TcpStream::connect(&addr).is_ok() {
+            delay_for(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("server kept accepting connections during the notice");
+
+    stopped.await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while let Ok(Some(_)) = foo.next_command().await {}
+    })
+    .await
+    .expect("server did not close the connection");
+}
+
+#[tokio::test]
+async fn capture_should_record_client_traffic() {
+    let capture_dir = temp_data_dir();
+    let mut config = Config::default();
+    config.capture.dir = Some(capture_dir.clone());
+    let server = RunningServer::start(config).await;
+    let client = server.login("foo", "").await;
+    server.probe.wait_until(|p| p.logins() == 1).await;
+    server.stop().await;
+    client.should_be_closed().await;
+
+    // the files are written in the background
+    let captured = timeout(Duration::from_secs(5), async {
+        loop {
+            let files = std::fs::read_dir(&capture_dir).unwrap();
+            let contents: Vec<String> = files
+                .map(|f| std::fs::read_to_string(f.unwrap().path()).unwrap())
+                .collect();
+            if let Some(login) = contents.into_iter().find(|c| c.contains(">> ")) {
+                return login;
+            }
+            delay_for(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no capture of the login");
+    assert!(captured.starts_with("# client "));
+    assert!(captured.contains(" << "));
+    assert!(captured.contains(" 66 6f 6f 00"));
+}